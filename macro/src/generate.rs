@@ -4,19 +4,68 @@ use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, quote_spanned};
 
 pub fn generate(syntax: Vec<(Syntax, Span)>) -> TokenStream {
-    let mut tokens = quote!(::bitcoin_script::Script::new(::bitcoin_script::function_name!()));
+    // Statements (subroutine definitions, let bindings) split the builder chain into several
+    // `let script_var = ...;` bindings. Without them the output is a single expression.
+    let mut statements = TokenStream::new();
+    let mut tokens = quote!(
+        ::bitcoin_script::Script::new(::bitcoin_script::function_name!())
+            .set_source_location(::core::file!(), ::core::line!())
+    );
+    // Metadata describes the whole script, so it is attached after all pushes
+    let mut metadata = TokenStream::new();
+    let mut folded = vec![];
 
     for (item, span) in syntax {
         let push = match item {
             Syntax::Opcode(opcode) => generate_opcode(opcode, span),
             Syntax::Bytes(bytes) => generate_bytes(bytes, span),
             Syntax::Int(int) => generate_int(int, span),
+            Syntax::Folded(expression, int) => {
+                // Plain literals are not worth listing
+                if expression != int.to_string() {
+                    folded.push(format!("{} = {}", expression, int));
+                }
+                generate_int(int, span)
+            }
             Syntax::Escape(expression) => generate_escape(expression, span),
+            Syntax::AssertStackDelta(delta) => {
+                quote_spanned!(span=> .assert_stack_delta(#delta))
+            }
+            Syntax::Repeat(count, body) => generate_repeat(count, body, span),
+            Syntax::ChunkHint => quote_spanned!(span=> .push_chunk_hint()),
+            Syntax::Def(name, body) => {
+                statements.extend(quote!(let script_var = #tokens;));
+                statements.extend(generate_def(name, body, span));
+                tokens = quote!(script_var);
+                continue;
+            }
+            Syntax::Metadata(key, value) => {
+                metadata.extend(quote_spanned!(span=> .set_metadata(#key, #value)));
+                continue;
+            }
+            Syntax::Let(binding) => {
+                statements.extend(quote!(let script_var = #tokens;));
+                statements.extend(quote_spanned!(span=> let #binding;));
+                tokens = quote!(script_var);
+                continue;
+            }
         };
         tokens.extend(push);
     }
+    if !folded.is_empty() {
+        let folded = folded.join("; ");
+        metadata.extend(quote!(.set_metadata(::bitcoin_script::builder::FOLDED, #folded)));
+    }
+    tokens.extend(metadata);
     // tokens.extend(quote! {.analyze_stack()}); // for debug
-    tokens
+    if statements.is_empty() {
+        tokens
+    } else {
+        quote!({
+            #statements
+            #tokens
+        })
+    }
 }
 
 fn generate_opcode(opcode: Opcode, span: Span) -> TokenStream {
@@ -43,3 +92,29 @@ fn generate_escape(expression: TokenStream, span: Span) -> TokenStream {
             .push_expression(#expression)
     )
 }
+
+fn generate_repeat(count: TokenStream, body: TokenStream, span: Span) -> TokenStream {
+    quote_spanned!(span=>
+        .push_repeated(#count, {
+            let mut block = ::bitcoin_script::script! { #body };
+            block.debug_identifier = format!("{}::repeat", ::bitcoin_script::function_name!());
+            block
+        })
+    )
+}
+
+fn generate_def(name: Ident, body: TokenStream, span: Span) -> TokenStream {
+    // The subroutine is built once and every call pushes a clone of it. Calls are
+    // plain identifiers which the parser already turns into `name()`.
+    quote_spanned!(span=>
+        let #name = {
+            let mut subroutine = ::bitcoin_script::script! { #body };
+            subroutine.debug_identifier = format!(
+                "{}::{}",
+                ::bitcoin_script::function_name!(),
+                stringify!(#name)
+            );
+            move || subroutine.clone()
+        };
+    )
+}
@@ -0,0 +1,239 @@
+use super::parse::parse_opcode;
+use bitcoin::{
+    blockdata::constants::MAX_SCRIPT_ELEMENT_SIZE,
+    blockdata::opcodes::{all::*, Opcode},
+    script::{Builder, Instruction, PushBytes, PushBytesBuf, Script},
+};
+use proc_macro2::{Literal, TokenStream, TokenTree};
+use proc_macro_error::abort;
+use quote::quote;
+use std::path::Path;
+
+pub fn include_script(tokens: TokenStream) -> TokenStream {
+    let mut tokens = tokens.into_iter();
+    let (literal, relative_path) = match (tokens.next(), tokens.next()) {
+        (Some(TokenTree::Literal(literal)), None) => {
+            let path = literal.to_string();
+            match path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+                Some(path) => (literal.clone(), path.to_string()),
+                None => abort!(literal.span(), "expected a string literal"),
+            }
+        }
+        _ => abort!(
+            proc_macro2::Span::call_site(),
+            "expected a single file path, e.g. `include_script!(\"gadget.asm\")`"
+        ),
+    };
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let path = Path::new(&manifest_dir).join(&relative_path);
+    let content = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+        abort!(literal.span(), "unable to read {} ({})", path.display(), err);
+    });
+
+    let is_hex = path.extension().is_some_and(|ext| ext == "hex");
+    let bytes = if is_hex {
+        parse_hex(&content)
+    } else {
+        parse_asm(&content)
+    }
+    .and_then(|bytes| check_push_sizes(&bytes).map(|_| bytes))
+    .unwrap_or_else(|err| abort!(literal.span(), "{}: {}", relative_path, err));
+
+    // Referencing the file makes cargo rebuild the script whenever it changes
+    let absolute_path = Literal::string(&path.to_string_lossy());
+    quote! {
+        {
+            const _: &[u8] = include_bytes!(#absolute_path);
+            ::bitcoin_script::Script::new(#literal)
+                .set_source_location(::core::file!(), ::core::line!())
+                .push_script(::bitcoin::ScriptBuf::from_bytes(vec![#(#bytes),*]))
+        }
+    }
+}
+
+/// Parses a hex encoded script, ignoring whitespace.
+fn parse_hex(content: &str) -> Result<Vec<u8>, String> {
+    let hex: String = content.split_whitespace().collect();
+    let hex = hex.strip_prefix("0x").unwrap_or(&hex);
+    hex::decode(hex).map_err(|err| format!("invalid hex ({})", err))
+}
+
+/// Parses a script in ASM format, i.e. whitespace separated opcodes, decimal integers and
+/// hex pushes. Hex pushes are either prefixed with `0x` or follow an explicit push opcode
+/// as in `OP_PUSHBYTES_2 abcd`. Everything after a `#` is a comment.
+///
+/// Every push is minimally encoded, so that the script compiles: `0x` pushes get their
+/// shortest encoding, and explicit push opcodes that are not the shortest are rejected.
+fn parse_asm(content: &str) -> Result<Vec<u8>, String> {
+    let mut builder = Builder::new();
+    let mut words = content
+        .lines()
+        .flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace());
+
+    while let Some(word) = words.next() {
+        builder = if let Some(hex) = word.strip_prefix("0x") {
+            push_minimal(builder, &parse_push(hex)?)
+        } else if let Ok(n) = word.parse::<i64>() {
+            builder.push_int(n)
+        } else if let Ok(opcode) = parse_opcode(word) {
+            match expected_push_len(opcode) {
+                Some(expected_len) => {
+                    let hex = words
+                        .next()
+                        .ok_or_else(|| format!("expected data after {}", word))?;
+                    let data = parse_push(hex)?;
+                    if let Some(len) = expected_len.filter(|len| *len != data.len()) {
+                        return Err(format!(
+                            "{} expects {} bytes, got {}",
+                            word,
+                            len,
+                            data.len()
+                        ));
+                    }
+                    let minimal = push_minimal(Builder::new(), &data).into_bytes()[0];
+                    if minimal != opcode.to_u8() {
+                        return Err(format!(
+                            "{} is not the minimal push of {} bytes, use {}",
+                            word,
+                            data.len(),
+                            Opcode::from(minimal)
+                        ));
+                    }
+                    push_minimal(builder, &data)
+                }
+                None => builder.push_opcode(opcode),
+            }
+        } else {
+            return Err(format!("unknown opcode \"{}\"", word));
+        };
+    }
+
+    Ok(builder.into_bytes())
+}
+
+/// Pushes the data with its shortest encoding, like `StructuredScript::push_bytes`.
+fn push_minimal(builder: Builder, data: &PushBytes) -> Builder {
+    match data.as_bytes() {
+        [n @ 1..=16] => builder.push_opcode(Opcode::from(OP_PUSHNUM_1.to_u8() + n - 1)),
+        [0x81] => builder.push_opcode(OP_PUSHNUM_NEG1),
+        _ => builder.push_slice(data),
+    }
+}
+
+fn parse_push(hex: &str) -> Result<PushBytesBuf, String> {
+    let data = hex::decode(hex).map_err(|err| format!("invalid hex push {} ({})", hex, err))?;
+    PushBytesBuf::try_from(data).map_err(|err| format!("invalid hex push {} ({})", hex, err))
+}
+
+/// Rejects pushes of more than 520 bytes, which make the script fail when it is executed.
+fn check_push_sizes(bytes: &[u8]) -> Result<(), String> {
+    let script = Script::from_bytes(bytes);
+    for instruction in script.instruction_indices() {
+        let (offset, instruction) =
+            instruction.map_err(|err| format!("invalid script ({})", err))?;
+        if let Instruction::PushBytes(data) = instruction {
+            if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                return Err(format!(
+                    "push of {} bytes at offset {} exceeds the maximum stack element size of {} bytes",
+                    data.len(),
+                    offset,
+                    MAX_SCRIPT_ELEMENT_SIZE
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns `Some` for opcodes which are followed by data in ASM, along with the data length
+/// if the opcode implies one.
+fn expected_push_len(opcode: Opcode) -> Option<Option<usize>> {
+    let byte = opcode.to_u8();
+    if (OP_PUSHBYTES_1.to_u8()..=OP_PUSHBYTES_75.to_u8()).contains(&byte) {
+        Some(Some(byte as usize))
+    } else if [OP_PUSHDATA1, OP_PUSHDATA2, OP_PUSHDATA4].contains(&opcode) {
+        Some(None)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_asm_script() {
+        let bytes = parse_asm(
+            "# hash lock
+            OP_SHA256 OP_PUSHBYTES_2 abcd OP_EQUALVERIFY
+            100 OP_CSV OP_DROP 0x0102 -1 EQUAL",
+        )
+        .unwrap();
+
+        assert_eq!(
+            bytes,
+            vec![168, 2, 0xab, 0xcd, 136, 1, 100, 178, 117, 2, 1, 2, 79, 135]
+        );
+    }
+
+    #[test]
+    fn parse_minimal_pushes() {
+        let asm = format!(
+            "0x05 0x81 0x00 0x OP_PUSHBYTES_1 00 OP_PUSHDATA1 {}",
+            "ab".repeat(76)
+        );
+        let bytes = parse_asm(&asm).unwrap();
+        // OP_5 OP_1NEGATE, the zero byte, OP_0, the zero byte again and OP_PUSHDATA1
+        assert_eq!(
+            bytes[..9],
+            [0x55, 0x4f, 0x01, 0x00, 0x00, 0x01, 0x00, 0x4c, 76]
+        );
+        assert_eq!(
+            parse_asm("OP_PUSHBYTES_1 05").unwrap_err(),
+            "OP_PUSHBYTES_1 is not the minimal push of 1 bytes, use OP_PUSHNUM_5"
+        );
+        assert_eq!(
+            parse_asm("OP_PUSHDATA1 0102").unwrap_err(),
+            "OP_PUSHDATA1 is not the minimal push of 2 bytes, use OP_PUSHBYTES_2"
+        );
+    }
+
+    #[test]
+    fn parse_asm_errors() {
+        assert_eq!(
+            parse_asm("OP_DUP OP_FOO").unwrap_err(),
+            "unknown opcode \"OP_FOO\""
+        );
+        assert_eq!(
+            parse_asm("OP_PUSHBYTES_3 abcd").unwrap_err(),
+            "OP_PUSHBYTES_3 expects 3 bytes, got 2"
+        );
+        assert_eq!(
+            parse_asm("OP_PUSHDATA1").unwrap_err(),
+            "expected data after OP_PUSHDATA1"
+        );
+    }
+
+    #[test]
+    fn parse_hex_script() {
+        assert_eq!(parse_hex("0x76a9\n14").unwrap(), vec![0x76, 0xa9, 0x14]);
+        assert!(parse_hex("76a").is_err());
+    }
+
+    #[test]
+    fn reject_oversized_pushes() {
+        let max = parse_asm(&format!("OP_DUP 0x{}", "ab".repeat(520))).unwrap();
+        assert_eq!(check_push_sizes(&max), Ok(()));
+        let oversized = parse_asm(&format!("OP_DUP OP_PUSHDATA2 {}", "ab".repeat(521))).unwrap();
+        assert_eq!(
+            check_push_sizes(&oversized).unwrap_err(),
+            "push of 521 bytes at offset 1 exceeds the maximum stack element size of 520 bytes"
+        );
+        assert_eq!(
+            check_push_sizes(&[OP_PUSHDATA1.to_u8(), 2, 0xab]).unwrap_err(),
+            "invalid script (unexpected end of script)"
+        );
+    }
+}
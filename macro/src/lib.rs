@@ -1,4 +1,5 @@
 mod generate;
+mod include;
 mod parse;
 
 use generate::generate;
@@ -13,3 +14,13 @@ pub fn script(tokens: TokenStream) -> TokenStream {
     set_dummy(quote!((::bitcoin::Script::new())));
     generate(parse(tokens.into())).into()
 }
+
+/// Loads a script from a file at compile time. The path is relative to the crate root.
+/// Files with a `.hex` extension contain the hex encoded script, all other files are
+/// read as ASM.
+#[proc_macro]
+#[proc_macro_error]
+pub fn include_script(tokens: TokenStream) -> TokenStream {
+    set_dummy(quote!((::bitcoin_script::Script::new(""))));
+    include::include_script(tokens.into()).into()
+}
@@ -1,12 +1,13 @@
 use bitcoin::{
-    blockdata::opcodes::Opcode,
+    blockdata::{constants::MAX_SCRIPT_ELEMENT_SIZE, opcodes::Opcode},
+    hashes::{hash160, sha256, Hash},
     opcodes::{all::*, OP_0, OP_FALSE, OP_NOP2, OP_NOP3, OP_TRUE},
 };
 use proc_macro2::{
-    Delimiter, Span, TokenStream,
+    Delimiter, Ident, Span, TokenStream,
     TokenTree::{self, *},
 };
-use quote::quote;
+use quote::{quote, quote_spanned};
 use std::iter::Peekable;
 use std::str::FromStr;
 
@@ -16,6 +17,13 @@ pub enum Syntax {
     Escape(TokenStream),
     Bytes(Vec<u8>),
     Int(i64),
+    Folded(String, i64),
+    Def(Ident, TokenStream),
+    Let(TokenStream),
+    AssertStackDelta(TokenStream),
+    Repeat(TokenStream, TokenStream),
+    Metadata(String, String),
+    ChunkHint,
 }
 
 macro_rules! emit_error {
@@ -46,7 +54,7 @@ macro_rules! abort {
 /// Generates a function that parses a string into an [`Opcode`].
 macro_rules! generate_opcode_parser {
     ($($op:ident => $val:expr, $doc:expr);*) => {
-        fn parse_opcode(s: &str) -> Result<Opcode, ()> {
+        pub fn parse_opcode(s: &str) -> Result<Opcode, ()> {
             match s {
                 // Special cases with aliases
                 "OP_0" => Ok(OP_0),
@@ -353,11 +361,45 @@ pub fn parse(tokens: TokenStream) -> Vec<(Syntax, Span)> {
             (Ident(_), "for") => parse_for_loop(token, &mut tokens),
             // Wrap if-else statements such that they return a Vec<ScriptBuf>
             (Ident(_), "if") => parse_if(token, &mut tokens),
+            // Wrap while loops like for loops, but cap the number of iterations
+            (Ident(_), "while") => parse_while_loop(token, &mut tokens),
+            // Wrap match statements such that every arm returns a script
+            (Ident(_), "match") => parse_match(token, &mut tokens),
+            // Named subroutine that can be called by its name later on
+            (Ident(_), "def") => parse_def(token, &mut tokens),
+            // Local binding for values that are pushed later on
+            (Ident(_), "let") => parse_let(token, &mut tokens),
             // Replace DEBUG with OP_RESERVED
-            (Ident(_), "DEBUG") => {
-                (Syntax::Opcode(OP_RESERVED), token.span())
+            (Ident(_), "DEBUG") => (Syntax::Opcode(OP_RESERVED), token.span()),
+
+            // Check the stack effect of the script so far
+            (Ident(_), "assert_stack_delta") if is_macro_call(tokens.peek()) => {
+                parse_directive(token, &mut tokens, Syntax::AssertStackDelta)
+            }
+
+            // Repeat a script without storing it more than once
+            (Ident(_), "repeat") if is_macro_call(tokens.peek()) => {
+                parse_directive(token, &mut tokens, parse_repeat)
             }
 
+            // Preferred chunk border
+            (Ident(_), "chunk_hint") if is_macro_call(tokens.peek()) => {
+                parse_directive(token, &mut tokens, parse_chunk_hint)
+            }
+
+            // Raw opcode byte, e.g. `opcode!(0xbb)` for OP_SUCCESS experiments
+            (Ident(_), "opcode") if is_macro_call(tokens.peek()) => {
+                parse_directive(token, &mut tokens, parse_raw_opcode)
+            }
+
+            // Push the digest of some data
+            (Ident(_), "sha256" | "hash160") if is_macro_call(tokens.peek()) => {
+                parse_directive(token, &mut tokens, |args| parse_hash(&token_str, args))
+            }
+
+            // macro invocation, e.g. `include_script!(...)`
+            (Ident(_), _) if is_macro_call(tokens.peek()) => parse_macro_call(token, &mut tokens),
+
             // identifier, look up opcode
             (Ident(_), _) => match parse_opcode(&token_str) {
                 Ok(opcode) => (Syntax::Opcode(opcode), token.span()),
@@ -371,15 +413,24 @@ pub fn parse(tokens: TokenStream) -> Vec<(Syntax, Span)> {
 
             (Group(inner), _) => {
                 let escape = inner.stream().clone();
-                (Syntax::Escape(escape), token.span())
+                (escape_or_constant(escape), token.span())
             }
 
+            // doc comment, `/// @key: value` attaches metadata to the script
+            (Punct(_), "#") => match parse_doc_comment(token, &mut tokens) {
+                Some(metadata) => metadata,
+                None => continue,
+            },
+
             // '<', start of escape (parse until first '>')
             (Punct(_), "<") => parse_escape(token, &mut tokens),
 
             // '~' start of escape (parse until the next '~') ignores '<' and '>'
             (Punct(_), "~") => parse_escape_extra(token, &mut tokens),
 
+            // string literal, push its UTF-8 bytes
+            (Literal(_), s) if is_string_literal(s) => parse_string(token),
+
             // literal, push data (int or bytes)
             (Literal(_), _) => parse_data(token),
 
@@ -397,9 +448,9 @@ fn parse_if<T>(token: TokenTree, tokens: &mut Peekable<T>) -> (Syntax, Span)
 where
     T: Iterator<Item = TokenTree>,
 {
-    // Use a Vec here to get rid of warnings when the variable is overwritten
+    // The condition is evaluated in Rust, so only the taken branch ends up in the script
     let mut escape = quote! {
-        let mut script_var = bitcoin_script::Script::new("if");
+        let mut script_var = ::bitcoin_script::Script::new("if");
     };
     escape.extend(std::iter::once(token.clone()));
 
@@ -409,7 +460,7 @@ where
                 let inner_block = block.stream();
                 escape.extend(quote! {
                     {
-                        script_var = script_var.push_env_script(script! {
+                        script_var = script_var.push_env_script(::bitcoin_script::script! {
                             #inner_block
                         });
                     }
@@ -440,7 +491,7 @@ where
     T: Iterator<Item = TokenTree>,
 {
     let mut escape = quote! {
-        let mut script_var = bitcoin_script::Script::new("for");
+        let mut script_var = ::bitcoin_script::Script::new("for");
     };
     escape.extend(std::iter::once(token.clone()));
 
@@ -450,7 +501,7 @@ where
                 let inner_block = block.stream();
                 escape.extend(quote! {
                     {
-                        script_var = script_var.push_env_script(script !{
+                        script_var = script_var.push_env_script(::bitcoin_script::script! {
                             #inner_block
                         });
                     }
@@ -468,6 +519,301 @@ where
     (Syntax::Escape(quote! { { #escape } }), token.span())
 }
 
+fn parse_while_loop<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
+where
+    T: Iterator<Item = TokenTree>,
+{
+    let mut condition = TokenStream::new();
+    let body = loop {
+        match tokens.next() {
+            Some(Group(block)) if block.delimiter() == Delimiter::Brace => break block.stream(),
+            Some(token) => condition.extend(std::iter::once(token)),
+            None => abort!(token.span(), "expected while loop body"),
+        }
+    };
+
+    let escape = quote! {
+        {
+            let mut script_var = ::bitcoin_script::Script::new("while");
+            let mut while_iterations = 0usize;
+            while #condition {
+                while_iterations += 1;
+                assert!(
+                    while_iterations <= ::bitcoin_script::MAX_WHILE_ITERATIONS,
+                    "while loop exceeded {} iterations",
+                    ::bitcoin_script::MAX_WHILE_ITERATIONS
+                );
+                script_var = script_var.push_env_script(::bitcoin_script::script! {
+                    #body
+                });
+            }
+            script_var
+        }
+    };
+    (Syntax::Escape(escape), token.span())
+}
+
+fn parse_match<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
+where
+    T: Iterator<Item = TokenTree>,
+{
+    let mut scrutinee = TokenStream::new();
+    let arms = loop {
+        match tokens.next() {
+            Some(Group(arms)) if arms.delimiter() == Delimiter::Brace => break arms,
+            Some(token) => scrutinee.extend(std::iter::once(token)),
+            None => abort!(token.span(), "expected match arms"),
+        }
+    };
+
+    let mut escape = TokenStream::new();
+    let mut arm_tokens = arms.stream().into_iter().peekable();
+    while arm_tokens.peek().is_some() {
+        // Everything up to `=>` is the pattern including an optional guard
+        let mut pattern = TokenStream::new();
+        loop {
+            match arm_tokens.next() {
+                Some(Punct(punct))
+                    if punct.as_char() == '=' && is_fat_arrow(&punct, arm_tokens.peek()) =>
+                {
+                    arm_tokens.next();
+                    break;
+                }
+                Some(token) => pattern.extend(std::iter::once(token)),
+                None => abort!(arms.span(), "expected `=>` in match arm"),
+            }
+        }
+
+        // The body is either a block or everything up to the next `,`
+        let body = match arm_tokens.next() {
+            Some(Group(block)) if block.delimiter() == Delimiter::Brace => {
+                if let Some(Punct(punct)) = arm_tokens.peek() {
+                    if punct.as_char() == ',' {
+                        arm_tokens.next();
+                    }
+                }
+                block.stream()
+            }
+            Some(token) => {
+                let mut body = TokenStream::from(token);
+                for token in arm_tokens.by_ref() {
+                    if let Punct(punct) = &token {
+                        if punct.as_char() == ',' {
+                            break;
+                        }
+                    }
+                    body.extend(std::iter::once(token));
+                }
+                body
+            }
+            None => abort!(arms.span(), "expected match arm body"),
+        };
+
+        escape.extend(quote! {
+            #pattern => ::bitcoin_script::script! { #body },
+        });
+    }
+
+    (
+        Syntax::Escape(quote! { match #scrutinee { #escape } }),
+        token.span(),
+    )
+}
+
+fn is_fat_arrow(punct: &proc_macro2::Punct, next: Option<&TokenTree>) -> bool {
+    punct.spacing() == proc_macro2::Spacing::Joint
+        && matches!(next, Some(Punct(next)) if next.as_char() == '>')
+}
+
+fn parse_def<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
+where
+    T: Iterator<Item = TokenTree>,
+{
+    let name = match tokens.next() {
+        Some(Ident(name)) => name,
+        _ => abort!(token.span(), "expected subroutine name after `def`"),
+    };
+    let body = match tokens.next() {
+        Some(Group(block)) if block.delimiter() == Delimiter::Brace => block.stream(),
+        _ => abort!(name.span(), "expected `{{ ... }}` after subroutine name"),
+    };
+    let span = token.span().join(name.span()).unwrap_or(token.span());
+
+    (Syntax::Def(name, body), span)
+}
+
+fn parse_let<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
+where
+    T: Iterator<Item = TokenTree>,
+{
+    let mut binding = TokenStream::new();
+    let mut span = token.span();
+
+    loop {
+        let token = tokens
+            .next()
+            .unwrap_or_else(|| abort!(token.span(), "expected `;` after let binding"));
+        span = span.join(token.span()).unwrap_or(token.span());
+
+        if let Punct(punct) = &token {
+            if punct.as_char() == ';' {
+                break;
+            }
+        }
+        binding.extend(TokenStream::from(token));
+    }
+
+    (Syntax::Let(binding), span)
+}
+
+fn is_macro_call(next: Option<&TokenTree>) -> bool {
+    matches!(next, Some(Punct(punct)) if punct.as_char() == '!')
+}
+
+/// Parses a built-in `name!(args)` directive, passing its arguments to `syntax`.
+fn parse_directive<T, F>(token: TokenTree, tokens: &mut T, syntax: F) -> (Syntax, Span)
+where
+    T: Iterator<Item = TokenTree>,
+    F: FnOnce(TokenStream) -> Syntax,
+{
+    // Skip the `!`
+    tokens.next();
+    let args = match tokens.next() {
+        Some(Group(args)) if args.delimiter() == Delimiter::Parenthesis => args,
+        _ => abort!(token.span(), "expected `(...)` after `{}!`", token),
+    };
+    let span = token.span().join(args.span()).unwrap_or(token.span());
+
+    (syntax(args.stream()), span)
+}
+
+fn parse_chunk_hint(args: TokenStream) -> Syntax {
+    if !args.is_empty() {
+        abort!(Span::call_site(), "expected `chunk_hint!()`");
+    }
+    Syntax::ChunkHint
+}
+
+/// Parses the byte of `opcode!(byte)`. Data pushes are rejected because they need data.
+fn parse_raw_opcode(args: TokenStream) -> Syntax {
+    let arg = args.to_string();
+    let byte = match arg.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+    .unwrap_or_else(|_| abort!(Span::call_site(), "expected an opcode byte, got `{}`", arg));
+    if (OP_PUSHBYTES_1.to_u8()..=OP_PUSHDATA4.to_u8()).contains(&byte) {
+        abort!(
+            Span::call_site(),
+            "{} pushes data, use a hex literal instead",
+            Opcode::from(byte)
+        );
+    }
+
+    Syntax::Opcode(Opcode::from(byte))
+}
+
+/// Splits the arguments of `repeat!(count, ...)` into the count and the repeated script.
+fn parse_repeat(args: TokenStream) -> Syntax {
+    let mut tokens = args.into_iter();
+    let count: TokenStream = tokens
+        .by_ref()
+        .take_while(|token| !matches!(token, Punct(punct) if punct.as_char() == ','))
+        .collect();
+    let body: TokenStream = tokens.collect();
+    if count.is_empty() || body.is_empty() {
+        abort!(Span::call_site(), "expected `repeat!(count, ...)`");
+    }
+
+    Syntax::Repeat(count, body)
+}
+
+/// Hashes literal data during macro expansion. Any other expression is hashed when the
+/// script is built and has to implement `AsRef<[u8]>`.
+fn parse_hash(name: &str, args: TokenStream) -> Syntax {
+    let mut tokens = args.clone().into_iter();
+    let literal = match (tokens.next(), tokens.next()) {
+        (Some(Literal(literal)), None) => literal_bytes(&literal.to_string()),
+        _ => None,
+    };
+
+    match literal {
+        Some(data) => Syntax::Bytes(match name {
+            "sha256" => sha256::Hash::hash(&data).to_byte_array().to_vec(),
+            _ => hash160::Hash::hash(&data).to_byte_array().to_vec(),
+        }),
+        None => {
+            let hash = Ident::new(name, Span::call_site());
+            // Fully qualified, so that the caller doesn't have to import the `Hash` trait
+            Syntax::Escape(quote!(
+                <::bitcoin::hashes::#hash::Hash as ::bitcoin::hashes::Hash>::to_byte_array(
+                    <::bitcoin::hashes::#hash::Hash as ::bitcoin::hashes::Hash>::hash(
+                        ::core::convert::AsRef::<[u8]>::as_ref(&(#args)),
+                    ),
+                )
+            ))
+        }
+    }
+}
+
+/// Returns the bytes of a hex literal or of a string literal without escape sequences.
+fn literal_bytes(literal: &str) -> Option<Vec<u8>> {
+    if let Some(hex_bytes) = literal.strip_prefix("0x") {
+        hex::decode(hex_bytes.replace('_', "")).ok()
+    } else if literal.starts_with('"') && !literal.contains('\\') {
+        Some(literal.as_bytes()[1..literal.len() - 1].to_vec())
+    } else {
+        None
+    }
+}
+
+fn parse_macro_call<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
+where
+    T: Iterator<Item = TokenTree>,
+{
+    let bang = tokens.next().unwrap_or_else(|| unreachable!());
+    let args = match tokens.next() {
+        Some(group @ Group(_)) => group,
+        _ => abort!(bang.span(), "expected arguments after macro name"),
+    };
+    let span = token.span().join(args.span()).unwrap_or(token.span());
+
+    (Syntax::Escape(quote!(#token #bang #args)), span)
+}
+
+fn parse_doc_comment<T>(token: TokenTree, tokens: &mut T) -> Option<(Syntax, Span)>
+where
+    T: Iterator<Item = TokenTree>,
+{
+    let attribute = match tokens.next() {
+        Some(Group(attribute)) if attribute.delimiter() == Delimiter::Bracket => attribute,
+        _ => abort!(token.span(), "unexpected token"),
+    };
+    let mut inner = attribute.stream().into_iter();
+    let comment = match (inner.next(), inner.next(), inner.next()) {
+        (Some(Ident(ident)), Some(Punct(punct)), Some(Literal(comment)))
+            if ident == "doc" && punct.as_char() == '=' =>
+        {
+            comment.to_string()
+        }
+        _ => abort!(attribute.span(), "only doc comments are supported"),
+    };
+
+    // Plain doc comments are ignored like any other comment
+    let comment = comment
+        .trim_start_matches('r')
+        .trim_matches('#')
+        .trim_matches('"')
+        .trim();
+    let (key, value) = comment.strip_prefix('@')?.split_once(':')?;
+    let span = token.span().join(attribute.span()).unwrap_or(token.span());
+
+    Some((
+        Syntax::Metadata(key.trim().to_string(), value.trim().to_string()),
+        span,
+    ))
+}
+
 fn parse_escape<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
 where
     T: Iterator<Item = TokenTree>,
@@ -491,7 +837,99 @@ where
         escape.extend(TokenStream::from(token));
     }
 
-    (Syntax::Escape(escape), span)
+    (escape_or_constant(escape), span)
+}
+
+/// Folds escapes that only consist of integer literals and arithmetic into a single
+/// integer push, keeping the expression for the debug output. Everything else is left to
+/// be evaluated at runtime.
+fn escape_or_constant(escape: TokenStream) -> Syntax {
+    match fold_constant(&escape) {
+        Some(n) => Syntax::Folded(escape.to_string(), n),
+        None => Syntax::Escape(escape),
+    }
+}
+
+fn fold_constant(tokens: &TokenStream) -> Option<i64> {
+    let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    let mut pos = 0;
+    let value = fold_expression(&tokens, &mut pos, 0)?;
+    (pos == tokens.len()).then_some(value)
+}
+
+fn fold_expression(tokens: &[TokenTree], pos: &mut usize, min_precedence: u8) -> Option<i64> {
+    let mut lhs = fold_operand(tokens, pos)?;
+    while let Some((operator, precedence, len)) = binary_operator(&tokens[*pos..]) {
+        if precedence < min_precedence {
+            break;
+        }
+        *pos += len;
+        let rhs = fold_expression(tokens, pos, precedence + 1)?;
+        lhs = match operator {
+            '*' => lhs.checked_mul(rhs),
+            '/' => lhs.checked_div(rhs),
+            '%' => lhs.checked_rem(rhs),
+            '+' => lhs.checked_add(rhs),
+            '-' => lhs.checked_sub(rhs),
+            '<' => lhs.checked_shl(u32::try_from(rhs).ok()?),
+            '>' => lhs.checked_shr(u32::try_from(rhs).ok()?),
+            '&' => Some(lhs & rhs),
+            '^' => Some(lhs ^ rhs),
+            '|' => Some(lhs | rhs),
+            _ => unreachable!(),
+        }?;
+    }
+    Some(lhs)
+}
+
+fn fold_operand(tokens: &[TokenTree], pos: &mut usize) -> Option<i64> {
+    let token = tokens.get(*pos)?;
+    *pos += 1;
+    match token {
+        Literal(literal) => {
+            // Suffixed literals keep their type and are evaluated by rustc
+            let literal = literal.to_string().replace('_', "");
+            let (digits, radix) = match literal.get(..2) {
+                Some("0x") => (&literal[2..], 16),
+                Some("0o") => (&literal[2..], 8),
+                Some("0b") => (&literal[2..], 2),
+                _ => (literal.as_str(), 10),
+            };
+            i64::from_str_radix(digits, radix).ok()
+        }
+        Punct(punct) if punct.as_char() == '-' => fold_operand(tokens, pos)?.checked_neg(),
+        Group(group) if group.delimiter() == Delimiter::Parenthesis => {
+            fold_constant(&group.stream())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the operator at the start of `tokens` along with its precedence and the number
+/// of tokens it spans. Shifts are returned as `<` and `>`.
+fn binary_operator(tokens: &[TokenTree]) -> Option<(char, u8, usize)> {
+    let punct_at = |i: usize| match tokens.get(i) {
+        Some(Punct(punct)) => Some(punct),
+        _ => None,
+    };
+    let first = punct_at(0)?;
+    // Whether the punctuation at `i` is directly followed by `c`, e.g. in `+=` or `&&`
+    let joint_with = |i: usize, c: char| {
+        punct_at(i).is_some_and(|punct| punct.spacing() == proc_macro2::Spacing::Joint)
+            && punct_at(i + 1).is_some_and(|next| next.as_char() == c)
+    };
+
+    match first.as_char() {
+        c @ ('<' | '>') if joint_with(0, c) && !joint_with(1, '=') => Some((c, 3, 2)),
+        // Compound operators like `+=`, `&&` or `->` can't be folded
+        _ if ['=', '&', '|', '>'].iter().any(|c| joint_with(0, *c)) => None,
+        c @ ('*' | '/' | '%') => Some((c, 5, 1)),
+        c @ ('+' | '-') => Some((c, 4, 1)),
+        '&' => Some(('&', 2, 1)),
+        '^' => Some(('^', 1, 1)),
+        '|' => Some(('|', 0, 1)),
+        _ => None,
+    }
 }
 
 fn parse_escape_extra<T>(token: TokenTree, tokens: &mut T) -> (Syntax, Span)
@@ -520,12 +958,30 @@ where
     (Syntax::Escape(escape), span)
 }
 
+fn is_string_literal(token_str: &str) -> bool {
+    ["\"", "r\"", "r#", "b\"", "br\"", "br#"]
+        .iter()
+        .any(|prefix| token_str.starts_with(prefix))
+}
+
+fn parse_string(token: TokenTree) -> (Syntax, Span) {
+    let span = token.span();
+    // Leave unescaping to rustc, byte strings are pushed as they are
+    let escape = if token.to_string().starts_with('b') {
+        quote_spanned!(span=> #token)
+    } else {
+        quote_spanned!(span=> #token.as_bytes())
+    };
+    (Syntax::Escape(escape), span)
+}
+
 fn parse_data(token: TokenTree) -> (Syntax, Span) {
     if token.to_string().starts_with("0x") {
         if token
             .to_string()
             .strip_prefix("0x")
             .unwrap_or_else(|| unreachable!())
+            .replace('_', "")
             .trim_start_matches('0')
             .len()
             <= 8
@@ -540,16 +996,32 @@ fn parse_data(token: TokenTree) -> (Syntax, Span) {
 }
 
 fn parse_bytes(token: TokenTree) -> (Syntax, Span) {
-    let hex_bytes = &token.to_string()[2..];
+    // Allow `_` separators to make long digests readable
+    let hex_bytes = token.to_string()[2..].replace('_', "");
+    if !hex_bytes.len().is_multiple_of(2) {
+        emit_error!(
+            token.span(),
+            "hex literal must have an even number of digits (got {})",
+            hex_bytes.len()
+        );
+    }
     let bytes = hex::decode(hex_bytes).unwrap_or_else(|err| {
         emit_error!(token.span(), "invalid hex literal ({})", err);
     });
+    if bytes.len() > MAX_SCRIPT_ELEMENT_SIZE {
+        emit_error!(
+            token.span(),
+            "hex literal of {} bytes exceeds the maximum stack element size of {} bytes",
+            bytes.len(),
+            MAX_SCRIPT_ELEMENT_SIZE
+        );
+    }
     (Syntax::Bytes(bytes), token.span())
 }
 
 fn parse_hex_int(token: TokenTree) -> (Syntax, Span) {
-    let token_str = &token.to_string()[2..];
-    let n: u32 = u32::from_str_radix(token_str, 16).unwrap_or_else(|err| {
+    let token_str = token.to_string()[2..].replace('_', "");
+    let n: u32 = u32::from_str_radix(&token_str, 16).unwrap_or_else(|err| {
         emit_error!(token.span(), "invalid hex string ({})", err);
     });
     (Syntax::Int(n as i64), token.span())
@@ -557,10 +1029,14 @@ fn parse_hex_int(token: TokenTree) -> (Syntax, Span) {
 
 fn parse_int(token: TokenTree, negative: bool) -> (Syntax, Span) {
     let token_str = token.to_string();
-    let n: i64 = token_str.parse().unwrap_or_else(|err| {
+    // Parse the magnitude separately so that i64::MIN can be written as a literal
+    let n: i128 = token_str.parse().unwrap_or_else(|err| {
         emit_error!(token.span(), "invalid number literal ({})", err);
     });
     let n = if negative { -n } else { n };
+    let n = i64::try_from(n).unwrap_or_else(|_| {
+        emit_error!(token.span(), "number literal {} does not fit in an i64", n);
+    });
     (Syntax::Int(n), token.span())
 }
 
@@ -579,14 +1055,15 @@ where
 
     let maybe_token = tokens.next();
 
-    if let Some(token) = maybe_token {
-        if let Literal(_) = token {
-            parse_int(token, true)
-        } else {
-            fail()
+    match maybe_token {
+        Some(token @ Literal(_)) if token.to_string().starts_with("0x") => {
+            match parse_data(token) {
+                (Syntax::Int(n), span) => (Syntax::Int(-n), span),
+                _ => fail(),
+            }
         }
-    } else {
-        fail()
+        Some(token @ Literal(_)) => parse_int(token, true),
+        _ => fail(),
     }
 }
 
@@ -697,6 +1174,263 @@ mod tests {
         assert!(matches!(syntax[4].0, Syntax::Opcode(OP_CHECKSIG)));
     }
 
+    #[test]
+    fn parse_if_else_chain() {
+        let syntax = parse(quote! {
+            OP_DUP
+            if a { OP_ADD } else if b { OP_SUB } else { OP_DROP }
+            OP_VERIFY
+        });
+
+        assert_eq!(syntax.len(), 3);
+        assert!(matches!(syntax[1].0, Syntax::Escape(_)));
+        assert!(matches!(syntax[2].0, Syntax::Opcode(OP_VERIFY)));
+    }
+
+    #[test]
+    fn parse_def() {
+        let syntax = parse(quote! {
+            def double { OP_DUP OP_ADD }
+            double
+        });
+
+        assert_eq!(syntax.len(), 2);
+        if let Syntax::Def(name, body) = &syntax[0].0 {
+            assert_eq!(name.to_string(), "double");
+            assert_eq!(body.to_string(), "OP_DUP OP_ADD");
+        } else {
+            panic!("Expected Syntax::Def, got {:?}", syntax[0].0);
+        }
+        assert!(matches!(syntax[1].0, Syntax::Escape(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected subroutine name after `def`")]
+    fn parse_def_without_name() {
+        parse(quote!(def { OP_ADD }));
+    }
+
+    #[test]
+    fn parse_let() {
+        let syntax = parse(quote! {
+            let len: Vec<u8> = foo(1, 2);
+            <len>
+        });
+
+        assert_eq!(syntax.len(), 2);
+        if let Syntax::Let(binding) = &syntax[0].0 {
+            assert_eq!(binding.to_string(), "len : Vec < u8 > = foo (1 , 2)");
+        } else {
+            panic!("Expected Syntax::Let, got {:?}", syntax[0].0);
+        }
+        assert!(matches!(syntax[1].0, Syntax::Escape(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `;` after let binding")]
+    fn parse_unterminated_let() {
+        parse(quote!(let x = 5));
+    }
+
+    #[test]
+    fn parse_macro_call() {
+        let syntax = parse(quote!(OP_DUP include_script!("gadget.asm") OP_DROP));
+
+        assert_eq!(syntax.len(), 3);
+        if let Syntax::Escape(tokens) = &syntax[1].0 {
+            assert_eq!(tokens.to_string(), "include_script ! (\"gadget.asm\")");
+        } else {
+            panic!("Expected Syntax::Escape, got {:?}", syntax[1].0);
+        }
+    }
+
+    #[test]
+    fn parse_raw_opcode() {
+        let syntax = parse(quote!(opcode!(0xbb) opcode!(135)));
+
+        assert_eq!(syntax.len(), 2);
+        assert!(matches!(syntax[0].0, Syntax::Opcode(opcode) if opcode.to_u8() == 0xbb));
+        assert!(matches!(syntax[1].0, Syntax::Opcode(OP_EQUAL)));
+    }
+
+    #[test]
+    fn parse_raw_opcode_names() {
+        // The generated code refers to opcodes by their name
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            assert_eq!(parse_opcode(&opcode.to_string()), Ok(opcode));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "OP_PUSHBYTES_32 pushes data, use a hex literal instead")]
+    fn parse_raw_push_opcode() {
+        parse(quote!(opcode!(0x20)));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected an opcode byte, got `256`")]
+    fn parse_invalid_raw_opcode() {
+        parse(quote!(opcode!(256)));
+    }
+
+    #[test]
+    fn parse_repeat() {
+        let syntax = parse(quote!(repeat!(n + 1, OP_DUP OP_ADD) OP_DROP));
+
+        assert_eq!(syntax.len(), 2);
+        if let Syntax::Repeat(count, body) = &syntax[0].0 {
+            assert_eq!(count.to_string(), "n + 1");
+            assert_eq!(body.to_string(), "OP_DUP OP_ADD");
+        } else {
+            panic!("Expected Syntax::Repeat, got {:?}", syntax[0].0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `repeat!(count, ...)`")]
+    fn parse_repeat_without_body() {
+        parse(quote!(repeat!(32)));
+    }
+
+    #[test]
+    fn parse_hash() {
+        let syntax = parse(quote!(sha256!("abc") hash160!(0x00_ff) sha256!(preimage)));
+
+        assert_eq!(syntax.len(), 3);
+        if let Syntax::Bytes(digest) = &syntax[0].0 {
+            assert_eq!(
+                hex::encode(digest),
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            );
+        } else {
+            panic!("Expected Syntax::Bytes, got {:?}", syntax[0].0);
+        }
+        if let Syntax::Bytes(digest) = &syntax[1].0 {
+            assert_eq!(digest.len(), 20);
+        } else {
+            panic!("Expected Syntax::Bytes, got {:?}", syntax[1].0);
+        }
+        assert!(matches!(syntax[2].0, Syntax::Escape(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `(...)` after `sha256!`")]
+    fn parse_hash_without_args() {
+        parse(quote!(sha256!));
+    }
+
+    #[test]
+    fn parse_assert_stack_delta() {
+        let syntax = parse(quote!(OP_ADD assert_stack_delta!(-1)));
+
+        assert_eq!(syntax.len(), 2);
+        if let Syntax::AssertStackDelta(delta) = &syntax[1].0 {
+            assert_eq!(delta.to_string(), "- 1");
+        } else {
+            panic!("Expected Syntax::AssertStackDelta, got {:?}", syntax[1].0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `(...)` after `assert_stack_delta!`")]
+    fn parse_assert_stack_delta_without_args() {
+        parse(quote!(OP_ADD assert_stack_delta!));
+    }
+
+    #[test]
+    fn parse_chunk_hint() {
+        let syntax = parse(quote!(OP_ADD chunk_hint!() OP_DUP));
+
+        assert_eq!(syntax.len(), 3);
+        assert!(matches!(syntax[1].0, Syntax::ChunkHint));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `chunk_hint!()`")]
+    fn parse_chunk_hint_with_args() {
+        parse(quote!(chunk_hint!(1)));
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let syntax = parse(quote! {
+            /// Duplicates the top stack item
+            OP_DUP
+            /// @label: dup
+        });
+
+        assert_eq!(syntax.len(), 2);
+        if let Syntax::Metadata(key, value) = &syntax[1].0 {
+            assert_eq!(key, "label");
+            assert_eq!(value, "dup");
+        } else {
+            panic!("Expected Syntax::Metadata, got {:?}", syntax[1].0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "only doc comments are supported")]
+    fn parse_attribute() {
+        parse(quote!(
+            #[inline]
+            OP_DUP
+        ));
+    }
+
+    #[test]
+    fn parse_while_loop() {
+        let syntax = parse(quote! {
+            while let Some(x) = values.pop() { { x } OP_ADD }
+            OP_VERIFY
+        });
+
+        assert_eq!(syntax.len(), 2);
+        assert!(matches!(syntax[0].0, Syntax::Escape(_)));
+        assert!(matches!(syntax[1].0, Syntax::Opcode(OP_VERIFY)));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected while loop body")]
+    fn parse_while_without_body() {
+        parse(quote!(while x > 0));
+    }
+
+    #[test]
+    fn parse_match() {
+        let syntax = parse(quote! {
+            match hash {
+                Hash::Sha256 => { OP_SHA256 },
+                Hash::Hash160 | Hash::Ripemd160 if short => OP_HASH160 OP_DUP,
+                _ => { OP_DROP }
+            }
+            OP_EQUAL
+        });
+
+        assert_eq!(syntax.len(), 2);
+        if let Syntax::Escape(tokens) = &syntax[0].0 {
+            assert_eq!(
+                tokens.to_string(),
+                quote! {
+                    match hash {
+                        Hash::Sha256 => ::bitcoin_script::script! { OP_SHA256 },
+                        Hash::Hash160 | Hash::Ripemd160 if short => ::bitcoin_script::script! { OP_HASH160 OP_DUP },
+                        _ => ::bitcoin_script::script! { OP_DROP },
+                    }
+                }
+                .to_string()
+            );
+        } else {
+            panic!("Expected Syntax::Escape, got {:?}", syntax[0].0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `=>` in match arm")]
+    fn parse_match_without_arrow() {
+        parse(quote!(match x { 1 OP_ADD }));
+    }
+
     #[test]
     fn parse_opcodes() {
         let syntax = parse(quote!(OP_CHECKSIG OP_HASH160));
@@ -772,6 +1506,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_negative_int_limits() {
+        let syntax = parse(quote!(-9223372036854775808 9223372036854775807 - 0xff));
+
+        assert!(matches!(syntax[0].0, Syntax::Int(i64::MIN)));
+        assert!(matches!(syntax[1].0, Syntax::Int(i64::MAX)));
+        assert!(matches!(syntax[2].0, Syntax::Int(-255)));
+    }
+
+    #[test]
+    #[should_panic(expected = "number literal 9223372036854775808 does not fit in an i64")]
+    fn parse_int_overflow() {
+        parse(quote!(9223372036854775808));
+    }
+
     #[test]
     fn parse_hex() {
         let syntax = parse(quote!(OP_CHECKSIG 0x123456789abcde));
@@ -782,4 +1531,87 @@ mod tests {
             panic!("Unable to cast Syntax as Syntax::Bytes")
         }
     }
+
+    #[test]
+    fn parse_string() {
+        let syntax = parse(quote!("TapLeaf" b"\x01\x02" r"raw"));
+
+        assert_eq!(syntax.len(), 3);
+        for (item, expected) in syntax.iter().zip([
+            "\"TapLeaf\" . as_bytes ()",
+            "b\"\\x01\\x02\"",
+            "r\"raw\" . as_bytes ()",
+        ]) {
+            if let Syntax::Escape(tokens) = &item.0 {
+                assert_eq!(tokens.to_string(), expected);
+            } else {
+                panic!("Expected Syntax::Escape, got {:?}", item.0);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_constant_expressions() {
+        let syntax = parse(quote! {
+            { 1 + 2 * 3 }
+            { (1 + 2) * 3 }
+            <2 * -3 - 1>
+            { 1 << 40 | 0x0f & 0b11 }
+            { 9 / 2 % 3 ^ 1_000 }
+            { -(4 - 6) }
+        });
+        let values: Vec<i64> = syntax
+            .iter()
+            .map(|(item, _)| match item {
+                Syntax::Folded(_, n) => *n,
+                _ => panic!("Expected Syntax::Folded, got {:?}", item),
+            })
+            .collect();
+
+        assert_eq!(values, vec![7, 9, -7, (1 << 40) | 3, 1001, 2]);
+        assert!(
+            matches!(&syntax[1].0, Syntax::Folded(expression, 9) if expression == "(1 + 2) * 3")
+        );
+    }
+
+    #[test]
+    fn parse_non_constant_expressions() {
+        let syntax = parse(quote! {
+            { a + 1 }
+            { 1u32 + 1 }
+            { 1 << 64 }
+            { i64::MAX + 1 }
+            { 5 / 0 }
+            { x += 1 }
+            { 1 && 2 }
+        });
+
+        for (item, _) in syntax {
+            assert!(matches!(item, Syntax::Escape(_)), "{:?}", item);
+        }
+    }
+
+    #[test]
+    fn parse_hex_with_separators() {
+        let syntax = parse(quote!(0x1234_5678_9abc 0xff_ff));
+
+        if let Syntax::Bytes(bytes) = &syntax[0].0 {
+            assert_eq!(bytes, &vec![0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        } else {
+            panic!("Unable to cast Syntax as Syntax::Bytes")
+        }
+        assert!(matches!(syntax[1].0, Syntax::Int(0xffff)));
+    }
+
+    #[test]
+    #[should_panic(expected = "hex literal must have an even number of digits (got 11)")]
+    fn parse_odd_hex() {
+        parse(quote!(0x123456789ab));
+    }
+
+    #[test]
+    #[should_panic(expected = "hex literal of 521 bytes exceeds the maximum stack element size")]
+    fn parse_oversized_hex() {
+        parse(TokenStream::from_str(&format!("0x{}", "ab".repeat(521))).unwrap());
+    }
 }
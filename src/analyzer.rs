@@ -0,0 +1,1393 @@
+use crate::builder::{Block, StructuredScript};
+use bitcoin::blockdata::constants::MAX_SCRIPT_ELEMENT_SIZE;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::{self, read_scriptint, Instruction};
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The effect of a script on the main and alt stack.
+///
+/// All values are relative to the stack height before the script is executed, i.e. a
+/// `deepest_stack_accessed` of `-2` means that the script reads the two topmost elements
+/// it did not push itself.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackStatus {
+    /// Deepest main stack element that may be accessed, assuming the largest possible depth
+    /// for every `OP_PICK` and `OP_ROLL` whose depth is not constant
+    pub deepest_stack_accessed: i32,
+    /// Deepest main stack element that is accessed if every `OP_PICK` and `OP_ROLL` whose
+    /// depth is not constant reads the topmost element. Together with
+    /// `deepest_stack_accessed` this is the range of possible depths.
+    pub deepest_stack_accessed_best_case: i32,
+    pub stack_changed: i32,
+    pub deepest_altstack_accessed: i32,
+    pub altstack_changed: i32,
+    /// Highest combined height of the main and alt stack over any execution path
+    pub max_stack_height: i32,
+    /// The effects of the branches of every `OP_IF` and `OP_NOTIF`, in the order of their
+    /// `OP_ENDIF`s
+    pub branches: Vec<BranchStatus>,
+}
+
+/// The effects of the two branches of an `OP_IF` or `OP_NOTIF` on the stacks. A missing
+/// `OP_ELSE` branch leaves the stacks untouched.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BranchStatus {
+    /// Position of the `OP_IF` or `OP_NOTIF` in the compiled script
+    pub offset: usize,
+    pub if_stack_changed: i32,
+    pub if_altstack_changed: i32,
+    pub else_stack_changed: i32,
+    pub else_altstack_changed: i32,
+}
+
+impl BranchStatus {
+    /// Returns true if the stack heights after the `OP_ENDIF` depend on the branch taken.
+    pub fn is_divergent(&self) -> bool {
+        self.if_stack_changed != self.else_stack_changed
+            || self.if_altstack_changed != self.else_altstack_changed
+    }
+}
+
+impl StackStatus {
+    fn access_stack(&mut self, accessed: i32, changed: i32) {
+        self.access_stack_dynamic(accessed, accessed, changed);
+    }
+
+    // Accesses between `min_accessed` and `max_accessed` elements depending on the stack
+    fn access_stack_dynamic(&mut self, min_accessed: i32, max_accessed: i32, changed: i32) {
+        self.deepest_stack_accessed = min(
+            self.deepest_stack_accessed,
+            self.stack_changed - max_accessed,
+        );
+        self.deepest_stack_accessed_best_case = min(
+            self.deepest_stack_accessed_best_case,
+            self.stack_changed - min_accessed,
+        );
+        self.stack_changed += changed;
+        self.update_max_stack_height();
+    }
+
+    fn access_altstack(&mut self, accessed: i32, changed: i32) {
+        self.deepest_altstack_accessed = min(
+            self.deepest_altstack_accessed,
+            self.altstack_changed - accessed,
+        );
+        self.altstack_changed += changed;
+        self.update_max_stack_height();
+    }
+
+    fn update_max_stack_height(&mut self) {
+        self.max_stack_height = max(
+            self.max_stack_height,
+            self.stack_changed + self.altstack_changed,
+        );
+    }
+}
+
+/// The first instruction that reads more elements than a stack holds, see
+/// [`StackAnalyzer::find_underflow`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackUnderflow {
+    /// Position of the instruction in the compiled script
+    pub offset: usize,
+    /// Name and source location of the script that contains the instruction
+    pub block: String,
+    /// Whether the alt stack underflows instead of the main stack
+    pub altstack: bool,
+}
+
+/// Where the labeled input elements of a script end up, see
+/// [`StackAnalyzer::track_labels`]. Both stacks are listed from the bottom to the top
+/// element. Elements that the script computes or pushes itself are `None`, as are inputs
+/// that are only moved in one branch of an `OP_IF`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackLabels {
+    pub stack: Vec<Option<String>>,
+    pub altstack: Vec<Option<String>>,
+}
+
+/// Why [`StackAnalyzer::try_analyze`] failed. Every error names the script and the
+/// position in the compiled script of the offending instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StackAnalysisError {
+    /// The effect of the opcode depends on the stack contents, e.g. `OP_IFDUP`.
+    UnsupportedOpcode {
+        opcode: Opcode,
+        offset: usize,
+        block: String,
+    },
+    /// The depth of an `OP_PICK` or `OP_ROLL` is not a preceding constant.
+    DynamicDepth {
+        opcode: Opcode,
+        offset: usize,
+        block: String,
+    },
+    /// The depth of an `OP_PICK` or `OP_ROLL` is negative.
+    NegativeDepth {
+        opcode: Opcode,
+        depth: i64,
+        offset: usize,
+        block: String,
+    },
+    /// The numbers of keys or signatures of an `OP_CHECKMULTISIG` are unknown or invalid.
+    InvalidMultisig {
+        opcode: Opcode,
+        reason: String,
+        offset: usize,
+        block: String,
+    },
+    /// An `OP_ELSE` or `OP_ENDIF` without an `OP_IF`, a second `OP_ELSE` or an `OP_IF`
+    /// that is never closed.
+    UnbalancedConditional {
+        opcode: Opcode,
+        offset: usize,
+        block: String,
+    },
+    /// The branches of an `OP_IF` change the stack heights differently.
+    DivergentBranches { branch: BranchStatus, block: String },
+    UnfilledPlaceholder {
+        name: String,
+        offset: usize,
+        block: String,
+    },
+    InvalidInstruction {
+        error: script::Error,
+        offset: usize,
+        block: String,
+    },
+}
+
+impl StackAnalysisError {
+    /// Position of the offending instruction in the compiled script
+    pub fn offset(&self) -> usize {
+        match self {
+            StackAnalysisError::DivergentBranches { branch, .. } => branch.offset,
+            StackAnalysisError::UnsupportedOpcode { offset, .. }
+            | StackAnalysisError::DynamicDepth { offset, .. }
+            | StackAnalysisError::NegativeDepth { offset, .. }
+            | StackAnalysisError::InvalidMultisig { offset, .. }
+            | StackAnalysisError::UnbalancedConditional { offset, .. }
+            | StackAnalysisError::UnfilledPlaceholder { offset, .. }
+            | StackAnalysisError::InvalidInstruction { offset, .. } => *offset,
+        }
+    }
+
+    /// Name and source location of the script that contains the offending instruction
+    pub fn block(&self) -> &str {
+        match self {
+            StackAnalysisError::UnsupportedOpcode { block, .. }
+            | StackAnalysisError::DynamicDepth { block, .. }
+            | StackAnalysisError::NegativeDepth { block, .. }
+            | StackAnalysisError::InvalidMultisig { block, .. }
+            | StackAnalysisError::UnbalancedConditional { block, .. }
+            | StackAnalysisError::DivergentBranches { block, .. }
+            | StackAnalysisError::UnfilledPlaceholder { block, .. }
+            | StackAnalysisError::InvalidInstruction { block, .. } => block,
+        }
+    }
+}
+
+impl fmt::Display for StackAnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackAnalysisError::UnsupportedOpcode { opcode, .. } => {
+                write!(f, "Stack effect of {} is not supported", opcode)?
+            }
+            StackAnalysisError::DynamicDepth { opcode, .. } => {
+                write!(f, "Depth of {} is not a preceding constant", opcode)?
+            }
+            StackAnalysisError::NegativeDepth { opcode, depth, .. } => {
+                write!(f, "Negative depth {} for {}", depth, opcode)?
+            }
+            StackAnalysisError::InvalidMultisig { opcode, reason, .. } => {
+                write!(f, "Invalid {}: {}", opcode, reason)?
+            }
+            StackAnalysisError::UnbalancedConditional { opcode, .. } => match *opcode {
+                OP_IF | OP_NOTIF => write!(f, "Unclosed {}", opcode)?,
+                OP_ELSE => write!(f, "Unexpected OP_ELSE")?,
+                _ => write!(f, "{} without OP_IF", opcode)?,
+            },
+            StackAnalysisError::DivergentBranches { branch, .. } => write!(
+                f,
+                "Stack change in if and else branch differ: {:+} vs {:+} (alt stack {:+} vs {:+})",
+                branch.if_stack_changed,
+                branch.else_stack_changed,
+                branch.if_altstack_changed,
+                branch.else_altstack_changed
+            )?,
+            StackAnalysisError::UnfilledPlaceholder { name, .. } => {
+                write!(f, "Unfilled placeholder {}", name)?
+            }
+            StackAnalysisError::InvalidInstruction { error, .. } => {
+                write!(f, "Invalid instruction: {}", error)?
+            }
+        }
+        write!(f, " in {} at offset {}", self.block(), self.offset())
+    }
+}
+
+impl std::error::Error for StackAnalysisError {}
+
+#[derive(Debug)]
+struct IfStackEle {
+    opcode: Opcode,
+    offset: usize,
+    block: String,
+    // Status before the first opcode of the branches
+    status_before_if: StackStatus,
+    // Status at the end of the OP_IF branch once OP_ELSE was seen
+    if_branch: Option<StackStatus>,
+}
+
+// The effect of a called script, which is reused for every call of the same script
+#[derive(Debug)]
+struct CallSummary {
+    // Branch offsets are relative to the start of the called script
+    status: StackStatus,
+    pushes: Vec<Option<i64>>,
+    seen_opcode: bool,
+    // Whether the called script reads constants pushed before it was called
+    reads_entry_pushes: bool,
+}
+
+/// Statically computes the [`StackStatus`] of a [`StructuredScript`].
+///
+/// The effect of every called script is computed once and reused for all of its calls,
+/// also across analyses with the same analyzer.
+///
+/// Both branches of an `OP_IF` must have the same effect on the stacks. The depth of an
+/// `OP_PICK` or `OP_ROLL` has to be pushed as a constant right before the opcode, unless
+/// a bound for such depths is set with [`Self::with_max_dynamic_depth`]. The
+/// numbers of keys and signatures of an `OP_CHECKMULTISIG` have to be pushed as constants
+/// together with the keys.
+#[derive(Debug, Default)]
+pub struct StackAnalyzer {
+    stack_status: StackStatus,
+    if_stack: Vec<IfStackEle>,
+    // The values pushed since the last opcode that is not a push, `None` for data that is
+    // not a number
+    pushes: Vec<Option<i64>>,
+    max_dynamic_depth: Option<u32>,
+    allow_divergent_branches: bool,
+    branches: Vec<BranchStatus>,
+    // Position of the current instruction in the compiled script
+    offset: usize,
+    // Number of elements on the main and alt stack before the script, if underflows are
+    // searched for
+    initial_stack_sizes: Option<(i32, i32)>,
+    underflow: Option<StackUnderflow>,
+    // Whether an opcode other than a push was analyzed and if one read the constants
+    // pushed before the analyzed script
+    seen_opcode: bool,
+    reads_entry_pushes: bool,
+    // Called scripts by their id, `None` if they can't be analyzed on their own
+    call_summaries: HashMap<u64, Option<Arc<CallSummary>>>,
+}
+
+impl StackAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assumes that `OP_PICK` and `OP_ROLL` never reach deeper than `max_depth` if their
+    /// depth is not a preceding constant, instead of failing the analysis.
+    pub fn with_max_dynamic_depth(mut self, max_depth: u32) -> Self {
+        self.max_dynamic_depth = Some(max_depth);
+        self.call_summaries.clear();
+        self
+    }
+
+    /// Accepts branches of an `OP_IF` that change the stack heights differently, continuing
+    /// with the smaller of the heights. Such branches are flagged in
+    /// [`StackStatus::branches`].
+    pub fn allow_divergent_branches(mut self) -> Self {
+        self.allow_divergent_branches = true;
+        self.call_summaries.clear();
+        self
+    }
+
+    /// Returns the first instruction that would access more elements than the main or alt
+    /// stack hold, assuming they start with `initial_stack` and `initial_altstack` elements.
+    /// Instructions in either branch of an `OP_IF` are considered.
+    pub fn find_underflow(
+        &mut self,
+        script: &StructuredScript,
+        initial_stack: usize,
+        initial_altstack: usize,
+    ) -> Result<Option<StackUnderflow>, StackAnalysisError> {
+        self.initial_stack_sizes = Some((initial_stack as i32, initial_altstack as i32));
+        let result = self.try_analyze(script).map(|_| self.underflow.take());
+        self.initial_stack_sizes = None;
+        result
+    }
+
+    /// Names the topmost main stack elements before the script with `inputs`, from the
+    /// bottom to the top element, and returns where they are after the script. This
+    /// documents the interface of a gadget, e.g. which inputs it consumes and which it
+    /// leaves on the stack in a different order.
+    ///
+    /// Only opcodes that move, copy or drop elements keep their labels. `OP_PICK` and
+    /// `OP_ROLL` with a depth that is not a constant lose the labels of all elements they
+    /// may move.
+    pub fn track_labels(
+        &mut self,
+        script: &StructuredScript,
+        inputs: &[&str],
+    ) -> Result<StackLabels, StackAnalysisError> {
+        self.try_analyze(script)?;
+        let mut stacks = LabeledStacks {
+            stack: (0..inputs.len()).map(LabeledValue::Input).collect(),
+            ..Default::default()
+        };
+        for instruction in script.instructions() {
+            match instruction.expect("instructions were checked by the analysis") {
+                Instruction::PushBytes(bytes) => stacks.stack.push(
+                    read_scriptint(bytes.as_bytes())
+                        .map_or(LabeledValue::Computed, LabeledValue::Constant),
+                ),
+                Instruction::Op(opcode) => stacks.apply(opcode),
+            }
+        }
+
+        let label = |value: LabeledValue| match value {
+            LabeledValue::Input(index) => Some(inputs[index].to_string()),
+            _ => None,
+        };
+        Ok(StackLabels {
+            stack: stacks.stack.into_iter().map(label).collect(),
+            altstack: stacks.altstack.into_iter().map(label).collect(),
+        })
+    }
+
+    /// Panics if the script can't be analyzed, see [`Self::try_analyze`].
+    pub fn analyze(&mut self, script: &StructuredScript) -> StackStatus {
+        self.try_analyze(script)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Returns an error if the branches of an `OP_IF` differ (unless they are allowed to),
+    /// the script uses an opcode whose effect depends on the stack contents or it contains
+    /// an unfilled placeholder.
+    pub fn try_analyze(
+        &mut self,
+        script: &StructuredScript,
+    ) -> Result<StackStatus, StackAnalysisError> {
+        *self = Self {
+            max_dynamic_depth: self.max_dynamic_depth,
+            allow_divergent_branches: self.allow_divergent_branches,
+            initial_stack_sizes: self.initial_stack_sizes,
+            call_summaries: std::mem::take(&mut self.call_summaries),
+            ..Self::new()
+        };
+        self.analyze_blocks(script)?;
+        if let Some(ele) = self.if_stack.pop() {
+            return Err(StackAnalysisError::UnbalancedConditional {
+                opcode: ele.opcode,
+                offset: ele.offset,
+                block: ele.block,
+            });
+        }
+        let mut status = self.stack_status.clone();
+        status.branches = std::mem::take(&mut self.branches);
+        Ok(status)
+    }
+
+    fn analyze_blocks(&mut self, script: &StructuredScript) -> Result<(), StackAnalysisError> {
+        for block in &script.blocks {
+            match block {
+                Block::Call(id) => {
+                    let called_script = script.get_structured_script(id);
+                    match self.summarize_call(*id, called_script) {
+                        Some(summary) => self.apply_summary(&summary, called_script.len()),
+                        None => self.analyze_blocks(called_script)?,
+                    }
+                }
+                Block::Placeholder { name, .. } => {
+                    return Err(StackAnalysisError::UnfilledPlaceholder {
+                        name: name.clone(),
+                        offset: self.offset,
+                        block: script.describe(),
+                    })
+                }
+                Block::Script(script_buf) => {
+                    let block_offset = self.offset;
+                    for instruction in script_buf.instruction_indices() {
+                        match instruction {
+                            Ok((index, Instruction::PushBytes(bytes))) => {
+                                self.offset = block_offset + index;
+                                self.pushes.push(read_scriptint(bytes.as_bytes()).ok());
+                                self.stack_status.access_stack(0, 1);
+                            }
+                            Ok((index, Instruction::Op(opcode))) => {
+                                self.offset = block_offset + index;
+                                self.analyze_opcode(opcode, script)?;
+                                self.check_underflow(script);
+                            }
+                            Err(error) => {
+                                return Err(StackAnalysisError::InvalidInstruction {
+                                    error,
+                                    offset: self.offset,
+                                    block: script.describe(),
+                                })
+                            }
+                        }
+                    }
+                    self.offset = block_offset + script_buf.len();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the effect of a called script if it can be reused at the current position
+    fn summarize_call(
+        &mut self,
+        id: u64,
+        called_script: &StructuredScript,
+    ) -> Option<Arc<CallSummary>> {
+        // Underflows have to be found at the exact instruction
+        if self.initial_stack_sizes.is_some() {
+            return None;
+        }
+        let summary = match self.call_summaries.get(&id) {
+            Some(summary) => summary.clone(),
+            None => {
+                let mut analyzer = StackAnalyzer {
+                    max_dynamic_depth: self.max_dynamic_depth,
+                    allow_divergent_branches: self.allow_divergent_branches,
+                    call_summaries: std::mem::take(&mut self.call_summaries),
+                    ..Self::new()
+                };
+                let result = analyzer.analyze_blocks(called_script);
+                self.call_summaries = std::mem::take(&mut analyzer.call_summaries);
+                // Errors are reported when the script is analyzed inline
+                let summary = match result {
+                    Ok(()) if analyzer.if_stack.is_empty() => {
+                        let mut status = analyzer.stack_status;
+                        status.branches = analyzer.branches;
+                        Some(Arc::new(CallSummary {
+                            status,
+                            pushes: analyzer.pushes,
+                            seen_opcode: analyzer.seen_opcode,
+                            reads_entry_pushes: analyzer.reads_entry_pushes,
+                        }))
+                    }
+                    _ => None,
+                };
+                self.call_summaries.insert(id, summary.clone());
+                summary
+            }
+        }?;
+        // Without preceding constants the script behaves as if analyzed on its own
+        if summary.reads_entry_pushes && !self.pushes.is_empty() {
+            return None;
+        }
+        Some(summary)
+    }
+
+    fn apply_summary(&mut self, summary: &CallSummary, len: usize) {
+        let called = &summary.status;
+        let status = &mut self.stack_status;
+        status.deepest_stack_accessed = min(
+            status.deepest_stack_accessed,
+            status.stack_changed + called.deepest_stack_accessed,
+        );
+        status.deepest_stack_accessed_best_case = min(
+            status.deepest_stack_accessed_best_case,
+            status.stack_changed + called.deepest_stack_accessed_best_case,
+        );
+        status.deepest_altstack_accessed = min(
+            status.deepest_altstack_accessed,
+            status.altstack_changed + called.deepest_altstack_accessed,
+        );
+        status.max_stack_height = max(
+            status.max_stack_height,
+            status.stack_changed + status.altstack_changed + called.max_stack_height,
+        );
+        status.stack_changed += called.stack_changed;
+        status.altstack_changed += called.altstack_changed;
+
+        let offset = self.offset;
+        self.branches
+            .extend(called.branches.iter().map(|branch| BranchStatus {
+                offset: offset + branch.offset,
+                ..branch.clone()
+            }));
+        self.offset += len;
+
+        if summary.reads_entry_pushes && !self.seen_opcode {
+            self.reads_entry_pushes = true;
+        }
+        if summary.seen_opcode {
+            self.pushes = summary.pushes.clone();
+            self.seen_opcode = true;
+        } else {
+            self.pushes.extend(&summary.pushes);
+        }
+    }
+
+    fn check_underflow(&mut self, script: &StructuredScript) {
+        if let (Some((initial_stack, initial_altstack)), None) =
+            (self.initial_stack_sizes, &self.underflow)
+        {
+            let stack_underflow = self.stack_status.deepest_stack_accessed < -initial_stack;
+            let altstack_underflow =
+                self.stack_status.deepest_altstack_accessed < -initial_altstack;
+            if stack_underflow || altstack_underflow {
+                self.underflow = Some(StackUnderflow {
+                    offset: self.offset,
+                    block: script.describe(),
+                    altstack: !stack_underflow,
+                });
+            }
+        }
+    }
+
+    fn analyze_opcode(
+        &mut self,
+        opcode: Opcode,
+        script: &StructuredScript,
+    ) -> Result<(), StackAnalysisError> {
+        if !self.seen_opcode
+            && matches!(
+                opcode,
+                OP_PICK | OP_ROLL | OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY
+            )
+        {
+            self.reads_entry_pushes = true;
+        }
+        if pushnum_value(opcode).is_none() {
+            self.seen_opcode = true;
+        }
+        let pushes = std::mem::take(&mut self.pushes);
+        let last_constant = pushes.last().copied().flatten();
+        let offset = self.offset;
+        let unbalanced = || StackAnalysisError::UnbalancedConditional {
+            opcode,
+            offset,
+            block: script.describe(),
+        };
+        let invalid_multisig = |reason: String| StackAnalysisError::InvalidMultisig {
+            opcode,
+            reason,
+            offset,
+            block: script.describe(),
+        };
+        match opcode {
+            OP_IF | OP_NOTIF => {
+                self.stack_status.access_stack(1, -1);
+                self.if_stack.push(IfStackEle {
+                    opcode,
+                    offset,
+                    block: script.describe(),
+                    status_before_if: self.stack_status.clone(),
+                    if_branch: None,
+                });
+            }
+            OP_ELSE => {
+                let ele = self.if_stack.last_mut().ok_or_else(unbalanced)?;
+                if ele.if_branch.is_some() {
+                    return Err(unbalanced());
+                }
+                ele.if_branch = Some(std::mem::replace(
+                    &mut self.stack_status,
+                    ele.status_before_if.clone(),
+                ));
+            }
+            OP_ENDIF => {
+                let ele = self.if_stack.pop().ok_or_else(unbalanced)?;
+                let before = &ele.status_before_if;
+                let branch = match &ele.if_branch {
+                    Some(if_branch) => BranchStatus {
+                        offset: ele.offset,
+                        if_stack_changed: if_branch.stack_changed - before.stack_changed,
+                        if_altstack_changed: if_branch.altstack_changed - before.altstack_changed,
+                        else_stack_changed: self.stack_status.stack_changed - before.stack_changed,
+                        else_altstack_changed: self.stack_status.altstack_changed
+                            - before.altstack_changed,
+                    },
+                    // Without OP_ELSE the branch that is not taken leaves the stack untouched
+                    None => BranchStatus {
+                        offset: ele.offset,
+                        if_stack_changed: self.stack_status.stack_changed - before.stack_changed,
+                        if_altstack_changed: self.stack_status.altstack_changed
+                            - before.altstack_changed,
+                        ..Default::default()
+                    },
+                };
+                let if_branch = ele.if_branch.unwrap_or(ele.status_before_if);
+                let status = &mut self.stack_status;
+                if branch.is_divergent() {
+                    if !self.allow_divergent_branches {
+                        return Err(StackAnalysisError::DivergentBranches {
+                            branch,
+                            block: ele.block,
+                        });
+                    }
+                    status.stack_changed = min(if_branch.stack_changed, status.stack_changed);
+                    status.altstack_changed =
+                        min(if_branch.altstack_changed, status.altstack_changed);
+                }
+                self.branches.push(branch);
+                status.deepest_stack_accessed = min(
+                    if_branch.deepest_stack_accessed,
+                    status.deepest_stack_accessed,
+                );
+                status.deepest_stack_accessed_best_case = min(
+                    if_branch.deepest_stack_accessed_best_case,
+                    status.deepest_stack_accessed_best_case,
+                );
+                status.deepest_altstack_accessed = min(
+                    if_branch.deepest_altstack_accessed,
+                    status.deepest_altstack_accessed,
+                );
+                status.max_stack_height = max(if_branch.max_stack_height, status.max_stack_height);
+            }
+            OP_TOALTSTACK => {
+                self.stack_status.access_stack(1, -1);
+                self.stack_status.access_altstack(0, 1);
+            }
+            OP_FROMALTSTACK => {
+                self.stack_status.access_altstack(1, -1);
+                self.stack_status.access_stack(0, 1);
+            }
+            OP_PICK | OP_ROLL => {
+                let changed = if opcode == OP_PICK { 0 } else { -1 };
+                match (last_constant, self.max_dynamic_depth) {
+                    (Some(depth), _) => {
+                        if depth < 0 {
+                            return Err(StackAnalysisError::NegativeDepth {
+                                opcode,
+                                depth,
+                                offset,
+                                block: script.describe(),
+                            });
+                        }
+                        self.stack_status.access_stack(depth as i32 + 2, changed);
+                    }
+                    (None, Some(max_depth)) => {
+                        self.stack_status
+                            .access_stack_dynamic(2, max_depth as i32 + 2, changed);
+                    }
+                    (None, None) => {
+                        return Err(StackAnalysisError::DynamicDepth {
+                            opcode,
+                            offset,
+                            block: script.describe(),
+                        })
+                    }
+                }
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                // The stack holds a dummy element, the signatures, their number, the keys
+                // and their number, which are usually pushed right before the opcode
+                let keys = last_constant.ok_or_else(|| {
+                    invalid_multisig("number of keys is not a preceding constant".to_string())
+                })?;
+                if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&keys) {
+                    return Err(invalid_multisig(format!("invalid number of keys {}", keys)));
+                }
+                let signatures = pushes
+                    .len()
+                    .checked_sub(keys as usize + 2)
+                    .and_then(|index| pushes[index])
+                    .ok_or_else(|| {
+                        invalid_multisig("number of signatures is not a constant".to_string())
+                    })?;
+                if !(0..=keys).contains(&signatures) {
+                    return Err(invalid_multisig(format!(
+                        "invalid number of signatures {}",
+                        signatures
+                    )));
+                }
+                let accessed = (keys + signatures + 3) as i32;
+                let changed = if opcode == OP_CHECKMULTISIG { 1 } else { 0 };
+                self.stack_status.access_stack(accessed, changed - accessed);
+            }
+            _ => {
+                let (accessed, changed) = opcode_stack_effect(opcode).ok_or_else(|| {
+                    StackAnalysisError::UnsupportedOpcode {
+                        opcode,
+                        offset,
+                        block: script.describe(),
+                    }
+                })?;
+                self.stack_status.access_stack(accessed, changed);
+                if let Some(n) = pushnum_value(opcode) {
+                    self.pushes = pushes;
+                    self.pushes.push(Some(n));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LabeledValue {
+    // Index of the label of an input element
+    Input(usize),
+    Constant(i64),
+    Computed,
+}
+
+// The symbolic stacks of StackAnalyzer::track_labels
+#[derive(Debug, Default)]
+struct LabeledStacks {
+    stack: Vec<LabeledValue>,
+    altstack: Vec<LabeledValue>,
+    // Stacks before every open OP_IF and at the end of its OP_IF branch once OP_ELSE was seen
+    if_stack: Vec<(LabeledStacks, Option<LabeledStacks>)>,
+}
+
+impl LabeledStacks {
+    fn apply(&mut self, opcode: Opcode) {
+        // Elements below the labeled inputs have no label
+        let min_len = match opcode {
+            OP_PICK | OP_ROLL => 1,
+            _ => opcode_stack_effect(opcode).map_or(0, |(accessed, _)| accessed as usize),
+        };
+        fill_unlabeled(&mut self.stack, min_len);
+        let len = self.stack.len();
+
+        match opcode {
+            OP_IF | OP_NOTIF => {
+                self.stack.pop();
+                self.if_stack.push((self.branch_start(), None));
+            }
+            OP_ELSE => {
+                let (before, if_branch) = self.if_stack.last_mut().expect("balanced by analysis");
+                let (stack, altstack) = (before.stack.clone(), before.altstack.clone());
+                *if_branch = Some(LabeledStacks {
+                    stack: std::mem::replace(&mut self.stack, stack),
+                    altstack: std::mem::replace(&mut self.altstack, altstack),
+                    if_stack: vec![],
+                });
+            }
+            OP_ENDIF => {
+                let (before, if_branch) = self.if_stack.pop().expect("balanced by analysis");
+                let other = if_branch.unwrap_or(before);
+                merge_branches(&mut self.stack, &other.stack);
+                merge_branches(&mut self.altstack, &other.altstack);
+            }
+            OP_TOALTSTACK => {
+                fill_unlabeled(&mut self.stack, 1);
+                let value = self.stack.pop().unwrap();
+                self.altstack.push(value);
+            }
+            OP_FROMALTSTACK => {
+                fill_unlabeled(&mut self.altstack, 1);
+                let value = self.altstack.pop().unwrap();
+                self.stack.push(value);
+            }
+            OP_DUP => self.stack.push(self.stack[len - 1].clone()),
+            OP_2DUP => self.stack.extend_from_within(len - 2..),
+            OP_3DUP => self.stack.extend_from_within(len - 3..),
+            OP_OVER => self.stack.push(self.stack[len - 2].clone()),
+            OP_2OVER => self.stack.extend_from_within(len - 4..len - 2),
+            OP_TUCK => self.stack.insert(len - 2, self.stack[len - 1].clone()),
+            OP_NIP => {
+                self.stack.remove(len - 2);
+            }
+            OP_DROP | OP_VERIFY => self.stack.truncate(len - 1),
+            OP_2DROP => self.stack.truncate(len - 2),
+            OP_SWAP => self.stack.swap(len - 1, len - 2),
+            OP_2SWAP => self.stack[len - 4..].rotate_left(2),
+            OP_ROT => self.stack[len - 3..].rotate_left(1),
+            OP_2ROT => self.stack[len - 6..].rotate_left(2),
+            OP_CLTV | OP_CSV => {}
+            OP_SIZE => self.stack.push(LabeledValue::Computed),
+            OP_PICK | OP_ROLL => match self.stack.pop() {
+                Some(LabeledValue::Constant(depth)) if depth >= 0 => {
+                    fill_unlabeled(&mut self.stack, depth as usize + 1);
+                    let index = self.stack.len() - 1 - depth as usize;
+                    let value = match opcode {
+                        OP_PICK => self.stack[index].clone(),
+                        _ => self.stack.remove(index),
+                    };
+                    self.stack.push(value);
+                }
+                _ => {
+                    if opcode == OP_ROLL {
+                        self.stack.fill(LabeledValue::Computed);
+                    } else {
+                        self.stack.push(LabeledValue::Computed);
+                    }
+                }
+            },
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                // The numbers of keys and signatures are constants, checked by the analysis
+                let count = |value: Option<&LabeledValue>| match value {
+                    Some(LabeledValue::Constant(n)) => *n as usize,
+                    _ => 0,
+                };
+                let keys = count(self.stack.last());
+                let sigs = count(len.checked_sub(keys + 2).map(|i| &self.stack[i]));
+                fill_unlabeled(&mut self.stack, keys + sigs + 3);
+                self.stack.truncate(self.stack.len() - (keys + sigs + 3));
+                if opcode == OP_CHECKMULTISIG {
+                    self.stack.push(LabeledValue::Computed);
+                }
+            }
+            _ => match pushnum_value(opcode) {
+                Some(n) => self.stack.push(LabeledValue::Constant(n)),
+                None => {
+                    let (accessed, changed) = opcode_stack_effect(opcode).unwrap_or((0, 0));
+                    self.stack.truncate(len - accessed as usize);
+                    let pushed = (accessed + changed) as usize;
+                    self.stack
+                        .extend(std::iter::repeat_n(LabeledValue::Computed, pushed));
+                }
+            },
+        }
+    }
+
+    fn branch_start(&self) -> LabeledStacks {
+        LabeledStacks {
+            stack: self.stack.clone(),
+            altstack: self.altstack.clone(),
+            if_stack: vec![],
+        }
+    }
+}
+
+fn fill_unlabeled(stack: &mut Vec<LabeledValue>, min_len: usize) {
+    if stack.len() < min_len {
+        let missing = min_len - stack.len();
+        stack.splice(0..0, std::iter::repeat_n(LabeledValue::Computed, missing));
+    }
+}
+
+// Keeps the labels that are at the same position after both branches
+fn merge_branches(stack: &mut Vec<LabeledValue>, other: &[LabeledValue]) {
+    // Both branches change the stack by the same number of elements, but may have read
+    // different numbers of unlabeled elements below the inputs, so they are lined up from
+    // the top
+    if stack.len() > other.len() {
+        stack.drain(..stack.len() - other.len());
+    }
+    for (value, other) in stack.iter_mut().rev().zip(other.iter().rev()) {
+        if value != other {
+            *value = LabeledValue::Computed;
+        }
+    }
+}
+
+/// A data push that is not encoded with the shortest possible opcode, which the
+/// `MINIMALDATA` standardness rule rejects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonMinimalPush {
+    /// Position of the push in the compiled script
+    pub offset: usize,
+    /// Name and source location of the script that contains the push
+    pub block: String,
+}
+
+/// A data push of more than [`MAX_SCRIPT_ELEMENT_SIZE`] bytes, which fails the script when
+/// it is executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OversizedPush {
+    /// Position of the push in the compiled script
+    pub offset: usize,
+    /// Name and source location of the script that contains the push
+    pub block: String,
+    /// Number of pushed bytes
+    pub size: usize,
+}
+
+impl fmt::Display for OversizedPush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Push of {} bytes in {} at offset {} exceeds the maximum stack element size of {} bytes",
+            self.size, self.block, self.offset, MAX_SCRIPT_ELEMENT_SIZE
+        )
+    }
+}
+
+/// Returns all pushes of the script and the scripts it calls that are not minimally
+/// encoded, ordered by their offset.
+pub fn find_non_minimal_pushes(script: &StructuredScript) -> Vec<NonMinimalPush> {
+    let mut pushes = Vec::new();
+    let select = |script: &StructuredScript, opcode: u8, data: &[u8]| {
+        (!is_minimal_push(opcode, data)).then(|| script.describe())
+    };
+    collect_pushes(script, 0, &select, &mut HashMap::new(), &mut pushes);
+    pushes
+        .into_iter()
+        .map(|(offset, block)| NonMinimalPush { offset, block })
+        .collect()
+}
+
+/// Returns all pushes of the script and the scripts it calls that exceed the stack element
+/// size limit, ordered by their offset.
+pub fn find_oversized_pushes(script: &StructuredScript) -> Vec<OversizedPush> {
+    let mut pushes = Vec::new();
+    let select = |script: &StructuredScript, _: u8, data: &[u8]| {
+        (data.len() > MAX_SCRIPT_ELEMENT_SIZE).then(|| (script.describe(), data.len()))
+    };
+    collect_pushes(script, 0, &select, &mut HashMap::new(), &mut pushes);
+    pushes
+        .into_iter()
+        .map(|(offset, (block, size))| OversizedPush {
+            offset,
+            block,
+            size,
+        })
+        .collect()
+}
+
+// Collects the offsets of the pushes that `select` returns a value for. Pushes of called
+// scripts are collected once relative to their start.
+fn collect_pushes<T, F>(
+    script: &StructuredScript,
+    mut offset: usize,
+    select: &F,
+    cache: &mut HashMap<u64, Vec<(usize, T)>>,
+    pushes: &mut Vec<(usize, T)>,
+) where
+    T: Clone,
+    F: Fn(&StructuredScript, u8, &[u8]) -> Option<T>,
+{
+    for block in &script.blocks {
+        match block {
+            Block::Call(id) => {
+                let called_script = script.get_structured_script(id);
+                if !cache.contains_key(id) {
+                    let mut called_pushes = Vec::new();
+                    collect_pushes(called_script, 0, select, cache, &mut called_pushes);
+                    cache.insert(*id, called_pushes);
+                }
+                pushes.extend(
+                    cache[id]
+                        .iter()
+                        .map(|(push_offset, value)| (offset + push_offset, value.clone())),
+                );
+                offset += called_script.len();
+            }
+            Block::Script(script_buf) => {
+                let bytes = script_buf.as_bytes();
+                for (index, instruction) in script_buf.instruction_indices().map_while(Result::ok) {
+                    if let Instruction::PushBytes(data) = instruction {
+                        if let Some(value) = select(script, bytes[index], data.as_bytes()) {
+                            pushes.push((offset + index, value));
+                        }
+                    }
+                }
+                offset += script_buf.len();
+            }
+            Block::Placeholder { len, .. } => offset += len,
+        }
+    }
+}
+
+fn is_minimal_push(opcode: u8, data: &[u8]) -> bool {
+    match data {
+        [] => opcode == OP_PUSHBYTES_0.to_u8(),
+        // Use OP_PUSHNUM_1 to OP_PUSHNUM_16 and OP_PUSHNUM_NEG1 instead
+        [1..=16] | [0x81] => false,
+        _ if data.len() <= 75 => opcode as usize == data.len(),
+        _ if data.len() <= 0xff => opcode == OP_PUSHDATA1.to_u8(),
+        _ if data.len() <= 0xffff => opcode == OP_PUSHDATA2.to_u8(),
+        _ => true,
+    }
+}
+
+// Consensus limit on the number of keys of OP_CHECKMULTISIG(VERIFY)
+const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+
+/// Returns the value pushed by OP_PUSHNUM_NEG1 and OP_PUSHNUM_1 to OP_PUSHNUM_16.
+pub(crate) fn pushnum_value(opcode: Opcode) -> Option<i64> {
+    let byte = opcode.to_u8();
+    if opcode == OP_PUSHNUM_NEG1 {
+        Some(-1)
+    } else if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&byte) {
+        Some((byte - OP_PUSHNUM_1.to_u8() + 1) as i64)
+    } else {
+        None
+    }
+}
+
+/// Returns how many of the topmost main stack elements an opcode accesses and by how much
+/// it changes the stack height. Opcodes that touch the alt stack or depend on the stack
+/// contents are handled by the [`StackAnalyzer`] itself, `None` means the effect is not
+/// known statically.
+pub(crate) fn opcode_stack_effect(opcode: Opcode) -> Option<(i32, i32)> {
+    Some(match opcode {
+        OP_PUSHNUM_NEG1 | OP_PUSHNUM_1 | OP_PUSHNUM_2 | OP_PUSHNUM_3 | OP_PUSHNUM_4
+        | OP_PUSHNUM_5 | OP_PUSHNUM_6 | OP_PUSHNUM_7 | OP_PUSHNUM_8 | OP_PUSHNUM_9
+        | OP_PUSHNUM_10 | OP_PUSHNUM_11 | OP_PUSHNUM_12 | OP_PUSHNUM_13 | OP_PUSHNUM_14
+        | OP_PUSHNUM_15 | OP_PUSHNUM_16 | OP_DEPTH => (0, 1),
+        OP_CLTV | OP_CSV => (1, 0),
+        OP_VERIFY | OP_DROP => (1, -1),
+        OP_2DROP => (2, -2),
+        OP_DUP | OP_SIZE => (1, 1),
+        OP_2DUP => (2, 2),
+        OP_3DUP => (3, 3),
+        OP_NIP => (2, -1),
+        OP_OVER | OP_TUCK => (2, 1),
+        OP_2OVER => (4, 2),
+        OP_ROT => (3, 0),
+        OP_2ROT => (6, 0),
+        OP_SWAP => (2, 0),
+        OP_2SWAP => (4, 0),
+        OP_1ADD | OP_1SUB | OP_2MUL | OP_2DIV | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL
+        | OP_INVERT | OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => (1, 0),
+        OP_ADD
+        | OP_SUB
+        | OP_MUL
+        | OP_DIV
+        | OP_MOD
+        | OP_LSHIFT
+        | OP_RSHIFT
+        | OP_BOOLAND
+        | OP_BOOLOR
+        | OP_NUMEQUAL
+        | OP_NUMNOTEQUAL
+        | OP_LESSTHAN
+        | OP_GREATERTHAN
+        | OP_LESSTHANOREQUAL
+        | OP_GREATERTHANOREQUAL
+        | OP_MIN
+        | OP_MAX
+        | OP_EQUAL
+        | OP_CAT
+        | OP_LEFT
+        | OP_RIGHT
+        | OP_AND
+        | OP_OR
+        | OP_XOR
+        | OP_CHECKSIG => (2, -1),
+        OP_EQUALVERIFY | OP_NUMEQUALVERIFY | OP_CHECKSIGVERIFY => (2, -2),
+        OP_WITHIN | OP_SUBSTR | OP_CHECKSIGADD => (3, -2),
+        OP_IFDUP => return None,
+        // Everything else either does not touch the stack or fails the script
+        _ => (0, 0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+    use bitcoin::ScriptBuf;
+
+    #[test]
+    fn analyze_simple() {
+        let status = script! { OP_ADD OP_DUP OP_TOALTSTACK 5 OP_ROLL }.analyze_stack();
+
+        assert_eq!(
+            status,
+            StackStatus {
+                deepest_stack_accessed: -7,
+                deepest_stack_accessed_best_case: -7,
+                stack_changed: -1,
+                deepest_altstack_accessed: 0,
+                altstack_changed: 1,
+                max_stack_height: 1,
+                branches: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn analyze_nested_calls() {
+        let inner = script! { OP_FROMALTSTACK OP_ADD OP_ADD };
+        let status = script! { OP_1 { inner.clone() } { inner } }.analyze_stack();
+
+        assert_eq!(status.stack_changed, -1);
+        assert_eq!(status.deepest_stack_accessed, -2);
+        assert_eq!(status.altstack_changed, -2);
+        assert_eq!(status.deepest_altstack_accessed, -2);
+    }
+
+    #[test]
+    fn analyze_if_else() {
+        let status = script! {
+            OP_IF
+                OP_2DROP
+            OP_ELSE
+                OP_DROP OP_DROP
+            OP_ENDIF
+            OP_NOTIF
+                OP_NOP
+            OP_ENDIF
+        }
+        .analyze_stack();
+
+        assert_eq!(status.stack_changed, -4);
+        assert_eq!(status.deepest_stack_accessed, -4);
+    }
+
+    #[test]
+    fn analyze_branches() {
+        let script = script! {
+            OP_IF
+                OP_DROP
+            OP_ELSE
+                OP_TOALTSTACK OP_DROP
+            OP_ENDIF
+            OP_NOTIF
+                OP_1
+            OP_ENDIF
+        };
+        let status = StackAnalyzer::new()
+            .allow_divergent_branches()
+            .analyze(&script);
+
+        assert_eq!(
+            status.branches,
+            vec![
+                BranchStatus {
+                    offset: 0,
+                    if_stack_changed: -1,
+                    if_altstack_changed: 0,
+                    else_stack_changed: -2,
+                    else_altstack_changed: 1,
+                },
+                BranchStatus {
+                    offset: 6,
+                    if_stack_changed: 1,
+                    if_altstack_changed: 0,
+                    else_stack_changed: 0,
+                    else_altstack_changed: 0,
+                },
+            ]
+        );
+        assert!(status.branches.iter().all(BranchStatus::is_divergent));
+        assert_eq!(status.stack_changed, -4);
+        assert_eq!(status.altstack_changed, 0);
+    }
+
+    #[test]
+    fn find_underflow() {
+        let script = script! {
+            def pop_alt {
+                OP_FROMALTSTACK
+            }
+
+            OP_ADD
+            OP_IF
+                OP_DROP
+            OP_ELSE
+                OP_NIP
+            OP_ENDIF
+            pop_alt pop_alt
+        };
+        let mut analyzer = StackAnalyzer::new();
+
+        assert_eq!(analyzer.find_underflow(&script, 4, 2), Ok(None));
+
+        let underflow = analyzer.find_underflow(&script, 3, 2).unwrap().unwrap();
+        assert!(!underflow.altstack);
+        assert_eq!(underflow.offset, 4);
+        assert!(underflow.block.contains("find_underflow"));
+
+        let underflow = analyzer.find_underflow(&script, 4, 1).unwrap().unwrap();
+        assert!(underflow.altstack);
+        assert_eq!(underflow.offset, 7);
+        assert!(underflow.block.contains("pop_alt"));
+    }
+
+    #[test]
+    fn analyze_max_stack_height() {
+        let status = script! {
+            OP_DUP OP_TOALTSTACK
+            OP_IF
+                OP_1 OP_2 OP_3 OP_2DROP OP_DROP
+            OP_ELSE
+                OP_1 OP_DROP
+            OP_ENDIF
+            OP_FROMALTSTACK OP_DROP
+        }
+        .analyze_stack();
+
+        assert_eq!(status.stack_changed, -1);
+        assert_eq!(status.max_stack_height, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack change in if and else branch differ")]
+    fn analyze_unbalanced_branches() {
+        script! { OP_IF OP_DROP OP_ENDIF }.analyze_stack();
+    }
+
+    #[test]
+    fn try_analyze_unclosed_if() {
+        let result = StackAnalyzer::new().try_analyze(&script! { OP_1 OP_IF OP_DROP });
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err,
+            StackAnalysisError::UnbalancedConditional {
+                opcode: OP_IF,
+                offset: 1,
+                ..
+            }
+        ));
+        assert!(err.to_string().starts_with("Unclosed OP_IF"));
+    }
+
+    #[test]
+    fn analyze_reused_calls() {
+        let script = script! {
+            def pick {
+                OP_PICK
+            }
+            def branch {
+                OP_IF OP_DROP OP_ELSE OP_NIP OP_ENDIF
+            }
+
+            OP_1 OP_2 pick
+            OP_ADD pick
+            branch branch
+        };
+        let mut analyzer = StackAnalyzer::new().with_max_dynamic_depth(10);
+        let status = analyzer.analyze(&script);
+
+        // The first pick reads the preceding constant, the second one has a dynamic depth
+        assert_eq!(status.stack_changed, -3);
+        assert_eq!(status.deepest_stack_accessed_best_case, -4);
+        assert_eq!(status.deepest_stack_accessed, -11);
+        assert_eq!(
+            status
+                .branches
+                .iter()
+                .map(|branch| branch.offset)
+                .collect::<Vec<_>>(),
+            vec![5, 10]
+        );
+        assert_eq!(analyzer.analyze(&script), status);
+    }
+
+    #[test]
+    fn non_minimal_pushes() {
+        let non_minimal = ScriptBuf::from_bytes(vec![
+            OP_PUSHBYTES_1.to_u8(),
+            5,
+            OP_PUSHBYTES_1.to_u8(),
+            0x81,
+        ]);
+        let script = script! {
+            OP_DUP
+            { StructuredScript::new("raw").push_script(non_minimal.clone()) }
+            OP_1 OP_2
+            { StructuredScript::new("raw").push_script(non_minimal) }
+        };
+
+        let pushes = find_non_minimal_pushes(&script);
+        assert_eq!(
+            pushes.iter().map(|push| push.offset).collect::<Vec<_>>(),
+            vec![1, 3, 7, 9]
+        );
+        assert!(pushes.iter().all(|push| push.block.ends_with("raw")));
+    }
+
+    #[test]
+    fn oversized_pushes() {
+        // A push of the maximum size followed by one that is a byte larger
+        let mut bytes = vec![OP_PUSHDATA2.to_u8(), 0x08, 0x02];
+        bytes.extend([0xab; 520]);
+        bytes.extend([OP_DROP.to_u8(), OP_PUSHDATA2.to_u8(), 0x09, 0x02]);
+        bytes.extend([0xab; 521]);
+        let oversized = StructuredScript::new("raw").push_script(ScriptBuf::from_bytes(bytes));
+        let script = script! { OP_DUP { oversized.clone() } OP_DROP { oversized } };
+
+        let pushes = find_oversized_pushes(&script);
+        assert_eq!(
+            pushes
+                .iter()
+                .map(|push| (push.offset, push.size))
+                .collect::<Vec<_>>(),
+            vec![(1 + 524, 521), (1 + 524 + 524 + 1 + 524, 521)]
+        );
+        assert!(pushes.iter().all(|push| push.block.ends_with("raw")));
+    }
+
+    #[test]
+    fn analyze_checkmultisig() {
+        let key = [2; 33];
+        let status = script! {
+            OP_2 { &key } { &key } { &key } OP_3 OP_CHECKMULTISIG
+        }
+        .analyze_stack();
+
+        // Consumes the dummy element and two signatures
+        assert_eq!(status.stack_changed, -2);
+        assert_eq!(status.deepest_stack_accessed, -3);
+
+        let status = script! { OP_1 { &key } OP_1 OP_CHECKMULTISIGVERIFY }.analyze_stack();
+        assert_eq!(status.stack_changed, -2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid OP_CHECKMULTISIG: number of signatures is not a constant")]
+    fn analyze_checkmultisig_dynamic_signatures() {
+        let key = [2; 33];
+        script! { OP_DUP { &key } OP_1 OP_CHECKMULTISIG }.analyze_stack();
+    }
+
+    #[test]
+    fn analyze_bounded_dynamic_pick() {
+        let script = script! { OP_ADD OP_PICK OP_1 OP_ROLL };
+        let status = StackAnalyzer::new()
+            .with_max_dynamic_depth(10)
+            .analyze(&script);
+
+        assert_eq!(status.stack_changed, -1);
+        assert_eq!(status.deepest_stack_accessed, -13);
+        assert_eq!(status.deepest_stack_accessed_best_case, -3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Depth of OP_PICK is not a preceding constant")]
+    fn analyze_dynamic_pick() {
+        script! { OP_DUP OP_PICK }.analyze_stack();
+    }
+
+    #[test]
+    fn track_labels() {
+        let labels = StackAnalyzer::new()
+            .track_labels(
+                &script! { OP_SWAP OP_ROT OP_ADD OP_TOALTSTACK OP_DUP 1 OP_PICK },
+                &["a", "b", "c"],
+            )
+            .unwrap();
+        let some = |label: &str| Some(label.to_string());
+
+        // a b c -> a c b -> c b a -> c (b+a) -> c
+        assert_eq!(labels.stack, vec![some("c"), some("c"), some("c")]);
+        assert_eq!(labels.altstack, vec![None]);
+
+        let labels = StackAnalyzer::new()
+            .track_labels(
+                &script! { OP_IF OP_SWAP OP_ELSE OP_NOP OP_ENDIF OP_2 OP_ADD },
+                &["x", "y", "z", "condition"],
+            )
+            .unwrap();
+        // Only x is at the same position after both branches
+        assert_eq!(labels.stack, vec![some("x"), None, None]);
+
+        // A branch that swaps with the element below the inputs loses the label
+        for script in [
+            script! { OP_IF OP_SWAP OP_ELSE OP_NOP OP_ENDIF },
+            script! { OP_IF OP_NOP OP_ELSE OP_SWAP OP_ENDIF },
+        ] {
+            let labels = StackAnalyzer::new()
+                .track_labels(&script, &["a", "cond"])
+                .unwrap();
+            assert_eq!(labels.stack, vec![None]);
+        }
+
+        // Inputs without a label can be moved to the altstack
+        let labels = StackAnalyzer::new()
+            .track_labels(&script! { OP_TOALTSTACK OP_TOALTSTACK }, &["a"])
+            .unwrap();
+        assert_eq!(labels.stack, Vec::<Option<String>>::new());
+        assert_eq!(labels.altstack, vec![some("a"), None]);
+    }
+}
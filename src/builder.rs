@@ -1,17 +1,53 @@
+use crate::analyzer::{StackAnalyzer, StackStatus};
+use bitcoin::blockdata::constants::MAX_SCRIPT_ELEMENT_SIZE;
+use bitcoin::blockdata::opcodes::all::{
+    OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY, OP_CHECKSIG, OP_CHECKSIGADD, OP_CHECKSIGVERIFY,
+    OP_ENDIF, OP_IF, OP_NOTIF, OP_PUSHNUM_1, OP_PUSHNUM_16,
+};
 use bitcoin::blockdata::opcodes::Opcode;
-use bitcoin::blockdata::script::{Instruction, PushBytes, PushBytesBuf, ScriptBuf};
+use bitcoin::blockdata::script::{self, Instruction, PushBytes, ScriptBuf};
+use bitcoin::consensus::encode::VarInt;
 use bitcoin::opcodes::{OP_0, OP_TRUE};
-use bitcoin::script::write_scriptint;
+use bitcoin::taproot::{
+    TAPROOT_CONTROL_BASE_SIZE, TAPROOT_CONTROL_MAX_NODE_COUNT, TAPROOT_CONTROL_NODE_SIZE,
+};
 use bitcoin::Witness;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
+use std::fmt;
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::{Add, RangeBounds};
+use std::sync::Arc;
 
-#[derive(Clone, Debug, Hash, Serialize, Deserialize, PartialEq)]
+mod cache;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod extract;
+mod parse;
+mod witness;
+pub use cache::CacheError;
+#[cfg(feature = "cbor")]
+pub use cbor::CborError;
+#[cfg(feature = "cbor")]
+pub(crate) use cbor::{from_cbor, to_cbor};
+pub use extract::RepeatedSequence;
+pub use parse::ParseError;
+pub use witness::{WitnessError, WitnessTemplate};
+
+#[derive(Clone, Debug, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Block {
     Call(u64),
     Script(ScriptBuf),
+    /// A script of at most `len` bytes that is not known yet, see
+    /// [`StructuredScript::placeholder`].
+    Placeholder {
+        name: String,
+        len: usize,
+    },
 }
 
 impl Block {
@@ -21,20 +57,150 @@ impl Block {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+/// A script that keeps the scripts pushed into it as separate, shared blocks.
+///
+/// Cloning is cheap because called scripts are reference counted instead of copied.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StructuredScript {
     size: usize,
     pub debug_identifier: String,
     pub blocks: Vec<Block>, //List?
-    script_map: HashMap<u64, StructuredScript>,
+    // Called scripts are shared, so cloning a script does not copy them
+    script_map: HashMap<u64, Arc<StructuredScript>>,
+    metadata: BTreeMap<String, String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    location: Option<SourceLocation>,
+}
+
+/// The place in the Rust sources where a script was built, see
+/// [`StructuredScript::source_location`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceLocation {
+    /// Borrowed from `file!()`, so recording the location doesn't allocate
+    pub file: Cow<'static, str>,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Scripts are equal if they compile to the same instructions, no matter how they are
+/// split into blocks and called scripts or how they are named. Unfilled placeholders
+/// are equal if they have the same name and length.
+impl PartialEq for StructuredScript {
+    fn eq(&self, other: &Self) -> bool {
+        if self.size != other.size {
+            return false;
+        }
+        let mut atoms = Vec::new();
+        self.for_each_atom(&mut |atom| atoms.push(atom));
+        let mut other_atoms = Vec::new();
+        other.for_each_atom(&mut |atom| other_atoms.push(atom));
+        atoms == other_atoms
+    }
 }
 
+impl Eq for StructuredScript {}
+
 impl Hash for StructuredScript {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.blocks.hash(state);
+        state.write_u64(self.content_hash());
+    }
+}
+
+/// The rules under which [`StructuredScript::num_sigops`] counts signature operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigopContext {
+    /// Output scripts and script sigs. P2SH redeem scripts are counted like `SegwitV0`.
+    Legacy,
+    /// Witness scripts of segwit v0 inputs
+    SegwitV0,
+    /// Leaf scripts of taproot inputs
+    Tapscript,
+}
+
+// Upper bound that legacy sigop counting assumes for every multisig check
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+// Every signature check of a tapscript spend costs this much of the sigop budget, which
+// starts at the same amount plus the size of the witness
+const TAPSCRIPT_SIGOP_COST: usize = 50;
+
+/// The witness of spending a taproot output via a leaf script, see
+/// [`StructuredScript::estimate_spend_weight`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpendWeight {
+    /// Weight of the witness in weight units, which equals its serialized size
+    pub weight: usize,
+    /// Signature checks of the script, assuming that every one of them is executed with a
+    /// non-empty signature
+    pub num_sigops: usize,
+}
+
+impl SpendWeight {
+    pub(crate) fn new(
+        script_len: usize,
+        num_sigops: usize,
+        control_block_depth: usize,
+        witness_items: &[Vec<u8>],
+    ) -> SpendWeight {
+        assert!(
+            control_block_depth <= TAPROOT_CONTROL_MAX_NODE_COUNT,
+            "Control block depth of {} exceeds the maximum of {}",
+            control_block_depth,
+            TAPROOT_CONTROL_MAX_NODE_COUNT
+        );
+        let control_block_len =
+            TAPROOT_CONTROL_BASE_SIZE + TAPROOT_CONTROL_NODE_SIZE * control_block_depth;
+        let element_lens = witness_items
+            .iter()
+            .map(Vec::len)
+            .chain([script_len, control_block_len]);
+        let weight = element_lens.fold(
+            VarInt(witness_items.len() as u64 + 2).size(),
+            |weight, len| weight + VarInt(len as u64).size() + len,
+        );
+        SpendWeight { weight, num_sigops }
+    }
+
+    /// The sigop budget of the input: 50 plus the serialized size of the witness.
+    pub fn sigop_budget(&self) -> usize {
+        TAPSCRIPT_SIGOP_COST + self.weight
+    }
+
+    /// Returns false if the signature checks may exceed the sigop budget, which every
+    /// check with a non-empty signature reduces by 50.
+    pub fn fits_sigop_budget(&self) -> bool {
+        TAPSCRIPT_SIGOP_COST * self.num_sigops <= self.sigop_budget()
     }
 }
 
+/// Metadata key of the scripts that mark preferred chunk borders
+pub const CHUNK_HINT: &str = "chunk_hint";
+
+/// Metadata key of the scripts that are never split into several chunks
+pub const ATOMIC: &str = "atomic";
+
+/// Metadata key under which `script!` lists the escapes it evaluated at compile time, as
+/// `expression = value` separated by semicolons
+pub const FOLDED: &str = "folded";
+
+/// Metadata key that names the stack inputs of a script, separated by commas from the
+/// bottom to the top element, see [`StructuredScript::witness_template`]
+pub const WITNESS: &str = "witness";
+
+/// An instruction or placeholder of a script, see [`StructuredScript::for_each_atom`].
+#[derive(Debug, Hash, PartialEq)]
+enum Atom<'a> {
+    Instruction(&'a [u8]),
+    Placeholder(&'a str, usize),
+}
+
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut hasher = DefaultHasher::new();
     t.hash(&mut hasher);
@@ -42,6 +208,13 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
 }
 
 impl StructuredScript {
+    /// The id under which the script is stored when it is called. It is derived from the
+    /// blocks and the metadata, so scripts that only differ in their metadata, e.g. an
+    /// atomic and a non-atomic copy, are stored separately.
+    pub(crate) fn id(&self) -> u64 {
+        calculate_hash(&(&self.blocks, &self.metadata))
+    }
+
     pub fn new(debug_info: &str) -> Self {
         let blocks = Vec::new();
         StructuredScript {
@@ -49,21 +222,579 @@ impl StructuredScript {
             debug_identifier: debug_info.to_string(),
             blocks,
             script_map: HashMap::new(),
+            metadata: BTreeMap::new(),
+            location: None,
         }
     }
 
+    /// Creates a template for a script of at most `expected_len` bytes that becomes
+    /// available later. The placeholder counts with its expected length towards the length
+    /// of the scripts it is pushed into and can be replaced with [`Self::fill`].
+    ///
+    /// Scripts that still contain placeholders can't be compiled or analyzed.
+    pub fn placeholder(name: &str, expected_len: usize) -> Self {
+        let mut script = StructuredScript::new(name);
+        script.size = expected_len;
+        script.blocks.push(Block::Placeholder {
+            name: name.to_string(),
+            len: expected_len,
+        });
+        script
+    }
+
     pub fn len(&self) -> usize {
         self.size
     }
 
-    pub fn add_structured_script(&mut self, id: u64, script: StructuredScript) {
-        self.script_map.entry(id).or_insert(script);
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn add_structured_script<S: Into<Arc<StructuredScript>>>(&mut self, id: u64, script: S) {
+        self.script_map.entry(id).or_insert_with(|| script.into());
     }
 
     pub fn get_structured_script(&self, id: &u64) -> &StructuredScript {
         self.script_map
             .get(id)
-            .expect(&format!("script id: {} not found in script_map.", id))
+            .unwrap_or_else(|| panic!("script id: {} not found in script_map.", id))
+    }
+
+    /// Attaches a `key: value` annotation to the script, replacing any previous value.
+    pub fn set_metadata(mut self, key: &str, value: &str) -> StructuredScript {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Records where the script is built. `script!` calls this with the location of its
+    /// invocation, so errors about a script can point at the code that produced it.
+    pub fn set_source_location(mut self, file: &'static str, line: u32) -> StructuredScript {
+        self.location = Some(SourceLocation {
+            file: Cow::Borrowed(file),
+            line,
+        });
+        self
+    }
+
+    pub fn source_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    // The debug identifier followed by the source location if it is known
+    pub(crate) fn describe(&self) -> String {
+        match &self.location {
+            Some(location) => format!("{} ({})", self.debug_identifier, location),
+            None => self.debug_identifier.clone(),
+        }
+    }
+
+    /// Returns the first script called from this script or any of its called scripts
+    /// whose name is `name`, searching in the order of the calls.
+    ///
+    /// Nested scripts are named by the last segment of their `debug_identifier`, e.g.
+    /// subroutines defined with `def double { ... }` are named `double`.
+    pub fn get_block_by_name(&self, name: &str) -> Option<&StructuredScript> {
+        self.blocks.iter().find_map(|block| match block {
+            Block::Call(id) => {
+                let called_script = self.get_structured_script(id);
+                if called_script.has_name(name) {
+                    Some(called_script)
+                } else {
+                    called_script.get_block_by_name(name)
+                }
+            }
+            Block::Script(_) | Block::Placeholder { .. } => None,
+        })
+    }
+
+    /// Replaces every script named `name` that is called from this script or any of its
+    /// called scripts with `new_script`. Returns whether any script was replaced.
+    pub fn replace_block(&mut self, name: &str, new_script: StructuredScript) -> bool {
+        let debug_identifier = format!("{} {}", self.debug_identifier, new_script.debug_identifier);
+        self.update_called_scripts(|called_script| {
+            if called_script.has_name(name) {
+                *called_script = new_script.clone();
+                called_script.debug_identifier = debug_identifier.clone();
+                true
+            } else {
+                called_script.replace_block(name, new_script.clone())
+            }
+        })
+    }
+
+    /// Replaces every placeholder named `name` in this script and its called scripts with
+    /// `script`. Returns whether any placeholder was filled.
+    ///
+    /// Panics if `script` is longer than the expected length of the placeholder.
+    pub fn fill(&mut self, name: &str, mut script: StructuredScript) -> bool {
+        let mut filled =
+            self.update_called_scripts(|called_script| called_script.fill(name, script.clone()));
+
+        let id = script.id();
+        let mut filled_here = false;
+        for block in &mut self.blocks {
+            match block {
+                Block::Placeholder {
+                    name: placeholder,
+                    len,
+                } if placeholder == name => {
+                    assert!(
+                        script.len() <= *len,
+                        "Script of {} bytes does not fit into placeholder {} of {} bytes",
+                        script.len(),
+                        name,
+                        len
+                    );
+                    *block = Block::Call(id);
+                    filled_here = true;
+                }
+                _ => (),
+            }
+        }
+        if filled_here {
+            script.debug_identifier = format!("{} {}", self.debug_identifier, name);
+            self.add_structured_script(id, script);
+            self.size = self.blocks.iter().map(|block| self.block_len(block)).sum();
+            filled = true;
+        }
+        filled
+    }
+
+    /// Applies `update` to every called script and returns whether it changed any of them.
+    /// Changed scripts get a new id because ids are derived from the blocks and metadata.
+    pub(crate) fn update_called_scripts<F>(&mut self, mut update: F) -> bool
+    where
+        F: FnMut(&mut StructuredScript) -> bool,
+    {
+        self.rekey_called_scripts(|id, called_script| {
+            if update(called_script) {
+                called_script.id()
+            } else {
+                id
+            }
+        })
+    }
+
+    /// Moves every called script to the id returned by `new_id`, merging scripts that end
+    /// up with the same id. Returns whether any id changed.
+    ///
+    /// The scripts are visited in the order in which they are first called, so that the
+    /// first called script of the merged ones is kept.
+    fn rekey_called_scripts<F>(&mut self, mut new_id: F) -> bool
+    where
+        F: FnMut(u64, &mut StructuredScript) -> u64,
+    {
+        let mut script_map = std::mem::take(&mut self.script_map);
+        let mut called = HashSet::new();
+        let mut ids: Vec<u64> = self
+            .blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Call(id) if called.insert(*id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        // Scripts that are not called (anymore) go last
+        let mut uncalled: Vec<u64> = script_map
+            .keys()
+            .filter(|id| !called.contains(*id))
+            .copied()
+            .collect();
+        uncalled.sort_unstable();
+        ids.extend(uncalled);
+
+        let mut new_ids = HashMap::new();
+        for id in ids {
+            let Some(mut called_script) = script_map.remove(&id) else {
+                continue;
+            };
+            // Only copies the called script if it is shared with another script
+            let new_id = new_id(id, Arc::make_mut(&mut called_script));
+            new_ids.insert(id, new_id);
+            self.add_structured_script(new_id, called_script);
+        }
+
+        let mut changed = false;
+        for block in &mut self.blocks {
+            if let Block::Call(id) = block {
+                changed |= new_ids[id] != *id;
+                *id = new_ids[id];
+            }
+        }
+        self.size = self.blocks.iter().map(|block| self.block_len(block)).sum();
+        changed
+    }
+
+    /// Merges called scripts that compile to the same instructions but were registered
+    /// under different ids, e.g. because their blocks are split differently. The script of
+    /// every group that is called first is kept, including its `debug_identifier`. Atomic
+    /// scripts are never merged with scripts that are not atomic.
+    ///
+    /// Called scripts are only merged within the script map they are stored in.
+    pub fn dedup(&mut self) {
+        self.rekey_called_scripts(|_, called_script| {
+            called_script.dedup();
+            calculate_hash(&(called_script.content_hash(), called_script.is_atomic()))
+        });
+    }
+
+    /// Hashes the instructions of the script independently of how they are split into
+    /// blocks and called scripts.
+    fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.for_each_atom(&mut |atom| atom.hash(&mut hasher));
+        hasher.finish()
+    }
+
+    /// Calls `f` with every instruction of the script and every placeholder, in order.
+    fn for_each_atom<'a, F: FnMut(Atom<'a>)>(&'a self, f: &mut F) {
+        for block in &self.blocks {
+            match block {
+                Block::Call(id) => self.get_structured_script(id).for_each_atom(f),
+                Block::Script(script_buf) => {
+                    // The exact bytes of every instruction, including its encoding
+                    let bytes = script_buf.as_bytes();
+                    let mut starts = script_buf
+                        .instruction_indices()
+                        .map_while(|instruction| instruction.ok().map(|(start, _)| start))
+                        .peekable();
+                    while let Some(start) = starts.next() {
+                        let end = starts.peek().copied().unwrap_or(bytes.len());
+                        f(Atom::Instruction(&bytes[start..end]));
+                    }
+                }
+                Block::Placeholder { name, len } => f(Atom::Placeholder(name, *len)),
+            }
+        }
+    }
+
+    /// Returns how often `opcode` occurs in the script and the scripts it calls. Data pushes
+    /// are counted by the opcode that starts them, e.g. `OP_PUSHBYTES_32`.
+    pub fn count_op(&self, opcode: Opcode) -> usize {
+        self.opcode_counts(&mut HashMap::new())[opcode.to_u8() as usize]
+    }
+
+    /// Returns how often every opcode that occurs in the script and the scripts it calls
+    /// is used, ordered by opcode. Unfilled placeholders are not counted.
+    pub fn opcode_histogram(&self) -> Vec<(Opcode, usize)> {
+        self.opcode_counts(&mut HashMap::new())
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(byte, count)| (Opcode::from(byte as u8), *count))
+            .collect()
+    }
+
+    /// Returns the number of opcodes that count towards the limit of 201 opcodes of scripts
+    /// outside of tapscript, i.e. all opcodes except data pushes and `OP_1` to `OP_16`.
+    /// Opcodes in both branches of an `OP_IF` are counted.
+    pub fn num_ops(&self) -> usize {
+        self.num_ops_cached(&mut HashMap::new())
+    }
+
+    pub(crate) fn num_ops_cached(&self, cache: &mut HashMap<u64, [usize; 256]>) -> usize {
+        self.opcode_counts(cache)[OP_PUSHNUM_16.to_u8() as usize + 1..]
+            .iter()
+            .sum()
+    }
+
+    // Scripts that are called several times are only counted once
+    fn opcode_counts(&self, cache: &mut HashMap<u64, [usize; 256]>) -> [usize; 256] {
+        let mut counts = [0; 256];
+        for block in &self.blocks {
+            match block {
+                Block::Call(id) => {
+                    let called_counts = match cache.get(id) {
+                        Some(called_counts) => *called_counts,
+                        None => {
+                            let called_counts = self.get_structured_script(id).opcode_counts(cache);
+                            cache.insert(*id, called_counts);
+                            called_counts
+                        }
+                    };
+                    for (count, called_count) in counts.iter_mut().zip(called_counts) {
+                        *count += called_count;
+                    }
+                }
+                Block::Script(script_buf) => {
+                    let bytes = script_buf.as_bytes();
+                    for (start, _) in script_buf.instruction_indices().map_while(Result::ok) {
+                        counts[bytes[start] as usize] += 1;
+                    }
+                }
+                Block::Placeholder { .. } => (),
+            }
+        }
+        counts
+    }
+
+    /// Attributes the bytes of the script to the names of the scripts they are written in,
+    /// ordered from the largest to the smallest share. Bytes of called scripts count
+    /// towards the called script once per call, so the shares add up to [`Self::len`].
+    pub fn size_report(&self) -> Vec<(String, usize)> {
+        let mut report: Vec<_> = self.named_sizes(&mut HashMap::new()).into_iter().collect();
+        report.sort_by(|(name, size), (other_name, other_size)| {
+            other_size.cmp(size).then_with(|| name.cmp(other_name))
+        });
+        report
+    }
+
+    fn named_sizes(
+        &self,
+        cache: &mut HashMap<u64, HashMap<String, usize>>,
+    ) -> HashMap<String, usize> {
+        let mut sizes = HashMap::new();
+        let mut own_size = 0;
+        for block in &self.blocks {
+            match block {
+                Block::Call(id) => {
+                    if !cache.contains_key(id) {
+                        let called_sizes = self.get_structured_script(id).named_sizes(cache);
+                        cache.insert(*id, called_sizes);
+                    }
+                    for (name, size) in &cache[id] {
+                        *sizes.entry(name.clone()).or_insert(0) += size;
+                    }
+                }
+                Block::Script(script_buf) => own_size += script_buf.len(),
+                Block::Placeholder { len, .. } => own_size += len,
+            }
+        }
+        if own_size > 0 {
+            *sizes.entry(self.name().to_string()).or_insert(0) += own_size;
+        }
+        sizes
+    }
+
+    /// Counts the signature operations of the script under the rules of `context`.
+    ///
+    /// Legacy scripts count every `OP_CHECKMULTISIG(VERIFY)` as 20 sigops. Segwit v0
+    /// scripts count the number of keys if it is pushed right before the opcode. Tapscript
+    /// has no multisig opcodes and counts every signature check once, including
+    /// `OP_CHECKSIGADD`, which is what the per-input sigop budget is charged for.
+    pub fn num_sigops(&self, context: SigopContext) -> usize {
+        let mut sigops = 0;
+        let mut last_opcode = None;
+        for instruction in self.instructions() {
+            let opcode = match instruction {
+                Ok(Instruction::Op(opcode)) => opcode,
+                Ok(Instruction::PushBytes(_)) => {
+                    last_opcode = None;
+                    continue;
+                }
+                Err(err) => panic!(
+                    "Invalid instruction in {}: {:?}",
+                    self.debug_identifier, err
+                ),
+            };
+            sigops += match (opcode, context) {
+                (OP_CHECKSIG | OP_CHECKSIGVERIFY, _) => 1,
+                (OP_CHECKSIGADD, SigopContext::Tapscript) => 1,
+                (OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY, SigopContext::Legacy) => {
+                    MAX_PUBKEYS_PER_MULTISIG
+                }
+                (OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY, SigopContext::SegwitV0) => {
+                    match last_opcode.map(Opcode::to_u8) {
+                        Some(n) if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&n) => {
+                            (n - OP_PUSHNUM_1.to_u8() + 1) as usize
+                        }
+                        _ => MAX_PUBKEYS_PER_MULTISIG,
+                    }
+                }
+                _ => 0,
+            };
+            last_opcode = Some(opcode);
+        }
+        sigops
+    }
+
+    /// Estimates the witness of spending a taproot output via this leaf script, whose
+    /// control block proves a depth of `control_block_depth` in the script tree, with
+    /// `witness_items` as the initial stack. Panics if the depth exceeds 128.
+    pub fn estimate_spend_weight(
+        &self,
+        control_block_depth: usize,
+        witness_items: &[Vec<u8>],
+    ) -> SpendWeight {
+        SpendWeight::new(
+            self.len(),
+            self.num_sigops(SigopContext::Tapscript),
+            control_block_depth,
+            witness_items,
+        )
+    }
+
+    /// Returns whether both scripts consist of the same blocks, called scripts and
+    /// placeholders. Unlike `==` this distinguishes scripts that compile to the same
+    /// instructions but are structured differently. Names and metadata are ignored.
+    pub fn structurally_eq(&self, other: &StructuredScript) -> bool {
+        self.blocks.len() == other.blocks.len()
+            && self
+                .blocks
+                .iter()
+                .zip(&other.blocks)
+                .all(|blocks| match blocks {
+                    (Block::Call(id), Block::Call(other_id)) => self
+                        .get_structured_script(id)
+                        .structurally_eq(other.get_structured_script(other_id)),
+                    (block, other_block) => block == other_block,
+                })
+    }
+
+    fn has_name(&self, name: &str) -> bool {
+        self.name() == name
+    }
+
+    /// Returns the number of opened minus the number of closed `OP_IF`s and `OP_NOTIF`s,
+    /// which is negative if the script closes conditionals of its caller.
+    pub fn num_unclosed_ifs(&self) -> i32 {
+        self.unclosed_ifs(&mut HashMap::new())
+    }
+
+    pub(crate) fn unclosed_ifs(&self, cache: &mut HashMap<u64, i32>) -> i32 {
+        let mut unclosed = 0;
+        for block in &self.blocks {
+            match block {
+                Block::Call(id) => {
+                    unclosed += match cache.get(id) {
+                        Some(called_unclosed) => *called_unclosed,
+                        None => {
+                            let called_unclosed =
+                                self.get_structured_script(id).unclosed_ifs(cache);
+                            cache.insert(*id, called_unclosed);
+                            called_unclosed
+                        }
+                    }
+                }
+                Block::Script(script_buf) => {
+                    for instruction in script_buf.instructions() {
+                        match instruction {
+                            Ok(Instruction::Op(OP_IF | OP_NOTIF)) => unclosed += 1,
+                            Ok(Instruction::Op(OP_ENDIF)) => unclosed -= 1,
+                            _ => (),
+                        }
+                    }
+                }
+                Block::Placeholder { .. } => (),
+            }
+        }
+        unclosed
+    }
+
+    // Returns a script for every block, named like this script. Called scripts are returned
+    // as they are.
+    pub(crate) fn split_blocks(&self) -> Vec<StructuredScript> {
+        self.blocks
+            .iter()
+            .map(|block| match block {
+                Block::Call(id) => self.get_structured_script(id).clone(),
+                Block::Script(script_buf) => StructuredScript {
+                    size: script_buf.len(),
+                    debug_identifier: self.debug_identifier.clone(),
+                    blocks: vec![block.clone()],
+                    script_map: HashMap::new(),
+                    metadata: BTreeMap::new(),
+                    location: self.location.clone(),
+                },
+                Block::Placeholder { name, len } => StructuredScript::placeholder(name, *len),
+            })
+            .collect()
+    }
+
+    /// Splits a script that consists of a single `ScriptBuf` in two at the instruction
+    /// border closest to its middle, preferring borders outside of `OP_IF`s. Returns `None`
+    /// for any other script and for a single instruction.
+    pub(crate) fn split_script_buf(&self) -> Option<[StructuredScript; 2]> {
+        let script_buf = match self.blocks.as_slice() {
+            [Block::Script(script_buf)] => script_buf,
+            _ => return None,
+        };
+        let middle = script_buf.len() / 2;
+        let mut num_unclosed_ifs: i32 = 0;
+        let mut split = None;
+        for (index, instruction) in script_buf.instruction_indices().map_while(Result::ok) {
+            let key = (num_unclosed_ifs.unsigned_abs(), index.abs_diff(middle));
+            if index > 0 && split.is_none_or(|(_, best_key)| key < best_key) {
+                split = Some((index, key));
+            }
+            match instruction {
+                Instruction::Op(OP_IF | OP_NOTIF) => num_unclosed_ifs += 1,
+                Instruction::Op(OP_ENDIF) => num_unclosed_ifs -= 1,
+                _ => (),
+            }
+        }
+        let (index, _) = split?;
+        let piece = |bytes: &[u8]| StructuredScript {
+            size: bytes.len(),
+            debug_identifier: self.debug_identifier.clone(),
+            blocks: vec![Block::Script(ScriptBuf::from_bytes(bytes.to_vec()))],
+            script_map: HashMap::new(),
+            metadata: BTreeMap::new(),
+            location: self.location.clone(),
+        };
+        let (first, second) = script_buf.as_bytes().split_at(index);
+        Some([piece(first), piece(second)])
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        // Calls prefix the identifier with the caller's and subroutines with the function
+        self.debug_identifier
+            .rsplit([' ', ':'])
+            .next()
+            .unwrap_or_else(|| unreachable!())
+    }
+
+    /// Formats the script as ASM with one instruction per line. Every called script starts
+    /// with a `# name` header and its instructions are indented one level deeper.
+    pub fn to_asm_tree(&self) -> String {
+        let mut asm = String::new();
+        self.write_asm_tree(&mut asm, 0);
+        asm
+    }
+
+    fn write_asm_tree(&self, asm: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth);
+        for block in &self.blocks {
+            match block {
+                Block::Call(id) => {
+                    let called_script = self.get_structured_script(id);
+                    asm.push_str(&format!("{}# {}\n", indent, called_script.name()));
+                    called_script.write_asm_tree(asm, depth + 1);
+                }
+                Block::Script(script_buf) => {
+                    let bytes = script_buf.as_bytes();
+                    let starts: Vec<usize> = script_buf
+                        .instruction_indices()
+                        .map(|instruction| match instruction {
+                            Ok((start, _)) => start,
+                            Err(err) => panic!(
+                                "Invalid instruction in {}: {:?}",
+                                self.debug_identifier, err
+                            ),
+                        })
+                        .collect();
+                    for (i, start) in starts.iter().enumerate() {
+                        let end = starts.get(i + 1).copied().unwrap_or(bytes.len());
+                        let instruction = script::Script::from_bytes(&bytes[*start..end]);
+                        asm.push_str(&format!("{}{}\n", indent, instruction.to_asm_string()));
+                    }
+                }
+                Block::Placeholder { name, len } => {
+                    asm.push_str(&format!(
+                        "{}# placeholder {} ({} bytes)\n",
+                        indent, name, len
+                    ));
+                }
+            }
+        }
     }
 
     // Return the debug information of the Opcode at position
@@ -89,6 +820,12 @@ impl StructuredScript {
                     }
                     current_pos += script_buf.len();
                 }
+                Block::Placeholder { name, len } => {
+                    if position >= current_pos && position < current_pos + len {
+                        return name.clone();
+                    }
+                    current_pos += len;
+                }
             }
         }
         panic!("No blocks in the structured script");
@@ -118,39 +855,152 @@ impl StructuredScript {
     }
 
     pub fn push_script(mut self, data: ScriptBuf) -> StructuredScript {
-        let mut pos = 0;
-        for instruction in data.instructions() {
-            match instruction {
-                Ok(Instruction::Op(_)) => pos += 1,
-                Ok(Instruction::PushBytes(pushbytes)) => pos += pushbytes.len() + 1,
-                _ => (),
-            };
-        }
-        assert_eq!(data.len(), pos, "Pos counting seems to be off");
+        assert!(
+            data.instructions().all(|instruction| instruction.is_ok()),
+            "Pushed script contains an invalid instruction"
+        );
         self.size += data.len();
         self.blocks.push(Block::Script(data));
         self
     }
 
+    /// Marks the position after the blocks pushed so far as a preferred chunk border, see
+    /// [`crate::Chunker`]. The hint is an empty script called `chunk_hint` with the
+    /// metadata key [`CHUNK_HINT`].
+    pub fn push_chunk_hint(mut self) -> StructuredScript {
+        let hint = StructuredScript::new(&format!("{} chunk_hint", self.debug_identifier))
+            .set_metadata(CHUNK_HINT, "");
+        let id = hint.id();
+        self.blocks.push(Block::Call(id));
+        self.add_structured_script(id, hint);
+        self
+    }
+
+    /// Keeps the script in a single chunk, see [`crate::Chunker`]. This sets the metadata
+    /// key [`ATOMIC`], which `/// @atomic: true` does inside `script!`.
+    pub fn atomic(self) -> StructuredScript {
+        self.set_metadata(ATOMIC, "true")
+    }
+
+    pub fn is_atomic(&self) -> bool {
+        self.metadata.contains_key(ATOMIC)
+    }
+
+    /// Returns true if this is a hint added by [`Self::push_chunk_hint`].
+    pub fn is_chunk_hint(&self) -> bool {
+        self.is_empty() && self.metadata.contains_key(CHUNK_HINT)
+    }
+
     pub fn push_env_script(mut self, mut data: StructuredScript) -> StructuredScript {
-        if data.len() == 0 {
+        if data.is_empty() {
             return self;
         }
-        if self.len() == 0 {
+        // Scripts with metadata are called, so that metadata set on this script later
+        // doesn't overwrite theirs
+        if self.is_empty() && data.metadata.is_empty() {
             return data;
         }
 
         data.debug_identifier = format!("{} {}", self.debug_identifier, data.debug_identifier);
         self.size += data.len();
-        let id = calculate_hash(&data);
+        let id = data.id();
         self.blocks.push(Block::Call(id));
         // Register script in the script map
         self.add_structured_script(id, data);
         self
     }
 
+    /// Appends the blocks of `other` to this script. Calls in `other` keep pointing to
+    /// their scripts and metadata of this script takes precedence over `other`'s.
+    pub fn concat(mut self, other: StructuredScript) -> StructuredScript {
+        self.size += other.size;
+        self.blocks.extend(other.blocks);
+        for (id, script) in other.script_map {
+            self.add_structured_script(id, script);
+        }
+        for (key, value) in other.metadata {
+            self.metadata.entry(key).or_insert(value);
+        }
+        self
+    }
+
+    /// Inserts the blocks of `script` before the block at `block_index`.
+    ///
+    /// Panics if `block_index` is larger than the number of blocks.
+    pub fn insert_at(&mut self, block_index: usize, script: StructuredScript) {
+        self.splice(block_index..block_index, script);
+    }
+
+    /// Replaces the blocks in `range` with the blocks of `script`. Scripts that are no
+    /// longer called are removed from the script map.
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn splice<R: RangeBounds<usize>>(&mut self, range: R, script: StructuredScript) {
+        let removed: Vec<Block> = self.blocks.splice(range, script.blocks).collect();
+        for block in &removed {
+            self.size -= self.block_len(block);
+        }
+        self.size += script.size;
+        for (id, called_script) in script.script_map {
+            self.add_structured_script(id, called_script);
+        }
+
+        let called: HashSet<u64> = self
+            .blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Call(id) => Some(*id),
+                Block::Script(_) | Block::Placeholder { .. } => None,
+            })
+            .collect();
+        self.script_map.retain(|id, _| called.contains(id));
+    }
+
+    fn block_len(&self, block: &Block) -> usize {
+        match block {
+            Block::Call(id) => self.get_structured_script(id).len(),
+            Block::Script(script_buf) => script_buf.len(),
+            Block::Placeholder { len, .. } => *len,
+        }
+    }
+
+    /// Calls `data` `count` times. The script is stored only once no matter how often it
+    /// is repeated.
+    pub fn push_repeated(mut self, count: usize, mut data: StructuredScript) -> StructuredScript {
+        if count == 0 || data.is_empty() {
+            return self;
+        }
+
+        data.debug_identifier = format!("{} {}", self.debug_identifier, data.debug_identifier);
+        self.size += count * data.len();
+        let id = data.id();
+        self.blocks.extend((0..count).map(|_| Block::Call(id)));
+        self.add_structured_script(id, data);
+        self
+    }
+
+    /// Iterates over the instructions of this script and all scripts it calls, in the
+    /// order in which they appear in the compiled script.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            stack: vec![(self, 0)],
+            current: None,
+        }
+    }
+
     /// Compiles the script to bytes.
     fn compile_to_bytes(&self) -> Vec<u8> {
+        let mut script = Vec::new();
+        self.compile_into(&mut script);
+        script
+    }
+
+    /// Appends the compiled script to `buffer`, reserving its length upfront.
+    ///
+    /// Unlike [`Self::compile`] this does not consume the script and does not check that
+    /// the result is minimally encoded. Reusing one buffer for several scripts avoids
+    /// allocating a new one for every script.
+    pub fn compile_into(&self, buffer: &mut Vec<u8>) {
         #[derive(Debug)]
         enum Task<'a> {
             CompileCall {
@@ -178,13 +1028,17 @@ impl StructuredScript {
                         });
                     }
                     Block::Script(buffer) => tasks.push(Task::PushRaw(buffer)),
+                    Block::Placeholder { name, .. } => {
+                        panic!("Unfilled placeholder {} in {}", name, script.describe())
+                    }
                 }
             }
         }
 
         let mut tasks = Vec::new();
         let mut cache = HashMap::new();
-        let mut script: Vec<u8> = Vec::with_capacity(self.size);
+        let script = buffer;
+        script.reserve(self.size);
         push_script(self, &mut tasks);
 
         while let Some(task) = tasks.pop() {
@@ -249,8 +1103,6 @@ impl StructuredScript {
                 }
             }
         }
-
-        script
     }
 
     pub fn compile(self) -> ScriptBuf {
@@ -289,9 +1141,21 @@ impl StructuredScript {
         }
     }
     fn push_int_non_minimal(self, data: i64) -> StructuredScript {
-        let mut buf = [0u8; 8];
-        let len = write_scriptint(&mut buf, data);
-        self.push_slice(&<&PushBytes>::from(&buf)[..len])
+        // Little endian magnitude with the sign in the most significant bit. Unlike
+        // `write_scriptint` this also handles i64::MIN, which takes nine bytes.
+        let mut buf = Vec::with_capacity(9);
+        let mut abs = data.unsigned_abs();
+        while abs > 0 {
+            buf.push(abs as u8);
+            abs >>= 8;
+        }
+        if buf.last().is_some_and(|byte| byte & 0x80 != 0) {
+            buf.push(0);
+        }
+        if data < 0 {
+            *buf.last_mut().unwrap_or_else(|| unreachable!()) |= 0x80;
+        }
+        self.push_slice(<&PushBytes>::try_from(buf.as_slice()).unwrap())
     }
 
     pub fn push_slice<T: AsRef<PushBytes>>(mut self, data: T) -> StructuredScript {
@@ -302,6 +1166,24 @@ impl StructuredScript {
         self
     }
 
+    /// Pushes `data` as a single stack element. Single bytes that have a minimal opcode,
+    /// i.e. 1 to 16 and `0x81` for -1, are pushed with it.
+    ///
+    /// Panics if `data` exceeds the 520 byte stack element limit.
+    pub fn push_bytes(self, data: &[u8]) -> StructuredScript {
+        assert!(
+            data.len() <= MAX_SCRIPT_ELEMENT_SIZE,
+            "Data push of {} bytes exceeds the maximum stack element size of {} bytes",
+            data.len(),
+            MAX_SCRIPT_ELEMENT_SIZE
+        );
+        match data {
+            [byte @ 1..=16] => self.push_int((*byte).into()),
+            [0x81] => self.push_int(-1),
+            _ => self.push_slice(<&PushBytes>::try_from(data).unwrap()),
+        }
+    }
+
     pub fn push_key(self, key: &::bitcoin::PublicKey) -> StructuredScript {
         if key.compressed {
             self.push_slice(key.inner.serialize())
@@ -317,6 +1199,151 @@ impl StructuredScript {
     pub fn push_expression<T: Pushable>(self, expression: T) -> StructuredScript {
         expression.bitcoin_script_push(self)
     }
+
+    /// Statically analyzes the effect of the script on the main and alt stack.
+    pub fn analyze_stack(&self) -> StackStatus {
+        StackAnalyzer::new().analyze(self)
+    }
+
+    /// Returns the worst-case number of elements on the main and alt stack combined over any
+    /// execution path, assuming the script starts with just the elements it consumes.
+    /// Consensus limits this to 1000 elements.
+    pub fn max_stack_depth(&self) -> usize {
+        let status = self.analyze_stack();
+        let inputs = -status.deepest_stack_accessed - status.deepest_altstack_accessed;
+        (inputs + status.max_stack_height) as usize
+    }
+
+    /// Returns the items that the script reads from the initial stack, which the witness
+    /// has to provide. The items are named by the [`WITNESS`] metadata of the script,
+    /// e.g. `/// @witness: signature, preimage`, and numbered otherwise.
+    ///
+    /// Panics if the script can't be analyzed, reads the alt stack or the metadata names a
+    /// different number of items.
+    pub fn witness_template(&self) -> WitnessTemplate {
+        let status = self.analyze_stack();
+        assert!(
+            status.deepest_altstack_accessed == 0,
+            "{} reads {} elements from the alt stack, which the witness can't provide",
+            self.describe(),
+            -status.deepest_altstack_accessed
+        );
+        let num_items = -status.deepest_stack_accessed as usize;
+        let items: Vec<String> = match self.get_metadata(WITNESS) {
+            Some(names) => names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            None => (0..num_items)
+                .map(|index| format!("input_{}", index))
+                .collect(),
+        };
+        assert_eq!(
+            items.len(),
+            num_items,
+            "{} reads {} stack elements, but its witness metadata names {}",
+            self.describe(),
+            num_items,
+            items.len()
+        );
+        WitnessTemplate::new(items)
+    }
+
+    /// Panics if the script does not change the main stack height by `delta`.
+    pub fn assert_stack_delta(self, delta: i32) -> StructuredScript {
+        let stack_changed = self.analyze_stack().stack_changed;
+        let location = self
+            .location
+            .as_ref()
+            .map(|location| format!(" at {}", location))
+            .unwrap_or_default();
+        assert_eq!(
+            stack_changed, delta,
+            "Stack delta of {} is {}, expected {}{}",
+            self.debug_identifier, stack_changed, delta, location
+        );
+        self
+    }
+
+    /// Panics if the script is longer than `max_size` bytes, listing the named scripts
+    /// that take up the most space.
+    pub fn assert_max_size(self, max_size: usize) -> StructuredScript {
+        if self.len() > max_size {
+            let largest: Vec<_> = self
+                .size_report()
+                .into_iter()
+                .take(5)
+                .map(|(name, size)| format!("{} ({} bytes)", name, size))
+                .collect();
+            panic!(
+                "Size of {} is {} bytes, expected at most {}. Largest parts: {}",
+                self.describe(),
+                self.len(),
+                max_size,
+                largest.join(", ")
+            );
+        }
+        self
+    }
+}
+
+/// Expands a value that is interpolated in `script!` into a whole script.
+///
+/// This is the extension point for gadget libraries: any type implementing this trait can
+/// be used as `{ value }` inside `script!`. The returned script is called like any other
+/// nested script, so its `debug_identifier` should name the gadget.
+pub trait ToStructuredScript {
+    fn to_structured_script(self) -> StructuredScript;
+}
+impl ToStructuredScript for StructuredScript {
+    fn to_structured_script(self) -> StructuredScript {
+        self
+    }
+}
+
+/// Iterator over the instructions of a [`StructuredScript`] that walks the called
+/// scripts instead of compiling them first. Created by [`StructuredScript::instructions`].
+pub struct Instructions<'a> {
+    // Scripts that are being walked together with the index of their next block
+    stack: Vec<(&'a StructuredScript, usize)>,
+    current: Option<script::Instructions<'a>>,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, script::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(instruction) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(instruction);
+            }
+            self.current = None;
+
+            let (script, index) = self.stack.last_mut()?;
+            let script: &'a StructuredScript = script;
+            let block = script.blocks.get(*index);
+            *index += 1;
+            match block {
+                Some(Block::Call(id)) => self.stack.push((script.get_structured_script(id), 0)),
+                Some(Block::Script(script_buf)) => self.current = Some(script_buf.instructions()),
+                Some(Block::Placeholder { name, .. }) => {
+                    panic!("Unfilled placeholder {} in {}", name, script.describe())
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl Add for StructuredScript {
+    type Output = StructuredScript;
+
+    fn add(self, other: StructuredScript) -> StructuredScript {
+        self.concat(other)
+    }
 }
 
 // We split up the bitcoin_script_push function to allow pushing a single u8 value as
@@ -348,12 +1375,22 @@ impl NotU8Pushable for usize {
 }
 impl NotU8Pushable for Vec<u8> {
     fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
-        // Push the element with a minimal opcode if it is a single number.
-        if self.len() == 1 {
-            builder.push_int(self[0].into())
-        } else {
-            builder.push_slice(PushBytesBuf::try_from(self.to_vec()).unwrap())
-        }
+        builder.push_bytes(&self)
+    }
+}
+impl NotU8Pushable for &[u8] {
+    fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
+        builder.push_bytes(self)
+    }
+}
+impl<const N: usize> NotU8Pushable for [u8; N] {
+    fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
+        builder.push_bytes(&self)
+    }
+}
+impl<const N: usize> NotU8Pushable for &[u8; N] {
+    fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
+        builder.push_bytes(self)
     }
 }
 impl NotU8Pushable for ::bitcoin::PublicKey {
@@ -361,6 +1398,16 @@ impl NotU8Pushable for ::bitcoin::PublicKey {
         builder.push_key(&self)
     }
 }
+impl NotU8Pushable for ::bitcoin::CompressedPublicKey {
+    fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
+        builder.push_slice(self.to_bytes())
+    }
+}
+impl NotU8Pushable for ::bitcoin::secp256k1::PublicKey {
+    fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
+        builder.push_slice(self.serialize())
+    }
+}
 impl NotU8Pushable for ::bitcoin::XOnlyPublicKey {
     fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
         builder.push_x_only_key(&self)
@@ -369,19 +1416,18 @@ impl NotU8Pushable for ::bitcoin::XOnlyPublicKey {
 impl NotU8Pushable for Witness {
     fn bitcoin_script_push(self, mut builder: StructuredScript) -> StructuredScript {
         for element in self.into_iter() {
-            // Push the element with a minimal opcode if it is a single number.
-            if element.len() == 1 {
-                builder = builder.push_int(element[0].into());
-            } else {
-                builder = builder.push_slice(PushBytesBuf::try_from(element.to_vec()).unwrap());
-            }
+            // A single zero byte, e.g. an encoded varint, is pushed as the number zero.
+            builder = match element {
+                [0] => builder.push_int(0),
+                _ => builder.push_bytes(element),
+            };
         }
         builder
     }
 }
-impl NotU8Pushable for StructuredScript {
+impl<T: ToStructuredScript> NotU8Pushable for T {
     fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript {
-        builder.push_env_script(self)
+        builder.push_env_script(self.to_structured_script())
     }
 }
 impl<T: NotU8Pushable> NotU8Pushable for Vec<T> {
@@ -392,6 +1438,14 @@ impl<T: NotU8Pushable> NotU8Pushable for Vec<T> {
         builder
     }
 }
+/// A value that can be interpolated in `script!`, e.g. as `{ value }` or `<value>`.
+///
+/// Implementations append the value to `builder` and return the extended script, usually
+/// by delegating to [`StructuredScript::push_int`], [`StructuredScript::push_bytes`] or the
+/// `Pushable` implementation of an inner value. A value may push any number of elements.
+///
+/// User types can implement this trait directly, for example to push a field element as
+/// its limbs. Vectors of user types are not pushable, push the elements in a loop instead.
 pub trait Pushable {
     fn bitcoin_script_push(self, builder: StructuredScript) -> StructuredScript;
 }
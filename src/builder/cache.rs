@@ -0,0 +1,258 @@
+//! Binary cache format for structured scripts.
+//!
+//! The format starts with a magic string and a version byte, followed by the script.
+//! A script is encoded as its debug identifier, its source location, its metadata, its
+//! called scripts and its blocks. Strings, byte strings and lists are prefixed with their
+//! length as `u32`, all integers are little endian.
+
+use super::{Block, SourceLocation, StructuredScript};
+use bitcoin::ScriptBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"BSSC";
+const VERSION: u8 = 2;
+
+const CALL_TAG: u8 = 0;
+const SCRIPT_TAG: u8 = 1;
+const PLACEHOLDER_TAG: u8 = 2;
+
+/// Error returned by [`StructuredScript::from_cache_bytes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheError {
+    /// The data does not start with the magic string of the cache format.
+    InvalidHeader,
+    /// The data was written by an incompatible version of this crate.
+    UnsupportedVersion(u8),
+    /// The data ends in the middle of a script.
+    UnexpectedEnd,
+    /// The data is malformed, e.g. it calls a script that is not included.
+    InvalidData(String),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::InvalidHeader => write!(f, "Not a cached script"),
+            CacheError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported cache version {}", version)
+            }
+            CacheError::UnexpectedEnd => write!(f, "Unexpected end of cached script"),
+            CacheError::InvalidData(reason) => write!(f, "Invalid cached script: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl StructuredScript {
+    /// Encodes the script including its block structure, names and metadata, so that it
+    /// can be stored and loaded with [`Self::from_cache_bytes`] instead of being rebuilt.
+    pub fn to_cache_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() + 64);
+        bytes.extend(MAGIC);
+        bytes.push(VERSION);
+        write_script(&mut bytes, self);
+        bytes
+    }
+
+    /// Decodes a script that was encoded with [`Self::to_cache_bytes`].
+    pub fn from_cache_bytes(bytes: &[u8]) -> Result<StructuredScript, CacheError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(CacheError::InvalidHeader);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(CacheError::UnsupportedVersion(version));
+        }
+        let script = read_script(&mut reader)?;
+        if !reader.bytes.is_empty() {
+            return Err(CacheError::InvalidData(format!(
+                "{} trailing bytes",
+                reader.bytes.len()
+            )));
+        }
+        Ok(script)
+    }
+}
+
+fn write_len(bytes: &mut Vec<u8>, len: usize) {
+    let len = u32::try_from(len).unwrap_or_else(|_| panic!("Length {} exceeds u32", len));
+    bytes.extend(len.to_le_bytes());
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    write_len(bytes, data.len());
+    bytes.extend(data);
+}
+
+fn write_script(bytes: &mut Vec<u8>, script: &StructuredScript) {
+    write_bytes(bytes, script.debug_identifier.as_bytes());
+
+    match &script.location {
+        Some(location) => {
+            bytes.push(1);
+            write_bytes(bytes, location.file.as_bytes());
+            bytes.extend(location.line.to_le_bytes());
+        }
+        None => bytes.push(0),
+    }
+
+    write_len(bytes, script.metadata.len());
+    for (key, value) in &script.metadata {
+        write_bytes(bytes, key.as_bytes());
+        write_bytes(bytes, value.as_bytes());
+    }
+
+    // Sorted, so that the same script is always encoded the same way
+    let mut called_scripts: Vec<_> = script.script_map.iter().collect();
+    called_scripts.sort_unstable_by_key(|(id, _)| **id);
+    write_len(bytes, called_scripts.len());
+    for (id, called_script) in called_scripts {
+        bytes.extend(id.to_le_bytes());
+        write_script(bytes, called_script);
+    }
+
+    write_len(bytes, script.blocks.len());
+    for block in &script.blocks {
+        match block {
+            Block::Call(id) => {
+                bytes.push(CALL_TAG);
+                bytes.extend(id.to_le_bytes());
+            }
+            Block::Script(script_buf) => {
+                bytes.push(SCRIPT_TAG);
+                write_bytes(bytes, script_buf.as_bytes());
+            }
+            Block::Placeholder { name, len } => {
+                bytes.push(PLACEHOLDER_TAG);
+                write_bytes(bytes, name.as_bytes());
+                bytes.extend((*len as u64).to_le_bytes());
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CacheError> {
+        if self.bytes.len() < len {
+            return Err(CacheError::UnexpectedEnd);
+        }
+        let (data, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8, CacheError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, CacheError> {
+        let data = self.take(4)?;
+        Ok(u32::from_le_bytes(
+            data.try_into().unwrap_or_else(|_| unreachable!()),
+        ))
+    }
+
+    fn u64(&mut self) -> Result<u64, CacheError> {
+        let data = self.take(8)?;
+        Ok(u64::from_le_bytes(
+            data.try_into().unwrap_or_else(|_| unreachable!()),
+        ))
+    }
+
+    fn len(&mut self) -> Result<usize, CacheError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], CacheError> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, CacheError> {
+        let data = self.bytes()?;
+        String::from_utf8(data.to_vec())
+            .map_err(|err| CacheError::InvalidData(format!("invalid string ({})", err)))
+    }
+}
+
+fn read_script(reader: &mut Reader) -> Result<StructuredScript, CacheError> {
+    let debug_identifier = reader.string()?;
+
+    let location = match reader.u8()? {
+        0 => None,
+        1 => Some(SourceLocation {
+            file: reader.string()?.into(),
+            line: reader.u32()?,
+        }),
+        flag => {
+            return Err(CacheError::InvalidData(format!(
+                "invalid location flag {}",
+                flag
+            )))
+        }
+    };
+
+    let mut metadata = BTreeMap::new();
+    for _ in 0..reader.len()? {
+        metadata.insert(reader.string()?, reader.string()?);
+    }
+
+    // Ids are recomputed instead of being trusted, because compiling copies scripts with
+    // the same id from each other
+    let mut script_map = HashMap::new();
+    let mut ids = HashMap::new();
+    for _ in 0..reader.len()? {
+        let cached_id = reader.u64()?;
+        let called_script = read_script(reader)?;
+        let id = called_script.id();
+        ids.insert(cached_id, id);
+        script_map.insert(id, Arc::new(called_script));
+    }
+
+    // Lengths are derived from the blocks instead of being trusted
+    let mut size = 0;
+    let mut blocks = Vec::new();
+    for _ in 0..reader.len()? {
+        let block = match reader.u8()? {
+            CALL_TAG => {
+                let cached_id = reader.u64()?;
+                let id = *ids.get(&cached_id).ok_or_else(|| {
+                    CacheError::InvalidData(format!("missing called script {}", cached_id))
+                })?;
+                let called_script = &script_map[&id];
+                size += called_script.len();
+                Block::Call(id)
+            }
+            SCRIPT_TAG => {
+                let script_buf = ScriptBuf::from_bytes(reader.bytes()?.to_vec());
+                size += script_buf.len();
+                Block::Script(script_buf)
+            }
+            PLACEHOLDER_TAG => {
+                let name = reader.string()?;
+                let len = reader.u64()? as usize;
+                size += len;
+                Block::Placeholder { name, len }
+            }
+            tag => return Err(CacheError::InvalidData(format!("unknown block {}", tag))),
+        };
+        blocks.push(block);
+    }
+
+    Ok(StructuredScript {
+        size,
+        debug_identifier,
+        blocks,
+        script_map,
+        metadata,
+        location,
+    })
+}
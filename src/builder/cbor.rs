@@ -0,0 +1,51 @@
+//! CBOR encoding of structured scripts and chunk manifests, for components that exchange
+//! compact binary metadata instead of JSON.
+
+use super::StructuredScript;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+/// Error returned when CBOR can't be decoded, see [`StructuredScript::from_cbor`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CborError {
+    /// The data is not valid CBOR or doesn't match the encoded type.
+    InvalidData(String),
+    /// The data was written in a version of the format that is not supported.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CborError::InvalidData(reason) => write!(f, "Invalid CBOR: {}", reason),
+            CborError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported CBOR version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CborError {}
+
+pub(crate) fn to_cbor<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![];
+    ciborium::into_writer(value, &mut bytes).expect("Writing to a vector can't fail");
+    bytes
+}
+
+pub(crate) fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CborError> {
+    ciborium::from_reader(bytes).map_err(|error| CborError::InvalidData(error.to_string()))
+}
+
+impl StructuredScript {
+    /// Encodes the script including its block structure, names and metadata as CBOR.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        to_cbor(self)
+    }
+
+    /// Decodes a script that was encoded with [`Self::to_cbor`].
+    pub fn from_cbor(bytes: &[u8]) -> Result<StructuredScript, CborError> {
+        from_cbor(bytes)
+    }
+}
@@ -0,0 +1,251 @@
+use super::{Block, StructuredScript};
+use bitcoin::ScriptBuf;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// An instruction sequence that occurs several times in a script, see
+/// [`StructuredScript::repeated_sequences`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepeatedSequence {
+    pub script: ScriptBuf,
+    /// Number of occurrences that don't overlap each other or other sequences.
+    pub count: usize,
+}
+
+impl RepeatedSequence {
+    /// Number of bytes that are stored once instead of `count` times if the sequence
+    /// becomes a shared block.
+    pub fn bytes_saved(&self) -> usize {
+        self.script.len() * self.count.saturating_sub(1)
+    }
+}
+
+// Offsets of the instructions of a block, followed by its length
+fn boundaries(script_buf: &ScriptBuf) -> Vec<usize> {
+    let mut boundaries: Vec<usize> = script_buf
+        .instruction_indices()
+        .map_while(|instruction| instruction.ok().map(|(start, _)| start))
+        .collect();
+    boundaries.push(script_buf.len());
+    boundaries
+}
+
+impl StructuredScript {
+    /// Finds instruction sequences of at least `min_size` bytes that occur several times
+    /// in the blocks of the script and of the scripts it calls, ordered from the most to
+    /// the fewest saved bytes. Sequences don't cross block borders, and a called script is
+    /// searched once no matter how often it is called.
+    ///
+    /// The search is greedy: occurrences of a sequence that saves more are taken first,
+    /// and shorter sequences are only reported where they don't overlap them.
+    pub fn repeated_sequences(&self, min_size: usize) -> Vec<RepeatedSequence> {
+        let mut script_bufs = vec![];
+        self.collect_script_bufs(&mut HashSet::new(), &mut script_bufs);
+        let boundaries: Vec<Vec<usize>> = script_bufs.iter().map(|s| boundaries(s)).collect();
+        let instruction = |block: usize, index: usize| {
+            let bounds = &boundaries[block];
+            &script_bufs[block].as_bytes()[bounds[index]..bounds[index + 1]]
+        };
+
+        // Positions (block, first instruction, end instruction) by the shortest sequence of
+        // at least `min_size` bytes that starts there
+        let mut seeds: HashMap<&[u8], Vec<(usize, usize, usize)>> = HashMap::new();
+        for (block, bounds) in boundaries.iter().enumerate() {
+            let num_instructions = bounds.len() - 1;
+            let mut end = 0;
+            for start in 0..num_instructions {
+                end = end.max(start + 1);
+                while end < num_instructions && bounds[end] - bounds[start] < min_size {
+                    end += 1;
+                }
+                if bounds[end] - bounds[start] < min_size {
+                    break;
+                }
+                let bytes = &script_bufs[block].as_bytes()[bounds[start]..bounds[end]];
+                seeds.entry(bytes).or_default().push((block, start, end));
+            }
+        }
+
+        let mut candidates = vec![];
+        for positions in seeds.into_values().filter(|positions| positions.len() > 1) {
+            // Positions that are preceded by the same instruction everywhere are part of a
+            // longer sequence
+            let (first_block, first_start, _) = positions[0];
+            let extends_left = positions.iter().all(|&(block, start, _)| {
+                start > 0
+                    && first_start > 0
+                    && instruction(block, start - 1) == instruction(first_block, first_start - 1)
+            });
+            if extends_left {
+                continue;
+            }
+            let mut extension = 0;
+            loop {
+                let (first_block, _, first_end) = positions[0];
+                let extends = positions.iter().all(|&(block, _, end)| {
+                    let next = end + extension;
+                    next + 1 < boundaries[block].len()
+                        && first_end + extension + 1 < boundaries[first_block].len()
+                        && instruction(block, next)
+                            == instruction(first_block, first_end + extension)
+                });
+                if !extends {
+                    break;
+                }
+                extension += 1;
+            }
+            let positions: Vec<(usize, usize, usize)> = positions
+                .into_iter()
+                .map(|(block, start, end)| (block, start, end + extension))
+                .collect();
+            let (block, start, end) = positions[0];
+            let bytes =
+                &script_bufs[block].as_bytes()[boundaries[block][start]..boundaries[block][end]];
+            candidates.push((bytes, positions));
+        }
+        candidates.sort_by_key(|(bytes, positions)| {
+            (Reverse(bytes.len() * (positions.len() - 1)), *bytes)
+        });
+
+        // Instructions that are part of an accepted occurrence
+        let mut covered: Vec<Vec<bool>> = boundaries
+            .iter()
+            .map(|bounds| vec![false; bounds.len() - 1])
+            .collect();
+        let mut repeats = vec![];
+        for (bytes, positions) in candidates {
+            let mut accepted: Vec<(usize, usize, usize)> = vec![];
+            for (block, start, end) in positions {
+                let overlaps_previous =
+                    accepted.last().is_some_and(|&(last_block, _, last_end)| {
+                        last_block == block && last_end > start
+                    });
+                if !overlaps_previous && !covered[block][start..end].contains(&true) {
+                    accepted.push((block, start, end));
+                }
+            }
+            if accepted.len() < 2 {
+                continue;
+            }
+            for &(block, start, end) in &accepted {
+                covered[block][start..end].fill(true);
+            }
+            repeats.push(RepeatedSequence {
+                script: ScriptBuf::from_bytes(bytes.to_vec()),
+                count: accepted.len(),
+            });
+        }
+        repeats.sort_by_key(|repeat| Reverse(repeat.bytes_saved()));
+        repeats
+    }
+
+    /// Moves the sequences found by [`Self::repeated_sequences`] into called scripts named
+    /// `common_0`, `common_1` and so on, which are stored once and shared by every call.
+    /// Returns the extracted sequences with the number of occurrences that were replaced.
+    ///
+    /// The compiled script doesn't change, but it takes less memory, and the extracted
+    /// scripts are deduplicated and cached like any other called script.
+    pub fn extract_repeated_sequences(&mut self, min_size: usize) -> Vec<RepeatedSequence> {
+        let mut repeats = self.repeated_sequences(min_size);
+        let extracted: Vec<(u64, Arc<StructuredScript>)> = repeats
+            .iter()
+            .enumerate()
+            .map(|(index, repeat)| {
+                let script = StructuredScript::new(&format!("common_{}", index))
+                    .push_script(repeat.script.clone());
+                (script.id(), Arc::new(script))
+            })
+            .collect();
+        let mut counts = vec![0; repeats.len()];
+        self.replace_sequences(&repeats, &extracted, &mut HashSet::new(), &mut counts);
+        for (repeat, count) in repeats.iter_mut().zip(counts) {
+            repeat.count = count;
+        }
+        repeats
+    }
+
+    // Pushes the script buffers of the script and of the scripts it calls, each called
+    // script once
+    fn collect_script_bufs<'a>(&'a self, visited: &mut HashSet<u64>, out: &mut Vec<&'a ScriptBuf>) {
+        for block in &self.blocks {
+            match block {
+                Block::Script(script_buf) => out.push(script_buf),
+                Block::Call(id) => {
+                    if visited.insert(*id) {
+                        self.get_structured_script(id)
+                            .collect_script_bufs(visited, out);
+                    }
+                }
+                Block::Placeholder { .. } => (),
+            }
+        }
+    }
+
+    // Replaces the occurrences in the script and the scripts it calls with calls of the
+    // extracted scripts and returns whether anything changed. Occurrences in called
+    // scripts that were replaced before are not counted again.
+    fn replace_sequences(
+        &mut self,
+        repeats: &[RepeatedSequence],
+        extracted: &[(u64, Arc<StructuredScript>)],
+        visited: &mut HashSet<u64>,
+        counts: &mut [usize],
+    ) -> bool {
+        // Called scripts go first, so that the extracted scripts are not searched
+        let mut changed = self.update_called_scripts(|called_script| {
+            let mut counted = vec![0; counts.len()];
+            let changed =
+                called_script.replace_sequences(repeats, extracted, visited, &mut counted);
+            if visited.insert(called_script.id()) {
+                for (count, counted) in counts.iter_mut().zip(counted) {
+                    *count += counted;
+                }
+            }
+            changed
+        });
+
+        let mut blocks = vec![];
+        for block in std::mem::take(&mut self.blocks) {
+            let script_buf = match block {
+                Block::Script(script_buf) => script_buf,
+                block => {
+                    blocks.push(block);
+                    continue;
+                }
+            };
+            let bytes = script_buf.as_bytes();
+            let mut piece_start = 0;
+            for start in boundaries(&script_buf) {
+                if start < piece_start || start == bytes.len() {
+                    continue;
+                }
+                let repeat = repeats
+                    .iter()
+                    .position(|repeat| bytes[start..].starts_with(repeat.script.as_bytes()));
+                if let Some(index) = repeat {
+                    if piece_start < start {
+                        blocks.push(Block::Script(ScriptBuf::from_bytes(
+                            bytes[piece_start..start].to_vec(),
+                        )));
+                    }
+                    let (id, script) = &extracted[index];
+                    blocks.push(Block::Call(*id));
+                    self.add_structured_script(*id, script.clone());
+                    counts[index] += 1;
+                    piece_start = start + repeats[index].script.len();
+                    changed = true;
+                }
+            }
+            if piece_start == 0 {
+                blocks.push(Block::Script(script_buf));
+            } else if piece_start < bytes.len() {
+                blocks.push(Block::Script(ScriptBuf::from_bytes(
+                    bytes[piece_start..].to_vec(),
+                )));
+            }
+        }
+        self.blocks = blocks;
+        changed
+    }
+}
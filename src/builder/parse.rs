@@ -0,0 +1,319 @@
+//! Rebuilds structured scripts from compiled scripts, ASM and miniscripts.
+
+use super::{Block, StructuredScript};
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::{Opcode, OP_FALSE, OP_TRUE};
+use bitcoin::blockdata::script::{self, Builder, Instruction, PushBytes, PushBytesBuf, Script};
+use bitcoin::hex::FromHex;
+use bitcoin::ScriptBuf;
+use lazy_static::lazy_static;
+#[cfg(feature = "miniscript")]
+use miniscript::{Miniscript, Tap, ToPublicKey};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Error returned when a script can't be rebuilt, see
+/// [`StructuredScript::from_script_buf`] and [`StructuredScript::parse_asm`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The script is cut off in the middle of a push.
+    InvalidInstruction { offset: usize, error: script::Error },
+    /// An `OP_ELSE` or `OP_ENDIF` without an `OP_IF`, or an `OP_IF` that is never closed.
+    UnbalancedConditional { opcode: Opcode, offset: usize },
+    /// A border that is not between two instructions.
+    InvalidBorder(usize),
+    /// A word in ASM that is neither an opcode, an integer nor a hex push.
+    UnknownOpcode(String),
+    /// A push opcode at the end of ASM without the hex data that it pushes.
+    MissingPushData(String),
+    /// Pushed data in ASM that is not valid hex.
+    InvalidHex(String),
+    /// A push opcode in ASM followed by data of a different length.
+    PushLengthMismatch {
+        opcode: Opcode,
+        expected: usize,
+        actual: usize,
+    },
+    /// A push opcode in ASM that is not the shortest encoding of its data, which
+    /// [`StructuredScript::compile`] rejects.
+    NonMinimalPush {
+        opcode: Opcode,
+        minimal: Opcode,
+        len: usize,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidInstruction { offset, error } => {
+                write!(f, "Invalid instruction at offset {}: {}", offset, error)
+            }
+            ParseError::UnbalancedConditional { opcode, offset } => match *opcode {
+                OP_IF | OP_NOTIF => write!(f, "Unclosed {} at offset {}", opcode, offset),
+                _ => write!(f, "{} without OP_IF at offset {}", opcode, offset),
+            },
+            ParseError::InvalidBorder(offset) => {
+                write!(
+                    f,
+                    "Border at offset {} is not between two instructions",
+                    offset
+                )
+            }
+            ParseError::UnknownOpcode(word) => write!(f, "Unknown opcode \"{}\"", word),
+            ParseError::MissingPushData(word) => write!(f, "Expected data after {}", word),
+            ParseError::InvalidHex(word) => write!(f, "Invalid hex push {}", word),
+            ParseError::PushLengthMismatch {
+                opcode,
+                expected,
+                actual,
+            } => write!(f, "{} expects {} bytes, got {}", opcode, expected, actual),
+            ParseError::NonMinimalPush {
+                opcode,
+                minimal,
+                len,
+            } => write!(
+                f,
+                "{} is not the minimal push of {} bytes, use {}",
+                opcode, len, minimal
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+lazy_static! {
+    // Opcodes by their name with and without the `OP_` prefix, like in `script!`
+    static ref OPCODES: HashMap<String, Opcode> = {
+        let mut opcodes = HashMap::new();
+        for byte in 0..=u8::MAX {
+            let opcode = Opcode::from(byte);
+            let name = opcode.to_string();
+            opcodes.insert(name[3..].to_string(), opcode);
+            opcodes.insert(name, opcode);
+        }
+        let aliases = [
+            ("OP_0", OP_PUSHBYTES_0),
+            ("OP_TRUE", OP_TRUE),
+            ("TRUE", OP_TRUE),
+            ("OP_FALSE", OP_FALSE),
+            ("FALSE", OP_FALSE),
+            ("OP_NOP2", OP_CLTV),
+            ("NOP2", OP_CLTV),
+            ("OP_NOP3", OP_CSV),
+            ("NOP3", OP_CSV),
+        ];
+        for (name, opcode) in aliases {
+            opcodes.insert(name.to_string(), opcode);
+        }
+        for n in 1..=16u8 {
+            let opcode = Opcode::from(OP_PUSHNUM_1.to_u8() + n - 1);
+            opcodes.insert(format!("OP_{}", n), opcode);
+        }
+        opcodes
+    };
+}
+
+// The scripts that are built while a compiled script is read, from the outermost to the
+// innermost open OP_IF
+struct ScriptStack<'a> {
+    bytes: &'a [u8],
+    scripts: Vec<(StructuredScript, usize)>,
+    // Start of the instructions that were not pushed into the innermost script yet
+    segment_start: usize,
+}
+
+impl ScriptStack<'_> {
+    // Pushes the instructions up to `end` into the innermost script
+    fn push_segment(&mut self, end: usize) {
+        if end > self.segment_start {
+            let segment = ScriptBuf::from_bytes(self.bytes[self.segment_start..end].to_vec());
+            let (script, _) = self.scripts.last_mut().unwrap_or_else(|| unreachable!());
+            script.size += segment.len();
+            script.blocks.push(Block::Script(segment));
+        }
+        self.segment_start = end;
+    }
+}
+
+impl StructuredScript {
+    /// Rebuilds the structure of a compiled script, e.g. one that was read from a
+    /// transaction, so that it can be analyzed and chunked like any other script. Every
+    /// `OP_IF` or `OP_NOTIF` becomes a called script up to its `OP_ENDIF`, named `if_` and
+    /// its offset, and every `OP_ELSE` starts a new block.
+    pub fn from_script_buf(script_buf: &Script) -> Result<StructuredScript, ParseError> {
+        Self::from_script_buf_with_borders(script_buf, &[])
+    }
+
+    /// Rebuilds a compiled script like [`Self::from_script_buf`] and additionally starts a
+    /// new block at every offset in `borders`, e.g. at known chunk borders.
+    pub fn from_script_buf_with_borders(
+        script_buf: &Script,
+        borders: &[usize],
+    ) -> Result<StructuredScript, ParseError> {
+        Self::rebuild("script_buf", script_buf, borders)
+    }
+
+    fn rebuild(
+        name: &str,
+        script_buf: &Script,
+        borders: &[usize],
+    ) -> Result<StructuredScript, ParseError> {
+        let mut instructions = vec![];
+        let mut indices = script_buf.instruction_indices();
+        loop {
+            let offset = script_buf.len() - indices.as_script().len();
+            match indices.next() {
+                Some(Ok(instruction)) => instructions.push(instruction),
+                Some(Err(error)) => return Err(ParseError::InvalidInstruction { offset, error }),
+                None => break,
+            }
+        }
+        let borders: BTreeSet<usize> = borders.iter().copied().collect();
+        let starts: BTreeSet<usize> = instructions
+            .iter()
+            .map(|(offset, _)| *offset)
+            .chain([script_buf.len()])
+            .collect();
+        if let Some(border) = borders.difference(&starts).next() {
+            return Err(ParseError::InvalidBorder(*border));
+        }
+
+        let mut stack = ScriptStack {
+            bytes: script_buf.as_bytes(),
+            scripts: vec![(StructuredScript::new(name), 0)],
+            segment_start: 0,
+        };
+        for (offset, instruction) in instructions {
+            if borders.contains(&offset) {
+                stack.push_segment(offset);
+            }
+            let opcode = match instruction {
+                Instruction::Op(opcode) => Some(opcode),
+                Instruction::PushBytes(_) => None,
+            };
+            match opcode {
+                Some(OP_IF | OP_NOTIF) => {
+                    stack.push_segment(offset);
+                    stack
+                        .scripts
+                        .push((StructuredScript::new(&format!("if_{}", offset)), offset));
+                }
+                Some(OP_ELSE) if stack.scripts.len() > 1 => stack.push_segment(offset),
+                Some(OP_ENDIF) if stack.scripts.len() > 1 => {
+                    stack.push_segment(offset + 1);
+                    let (script, _) = stack.scripts.pop().unwrap_or_else(|| unreachable!());
+                    let (caller, _) = stack.scripts.last_mut().unwrap_or_else(|| unreachable!());
+                    caller.push_call(script);
+                }
+                Some(opcode @ (OP_ELSE | OP_ENDIF)) => {
+                    return Err(ParseError::UnbalancedConditional { opcode, offset })
+                }
+                _ => (),
+            }
+        }
+        stack.push_segment(script_buf.len());
+        let (script, start) = stack.scripts.pop().unwrap_or_else(|| unreachable!());
+        if !stack.scripts.is_empty() {
+            let opcode = match script_buf.as_bytes()[start] {
+                byte if byte == OP_NOTIF.to_u8() => OP_NOTIF,
+                _ => OP_IF,
+            };
+            return Err(ParseError::UnbalancedConditional {
+                opcode,
+                offset: start,
+            });
+        }
+        Ok(script)
+    }
+
+    /// Parses a script in the ASM format of `include_script!` at runtime, e.g. one read
+    /// from a configuration file or a test vector. Words are opcodes with or without the
+    /// `OP_` prefix, decimal integers, `0x` hex pushes or push opcodes followed by their
+    /// hex data, and `#` starts a comment. Like in `include_script!`, every push is
+    /// minimally encoded, so that the script compiles: `0x` pushes get their shortest
+    /// encoding, and push opcodes that are not the shortest encoding of their data are
+    /// rejected. The structure is rebuilt like in [`Self::from_script_buf`].
+    pub fn parse_asm(asm: &str) -> Result<StructuredScript, ParseError> {
+        let mut builder = Builder::new();
+        let mut words = asm.lines().flat_map(|line| {
+            line.split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+        });
+        while let Some(word) = words.next() {
+            builder = if let Some(hex) = word.strip_prefix("0x") {
+                push_minimal(builder, &parse_push(hex)?)
+            } else if let Ok(n) = word.parse::<i64>() {
+                builder.push_int(n)
+            } else if let Some(opcode) = OPCODES.get(word).copied() {
+                let byte = opcode.to_u8();
+                if (OP_PUSHBYTES_1.to_u8()..=OP_PUSHDATA4.to_u8()).contains(&byte) {
+                    let hex = words
+                        .next()
+                        .ok_or_else(|| ParseError::MissingPushData(word.to_string()))?;
+                    let data = parse_push(hex)?;
+                    if byte <= OP_PUSHBYTES_75.to_u8() && data.len() != byte as usize {
+                        return Err(ParseError::PushLengthMismatch {
+                            opcode,
+                            expected: byte as usize,
+                            actual: data.len(),
+                        });
+                    }
+                    let minimal = push_minimal(Builder::new(), &data).into_bytes()[0];
+                    if minimal != byte {
+                        return Err(ParseError::NonMinimalPush {
+                            opcode,
+                            minimal: Opcode::from(minimal),
+                            len: data.len(),
+                        });
+                    }
+                    push_minimal(builder, &data)
+                } else {
+                    builder.push_opcode(opcode)
+                }
+            } else {
+                return Err(ParseError::UnknownOpcode(word.to_string()));
+            };
+        }
+        Self::from_script_buf(builder.as_script())
+    }
+
+    // Calls `script` even if this script is empty, unlike `push_env_script`
+    fn push_call(&mut self, mut script: StructuredScript) {
+        script.debug_identifier = format!("{} {}", self.debug_identifier, script.debug_identifier);
+        self.size += script.len();
+        let id = script.id();
+        self.blocks.push(Block::Call(id));
+        self.add_structured_script(id, script);
+    }
+}
+
+/// Rebuilds the compiled miniscript like [`StructuredScript::from_script_buf`], so that
+/// conditions derived from a policy can be composed with other scripts. The script is
+/// named `miniscript`.
+#[cfg(feature = "miniscript")]
+impl<Pk: ToPublicKey> From<Miniscript<Pk, Tap>> for StructuredScript {
+    fn from(miniscript: Miniscript<Pk, Tap>) -> Self {
+        StructuredScript::rebuild("miniscript", &miniscript.encode(), &[])
+            .expect("Miniscripts compile to balanced conditionals")
+    }
+}
+
+// Pushes the data with its shortest encoding, like `StructuredScript::push_bytes`
+fn push_minimal(builder: Builder, data: &PushBytes) -> Builder {
+    match data.as_bytes() {
+        [n @ 1..=16] => builder.push_opcode(Opcode::from(OP_PUSHNUM_1.to_u8() + n - 1)),
+        [0x81] => builder.push_opcode(OP_PUSHNUM_NEG1),
+        _ => builder.push_slice(data),
+    }
+}
+
+fn parse_push(hex: &str) -> Result<PushBytesBuf, ParseError> {
+    Vec::<u8>::from_hex(hex)
+        .ok()
+        .and_then(|data| PushBytesBuf::try_from(data).ok())
+        .ok_or_else(|| ParseError::InvalidHex(hex.to_string()))
+}
@@ -0,0 +1,72 @@
+//! Witness stacks for scripts whose inputs become known later.
+
+use bitcoin::Witness;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The stack items that a script reads from the witness, see
+/// [`super::StructuredScript::witness_template`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WitnessTemplate {
+    items: Vec<String>,
+}
+
+/// Error returned by [`WitnessTemplate::fill`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WitnessError {
+    /// No value was given for the item.
+    MissingItem(String),
+    /// A value was given for an item that the template doesn't contain.
+    UnknownItem(String),
+}
+
+impl fmt::Display for WitnessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WitnessError::MissingItem(name) => write!(f, "Missing witness item {}", name),
+            WitnessError::UnknownItem(name) => write!(f, "Unknown witness item {}", name),
+        }
+    }
+}
+
+impl std::error::Error for WitnessError {}
+
+impl WitnessTemplate {
+    pub(super) fn new(items: Vec<String>) -> Self {
+        WitnessTemplate { items }
+    }
+
+    /// Names of the items, from the bottom to the top of the initial stack. An item that
+    /// is read several times appears several times.
+    pub fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Builds the witness stack from the values of the items. The script and the control
+    /// block of a taproot spend still have to be pushed after the items.
+    pub fn fill(&self, values: &[(&str, &[u8])]) -> Result<Witness, WitnessError> {
+        if let Some((name, _)) = values
+            .iter()
+            .find(|(name, _)| !self.items.iter().any(|item| item == name))
+        {
+            return Err(WitnessError::UnknownItem(name.to_string()));
+        }
+        let values: HashMap<&str, &[u8]> = values.iter().copied().collect();
+        let mut witness = Witness::new();
+        for item in &self.items {
+            match values.get(item.as_str()) {
+                Some(value) => witness.push(value),
+                None => return Err(WitnessError::MissingItem(item.clone())),
+            }
+        }
+        Ok(witness)
+    }
+}
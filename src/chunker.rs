@@ -0,0 +1,1691 @@
+//! Splits a script into chunks of a target size, e.g. to distribute a program that is too
+//! large for a single script over several taproot leaves.
+//!
+//! Chunks are cut between the blocks of the script. Scripts that don't fit into the
+//! current chunk are split into their blocks, so a chunk border can lie inside a called
+//! script, unless the script is marked as [`StructuredScript::atomic`]. `ScriptBuf`s that
+//! don't fit are split between their instructions. Every chunk closes all `OP_IF`s it opens.
+//!
+//! Besides the size in bytes, the number of opcodes of a chunk can be limited, see
+//! [`Chunker::with_max_ops`].
+//!
+//! Chunking is deterministic: the same script and settings always result in the same
+//! chunks, on every platform. [`chunk_fingerprint`] summarizes the chunks in a single hash.
+//! [`chunks_to_taproot`] commits to the chunks as the leaves of a taproot output.
+//!
+//! With the `json` feature, `Chunk::to_json` and `Chunk::from_json` write and read chunks
+//! in a stable JSON format for tools outside of Rust. A [`Manifest`] summarizes the chunks
+//! by their hashes.
+
+#[cfg(feature = "json")]
+mod json;
+mod manifest;
+mod strategy;
+mod taproot;
+
+#[cfg(feature = "json")]
+pub use self::json::{JsonError, CHUNK_JSON_VERSION};
+pub use self::manifest::{Manifest, ManifestEntry, MANIFEST_VERSION};
+pub use self::strategy::{Balanced, ChunkStrategy, DpOptimal, Greedy, Rebalanced};
+pub use self::taproot::{chunks_to_taproot, TaprootChunks};
+
+use crate::analyzer::{StackAnalysisError, StackAnalyzer, StackStatus};
+use crate::builder::{Block, SigopContext, SpendWeight, StructuredScript};
+use bitcoin::blockdata::opcodes::all::{OP_ENDIF, OP_IF, OP_NOTIF, OP_PUSHNUM_16};
+use bitcoin::blockdata::script::{Instruction, ScriptBuf};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::hex::DisplayHex;
+use bitcoin::policy::MAX_STANDARD_TX_WEIGHT;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Consensus limit on the combined number of elements on the main and alt stack
+pub const MAX_STACK_SIZE: usize = 1000;
+
+/// Weight that [`Chunker::for_tapscript`] reserves for the control block, the length
+/// prefixes of the witness elements and the non-witness part of the transaction.
+pub const TAPSCRIPT_TX_OVERHEAD: usize = 10_000;
+
+/// Tolerance of the chunks of [`Chunker::for_tapscript`].
+pub const TAPSCRIPT_TOLERANCE: usize = 10_000;
+
+/// The number of elements a chunk reads from and leaves on the main and alt stack. The
+/// witness of a chunk has to provide its inputs, which are the outputs of the preceding
+/// chunks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkInterface {
+    inputs: usize,
+    outputs: usize,
+    altstack_inputs: usize,
+    altstack_outputs: usize,
+}
+
+impl ChunkInterface {
+    pub fn new(
+        inputs: usize,
+        outputs: usize,
+        altstack_inputs: usize,
+        altstack_outputs: usize,
+    ) -> ChunkInterface {
+        ChunkInterface {
+            inputs,
+            outputs,
+            altstack_inputs,
+            altstack_outputs,
+        }
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    pub fn altstack_inputs(&self) -> usize {
+        self.altstack_inputs
+    }
+
+    pub fn altstack_outputs(&self) -> usize {
+        self.altstack_outputs
+    }
+}
+
+/// The results of the stack analysis of a chunk.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkStats {
+    interface: ChunkInterface,
+    // Worst-case number of elements on both stacks, including the elements left by the
+    // preceding chunks
+    max_stack_depth: usize,
+}
+
+impl ChunkStats {
+    pub fn new(interface: ChunkInterface, max_stack_depth: usize) -> ChunkStats {
+        ChunkStats {
+            interface,
+            max_stack_depth,
+        }
+    }
+
+    pub fn interface(&self) -> &ChunkInterface {
+        &self.interface
+    }
+
+    /// The largest number of elements on the main and alt stack combined, see
+    /// [`Chunk::max_stack_depth`].
+    pub fn max_stack_depth(&self) -> usize {
+        self.max_stack_depth
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Chunk {
+    scripts: Vec<StructuredScript>,
+    size: usize,
+    stats: Option<ChunkStats>,
+}
+
+impl Chunk {
+    fn new(scripts: Vec<StructuredScript>, size: usize) -> Chunk {
+        Chunk {
+            scripts,
+            size,
+            stats: None,
+        }
+    }
+
+    pub fn scripts(self) -> Vec<StructuredScript> {
+        self.scripts
+    }
+
+    /// Iterates over the scripts of the chunk without consuming it.
+    pub fn iter_scripts(&self) -> impl Iterator<Item = &StructuredScript> {
+        self.scripts.iter()
+    }
+
+    /// Returns the size of the chunk in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns which scripts the chunk consists of. Consecutive parts of the same script,
+    /// e.g. the blocks of a script that was split, are merged into one range. Empty
+    /// scripts like chunk hints are left out.
+    pub fn provenance(&self) -> Vec<ChunkSource> {
+        let mut sources: Vec<ChunkSource> = vec![];
+        let mut offset = 0;
+        for script in &self.scripts {
+            let range = offset..offset + script.len();
+            offset = range.end;
+            match sources.last_mut() {
+                _ if range.is_empty() => (),
+                Some(source) if source.script == script.debug_identifier => {
+                    source.range.end = range.end
+                }
+                _ => sources.push(ChunkSource {
+                    script: script.debug_identifier.clone(),
+                    range,
+                }),
+            }
+        }
+        sources
+    }
+
+    /// Estimates the witness of spending a taproot output via the chunk as a leaf script,
+    /// see [`StructuredScript::estimate_spend_weight`].
+    pub fn estimate_spend_weight(
+        &self,
+        control_block_depth: usize,
+        witness_items: &[Vec<u8>],
+    ) -> SpendWeight {
+        let num_sigops = self
+            .scripts
+            .iter()
+            .map(|script| script.num_sigops(SigopContext::Tapscript))
+            .sum();
+        SpendWeight::new(self.size, num_sigops, control_block_depth, witness_items)
+    }
+
+    /// Compiles the scripts of the chunk into one script.
+    ///
+    /// Panics if the compiled script is not exactly [`Self::size`] bytes long, which would
+    /// mean that the chunker miscounted the chunk.
+    pub fn compile(&self) -> ScriptBuf {
+        let mut bytes = Vec::with_capacity(self.size);
+        for script in &self.scripts {
+            script.compile_into(&mut bytes);
+        }
+        assert_eq!(
+            bytes.len(),
+            self.size,
+            "Chunk compiles to {} bytes, but the chunker counted {} bytes",
+            bytes.len(),
+            self.size
+        );
+        ScriptBuf::from_bytes(bytes)
+    }
+
+    /// Returns the bytes of the compiled chunk, see [`Self::compile`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.compile().into_bytes()
+    }
+
+    /// Returns the hex encoded bytes of the compiled chunk, see [`Self::compile`].
+    pub fn to_hex(&self) -> String {
+        self.to_bytes().to_lower_hex_string()
+    }
+
+    // The scripts of the chunk as a single script
+    pub(crate) fn script(&self) -> StructuredScript {
+        let mut script = StructuredScript::new("chunk");
+        for chunk_script in &self.scripts {
+            script = script.push_env_script(chunk_script.clone());
+        }
+        script
+    }
+
+    /// Returns the results of the stack analysis, which is `None` unless the chunk was
+    /// returned by [`Chunker::find_chunks_and_analyze_stack`].
+    pub fn stats(&self) -> Option<&ChunkStats> {
+        self.stats.as_ref()
+    }
+
+    /// Returns the stack interface of the chunk, which is `None` unless the chunk was
+    /// returned by [`Chunker::find_chunks_and_analyze_stack`].
+    pub fn interface(&self) -> Option<&ChunkInterface> {
+        self.stats.as_ref().map(|stats| &stats.interface)
+    }
+
+    /// Returns the largest number of elements on the main and alt stack combined while
+    /// the chunk is executed, starting with the elements left by the preceding chunks.
+    /// This is `None` unless the chunk was returned by
+    /// [`Chunker::find_chunks_and_analyze_stack`].
+    pub fn max_stack_depth(&self) -> Option<usize> {
+        self.stats.as_ref().map(|stats| stats.max_stack_depth)
+    }
+
+    /// Returns true if the chunk may exceed the consensus limit of [`MAX_STACK_SIZE`]
+    /// stack elements.
+    pub fn exceeds_stack_limit(&self) -> bool {
+        self.max_stack_depth()
+            .is_some_and(|depth| depth > MAX_STACK_SIZE)
+    }
+}
+
+/// Bytes of a chunk that come from a single script, see [`Chunk::provenance`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkSource {
+    /// Debug identifier of the script
+    pub script: String,
+    /// Position in the chunk
+    pub range: Range<usize>,
+}
+
+/// Two adjacent chunks whose stack interfaces don't match, see
+/// [`Chunker::verify_composition`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompositionMismatch {
+    /// Index of the first chunk of the pair
+    pub index: usize,
+    pub stack_output_size: usize,
+    pub next_stack_input_size: usize,
+    pub altstack_output_size: usize,
+    pub next_altstack_input_size: usize,
+}
+
+impl fmt::Display for CompositionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Chunk {} leaves {} stack and {} alt stack elements, chunk {} expects {} and {}",
+            self.index,
+            self.stack_output_size,
+            self.altstack_output_size,
+            self.index + 1,
+            self.next_stack_input_size,
+            self.next_altstack_input_size
+        )
+    }
+}
+
+/// Why a script can't be split into chunks. Every error contains the sizes of the chunks
+/// found before the error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkerError {
+    /// The chunk would have to end within an `OP_IF`, and the scripts that open the
+    /// `OP_IF` make up the whole chunk.
+    UnclosedIfsExceedTolerance {
+        num_unclosed_ifs: i32,
+        borders: Vec<usize>,
+    },
+    /// The next script can't be added to a chunk without exceeding the target size.
+    ChunkDoesNotFit {
+        script: String,
+        size: usize,
+        borders: Vec<usize>,
+    },
+    /// A single instruction or placeholder is larger than the target size and can't be split.
+    ScriptBufTooLarge {
+        script: String,
+        size: usize,
+        borders: Vec<usize>,
+    },
+    /// The stack of the next chunk can't be analyzed, see [`StackAnalyzer::try_analyze`].
+    StackAnalysis {
+        error: StackAnalysisError,
+        borders: Vec<usize>,
+    },
+}
+
+impl ChunkerError {
+    /// Sizes of the chunks that were found before the error
+    pub fn borders(&self) -> &[usize] {
+        match self {
+            ChunkerError::UnclosedIfsExceedTolerance { borders, .. }
+            | ChunkerError::ChunkDoesNotFit { borders, .. }
+            | ChunkerError::ScriptBufTooLarge { borders, .. }
+            | ChunkerError::StackAnalysis { borders, .. } => borders,
+        }
+    }
+
+    fn with_borders(mut self, chunk_borders: Vec<usize>) -> Self {
+        match &mut self {
+            ChunkerError::UnclosedIfsExceedTolerance { borders, .. }
+            | ChunkerError::ChunkDoesNotFit { borders, .. }
+            | ChunkerError::ScriptBufTooLarge { borders, .. }
+            | ChunkerError::StackAnalysis { borders, .. } => *borders = chunk_borders,
+        }
+        self
+    }
+}
+
+impl fmt::Display for ChunkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkerError::UnclosedIfsExceedTolerance {
+                num_unclosed_ifs, ..
+            } => write!(
+                f,
+                "Unable to close {} OP_IFs within a chunk",
+                num_unclosed_ifs
+            )?,
+            ChunkerError::ChunkDoesNotFit { script, size, .. } => write!(
+                f,
+                "Unable to fit the next script into a chunk: {} has {} bytes",
+                script, size
+            )?,
+            ChunkerError::ScriptBufTooLarge { script, size, .. } => write!(
+                f,
+                "Unable to split the next instruction: {} has {} bytes",
+                script, size
+            )?,
+            ChunkerError::StackAnalysis { error, .. } => {
+                write!(f, "Unable to analyze the next chunk: {}", error)?
+            }
+        }
+        write!(f, ", borders so far: {:?}", self.borders())
+    }
+}
+
+impl std::error::Error for ChunkerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChunkerError::StackAnalysis { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A position at which a chunk may end, see [`Chunker::possible_borders`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkBorder {
+    /// Position in the rest of the script
+    pub offset: usize,
+    /// Number of opcodes in the rest of the script before the border, counted like
+    /// [`StructuredScript::num_ops`]
+    pub num_ops: usize,
+}
+
+/// The progress of a [`Chunker`], which can be saved to resume the chunking later, see
+/// [`Chunker::state`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkerState {
+    target_chunk_size: usize,
+    tolerance: usize,
+    max_ops: Option<usize>,
+    call_stack: Vec<StructuredScript>,
+    top_level_only: bool,
+    top_level_split: bool,
+    chunk_sizes: Vec<usize>,
+}
+
+impl ChunkerState {
+    /// Sizes of the chunks that were found before the state was saved
+    pub fn chunk_sizes(&self) -> &[usize] {
+        &self.chunk_sizes
+    }
+
+    /// Returns true if the whole script has been chunked.
+    pub fn is_done(&self) -> bool {
+        self.call_stack.is_empty()
+    }
+}
+
+pub struct Chunker {
+    // Each chunk has to be in the interval [target_chunk_size - tolerance, target_chunk_size]
+    target_chunk_size: usize,
+    tolerance: usize,
+    max_ops: Option<usize>,
+    // Scripts that are not part of a chunk yet, the next one is at the end
+    call_stack: Vec<StructuredScript>,
+    strategy: Arc<dyn ChunkStrategy>,
+    // Opcode counts of the called scripts by their id
+    op_counts: HashMap<u64, [usize; 256]>,
+    // Whether only the top-level script may be split and if it has been split already
+    top_level_only: bool,
+    top_level_split: bool,
+    // Sizes of the chunks found so far
+    chunk_sizes: Vec<usize>,
+}
+
+impl Chunker {
+    pub fn new(
+        top_level_script: StructuredScript,
+        target_chunk_size: usize,
+        tolerance: usize,
+    ) -> Chunker {
+        assert!(
+            tolerance <= target_chunk_size,
+            "Tolerance {} exceeds the target chunk size {}",
+            tolerance,
+            target_chunk_size
+        );
+        Chunker {
+            target_chunk_size,
+            tolerance,
+            max_ops: None,
+            call_stack: vec![top_level_script],
+            strategy: Arc::new(Greedy),
+            op_counts: HashMap::new(),
+            top_level_only: false,
+            top_level_split: false,
+            chunk_sizes: vec![],
+        }
+    }
+
+    /// Returns the rest of the script that is not part of a chunk yet together with the
+    /// settings and the sizes of the chunks found so far. The strategy is not saved.
+    pub fn state(&self) -> ChunkerState {
+        ChunkerState {
+            target_chunk_size: self.target_chunk_size,
+            tolerance: self.tolerance,
+            max_ops: self.max_ops,
+            call_stack: self.call_stack.clone(),
+            top_level_only: self.top_level_only,
+            top_level_split: self.top_level_split,
+            chunk_sizes: self.chunk_sizes.clone(),
+        }
+    }
+
+    /// Continues chunking from a state returned by [`Self::state`] with the [`Greedy`]
+    /// strategy, unless another one is set.
+    pub fn resume(state: ChunkerState) -> Chunker {
+        Chunker {
+            target_chunk_size: state.target_chunk_size,
+            tolerance: state.tolerance,
+            max_ops: state.max_ops,
+            call_stack: state.call_stack,
+            strategy: Arc::new(Greedy),
+            op_counts: HashMap::new(),
+            top_level_only: state.top_level_only,
+            top_level_split: state.top_level_split,
+            chunk_sizes: state.chunk_sizes,
+        }
+    }
+
+    /// Chunks the script into tapscripts that can be spent by standard transactions, i.e.
+    /// transactions of at most [`MAX_STANDARD_TX_WEIGHT`] weight units. Every chunk is
+    /// spent with `witness_size` bytes of witness elements, which count one weight unit
+    /// per byte like the chunk itself.
+    pub fn for_tapscript(top_level_script: StructuredScript, witness_size: usize) -> Chunker {
+        let max_weight = MAX_STANDARD_TX_WEIGHT as usize - TAPSCRIPT_TX_OVERHEAD;
+        assert!(
+            witness_size + TAPSCRIPT_TOLERANCE <= max_weight,
+            "Witness size {} leaves no room for chunks in a standard transaction",
+            witness_size
+        );
+        Chunker::new(
+            top_level_script,
+            max_weight - witness_size,
+            TAPSCRIPT_TOLERANCE,
+        )
+    }
+
+    /// Only cuts chunks between the blocks of the top-level script, i.e. between the
+    /// scripts it calls, so every chunk consists of whole gadgets.
+    pub fn top_level_borders_only(mut self) -> Chunker {
+        self.top_level_only = true;
+        self
+    }
+
+    /// Additionally limits every chunk to `max_ops` opcodes, counted like
+    /// [`StructuredScript::num_ops`]. To limit chunks by their opcodes only, use
+    /// `usize::MAX` as the target chunk size and no tolerance.
+    pub fn with_max_ops(mut self, max_ops: usize) -> Chunker {
+        self.max_ops = Some(max_ops);
+        self
+    }
+
+    /// Chooses the chunk borders with `strategy` instead of [`Greedy`].
+    pub fn with_strategy(mut self, strategy: impl ChunkStrategy + 'static) -> Chunker {
+        self.strategy = Arc::new(strategy);
+        self
+    }
+
+    pub fn target_chunk_size(&self) -> usize {
+        self.target_chunk_size
+    }
+
+    pub fn tolerance(&self) -> usize {
+        self.tolerance
+    }
+
+    pub fn max_ops(&self) -> Option<usize> {
+        self.max_ops
+    }
+
+    /// Returns true if a chunk from `start` to `end` stays within the limits.
+    pub fn fits(&self, start: &ChunkBorder, end: &ChunkBorder) -> bool {
+        end.offset - start.offset <= self.target_chunk_size
+            && self
+                .max_ops
+                .is_none_or(|max_ops| end.num_ops - start.num_ops <= max_ops)
+    }
+
+    /// Returns the sizes of the chunks.
+    pub fn find_chunks(&mut self) -> Result<Vec<usize>, ChunkerError> {
+        Ok(self
+            .find_all_chunks()?
+            .iter()
+            .map(|chunk| chunk.size)
+            .collect())
+    }
+
+    /// Returns the chunks together with their stack interface. With the `rayon` feature the
+    /// chunks are analyzed in parallel.
+    pub fn find_chunks_and_analyze_stack(&mut self) -> Result<Vec<Chunk>, ChunkerError> {
+        let mut chunks = self.find_all_chunks()?;
+        let statuses =
+            analyze_chunks(&chunks).map_err(|(index, error)| ChunkerError::StackAnalysis {
+                error,
+                borders: chunks[..index].iter().map(|chunk| chunk.size).collect(),
+            })?;
+
+        // The elements that the preceding chunks leave on the stacks
+        let mut stack_size = 0;
+        let mut altstack_size = 0;
+        for (chunk, status) in chunks.iter_mut().zip(statuses) {
+            let stack_input_size = -status.deepest_stack_accessed;
+            let altstack_input_size = -status.deepest_altstack_accessed;
+            stack_size = max(stack_size, stack_input_size);
+            altstack_size = max(altstack_size, altstack_input_size);
+            let max_stack_depth = stack_size + altstack_size + status.max_stack_height;
+            stack_size += status.stack_changed;
+            altstack_size += status.altstack_changed;
+
+            chunk.stats = Some(ChunkStats {
+                interface: ChunkInterface {
+                    inputs: stack_input_size as usize,
+                    outputs: (stack_input_size + status.stack_changed) as usize,
+                    altstack_inputs: altstack_input_size as usize,
+                    altstack_outputs: (altstack_input_size + status.altstack_changed) as usize,
+                },
+                max_stack_depth: max_stack_depth as usize,
+            });
+        }
+        Ok(chunks)
+    }
+
+    fn find_all_chunks(&mut self) -> Result<Vec<Chunk>, ChunkerError> {
+        let strategy = self.strategy.clone();
+        strategy.find_chunks(self)
+    }
+
+    /// Fills every chunk up to at least `target_chunk_size - tolerance` bytes, splitting the
+    /// next script only if it doesn't fit as a whole. Chunks that would end inside an `OP_IF`
+    /// are cut before it instead. This is the [`Greedy`] strategy.
+    pub fn find_greedy_chunks(&mut self) -> Result<Vec<Chunk>, ChunkerError> {
+        self.collect()
+    }
+
+    /// Checks that every chunk leaves exactly as many elements on the main and alt stack as
+    /// the next chunk reads, and returns all pairs of chunks where this is not the case.
+    /// The chunks have to be returned by [`Self::find_chunks_and_analyze_stack`].
+    pub fn verify_composition(chunks: &[Chunk]) -> Result<(), Vec<CompositionMismatch>> {
+        let stats = |index: usize| {
+            chunks[index]
+                .stats
+                .as_ref()
+                .unwrap_or_else(|| panic!("Stack of chunk {} was not analyzed", index))
+        };
+        let mismatches: Vec<_> = (1..chunks.len())
+            .filter_map(|index| {
+                let (stats, next_stats) = (stats(index - 1), stats(index));
+                let mismatch = CompositionMismatch {
+                    index: index - 1,
+                    stack_output_size: stats.interface.outputs,
+                    next_stack_input_size: next_stats.interface.inputs,
+                    altstack_output_size: stats.interface.altstack_outputs,
+                    next_altstack_input_size: next_stats.interface.altstack_inputs,
+                };
+                let matches = mismatch.stack_output_size == mismatch.next_stack_input_size
+                    && mismatch.altstack_output_size == mismatch.next_altstack_input_size;
+                (!matches).then_some(mismatch)
+            })
+            .collect();
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(mismatches)
+        }
+    }
+
+    // Errors don't contain the borders of the preceding chunks yet
+    fn find_next_chunk(&mut self) -> Result<Chunk, ChunkerError> {
+        let mut chunk_scripts = vec![];
+        let mut chunk = ChunkBorder::default();
+        let mut num_unclosed_ifs = 0;
+        // The number of scripts and bytes of every chunk that is within the tolerance and
+        // closes all of its OP_IFs, and whether it ends with a chunk hint
+        let mut candidates = vec![];
+
+        loop {
+            let is_full = chunk.offset >= self.target_chunk_size - self.tolerance
+                || self.max_ops.is_some_and(|max_ops| chunk.num_ops >= max_ops);
+            if num_unclosed_ifs == 0 && (is_full || self.call_stack.is_empty()) {
+                let is_hint = chunk_scripts
+                    .last()
+                    .is_some_and(StructuredScript::is_chunk_hint);
+                candidates.push((chunk_scripts.len(), chunk.offset, is_hint));
+            }
+            let script = match self.call_stack.pop() {
+                Some(script) => script,
+                None => break,
+            };
+            let end = ChunkBorder {
+                offset: chunk.offset + script.len(),
+                num_ops: chunk.num_ops + script.num_ops_cached(&mut self.op_counts),
+            };
+            if self.fits(&ChunkBorder::default(), &end) {
+                chunk = end;
+                num_unclosed_ifs += script.num_unclosed_ifs();
+                chunk_scripts.push(script);
+            } else if !self.is_splittable(&script) && !chunk_scripts.is_empty() {
+                // The script may fit into the next chunk
+                self.call_stack.push(script);
+                break;
+            } else {
+                self.split_next(script)?;
+            }
+        }
+
+        if candidates.is_empty() {
+            let mut chunk_len = chunk.offset;
+            // Undo the lately added scripts if we are not at the end of the script
+            if !self.call_stack.is_empty() {
+                chunk_len -= self.undo(num_unclosed_ifs, &mut chunk_scripts)?;
+                if chunk_scripts.is_empty() {
+                    return Err(ChunkerError::UnclosedIfsExceedTolerance {
+                        num_unclosed_ifs,
+                        borders: vec![],
+                    });
+                }
+            }
+            return Ok(Chunk::new(chunk_scripts, chunk_len));
+        }
+
+        // Chunk hints within the tolerance take precedence
+        if candidates.iter().any(|(_, _, is_hint)| *is_hint) {
+            candidates.retain(|(_, _, is_hint)| *is_hint);
+        }
+        let (num_scripts, chunk_len, _) = candidates[best_candidate(&chunk_scripts, &candidates)];
+        self.call_stack
+            .extend(chunk_scripts.drain(num_scripts..).rev());
+        Ok(Chunk::new(chunk_scripts, chunk_len))
+    }
+
+    /// Returns the positions in the rest of the script at which a chunk may end, i.e. the
+    /// borders between blocks outside of any `OP_IF`. With [`Self::top_level_borders_only`]
+    /// these are only the borders between the blocks of the top-level script. The positions
+    /// start with 0 and end with the length of the rest of the script.
+    pub fn possible_borders(&self) -> Vec<ChunkBorder> {
+        self.borders_within(ChunkBorder {
+            offset: self.target_chunk_size,
+            num_ops: self.max_ops.unwrap_or(usize::MAX),
+        })
+    }
+
+    /// Estimates the sizes of the chunks of the rest of the script with a different target
+    /// size, without splitting any scripts. Every chunk ends at the last of the
+    /// [`Self::possible_borders`] that fits, which is where the greedy strategy ends chunks
+    /// unless the stack or chunk hints suggest a different border. The estimate stops at
+    /// the first chunk that can't end at a border.
+    pub fn estimate(&self, target_chunk_size: usize) -> Vec<usize> {
+        let max_chunk = ChunkBorder {
+            offset: target_chunk_size,
+            num_ops: self.max_ops.unwrap_or(usize::MAX),
+        };
+        let fits = |start: &ChunkBorder, end: &ChunkBorder| {
+            end.offset - start.offset <= max_chunk.offset
+                && end.num_ops - start.num_ops <= max_chunk.num_ops
+        };
+        let borders = self.borders_within(max_chunk);
+        let mut sizes = vec![];
+        let mut start = 0;
+        while start + 1 < borders.len() {
+            let end = (start + 1..borders.len())
+                .take_while(|&end| fits(&borders[start], &borders[end]))
+                .last();
+            match end {
+                Some(end) => {
+                    sizes.push(borders[end].offset - borders[start].offset);
+                    start = end;
+                }
+                None => break,
+            }
+        }
+        sizes
+    }
+
+    // Borders of the rest of the script, including the borders between the instructions of
+    // ScriptBufs that are larger than `max_chunk`
+    fn borders_within(&self, max_chunk: ChunkBorder) -> Vec<ChunkBorder> {
+        let mut borders = vec![ChunkBorder::default()];
+        let mut position = ChunkBorder::default();
+        let mut num_unclosed_ifs = 0;
+        for script in self.call_stack.iter().rev() {
+            if self.keeps_whole(script) {
+                add_border(script, &mut position, &mut num_unclosed_ifs, &mut borders);
+            } else {
+                let recurse = !self.top_level_only;
+                collect_borders(
+                    script,
+                    recurse.then_some(max_chunk),
+                    &mut position,
+                    &mut num_unclosed_ifs,
+                    &mut borders,
+                );
+            }
+        }
+        if borders.last() != Some(&position) {
+            borders.push(position);
+        }
+        borders
+    }
+
+    /// Cuts the next chunks with the given sizes in bytes off the rest of the script. Every
+    /// chunk has to end at one of the [`Self::possible_borders`]. Returns an error for the
+    /// rest of the script if the sizes don't add up to its length.
+    pub fn split_at(&mut self, sizes: &[usize]) -> Result<Vec<Chunk>, ChunkerError> {
+        let mut chunks: Vec<Chunk> = vec![];
+        for &size in sizes {
+            let borders = || chunks.iter().map(|chunk| chunk.size).collect();
+            let mut chunk_scripts = vec![];
+            let mut chunk_len = 0;
+            while chunk_len < size {
+                let script = match self.call_stack.pop() {
+                    Some(script) => script,
+                    None => break,
+                };
+                if chunk_len + script.len() <= size {
+                    chunk_len += script.len();
+                    chunk_scripts.push(script);
+                } else {
+                    self.split_next(script)
+                        .map_err(|err| err.with_borders(borders()))?;
+                }
+            }
+            chunks.push(Chunk::new(chunk_scripts, chunk_len));
+        }
+
+        // The rest of the script doesn't fit into a chunk, find the first script that
+        // doesn't fit on its own
+        let borders: Vec<_> = chunks.iter().map(|chunk| chunk.size).collect();
+        while let Some(script) = self.call_stack.pop() {
+            let end = ChunkBorder {
+                offset: script.len(),
+                num_ops: script.num_ops_cached(&mut self.op_counts),
+            };
+            if self.fits(&ChunkBorder::default(), &end) {
+                return Err(ChunkerError::ChunkDoesNotFit {
+                    script: script.debug_identifier.clone(),
+                    size: script.len(),
+                    borders,
+                });
+            }
+            self.split_next(script)
+                .map_err(|err| err.with_borders(borders.clone()))?;
+        }
+        Ok(chunks)
+    }
+
+    // Chunks inside the script, continuing with its blocks
+    fn split_next(&mut self, script: StructuredScript) -> Result<(), ChunkerError> {
+        if self.keeps_whole(&script) {
+            return Err(ChunkerError::ChunkDoesNotFit {
+                script: script.debug_identifier.clone(),
+                size: script.len(),
+                borders: vec![],
+            });
+        }
+        if !self.is_splittable(&script) {
+            return Err(ChunkerError::ScriptBufTooLarge {
+                script: script.debug_identifier.clone(),
+                size: script.len(),
+                borders: vec![],
+            });
+        }
+        match script.split_script_buf() {
+            Some(pieces) => self.call_stack.extend(pieces.into_iter().rev()),
+            None => self
+                .call_stack
+                .extend(script.split_blocks().into_iter().rev()),
+        }
+        self.top_level_split = true;
+        Ok(())
+    }
+
+    fn keeps_whole(&self, script: &StructuredScript) -> bool {
+        script.is_atomic() || (self.top_level_only && self.top_level_split)
+    }
+
+    // Scripts that consist of a single instruction or placeholder can't be split, neither
+    // can atomic scripts or called scripts if only the top-level script may be split
+    fn is_splittable(&self, script: &StructuredScript) -> bool {
+        !self.keeps_whole(script)
+            && match script.blocks.as_slice() {
+                [Block::Script(script_buf)] => script_buf.instructions().nth(1).is_some(),
+                [Block::Placeholder { .. }] => false,
+                _ => true,
+            }
+    }
+
+    // Moves the last scripts of the chunk back to the call stack until the chunk closes all
+    // of its OP_IFs. OP_ELSE doesn't change the number of unclosed OP_IFs, so the chunk
+    // can't end inside either branch. Returns the number of removed bytes.
+    fn undo(
+        &mut self,
+        mut num_unclosed_ifs: i32,
+        chunk_scripts: &mut Vec<StructuredScript>,
+    ) -> Result<usize, ChunkerError> {
+        let mut removed_len = 0;
+        while num_unclosed_ifs != 0 {
+            let script = chunk_scripts
+                .pop()
+                .ok_or(ChunkerError::UnclosedIfsExceedTolerance {
+                    num_unclosed_ifs,
+                    borders: vec![],
+                })?;
+            removed_len += script.len();
+            num_unclosed_ifs -= script.num_unclosed_ifs();
+            self.call_stack.push(script);
+        }
+        Ok(removed_len)
+    }
+}
+
+/// Yields the chunks one by one as [`Chunker::find_greedy_chunks`] finds them, regardless
+/// of the strategy. The iteration ends after the first error.
+impl Iterator for Chunker {
+    type Item = Result<Chunk, ChunkerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.call_stack.is_empty() {
+            return None;
+        }
+        let chunk = match self.find_next_chunk() {
+            Ok(chunk) if chunk.size == 0 => match self.call_stack.last() {
+                // Only empty scripts like chunk hints were left
+                None => return None,
+                Some(script) => Err(ChunkerError::ChunkDoesNotFit {
+                    script: script.debug_identifier.clone(),
+                    size: script.len(),
+                    borders: vec![],
+                }),
+            },
+            chunk => chunk,
+        };
+        match chunk {
+            Ok(chunk) => {
+                self.chunk_sizes.push(chunk.size);
+                Some(Ok(chunk))
+            }
+            Err(err) => {
+                self.call_stack.clear();
+                Some(Err(err.with_borders(self.chunk_sizes.clone())))
+            }
+        }
+    }
+}
+
+/// Returns a hash of the compiled chunks and their borders. Chunking is deterministic, so
+/// the fingerprint of the chunks of a script only changes if the script, the settings of
+/// the chunker or the chunking itself change. Pinning it in a test detects such changes.
+pub fn chunk_fingerprint(chunks: &[Chunk]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    let mut buffer = vec![];
+    for chunk in chunks {
+        buffer.clear();
+        for script in &chunk.scripts {
+            script.compile_into(&mut buffer);
+        }
+        engine.input(&(buffer.len() as u64).to_le_bytes());
+        engine.input(&buffer);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+// Every chunk is analyzed on its own, so that this can happen in parallel. Returns the
+// index of the first chunk that can't be analyzed together with the error.
+#[cfg(not(feature = "rayon"))]
+fn analyze_chunks(chunks: &[Chunk]) -> Result<Vec<StackStatus>, (usize, StackAnalysisError)> {
+    let mut analyzer = StackAnalyzer::new();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| analyze_chunk(&mut analyzer, (index, chunk)))
+        .collect()
+}
+
+#[cfg(feature = "rayon")]
+fn analyze_chunks(chunks: &[Chunk]) -> Result<Vec<StackStatus>, (usize, StackAnalysisError)> {
+    use rayon::prelude::*;
+    chunks
+        .par_iter()
+        .enumerate()
+        .map_init(StackAnalyzer::new, analyze_chunk)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn analyze_chunk(
+    analyzer: &mut StackAnalyzer,
+    (index, chunk): (usize, &Chunk),
+) -> Result<StackStatus, (usize, StackAnalysisError)> {
+    analyzer
+        .try_analyze(&chunk.script())
+        .map_err(|error| (index, error))
+}
+
+// Returns the index of the candidate chunk that reads and leaves the fewest elements on
+// the stacks, preferring larger chunks. Candidates after a part of the chunk that can't be
+// analyzed are not considered.
+fn best_candidate(scripts: &[StructuredScript], candidates: &[(usize, usize, bool)]) -> usize {
+    let mut analyzer = StackAnalyzer::new();
+    let mut best = (0, i64::MAX);
+    // Deepest accessed element and change of the main and alt stack of the chunk so far
+    let (mut stack_accessed, mut stack_changed) = (0, 0);
+    let (mut altstack_accessed, mut altstack_changed) = (0, 0);
+    let mut start = 0;
+    for (index, (end, _, _)) in candidates.iter().enumerate() {
+        // The scripts between two candidates close all of their OP_IFs
+        let mut part = StructuredScript::new("chunk");
+        for script in &scripts[start..*end] {
+            part = part.push_env_script(script.clone());
+        }
+        let status = match analyzer.try_analyze(&part) {
+            Ok(status) => status,
+            Err(_) => break,
+        };
+        stack_accessed = min(
+            stack_accessed,
+            stack_changed + status.deepest_stack_accessed,
+        );
+        stack_changed += status.stack_changed;
+        altstack_accessed = min(
+            altstack_accessed,
+            altstack_changed + status.deepest_altstack_accessed,
+        );
+        altstack_changed += status.altstack_changed;
+
+        // Inputs and outputs of both stacks. Dynamic depths of OP_PICK and OP_ROLL may
+        // access very deep elements, so this is computed in i64.
+        let cost = -2 * i64::from(stack_accessed) + i64::from(stack_changed)
+            - 2 * i64::from(altstack_accessed)
+            + i64::from(altstack_changed);
+        if cost <= best.1 {
+            best = (index, cost);
+        }
+        start = *end;
+    }
+    best.0
+}
+
+fn count_ops(script_buf: &ScriptBuf) -> usize {
+    script_buf
+        .instructions()
+        .filter(|instruction| {
+            matches!(instruction, Ok(Instruction::Op(opcode)) if opcode.to_u8() > OP_PUSHNUM_16.to_u8())
+        })
+        .count()
+}
+
+// Adds the script as a whole
+fn add_border(
+    script: &StructuredScript,
+    position: &mut ChunkBorder,
+    num_unclosed_ifs: &mut i32,
+    borders: &mut Vec<ChunkBorder>,
+) {
+    position.offset += script.len();
+    position.num_ops += script.num_ops();
+    *num_unclosed_ifs += script.num_unclosed_ifs();
+    if *num_unclosed_ifs == 0 && borders.last() != Some(position) {
+        borders.push(*position);
+    }
+}
+
+// Adds the borders between the blocks of the script and, if the largest chunk is given, of
+// the scripts it calls and between the instructions of ScriptBufs that don't fit into it
+fn collect_borders(
+    script: &StructuredScript,
+    max_chunk: Option<ChunkBorder>,
+    position: &mut ChunkBorder,
+    num_unclosed_ifs: &mut i32,
+    borders: &mut Vec<ChunkBorder>,
+) {
+    for block in &script.blocks {
+        match block {
+            Block::Call(id) => {
+                let called_script = script.get_structured_script(id);
+                if max_chunk.is_some() && !called_script.is_atomic() {
+                    collect_borders(
+                        called_script,
+                        max_chunk,
+                        position,
+                        num_unclosed_ifs,
+                        borders,
+                    );
+                } else {
+                    add_border(called_script, position, num_unclosed_ifs, borders);
+                }
+                continue;
+            }
+            Block::Script(script_buf) => {
+                let start = *position;
+                let split = max_chunk.is_some_and(|max_chunk| {
+                    script_buf.len() > max_chunk.offset || count_ops(script_buf) > max_chunk.num_ops
+                });
+                for (index, instruction) in script_buf.instruction_indices().map_while(Result::ok) {
+                    position.offset = start.offset + index;
+                    if split && *num_unclosed_ifs == 0 && borders.last() != Some(position) {
+                        borders.push(*position);
+                    }
+                    match instruction {
+                        Instruction::Op(OP_IF | OP_NOTIF) => *num_unclosed_ifs += 1,
+                        Instruction::Op(OP_ENDIF) => *num_unclosed_ifs -= 1,
+                        _ => (),
+                    }
+                    if let Instruction::Op(opcode) = instruction {
+                        if opcode.to_u8() > OP_PUSHNUM_16.to_u8() {
+                            position.num_ops += 1;
+                        }
+                    }
+                }
+                position.offset = start.offset + script_buf.len();
+            }
+            Block::Placeholder { len, .. } => position.offset += len,
+        }
+        if *num_unclosed_ifs == 0 && borders.last() != Some(position) {
+            borders.push(*position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+
+    #[test]
+    fn find_chunks() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_0
+            for _ in 0..10 {
+                gadget
+            }
+            OP_DROP
+        };
+
+        let chunks = Chunker::new(script, 30, 10).find_chunks().unwrap();
+        // Every chunk leaves one element on the stack, so the chunks are as large as possible
+        assert_eq!(chunks, vec![30, 30, 30, 12]);
+    }
+
+    #[test]
+    fn chunks_close_their_ifs() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1 OP_2 gadget
+            OP_IF gadget OP_ENDIF
+            gadget gadget
+        };
+
+        let chunks = Chunker::new(script.clone(), 25, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![25, 19]);
+        let chunks = Chunker::new(script, 22, 2).find_chunks().unwrap();
+        assert_eq!(chunks, vec![12, 22, 10]);
+    }
+
+    #[test]
+    fn chunks_close_their_else_branches() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1 gadget
+            OP_IF gadget gadget OP_ELSE gadget gadget OP_ENDIF
+            gadget
+        };
+
+        // No border between OP_IF and OP_ELSE or between OP_ELSE and OP_ENDIF
+        let borders = Chunker::new(script.clone(), 20, 0).possible_borders();
+        assert_eq!(
+            borders
+                .iter()
+                .map(|border| border.offset)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 6, 29, 34]
+        );
+
+        let chunks = Chunker::new(script, 25, 5)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.size).collect::<Vec<_>>(),
+            vec![6, 25, 3]
+        );
+    }
+
+    #[test]
+    fn chunk_stack_depth() {
+        let script = script! {
+            def push {
+                OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1
+            }
+            def drop {
+                OP_2DROP OP_2DROP OP_2DROP OP_2DROP OP_2DROP
+            }
+
+            OP_1
+            for _ in 0..110 {
+                push
+            }
+            for _ in 0..110 {
+                drop
+            }
+            OP_DROP
+        };
+
+        let chunks = Chunker::new(script, 700, 100)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        assert_eq!(chunks[0].max_stack_depth(), Some(601));
+        let interface = chunks[0].interface().unwrap();
+        assert_eq!(interface.inputs(), 0);
+        assert_eq!(interface.outputs(), 601);
+        assert_eq!(interface.altstack_outputs(), 0);
+        assert!(!chunks[0].exceeds_stack_limit());
+        assert_eq!(
+            chunks[0].stats(),
+            Some(&ChunkStats::new(ChunkInterface::new(0, 601, 0, 0), 601))
+        );
+        assert_eq!(
+            chunks[0]
+                .iter_scripts()
+                .map(StructuredScript::len)
+                .sum::<usize>(),
+            chunks[0].size()
+        );
+        // Starts with the elements of the first chunk on the stack
+        assert_eq!(chunks[1].max_stack_depth(), Some(1101));
+        assert!(chunks[1].exceeds_stack_limit());
+    }
+
+    #[test]
+    fn verify_composition() {
+        let script = script! {
+            def push {
+                OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1
+            }
+            def add {
+                OP_ADD OP_ADD OP_ADD OP_ADD OP_ADD OP_ADD OP_ADD OP_ADD OP_ADD OP_DROP
+            }
+
+            OP_NOP push add
+        };
+        let chunks = Chunker::new(script, 11, 1)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(Chunker::verify_composition(&chunks), Ok(()));
+
+        let script = script! {
+            def push {
+                OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1 OP_1
+            }
+
+            OP_1 push push OP_ADD OP_ADD
+        };
+        let chunks = Chunker::new(script, 11, 1)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        let mismatches = Chunker::verify_composition(&chunks).unwrap_err();
+        // The later chunks don't read the elements left by their predecessors
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[1].index, 1);
+        assert_eq!(mismatches[1].stack_output_size, 9);
+        assert_eq!(mismatches[1].next_stack_input_size, 2);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "Chunk 0 leaves 11 stack and 0 alt stack elements, chunk 1 expects 0 and 0"
+        );
+    }
+
+    #[test]
+    fn empty_scripts() {
+        let empty = StructuredScript::new("empty");
+        let script = StructuredScript::new("calls")
+            .push_env_script(empty.clone())
+            .push_env_script(empty);
+        let chunks = Chunker::new(script, 25, 15).find_chunks().unwrap();
+        assert_eq!(chunks, Vec::<usize>::new());
+    }
+
+    #[test]
+    fn chunk_errors() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1 gadget gadget
+            OP_IF gadget gadget gadget OP_ENDIF
+        };
+        // The second chunk has to end inside the OP_IF and is empty once it is closed
+        let err = Chunker::new(script, 12, 2).find_chunks().unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkerError::UnclosedIfsExceedTolerance {
+                num_unclosed_ifs: 1,
+                ..
+            }
+        ));
+        assert_eq!(err.borders(), &[11]);
+
+        let script = script! {
+            OP_1 { vec![0u8; 10] }
+        };
+        let err = Chunker::new(script, 5, 0).find_chunks().unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkerError::ScriptBufTooLarge { size: 11, .. }
+        ));
+        assert_eq!(err.borders(), &[1]);
+        assert!(err
+            .to_string()
+            .starts_with("Unable to split the next instruction"));
+
+        // The second chunk depends on the stack contents
+        let script = script! {
+            OP_1 OP_1ADD OP_1ADD OP_1ADD OP_IFDUP OP_DROP
+        };
+        let err = Chunker::new(script, 4, 0)
+            .find_chunks_and_analyze_stack()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkerError::StackAnalysis {
+                error: StackAnalysisError::UnsupportedOpcode { .. },
+                ..
+            }
+        ));
+        assert_eq!(err.borders(), &[4]);
+    }
+
+    #[test]
+    fn split_script_bufs() {
+        let script = script! {
+            OP_1 OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            OP_IF OP_1ADD OP_1ADD OP_1ADD OP_ENDIF
+        };
+        assert_eq!(script.blocks.len(), 1);
+        assert_eq!(
+            Chunker::new(script.clone(), 5, 0).find_chunks().unwrap(),
+            vec![5, 2, 5]
+        );
+        assert_eq!(
+            Chunker::new(script.clone(), 6, 0)
+                .with_strategy(DpOptimal)
+                .find_chunks()
+                .unwrap(),
+            vec![6, 6]
+        );
+        let borders = Chunker::new(script, 5, 0).possible_borders();
+        assert_eq!(
+            borders
+                .iter()
+                .map(|border| border.offset)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 12]
+        );
+    }
+
+    #[test]
+    fn chunk_iterator() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1 gadget gadget
+            OP_IF gadget gadget gadget OP_ENDIF
+        };
+        let mut chunker = Chunker::new(script, 12, 2);
+        assert_eq!(chunker.next().unwrap().unwrap().size, 11);
+        let err = chunker.next().unwrap().unwrap_err();
+        assert_eq!(err.borders(), &[11]);
+        assert!(chunker.next().is_none());
+
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1
+            for _ in 0..10 {
+                gadget
+            }
+        };
+        let sizes: Vec<_> = Chunker::new(script.clone(), 20, 5)
+            .map(|chunk| chunk.unwrap().size)
+            .collect();
+        assert_eq!(sizes, Chunker::new(script, 20, 5).find_chunks().unwrap());
+    }
+
+    #[test]
+    fn tapscript_presets() {
+        let chunker = Chunker::for_tapscript(script! { OP_NOP }, 50_000);
+        assert_eq!(chunker.target_chunk_size(), 340_000);
+        assert_eq!(chunker.tolerance(), TAPSCRIPT_TOLERANCE);
+    }
+
+    #[test]
+    fn chunk_provenance() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+            def other_gadget {
+                OP_1SUB OP_1SUB OP_1SUB OP_1SUB OP_1SUB
+            }
+
+            OP_1 gadget
+            chunk_hint!()
+            other_gadget OP_DROP
+        };
+
+        let chunks = Chunker::new(script, 8, 2)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        let sources: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .provenance()
+                    .into_iter()
+                    .map(|source| {
+                        (
+                            source.script.rsplit([' ', ':']).next().unwrap().to_string(),
+                            source.range,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                vec![
+                    ("chunk_provenance".to_string(), 0..1),
+                    ("gadget".to_string(), 1..6)
+                ],
+                vec![
+                    ("other_gadget".to_string(), 0..5),
+                    ("chunk_provenance".to_string(), 5..6)
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn estimate_chunks() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD
+            }
+
+            OP_0
+            for _ in 0..50 {
+                gadget
+            }
+            OP_DROP
+        };
+
+        let chunker = Chunker::new(script.clone(), 35, 5);
+        assert_eq!(chunker.estimate(35), vec![35, 34, 33]);
+        // The greedy strategy also splits the gadget that doesn't fit
+        assert_eq!(
+            Chunker::new(script, 35, 5).find_chunks().unwrap(),
+            vec![35, 35, 32]
+        );
+        assert_eq!(chunker.estimate(60), vec![59, 43]);
+
+        // The OP_IF doesn't fit into a chunk
+        let chunker = Chunker::new(script! { OP_1 OP_IF OP_1ADD OP_ENDIF }, 2, 0);
+        assert_eq!(chunker.estimate(2), vec![1]);
+    }
+
+    #[test]
+    fn fingerprint() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD
+            }
+
+            OP_0
+            for _ in 0..50 {
+                gadget
+            }
+            OP_DROP
+        };
+        let greedy = Chunker::new(script.clone(), 35, 5)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        let balanced = Chunker::new(script.clone(), 35, 5)
+            .with_strategy(Balanced)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+
+        assert_eq!(
+            chunk_fingerprint(&greedy).to_string(),
+            "682b873d37cfcd279c6c0157fd8a3479b8f7f12f143d0b23af344acc4365ccc0"
+        );
+        let chunks = Chunker::new(script, 35, 5)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        assert_eq!(chunk_fingerprint(&chunks), chunk_fingerprint(&greedy));
+        // Same bytes, different borders
+        assert_ne!(chunk_fingerprint(&balanced), chunk_fingerprint(&greedy));
+    }
+
+    #[test]
+    fn possible_borders() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD
+            }
+
+            OP_1 gadget
+            OP_IF gadget OP_ENDIF
+            gadget
+        };
+
+        let borders = Chunker::new(script, 10, 0).possible_borders();
+        assert_eq!(
+            borders
+                .iter()
+                .map(|border| border.offset)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 3, 7, 9]
+        );
+        assert_eq!(borders[3].num_ops, 6);
+    }
+
+    #[test]
+    fn chunk_by_ops() {
+        let script = script! {
+            def gadget {
+                OP_1 OP_1ADD OP_1ADD OP_DROP
+            }
+
+            OP_NOP
+            for _ in 0..5 {
+                gadget
+            }
+        };
+        assert_eq!(script.num_ops(), 16);
+
+        let chunks = Chunker::new(script.clone(), usize::MAX, 0)
+            .with_max_ops(7)
+            .find_chunks()
+            .unwrap();
+        // The last gadget is split to fill the second chunk
+        assert_eq!(chunks, vec![9, 10, 2]);
+
+        // Both limits apply
+        let chunks = Chunker::new(script, 8, 0)
+            .with_max_ops(7)
+            .find_chunks()
+            .unwrap();
+        assert_eq!(chunks, vec![8, 8, 5]);
+    }
+
+    #[test]
+    fn top_level_borders_only() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+            def large_gadget {
+                gadget gadget
+            }
+
+            OP_1 large_gadget gadget large_gadget
+        };
+
+        let chunks = Chunker::new(script.clone(), 25, 10).find_chunks().unwrap();
+        assert_eq!(chunks, vec![25, 25, 1]);
+        // The second chunk can't contain half of large_gadget
+        let chunks = Chunker::new(script.clone(), 25, 10)
+            .top_level_borders_only()
+            .find_chunks()
+            .unwrap();
+        assert_eq!(chunks, vec![21, 10, 20]);
+
+        let err = Chunker::new(script, 15, 5)
+            .top_level_borders_only()
+            .find_chunks()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkerError::ChunkDoesNotFit { size: 20, .. }
+        ));
+    }
+
+    #[test]
+    fn chunk_hints() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1 gadget gadget gadget
+            chunk_hint!()
+            gadget gadget gadget
+        };
+        let chunks = Chunker::new(script, 25, 15).find_chunks().unwrap();
+        assert_eq!(chunks, vec![16, 15]);
+
+        // Without the hint the first chunk is as large as possible
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+
+            OP_1 gadget gadget gadget gadget gadget gadget
+        };
+        let chunks = Chunker::new(script, 25, 15).find_chunks().unwrap();
+        assert_eq!(chunks, vec![25, 6]);
+
+        // Hints alone don't make up a chunk
+        let script = StructuredScript::new("hints").push_chunk_hint();
+        let chunks = Chunker::new(script, 25, 15).find_chunks().unwrap();
+        assert_eq!(chunks, Vec::<usize>::new());
+    }
+
+    #[test]
+    fn atomic_scripts() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+            def large_gadget {
+                gadget gadget gadget
+            }
+
+            OP_1 gadget large_gadget gadget
+        };
+        let chunks = Chunker::new(script, 15, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![15, 11]);
+
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+            }
+            def large_gadget {
+                /// @atomic: true
+                gadget gadget gadget
+            }
+
+            OP_1 gadget large_gadget gadget
+        };
+        // large_gadget starts the next chunk instead
+        let chunks = Chunker::new(script.clone(), 15, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![6, 15, 5]);
+
+        let err = Chunker::new(script, 12, 2).find_chunks().unwrap_err();
+        assert!(matches!(
+            err,
+            ChunkerError::ChunkDoesNotFit { size: 15, .. }
+        ));
+
+        // Scripts that only differ in being atomic are kept apart, whichever comes first
+        let gadget = script! {
+            for _ in 0..15 {
+                OP_1ADD
+            }
+        };
+        let atomic_gadget = gadget.clone().atomic();
+        let script = script! { OP_1 { gadget.clone() } { atomic_gadget.clone() } };
+        let chunks = Chunker::new(script.clone(), 15, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![15, 1, 15]);
+        let mut deduped = script;
+        deduped.dedup();
+        let chunks = Chunker::new(deduped, 15, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![15, 1, 15]);
+
+        let script = script! { OP_1 { atomic_gadget } { gadget } };
+        let chunks = Chunker::new(script.clone(), 15, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![1, 15, 15]);
+        let mut deduped = script;
+        deduped.dedup();
+        let chunks = Chunker::new(deduped, 15, 5).find_chunks().unwrap();
+        assert_eq!(chunks, vec![1, 15, 15]);
+    }
+
+    #[test]
+    fn compile_chunks() {
+        let script = script! {
+            OP_1
+            for i in 0..20 {
+                { i * 1000 } OP_ADD
+            }
+        };
+        let compiled = script.clone().compile();
+        let chunks = Chunker::new(script, 10, 2).find_greedy_chunks().unwrap();
+        let bytes: Vec<u8> = chunks.iter().flat_map(Chunk::to_bytes).collect();
+        assert_eq!(bytes, compiled.as_bytes());
+        // OP_1 0 OP_ADD 1000 OP_ADD 2000
+        assert_eq!(chunks[0].to_hex(), "51009302e8039302d007");
+    }
+
+    #[test]
+    #[should_panic(expected = "Chunk compiles to 1 bytes, but the chunker counted 2 bytes")]
+    fn compile_miscounted_chunk() {
+        Chunk::new(vec![script! { OP_1 }], 2).compile();
+    }
+}
@@ -0,0 +1,215 @@
+//! A stable JSON format of chunks for tools outside of Rust, e.g. provers and watchtowers.
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "chunks": [
+//!     {
+//!       "script": "7693",
+//!       "size": 2,
+//!       "interface": {
+//!         "inputs": 1,
+//!         "outputs": 1,
+//!         "altstack_inputs": 0,
+//!         "altstack_outputs": 0,
+//!         "max_stack_depth": 2
+//!       },
+//!       "provenance": [{ "script": "double", "start": 0, "end": 2 }]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `script` is the hex encoded chunk and `provenance` lists the scripts it consists of, see
+//! [`Chunk::provenance`]. `interface` is `null` unless the stack of the chunk was analyzed.
+//! Fields are only ever added within the same `version`.
+
+use super::{Chunk, ChunkInterface, ChunkStats};
+use crate::builder::StructuredScript;
+use bitcoin::hex::FromHex;
+use bitcoin::ScriptBuf;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the JSON format written by [`Chunk::to_json`].
+pub const CHUNK_JSON_VERSION: u32 = 1;
+
+/// Error returned by [`Chunk::from_json`].
+#[derive(Debug)]
+pub enum JsonError {
+    /// The JSON doesn't match the format.
+    Json(serde_json::Error),
+    /// The JSON was written in a version of the format that is not supported.
+    UnsupportedVersion(u32),
+    /// The script of the chunk with this index is not valid hex.
+    InvalidScript(usize),
+    /// The size of the chunk with this index doesn't match its script.
+    SizeMismatch {
+        index: usize,
+        size: usize,
+        actual: usize,
+    },
+    /// The provenance of the chunk with this index doesn't cover its script from start to
+    /// end, or contains a script with an invalid instruction.
+    InvalidProvenance(usize),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Json(error) => write!(f, "Invalid chunk JSON: {}", error),
+            JsonError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported chunk JSON version {}", version)
+            }
+            JsonError::InvalidScript(index) => write!(f, "Chunk {} has an invalid script", index),
+            JsonError::SizeMismatch {
+                index,
+                size,
+                actual,
+            } => write!(
+                f,
+                "Chunk {} has a size of {} bytes, but its script has {} bytes",
+                index, size, actual
+            ),
+            JsonError::InvalidProvenance(index) => {
+                write!(f, "Chunk {} has an invalid provenance", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonError::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(error: serde_json::Error) -> Self {
+        JsonError::Json(error)
+    }
+}
+
+// The format is defined by these types rather than by `Chunk`, so that it doesn't change
+// with the internals of the chunker
+#[derive(Serialize, Deserialize)]
+struct JsonChunks {
+    version: u32,
+    chunks: Vec<JsonChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonChunk {
+    script: String,
+    size: usize,
+    interface: Option<JsonInterface>,
+    provenance: Vec<JsonSource>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonInterface {
+    inputs: usize,
+    outputs: usize,
+    altstack_inputs: usize,
+    altstack_outputs: usize,
+    max_stack_depth: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonSource {
+    script: String,
+    start: usize,
+    end: usize,
+}
+
+impl Chunk {
+    /// Writes the chunks in the JSON format of [`CHUNK_JSON_VERSION`], see
+    /// [`crate::chunker`].
+    pub fn to_json(chunks: &[Chunk]) -> String {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| JsonChunk {
+                script: chunk.to_hex(),
+                size: chunk.size,
+                interface: chunk.stats.as_ref().map(|stats| JsonInterface {
+                    inputs: stats.interface.inputs,
+                    outputs: stats.interface.outputs,
+                    altstack_inputs: stats.interface.altstack_inputs,
+                    altstack_outputs: stats.interface.altstack_outputs,
+                    max_stack_depth: stats.max_stack_depth,
+                }),
+                provenance: chunk
+                    .provenance()
+                    .into_iter()
+                    .map(|source| JsonSource {
+                        script: source.script,
+                        start: source.range.start,
+                        end: source.range.end,
+                    })
+                    .collect(),
+            })
+            .collect();
+        let json = JsonChunks {
+            version: CHUNK_JSON_VERSION,
+            chunks,
+        };
+        serde_json::to_string_pretty(&json).expect("Chunks can always be serialized")
+    }
+
+    /// Reads chunks written by [`Self::to_json`]. Every script of the provenance becomes
+    /// a script of the chunk with its bytes and name, so the block structure within the
+    /// scripts is lost.
+    pub fn from_json(json: &str) -> Result<Vec<Chunk>, JsonError> {
+        let json: JsonChunks = serde_json::from_str(json)?;
+        if json.version != CHUNK_JSON_VERSION {
+            return Err(JsonError::UnsupportedVersion(json.version));
+        }
+        json.chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let bytes = Vec::<u8>::from_hex(&chunk.script)
+                    .map_err(|_| JsonError::InvalidScript(index))?;
+                if bytes.len() != chunk.size {
+                    return Err(JsonError::SizeMismatch {
+                        index,
+                        size: chunk.size,
+                        actual: bytes.len(),
+                    });
+                }
+                let mut scripts = vec![];
+                let mut offset = 0;
+                for JsonSource { script, start, end } in chunk.provenance {
+                    if start != offset || end <= start || end > bytes.len() {
+                        return Err(JsonError::InvalidProvenance(index));
+                    }
+                    let part = ScriptBuf::from_bytes(bytes[start..end].to_vec());
+                    if part.instructions().any(|instruction| instruction.is_err()) {
+                        return Err(JsonError::InvalidProvenance(index));
+                    }
+                    scripts.push(StructuredScript::new(&script).push_script(part));
+                    offset = end;
+                }
+                if offset != bytes.len() {
+                    return Err(JsonError::InvalidProvenance(index));
+                }
+                let mut result = Chunk::new(scripts, chunk.size);
+                result.stats = chunk.interface.map(|interface| {
+                    ChunkStats::new(
+                        ChunkInterface::new(
+                            interface.inputs,
+                            interface.outputs,
+                            interface.altstack_inputs,
+                            interface.altstack_outputs,
+                        ),
+                        interface.max_stack_depth,
+                    )
+                });
+                Ok(result)
+            })
+            .collect()
+    }
+}
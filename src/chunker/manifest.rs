@@ -0,0 +1,90 @@
+use super::{Chunk, ChunkInterface};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::taproot::{LeafVersion, TapLeafHash};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Version of the manifest format written by [`Manifest::new`].
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Summary of the chunks of a program that operators and verifiers exchange instead of the
+/// chunks themselves. A verifier checks chunks against a manifest by comparing it with
+/// `Manifest::new(&chunks)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Manifest {
+    pub version: u32,
+    pub chunks: Vec<ManifestEntry>,
+}
+
+/// A chunk in a [`Manifest`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ManifestEntry {
+    /// Position of the chunk in the program
+    pub id: usize,
+    /// SHA256 of the compiled chunk
+    pub content_hash: sha256::Hash,
+    pub size: usize,
+    /// Stack interface, unless the stack of the chunk was not analyzed
+    pub interface: Option<ChunkInterface>,
+    /// Hash of the chunk as a tapscript leaf, see [`super::chunks_to_taproot`]
+    pub leaf_hash: TapLeafHash,
+}
+
+impl Manifest {
+    pub fn new(chunks: &[Chunk]) -> Manifest {
+        let chunks = chunks
+            .iter()
+            .enumerate()
+            .map(|(id, chunk)| {
+                let script = chunk.compile();
+                ManifestEntry {
+                    id,
+                    content_hash: sha256::Hash::hash(script.as_bytes()),
+                    size: chunk.size(),
+                    interface: chunk.interface().cloned(),
+                    leaf_hash: TapLeafHash::from_script(&script, LeafVersion::TapScript),
+                }
+            })
+            .collect();
+        Manifest {
+            version: MANIFEST_VERSION,
+            chunks,
+        }
+    }
+
+    /// Writes the manifest as JSON, in which hashes are hex encoded.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Manifests can always be serialized")
+    }
+
+    /// Reads a manifest written by [`Self::to_json`].
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<Manifest, super::JsonError> {
+        let manifest: Manifest = serde_json::from_str(json)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(super::JsonError::UnsupportedVersion(manifest.version));
+        }
+        Ok(manifest)
+    }
+
+    /// Encodes the manifest as CBOR.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Vec<u8> {
+        crate::builder::to_cbor(self)
+    }
+
+    /// Decodes a manifest that was encoded with [`Self::to_cbor`].
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Manifest, crate::builder::CborError> {
+        let manifest: Manifest = crate::builder::from_cbor(bytes)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(crate::builder::CborError::UnsupportedVersion(
+                manifest.version,
+            ));
+        }
+        Ok(manifest)
+    }
+}
@@ -0,0 +1,270 @@
+use super::{Chunk, ChunkBorder, Chunker, ChunkerError};
+use std::cmp::max;
+
+/// Decides where a [`Chunker`] cuts the script into chunks.
+///
+/// Strategies can either let the chunker fill the chunks with
+/// [`Chunker::find_greedy_chunks`] or choose chunk sizes from the
+/// [`Chunker::possible_borders`] and cut them with [`Chunker::split_at`].
+pub trait ChunkStrategy {
+    fn find_chunks(&self, chunker: &mut Chunker) -> Result<Vec<Chunk>, ChunkerError>;
+}
+
+/// Fills every chunk to at least `target_chunk_size - tolerance` bytes before starting the
+/// next one. This is fast, but the last chunk may be much smaller than the others.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Greedy;
+
+impl ChunkStrategy for Greedy {
+    fn find_chunks(&self, chunker: &mut Chunker) -> Result<Vec<Chunk>, ChunkerError> {
+        chunker.find_greedy_chunks()
+    }
+}
+
+/// Fills the chunks like [`Greedy`] and then moves whole scripts from the end of every chunk
+/// to the start of the next one as long as this makes the sizes of both chunks more even,
+/// so the last chunk isn't much smaller than the others. Chunks may end up smaller than
+/// `target_chunk_size - tolerance`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rebalanced;
+
+impl ChunkStrategy for Rebalanced {
+    fn find_chunks(&self, chunker: &mut Chunker) -> Result<Vec<Chunk>, ChunkerError> {
+        let mut chunks = chunker.find_greedy_chunks()?;
+        rebalance(chunker, &mut chunks);
+        Ok(chunks)
+    }
+}
+
+fn rebalance(chunker: &mut Chunker, chunks: &mut [Chunk]) {
+    let mut moved = true;
+    while moved {
+        moved = false;
+        for index in (1..chunks.len()).rev() {
+            while move_scripts(chunker, chunks, index) {
+                moved = true;
+            }
+        }
+    }
+}
+
+// Moves the shortest suffix of the chunk before `index` that leaves it closing all of its
+// OP_IFs to the chunk at `index` if this makes their sizes more even
+fn move_scripts(chunker: &mut Chunker, chunks: &mut [Chunk], index: usize) -> bool {
+    let (previous, next) = (&chunks[index - 1], &chunks[index]);
+    let mut num_unclosed_ifs = 0;
+    let mut moved = ChunkBorder::default();
+    let mut split = previous.scripts.len();
+    while split > 0 {
+        split -= 1;
+        let script = &previous.scripts[split];
+        num_unclosed_ifs += script.num_unclosed_ifs();
+        moved.offset += script.len();
+        moved.num_ops += script.num_ops_cached(&mut chunker.op_counts);
+        if num_unclosed_ifs == 0 {
+            break;
+        }
+    }
+    let next_end = ChunkBorder {
+        offset: next.size + moved.offset,
+        num_ops: num_ops(chunker, next) + moved.num_ops,
+    };
+    if split == 0
+        || num_unclosed_ifs != 0
+        || next_end.offset >= previous.size
+        || !chunker.fits(&ChunkBorder::default(), &next_end)
+    {
+        return false;
+    }
+
+    let scripts: Vec<_> = chunks[index - 1].scripts.drain(split..).collect();
+    chunks[index - 1].size -= moved.offset;
+    chunks[index].scripts.splice(0..0, scripts);
+    chunks[index].size += moved.offset;
+    true
+}
+
+fn num_ops(chunker: &mut Chunker, chunk: &Chunk) -> usize {
+    chunk
+        .scripts
+        .iter()
+        .map(|script| script.num_ops_cached(&mut chunker.op_counts))
+        .sum()
+}
+
+/// Uses as many chunks as the greedy strategy would need at best and ends every chunk at the
+/// border closest to an even share of the rest of the script. The tolerance is ignored, so
+/// chunks may be smaller than `target_chunk_size - tolerance`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Balanced;
+
+impl ChunkStrategy for Balanced {
+    fn find_chunks(&self, chunker: &mut Chunker) -> Result<Vec<Chunk>, ChunkerError> {
+        let borders = chunker.possible_borders();
+        let sizes = balanced_sizes(chunker, &borders);
+        chunker.split_at(&sizes)
+    }
+}
+
+fn balanced_sizes(chunker: &Chunker, borders: &[ChunkBorder]) -> Vec<usize> {
+    let total = borders[borders.len() - 1];
+    let mut num_chunks = max(1, total.offset.div_ceil(chunker.target_chunk_size()));
+    if let Some(max_ops) = chunker.max_ops() {
+        num_chunks = max(num_chunks, total.num_ops.div_ceil(max(1, max_ops)));
+    }
+    let mut sizes = vec![];
+    let mut start = 0;
+    while borders[start].offset < total.offset {
+        let remaining_chunks = max(1, num_chunks.saturating_sub(sizes.len()));
+        let goal = borders[start].offset
+            + (total.offset - borders[start].offset).div_ceil(remaining_chunks);
+        let end = (start + 1..borders.len())
+            .take_while(|&end| chunker.fits(&borders[start], &borders[end]))
+            .min_by_key(|&end| borders[end].offset.abs_diff(goal));
+        match end {
+            Some(end) => {
+                sizes.push(borders[end].offset - borders[start].offset);
+                start = end;
+            }
+            None => break,
+        }
+    }
+    sizes
+}
+
+/// Finds the smallest number of chunks and among those the most even chunk sizes, i.e. the
+/// smallest sum of squared chunk sizes. No strategy that cuts the script at the
+/// [`Chunker::possible_borders`] needs fewer chunks, whereas [`Greedy`] may end chunks early
+/// to close an `OP_IF`. The running time grows with the number of possible borders within a
+/// chunk. The tolerance is ignored like with [`Balanced`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DpOptimal;
+
+impl ChunkStrategy for DpOptimal {
+    fn find_chunks(&self, chunker: &mut Chunker) -> Result<Vec<Chunk>, ChunkerError> {
+        let borders = chunker.possible_borders();
+        let sizes = optimal_sizes(chunker, &borders);
+        chunker.split_at(&sizes)
+    }
+}
+
+fn optimal_sizes(chunker: &Chunker, borders: &[ChunkBorder]) -> Vec<usize> {
+    // The number of chunks, the sum of their squared sizes and the previous border of the
+    // best split of the script up to every border
+    let mut best: Vec<Option<(usize, u128, usize)>> = vec![None; borders.len()];
+    best[0] = Some((0, 0, 0));
+    let mut first = 0;
+    for end in 1..borders.len() {
+        while first < end && !chunker.fits(&borders[first], &borders[end]) {
+            first += 1;
+        }
+        for start in first..end {
+            if let Some((num_chunks, cost, _)) = best[start] {
+                let size = (borders[end].offset - borders[start].offset) as u128;
+                let candidate = (num_chunks + 1, cost + size * size, start);
+                if best[end].is_none_or(|(n, c, _)| (candidate.0, candidate.1) < (n, c)) {
+                    best[end] = Some(candidate);
+                }
+            }
+        }
+    }
+
+    // Splits as much of the script as possible if the rest doesn't fit into a chunk
+    let mut end = (0..borders.len())
+        .rev()
+        .find(|&end| best[end].is_some())
+        .unwrap_or(0);
+    let mut sizes = vec![];
+    while end > 0 {
+        let (_, _, start) = best[end].expect("only reachable borders are visited");
+        sizes.push(borders[end].offset - borders[start].offset);
+        end = start;
+    }
+    sizes.reverse();
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+
+    #[test]
+    fn chunk_strategies() {
+        let script = script! {
+            def gadget {
+                OP_1ADD OP_1ADD
+            }
+
+            OP_0
+            for _ in 0..50 {
+                gadget
+            }
+            OP_DROP
+        };
+
+        let greedy = Chunker::new(script.clone(), 35, 5).find_chunks().unwrap();
+        assert_eq!(greedy, vec![35, 35, 32]);
+        let balanced = Chunker::new(script.clone(), 35, 5)
+            .with_strategy(Balanced)
+            .find_chunks()
+            .unwrap();
+        assert_eq!(balanced, vec![33, 34, 35]);
+        let optimal = Chunker::new(script.clone(), 35, 5)
+            .with_strategy(DpOptimal)
+            .find_chunks()
+            .unwrap();
+        assert_eq!(optimal, vec![33, 34, 35]);
+        let rebalanced = Chunker::new(script, 35, 5)
+            .with_strategy(Rebalanced)
+            .find_chunks()
+            .unwrap();
+        assert_eq!(rebalanced, vec![35, 34, 33]);
+    }
+
+    #[test]
+    fn fewest_chunks() {
+        let script = script! {
+            def push {
+                OP_1 OP_1 OP_1 OP_1 OP_1
+            }
+            def drop {
+                OP_DROP OP_DROP OP_DROP OP_DROP OP_DROP
+            }
+
+            push drop push push push drop drop drop
+        };
+
+        // The first chunk ends where it leaves no element on the stack
+        let greedy = Chunker::new(script.clone(), 20, 10).find_chunks().unwrap();
+        assert_eq!(greedy, vec![10, 20, 10]);
+        let optimal = Chunker::new(script, 20, 10)
+            .with_strategy(DpOptimal)
+            .find_chunks()
+            .unwrap();
+        assert_eq!(optimal, vec![20, 20]);
+    }
+
+    fn borders(offsets: &[usize]) -> Vec<ChunkBorder> {
+        offsets
+            .iter()
+            .map(|&offset| ChunkBorder { offset, num_ops: 0 })
+            .collect()
+    }
+
+    #[test]
+    fn chunk_sizes_are_even() {
+        let chunker = Chunker::new(script! { OP_NOP }, 30, 0);
+        // Filling the first chunk would leave a chunk of 20 bytes
+        let even_borders = borders(&[0, 10, 25, 30, 50]);
+        assert_eq!(balanced_sizes(&chunker, &even_borders), vec![25, 25]);
+        assert_eq!(optimal_sizes(&chunker, &even_borders), vec![25, 25]);
+
+        // The rest of the script doesn't fit into a chunk
+        let chunker = Chunker::new(script! { OP_NOP }, 15, 0);
+        assert_eq!(
+            optimal_sizes(&chunker, &borders(&[0, 10, 30, 50])),
+            vec![10]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use super::Chunk;
+use bitcoin::key::UntweakedPublicKey;
+use bitcoin::psbt;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::taproot::{
+    ControlBlock, LeafVersion, TaprootBuilder, TaprootBuilderError, TaprootSpendInfo,
+};
+use bitcoin::ScriptBuf;
+
+/// A taproot output with one leaf per chunk, see [`chunks_to_taproot`].
+#[derive(Clone, Debug)]
+pub struct TaprootChunks {
+    spend_info: TaprootSpendInfo,
+    leaves: Vec<(ScriptBuf, ControlBlock)>,
+}
+
+impl TaprootChunks {
+    pub fn spend_info(&self) -> &TaprootSpendInfo {
+        &self.spend_info
+    }
+
+    /// The output script that commits to the chunks.
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        ScriptBuf::new_p2tr_tweaked(self.spend_info.output_key())
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// The compiled chunk with this index.
+    pub fn leaf_script(&self, index: usize) -> &ScriptBuf {
+        &self.leaves[index].0
+    }
+
+    /// The control block that proves that the chunk with this index is a leaf of the
+    /// output. Equal chunks share the control block of the leaf closest to the root.
+    pub fn control_block(&self, index: usize) -> &ControlBlock {
+        &self.leaves[index].1
+    }
+
+    /// Returns the `tr()` output descriptor with checksum, whose script tree has a
+    /// `rawleaf(HEX)` expression per chunk, so that wallets and watchers can import the
+    /// output.
+    pub fn descriptor(&self) -> String {
+        let leaves: Vec<(&ScriptBuf, u8)> = self
+            .leaves
+            .iter()
+            .map(|(script, _)| script)
+            .zip(leaf_depths(self.leaves.len()))
+            .collect();
+        let mut descriptor = format!("tr({}", self.spend_info.internal_key());
+        descriptor.push(',');
+        write_tree(&leaves, &mut 0, 0, &mut descriptor);
+        descriptor.push(')');
+        add_checksum(&descriptor)
+    }
+
+    /// Adds the taproot fields for spending the output via the chunk with this index to a
+    /// PSBT input: the internal key, the merkle root, and the leaf script with its control
+    /// block. Other leaves that were added before are kept, so a signer can choose.
+    pub fn update_psbt_input(&self, input: &mut psbt::Input, index: usize) {
+        let (script, control_block) = &self.leaves[index];
+        input.tap_internal_key = Some(self.spend_info.internal_key());
+        input.tap_merkle_root = self.spend_info.merkle_root();
+        input.tap_scripts.insert(
+            control_block.clone(),
+            (script.clone(), control_block.leaf_version),
+        );
+    }
+}
+
+// Depths of the leaves of a complete binary tree in depth-first order, deeper leaves first
+fn leaf_depths(num_leaves: usize) -> impl Iterator<Item = u8> {
+    let depth = num_leaves.next_power_of_two().trailing_zeros() as u8;
+    let num_deep = 2 * num_leaves - (1 << depth);
+    (0..num_leaves).map(move |index| if index < num_deep { depth } else { depth - 1 })
+}
+
+// Writes the leaves from `index` on as the tree expression of a descriptor
+fn write_tree(leaves: &[(&ScriptBuf, u8)], index: &mut usize, depth: u8, out: &mut String) {
+    let (script, leaf_depth) = leaves[*index];
+    if leaf_depth == depth {
+        out.push_str(&format!("rawleaf({:x})", script));
+        *index += 1;
+    } else {
+        out.push('{');
+        write_tree(leaves, index, depth + 1, out);
+        out.push(',');
+        write_tree(leaves, index, depth + 1, out);
+        out.push('}');
+    }
+}
+
+// Appends the checksum of BIP 380 to a descriptor
+fn add_checksum(descriptor: &str) -> String {
+    const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}\
+        IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+    const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+    fn polymod(checksum: u64, value: u64) -> u64 {
+        let top = checksum >> 35;
+        let mut checksum = ((checksum & 0x7ffffffff) << 5) ^ value;
+        for (bit, generator) in GENERATOR.iter().enumerate() {
+            if (top >> bit) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+        checksum
+    }
+
+    let mut checksum = 1;
+    let mut classes = 0;
+    let mut num_classes = 0;
+    for c in descriptor.chars() {
+        let position = INPUT_CHARSET
+            .find(c)
+            .expect("Descriptors only contain characters of the input charset")
+            as u64;
+        checksum = polymod(checksum, position & 31);
+        classes = classes * 3 + (position >> 5);
+        num_classes += 1;
+        if num_classes == 3 {
+            checksum = polymod(checksum, classes);
+            classes = 0;
+            num_classes = 0;
+        }
+    }
+    if num_classes > 0 {
+        checksum = polymod(checksum, classes);
+    }
+    for _ in 0..8 {
+        checksum = polymod(checksum, 0);
+    }
+    checksum ^= 1;
+    let checksum: String = (0..8)
+        .map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char)
+        .collect();
+    format!("{}#{}", descriptor, checksum)
+}
+
+/// Commits to the chunks as tapscript leaves of a taproot output with `internal_key`. The
+/// leaves form a balanced tree in the order of the chunks, so every chunk is spent with a
+/// control block of at most `ceil(log2(chunks.len()))` hashes.
+///
+/// Returns [`TaprootBuilderError::EmptyTree`] if there are no chunks.
+pub fn chunks_to_taproot(
+    chunks: &[Chunk],
+    internal_key: UntweakedPublicKey,
+) -> Result<TaprootChunks, TaprootBuilderError> {
+    if chunks.is_empty() {
+        return Err(TaprootBuilderError::EmptyTree);
+    }
+    let scripts: Vec<ScriptBuf> = chunks.iter().map(Chunk::compile).collect();
+
+    let mut builder = TaprootBuilder::new();
+    for (script, depth) in scripts.iter().zip(leaf_depths(scripts.len())) {
+        builder = builder.add_leaf_with_ver(depth, script.clone(), LeafVersion::TapScript)?;
+    }
+    let spend_info = builder
+        .finalize(&Secp256k1::verification_only(), internal_key)
+        .unwrap_or_else(|_| unreachable!("A complete binary tree is always finalized"));
+
+    let leaves = scripts
+        .into_iter()
+        .map(|script| {
+            let control_block = spend_info
+                .control_block(&(script.clone(), LeafVersion::TapScript))
+                .unwrap_or_else(|| unreachable!("Every chunk is a leaf"));
+            (script, control_block)
+        })
+        .collect();
+    Ok(TaprootChunks { spend_info, leaves })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_checksum() {
+        assert_eq!(add_checksum("raw(deadbeef)"), "raw(deadbeef)#89f8spxm");
+    }
+
+    #[test]
+    fn complete_tree() {
+        assert_eq!(leaf_depths(1).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(leaf_depths(3).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert_eq!(leaf_depths(4).collect::<Vec<_>>(), vec![2, 2, 2, 2]);
+    }
+}
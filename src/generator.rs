@@ -0,0 +1,246 @@
+//! Random scripts for fuzzing the chunker and the stack analyzer.
+//!
+//! The scripts are structurally valid but usually fail when executed: every `OP_IF` is
+//! closed within its script, pushes respect the stack element size limit and scripts call
+//! each other up to a maximum depth.
+//!
+//! With the `proptest` feature, [`structured_script`] and the `Arbitrary` implementation
+//! of `StructuredScript` generate such scripts as proptest strategies.
+
+#[cfg(feature = "proptest")]
+mod strategy;
+
+#[cfg(feature = "proptest")]
+pub use self::strategy::{structured_script, ScriptParams};
+
+use crate::builder::StructuredScript;
+use bitcoin::blockdata::constants::MAX_SCRIPT_ELEMENT_SIZE;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::secp256k1::rand::rngs::StdRng;
+use bitcoin::secp256k1::rand::{Rng, SeedableRng};
+use bitcoin::ScriptBuf;
+
+// Opcodes that don't change the control flow
+const OPCODES: [Opcode; 31] = [
+    OP_DUP,
+    OP_DROP,
+    OP_SWAP,
+    OP_OVER,
+    OP_ROT,
+    OP_NIP,
+    OP_TUCK,
+    OP_2DUP,
+    OP_2DROP,
+    OP_2SWAP,
+    OP_PICK,
+    OP_ROLL,
+    OP_DEPTH,
+    OP_SIZE,
+    OP_TOALTSTACK,
+    OP_FROMALTSTACK,
+    OP_1ADD,
+    OP_1SUB,
+    OP_ADD,
+    OP_SUB,
+    OP_NOT,
+    OP_BOOLAND,
+    OP_NUMEQUAL,
+    OP_MIN,
+    OP_MAX,
+    OP_WITHIN,
+    OP_EQUAL,
+    OP_EQUALVERIFY,
+    OP_VERIFY,
+    OP_SHA256,
+    OP_CHECKSIG,
+];
+
+/// Generates random scripts from a seed. The same seed and limits always generate the same
+/// scripts.
+pub struct ScriptGenerator {
+    rng: StdRng,
+    max_items: usize,
+    max_depth: usize,
+    max_push_size: usize,
+    // Scripts generated so far and how deeply they nest calls, which are called again at
+    // random where they don't exceed the maximum depth
+    scripts: Vec<(StructuredScript, usize)>,
+    num_started: usize,
+}
+
+impl ScriptGenerator {
+    /// Creates a generator of scripts with up to 20 items, i.e. opcodes, pushes, `OP_IF`s
+    /// and calls, calls that are nested up to 3 times and pushes of up to 80 bytes.
+    pub fn new(seed: u64) -> Self {
+        ScriptGenerator {
+            rng: StdRng::seed_from_u64(seed),
+            max_items: 20,
+            max_depth: 3,
+            max_push_size: 80,
+            scripts: vec![],
+            num_started: 0,
+        }
+    }
+
+    pub fn with_max_items(mut self, max_items: usize) -> Self {
+        assert!(max_items > 0, "Scripts need at least one item");
+        self.max_items = max_items;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Panics if `max_push_size` exceeds the 520 byte stack element limit.
+    pub fn with_max_push_size(mut self, max_push_size: usize) -> Self {
+        assert!(
+            max_push_size <= MAX_SCRIPT_ELEMENT_SIZE,
+            "Pushes of {} bytes exceed the maximum stack element size of {} bytes",
+            max_push_size,
+            MAX_SCRIPT_ELEMENT_SIZE
+        );
+        self.max_push_size = max_push_size;
+        self
+    }
+
+    /// Generates the next script.
+    pub fn generate(&mut self) -> StructuredScript {
+        self.generate_script(0).0
+    }
+
+    // Returns the script and how deeply it nests calls
+    fn generate_script(&mut self, depth: usize) -> (StructuredScript, usize) {
+        // Nested scripts are finished first, so they are named in the order they are started
+        let name = format!("generated_{}", self.num_started);
+        self.num_started += 1;
+        let num_items = self.rng.gen_range(1..=self.max_items);
+        let mut height = 0;
+        let script = self.push_items(StructuredScript::new(&name), num_items, depth, &mut height);
+        self.scripts.push((script.clone(), height));
+        (script, height)
+    }
+
+    // Pushes `num_items` random items, which are nested `depth` calls deep, and raises
+    // `height` to the deepest nesting of the calls among them
+    fn push_items(
+        &mut self,
+        mut script: StructuredScript,
+        num_items: usize,
+        depth: usize,
+        height: &mut usize,
+    ) -> StructuredScript {
+        let mut remaining = num_items;
+        while remaining > 0 {
+            remaining -= 1;
+            script = match self.rng.gen_range(0..10) {
+                0..=3 => script.push_opcode(OPCODES[self.rng.gen_range(0..OPCODES.len())]),
+                4 => script.push_int(self.rng.gen_range(-1000..1000)),
+                5 => {
+                    let size = self.rng.gen_range(0..=self.max_push_size);
+                    let bytes: Vec<u8> = (0..size).map(|_| self.rng.gen()).collect();
+                    script.push_bytes(&bytes)
+                }
+                6 => {
+                    let opcodes: Vec<u8> = (0..self.rng.gen_range(1..=8))
+                        .map(|_| OPCODES[self.rng.gen_range(0..OPCODES.len())].to_u8())
+                        .collect();
+                    script.push_script(ScriptBuf::from_bytes(opcodes))
+                }
+                7 if remaining > 0 => {
+                    // The branches take up some of the remaining items
+                    let if_items = self.rng.gen_range(0..=remaining);
+                    let else_items = self.rng.gen_range(0..=remaining - if_items);
+                    remaining -= if_items + else_items;
+                    let opcode = if self.rng.gen() { OP_IF } else { OP_NOTIF };
+                    script = self.push_items(script.push_opcode(opcode), if_items, depth, height);
+                    if else_items > 0 || self.rng.gen() {
+                        script = script.push_opcode(OP_ELSE);
+                        script = self.push_items(script, else_items, depth, height);
+                    }
+                    script.push_opcode(OP_ENDIF)
+                }
+                8 if depth < self.max_depth => {
+                    let reusable: Vec<_> = self
+                        .scripts
+                        .iter()
+                        .filter(|(_, call_height)| depth + 1 + call_height <= self.max_depth)
+                        .cloned()
+                        .collect();
+                    let (call, call_height) = match self.rng.gen_range(0..=reusable.len()) {
+                        index if index < reusable.len() => reusable[index].clone(),
+                        _ => self.generate_script(depth + 1),
+                    };
+                    *height = (*height).max(call_height + 1);
+                    script.push_env_script(call)
+                }
+                9 => script.push_chunk_hint(),
+                _ => script.push_opcode(OP_DUP),
+            };
+        }
+        script
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::StackAnalyzer;
+    use crate::builder::Block;
+    use crate::chunker::{Chunker, DpOptimal, Rebalanced};
+
+    #[test]
+    fn generate_deterministically() {
+        let mut first = ScriptGenerator::new(42);
+        let mut second = ScriptGenerator::new(42);
+        for _ in 0..10 {
+            assert_eq!(
+                first.generate().compile().as_bytes(),
+                second.generate().compile().as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn nest_calls_up_to_max_depth() {
+        fn nesting(script: &StructuredScript) -> usize {
+            script
+                .blocks
+                .iter()
+                .filter_map(|block| match block {
+                    Block::Call(id) => Some(1 + nesting(script.get_structured_script(id))),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0)
+        }
+
+        let mut generator = ScriptGenerator::new(7).with_max_items(30).with_max_depth(2);
+        let max_nesting = (0..20)
+            .map(|_| nesting(&generator.generate()))
+            .max()
+            .unwrap();
+        assert_eq!(max_nesting, 2);
+    }
+
+    #[test]
+    fn fuzz_chunker_and_analyzer() {
+        for seed in 0..200 {
+            let script = ScriptGenerator::new(seed).with_max_items(30).generate();
+            let _ = StackAnalyzer::new().try_analyze(&script);
+            for (target_chunk_size, tolerance) in [(100, 30), (40, 0)] {
+                let mut chunker = Chunker::new(script.clone(), target_chunk_size, tolerance);
+                let _ = chunker.estimate(target_chunk_size);
+                let _ = chunker.find_chunks();
+                let _ = Chunker::new(script.clone(), target_chunk_size, tolerance)
+                    .with_strategy(DpOptimal)
+                    .find_chunks();
+                let _ = Chunker::new(script.clone(), target_chunk_size, tolerance)
+                    .with_strategy(Rebalanced)
+                    .find_chunks();
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use super::OPCODES;
+use crate::builder::StructuredScript;
+use bitcoin::blockdata::constants::MAX_SCRIPT_ELEMENT_SIZE;
+use bitcoin::blockdata::opcodes::all::{OP_ELSE, OP_ENDIF, OP_IF};
+use bitcoin::blockdata::opcodes::Opcode;
+use proptest::prelude::*;
+
+/// Parameters of [`structured_script`], which are also the parameters of
+/// `StructuredScript` as a proptest `Arbitrary`.
+#[derive(Clone, Debug)]
+pub struct ScriptParams {
+    /// Maximum nesting of `OP_IF`s and calls
+    pub max_depth: u32,
+    /// Maximum number of items, i.e. opcodes, pushes, `OP_IF`s and calls, of every script
+    /// and branch
+    pub max_items: usize,
+    /// Maximum size of pushed data, at most the 520 byte stack element limit
+    pub max_push_size: usize,
+    /// Opcodes to choose from, which must not change the control flow
+    pub opcodes: Vec<Opcode>,
+}
+
+impl Default for ScriptParams {
+    fn default() -> Self {
+        ScriptParams {
+            max_depth: 3,
+            max_items: 10,
+            max_push_size: 80,
+            opcodes: OPCODES.to_vec(),
+        }
+    }
+}
+
+// A script before it is built, which proptest can shrink
+#[derive(Clone, Debug)]
+enum Item {
+    Opcode(Opcode),
+    Int(i64),
+    Bytes(Vec<u8>),
+    If(Vec<Item>, Option<Vec<Item>>),
+    Call(Vec<Item>),
+}
+
+/// Returns a strategy for structurally valid scripts like the ones of
+/// [`super::ScriptGenerator`]. Failing scripts are shrunk by removing items and
+/// flattening `OP_IF`s and calls.
+pub fn structured_script(params: ScriptParams) -> impl Strategy<Value = StructuredScript> {
+    assert!(
+        params.max_push_size <= MAX_SCRIPT_ELEMENT_SIZE,
+        "Pushes of {} bytes exceed the maximum stack element size of {} bytes",
+        params.max_push_size,
+        MAX_SCRIPT_ELEMENT_SIZE
+    );
+    assert!(!params.opcodes.is_empty(), "No opcodes to choose from");
+    let max_items = params.max_items;
+    let leaf = prop_oneof![
+        4 => proptest::sample::select(params.opcodes).prop_map(Item::Opcode),
+        1 => (-1000i64..1000).prop_map(Item::Int),
+        1 => proptest::collection::vec(any::<u8>(), 0..=params.max_push_size)
+            .prop_map(Item::Bytes),
+    ];
+    let item = leaf.prop_recursive(
+        params.max_depth,
+        (max_items as u32).pow(params.max_depth.min(2)),
+        max_items as u32,
+        move |inner| {
+            let items = proptest::collection::vec(inner, 0..=max_items);
+            prop_oneof![
+                (items.clone(), proptest::option::of(items.clone()))
+                    .prop_map(|(if_items, else_items)| Item::If(if_items, else_items)),
+                items.prop_map(Item::Call),
+            ]
+        },
+    );
+    proptest::collection::vec(item, 1..=max_items).prop_map(|items| {
+        let mut num_scripts = 0;
+        build(StructuredScript::new("arbitrary"), &items, &mut num_scripts)
+    })
+}
+
+fn build(
+    mut script: StructuredScript,
+    items: &[Item],
+    num_scripts: &mut usize,
+) -> StructuredScript {
+    for item in items {
+        script = match item {
+            Item::Opcode(opcode) => script.push_opcode(*opcode),
+            Item::Int(value) => script.push_int(*value),
+            Item::Bytes(bytes) => script.push_bytes(bytes),
+            Item::If(if_items, else_items) => {
+                script = build(script.push_opcode(OP_IF), if_items, num_scripts);
+                if let Some(else_items) = else_items {
+                    script = build(script.push_opcode(OP_ELSE), else_items, num_scripts);
+                }
+                script.push_opcode(OP_ENDIF)
+            }
+            Item::Call(items) => {
+                *num_scripts += 1;
+                let name = format!("arbitrary_{}", num_scripts);
+                script.push_env_script(build(StructuredScript::new(&name), items, num_scripts))
+            }
+        };
+    }
+    script
+}
+
+impl Arbitrary for StructuredScript {
+    type Parameters = ScriptParams;
+    type Strategy = BoxedStrategy<StructuredScript>;
+
+    fn arbitrary_with(params: Self::Parameters) -> Self::Strategy {
+        structured_script(params).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::StackAnalyzer;
+    use crate::chunker::Chunker;
+    use bitcoin::blockdata::opcodes::all::{OP_ADD, OP_DUP, OP_PUSHNUM_16};
+    use bitcoin::blockdata::script::Instruction;
+
+    proptest! {
+        #[test]
+        fn chunks_add_up(script in any::<StructuredScript>()) {
+            let len = script.len();
+            let chunks = Chunker::new(script, 40, 10).find_chunks();
+            if let Ok(chunks) = chunks {
+                prop_assert_eq!(chunks.iter().sum::<usize>(), len);
+            }
+        }
+
+        #[test]
+        fn use_given_opcodes(script in structured_script(ScriptParams {
+            max_push_size: 0,
+            opcodes: vec![OP_DUP, OP_ADD],
+            ..Default::default()
+        })) {
+            let _ = StackAnalyzer::new().try_analyze(&script);
+            let allowed = [OP_DUP, OP_ADD, OP_IF, OP_ELSE, OP_ENDIF];
+            for instruction in script.compile().instructions() {
+                if let Instruction::Op(opcode) = instruction.unwrap() {
+                    prop_assert!(
+                        allowed.contains(&opcode) || opcode.to_u8() <= OP_PUSHNUM_16.to_u8()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,891 @@
+//! Executes scripts without a transaction, e.g. to unit-test gadgets.
+//!
+//! Signatures are not checked: `OP_CHECKSIG` and its variants accept every non-empty
+//! signature, like a valid signature, and reject the empty signature. Otherwise the
+//! tapscript rules apply, e.g. `OP_IF` requires an empty or `1` argument. Opcodes that
+//! are disabled or would make a tapscript succeed unconditionally are not supported.
+
+mod debugger;
+mod equivalence;
+mod profile;
+mod trace;
+
+pub use self::debugger::{Breakpoint, Debugger};
+pub use self::equivalence::{equivalent, Inequivalence};
+pub use self::profile::{BlockProfile, Profile};
+pub use self::trace::{Trace, TraceStep};
+
+use crate::builder::{Block, StructuredScript};
+use crate::chunker::{Chunk, MAX_STACK_SIZE};
+use bitcoin::blockdata::constants::MAX_SCRIPT_ELEMENT_SIZE;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::{self, read_scriptbool, write_scriptint, Instruction};
+use bitcoin::hashes::{hash160, ripemd160, sha1, sha256, sha256d, Hash};
+use std::fmt;
+use std::ops::Range;
+
+/// Why the execution of a script failed, see [`ExecutionError`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionFailure {
+    /// The opcode needs more elements than the main stack holds, or the depth of an
+    /// `OP_PICK` or `OP_ROLL` is negative or exceeds the stack.
+    StackUnderflow(Opcode),
+    /// `OP_FROMALTSTACK` with an empty alt stack.
+    AltStackUnderflow,
+    /// A number is longer than 4 bytes or not minimally encoded.
+    InvalidNumber(Opcode),
+    /// The argument of an `OP_IF` or `OP_NOTIF` is neither empty nor `1`.
+    NonMinimalIf(Opcode),
+    /// An `OP_VERIFY` or a `...VERIFY` opcode found a false value.
+    VerifyFailed(Opcode),
+    OpReturn,
+    /// An `OP_ELSE` or `OP_ENDIF` without an `OP_IF`, or an `OP_IF` that is never closed.
+    UnbalancedConditional(Opcode),
+    /// A negative lock time for `OP_CHECKLOCKTIMEVERIFY` or `OP_CHECKSEQUENCEVERIFY`.
+    NegativeLocktime(Opcode),
+    UnsupportedOpcode(Opcode),
+    /// The main and alt stack hold more than [`MAX_STACK_SIZE`] elements.
+    StackSizeExceeded,
+    /// A push of more than [`MAX_SCRIPT_ELEMENT_SIZE`] bytes.
+    PushSizeExceeded(usize),
+    UnfilledPlaceholder(String),
+    InvalidInstruction(script::Error),
+}
+
+impl fmt::Display for ExecutionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionFailure::StackUnderflow(opcode) => write!(f, "Stack underflow at {}", opcode),
+            ExecutionFailure::AltStackUnderflow => write!(f, "Alt stack underflow"),
+            ExecutionFailure::InvalidNumber(opcode) => {
+                write!(f, "Invalid number argument of {}", opcode)
+            }
+            ExecutionFailure::NonMinimalIf(opcode) => {
+                write!(f, "Argument of {} is neither empty nor 1", opcode)
+            }
+            ExecutionFailure::VerifyFailed(opcode) => write!(f, "{} failed", opcode),
+            ExecutionFailure::OpReturn => write!(f, "OP_RETURN"),
+            ExecutionFailure::UnbalancedConditional(opcode) => match *opcode {
+                OP_IF | OP_NOTIF => write!(f, "Unclosed {}", opcode),
+                _ => write!(f, "{} without OP_IF", opcode),
+            },
+            ExecutionFailure::NegativeLocktime(opcode) => {
+                write!(f, "Negative lock time for {}", opcode)
+            }
+            ExecutionFailure::UnsupportedOpcode(opcode) => {
+                write!(f, "Execution of {} is not supported", opcode)
+            }
+            ExecutionFailure::StackSizeExceeded => {
+                write!(f, "More than {} stack elements", MAX_STACK_SIZE)
+            }
+            ExecutionFailure::PushSizeExceeded(size) => write!(
+                f,
+                "Push of {} bytes exceeds the limit of {} bytes",
+                size, MAX_SCRIPT_ELEMENT_SIZE
+            ),
+            ExecutionFailure::UnfilledPlaceholder(name) => {
+                write!(f, "Unfilled placeholder {}", name)
+            }
+            ExecutionFailure::InvalidInstruction(error) => {
+                write!(f, "Invalid instruction: {}", error)
+            }
+        }
+    }
+}
+
+/// A failed execution together with the position of the failing instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecutionError {
+    pub failure: ExecutionFailure,
+    /// Position of the failing instruction in the compiled script
+    pub offset: usize,
+    /// Name and source location of the script that contains the failing instruction
+    pub block: String,
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {} at offset {}",
+            self.failure, self.block, self.offset
+        )
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+/// The outcome of [`execute`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecutionResult {
+    /// Why the execution stopped early, `None` if every instruction was executed
+    pub error: Option<ExecutionError>,
+    /// The main stack after the execution, from the bottom to the top element
+    pub final_stack: Vec<Vec<u8>>,
+    pub final_altstack: Vec<Vec<u8>>,
+}
+
+impl ExecutionResult {
+    /// Returns true if the script was executed without an error and left a single true
+    /// element on the stack, as tapscripts have to.
+    pub fn success(&self) -> bool {
+        self.error.is_none() && self.final_stack.len() == 1 && read_scriptbool(&self.final_stack[0])
+    }
+}
+
+/// Executes the script starting with `initial_stack`, from the bottom to the top element,
+/// on the main stack.
+pub fn execute(script: &StructuredScript, initial_stack: Vec<Vec<u8>>) -> ExecutionResult {
+    Interpreter::new(script, initial_stack).finish()
+}
+
+/// Executes the chunks one after another, each starting with the stacks that the previous
+/// chunk left, and returns the result of every executed chunk. The execution stops after
+/// the first chunk that fails.
+pub fn execute_chunks(chunks: &[Chunk], initial_stack: Vec<Vec<u8>>) -> Vec<ExecutionResult> {
+    let mut results: Vec<ExecutionResult> = vec![];
+    let (mut stack, mut altstack) = (initial_stack, vec![]);
+    for chunk in chunks {
+        let script = chunk.script();
+        let result = Interpreter::new(&script, stack)
+            .with_altstack(altstack)
+            .finish();
+        stack = result.final_stack.clone();
+        altstack = result.final_altstack.clone();
+        let failed = result.error.is_some();
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+    results
+}
+
+/// Panics unless executing the chunks with [`execute_chunks`] ends like executing `script`
+/// at once: either both fail or both leave the same stacks. Chunks that don't hand over
+/// the stacks correctly are caught this way in tests.
+pub fn assert_chunked_execution(
+    script: &StructuredScript,
+    chunks: &[Chunk],
+    initial_stack: Vec<Vec<u8>>,
+) {
+    let expected = execute(script, initial_stack.clone());
+    let mut results = execute_chunks(chunks, initial_stack.clone());
+    let actual = results.pop().unwrap_or(ExecutionResult {
+        error: None,
+        final_stack: initial_stack,
+        final_altstack: vec![],
+    });
+    match (expected.error, actual.error) {
+        (None, Some(error)) => panic!(
+            "Chunk {} failed but the whole script succeeded: {}",
+            results.len(),
+            error
+        ),
+        (Some(error), None) => panic!(
+            "The whole script failed but its chunks succeeded: {}",
+            error
+        ),
+        (None, None) => {
+            assert_eq!(
+                actual.final_stack, expected.final_stack,
+                "The chunks leave a different stack than the whole script"
+            );
+            assert_eq!(
+                actual.final_altstack, expected.final_altstack,
+                "The chunks leave a different alt stack than the whole script"
+            );
+        }
+        (Some(_), Some(_)) => (),
+    }
+}
+
+/// Verifies with libbitcoinconsensus that input `input_index` of `tx` spends an output of
+/// `amount` that is locked by `script_pubkey`. Unlike [`execute`], this checks signatures.
+/// libbitcoinconsensus doesn't support taproot, so the script is verified under the legacy
+/// and segwit v0 rules.
+#[cfg(feature = "bitcoinconsensus")]
+pub fn verify_with_consensus(
+    script_pubkey: &StructuredScript,
+    tx: &bitcoin::Transaction,
+    input_index: usize,
+    amount: bitcoin::Amount,
+) -> Result<(), bitcoin::consensus::validation::BitcoinconsensusError> {
+    let mut bytes = vec![];
+    script_pubkey.compile_into(&mut bytes);
+    bitcoin::ScriptBuf::from_bytes(bytes).verify(
+        input_index,
+        amount,
+        &bitcoin::consensus::serialize(tx),
+    )
+}
+
+// A call of a script and the steps of its instructions, including the instructions of
+// the scripts it calls
+struct Call<'a> {
+    steps: Range<usize>,
+    block: &'a StructuredScript,
+}
+
+// An instruction of the script together with the script that contains it
+struct Step<'a> {
+    offset: usize,
+    instruction: Result<Instruction<'a>, ExecutionFailure>,
+    block: &'a StructuredScript,
+}
+
+/// Executes a script one instruction at a time, see [`execute`].
+pub struct Interpreter<'a> {
+    steps: Vec<Step<'a>>,
+    // Every executed script in the order of their first steps
+    calls: Vec<Call<'a>>,
+    next_step: usize,
+    stack: Vec<Vec<u8>>,
+    altstack: Vec<Vec<u8>>,
+    // Whether the branches of the enclosing OP_IFs are executed
+    conditions: Vec<bool>,
+    error: Option<ExecutionError>,
+    script_len: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(script: &'a StructuredScript, initial_stack: Vec<Vec<u8>>) -> Self {
+        let mut steps = vec![];
+        let mut calls = vec![];
+        collect_steps(script, &mut 0, &mut steps, &mut calls);
+        Interpreter {
+            steps,
+            calls,
+            next_step: 0,
+            stack: initial_stack,
+            altstack: vec![],
+            conditions: vec![],
+            error: None,
+            script_len: script.len(),
+        }
+    }
+
+    /// Starts the execution with `altstack`, from the bottom to the top element, on the
+    /// alt stack.
+    pub fn with_altstack(mut self, altstack: Vec<Vec<u8>>) -> Self {
+        self.altstack = altstack;
+        self
+    }
+
+    /// The main stack, from the bottom to the top element
+    pub fn stack(&self) -> &[Vec<u8>] {
+        &self.stack
+    }
+
+    pub fn altstack(&self) -> &[Vec<u8>] {
+        &self.altstack
+    }
+
+    pub fn error(&self) -> Option<&ExecutionError> {
+        self.error.as_ref()
+    }
+
+    /// The next instruction, `None` if the execution is finished or the next part of the
+    /// script is not a valid instruction
+    pub fn next_instruction(&self) -> Option<Instruction<'a>> {
+        self.next()
+            .and_then(|step| step.instruction.as_ref().ok().copied())
+    }
+
+    /// Position of the next instruction in the compiled script
+    pub fn next_offset(&self) -> Option<usize> {
+        self.next().map(|step| step.offset)
+    }
+
+    /// The script that contains the next instruction
+    pub fn next_block(&self) -> Option<&'a StructuredScript> {
+        self.next().map(|step| step.block)
+    }
+
+    /// The scripts whose execution starts with the next instruction, from the outermost
+    /// to the innermost script
+    pub fn entered_blocks(&self) -> impl Iterator<Item = &'a StructuredScript> + '_ {
+        let next_step = if self.is_finished() {
+            usize::MAX
+        } else {
+            self.next_step
+        };
+        let start = self
+            .calls
+            .partition_point(|call| call.steps.start < next_step);
+        self.calls[start..]
+            .iter()
+            .take_while(move |call| call.steps.start == next_step)
+            .map(|call| call.block)
+    }
+
+    fn next(&self) -> Option<&Step<'a>> {
+        match self.is_finished() {
+            true => None,
+            false => self.steps.get(self.next_step),
+        }
+    }
+
+    /// Returns true if all instructions were executed or the execution failed.
+    pub fn is_finished(&self) -> bool {
+        self.error.is_some() || self.next_step > self.steps.len()
+    }
+
+    /// Executes the next instruction. Returns false if the execution is finished.
+    pub fn step(&mut self) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        let step = match self.steps.get(self.next_step) {
+            Some(step) => step,
+            None => {
+                // All OP_IFs have to be closed at the end of the script
+                self.next_step += 1;
+                if !self.conditions.is_empty() {
+                    self.error = Some(ExecutionError {
+                        failure: ExecutionFailure::UnbalancedConditional(OP_IF),
+                        offset: self.script_len,
+                        block: self
+                            .steps
+                            .last()
+                            .map_or_else(String::new, |step| step.block.describe()),
+                    });
+                }
+                return false;
+            }
+        };
+        self.next_step += 1;
+        let result = match &step.instruction {
+            Ok(instruction) => {
+                let instruction = *instruction;
+                self.execute_instruction(instruction)
+            }
+            Err(failure) => Err(failure.clone()),
+        };
+        if let Err(failure) = result {
+            let step = &self.steps[self.next_step - 1];
+            self.error = Some(ExecutionError {
+                failure,
+                offset: step.offset,
+                block: step.block.describe(),
+            });
+            return false;
+        }
+        true
+    }
+
+    /// Executes the remaining instructions.
+    pub fn finish(mut self) -> ExecutionResult {
+        while self.step() {}
+        ExecutionResult {
+            error: self.error,
+            final_stack: self.stack,
+            final_altstack: self.altstack,
+        }
+    }
+
+    fn is_executing(&self) -> bool {
+        self.conditions.iter().all(|condition| *condition)
+    }
+
+    fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), ExecutionFailure> {
+        match instruction {
+            Instruction::PushBytes(bytes) => {
+                if bytes.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ExecutionFailure::PushSizeExceeded(bytes.len()));
+                }
+                if self.is_executing() {
+                    self.stack.push(bytes.as_bytes().to_vec());
+                }
+            }
+            Instruction::Op(opcode) => match opcode {
+                OP_IF | OP_NOTIF => {
+                    let condition = if self.is_executing() {
+                        let value = self.pop(opcode)?;
+                        if value.len() > 1 || value.first().is_some_and(|byte| *byte != 1) {
+                            return Err(ExecutionFailure::NonMinimalIf(opcode));
+                        }
+                        value.is_empty() == (opcode == OP_NOTIF)
+                    } else {
+                        false
+                    };
+                    self.conditions.push(condition);
+                }
+                OP_ELSE => match self.conditions.last_mut() {
+                    Some(condition) => *condition = !*condition,
+                    None => return Err(ExecutionFailure::UnbalancedConditional(opcode)),
+                },
+                OP_ENDIF => {
+                    if self.conditions.pop().is_none() {
+                        return Err(ExecutionFailure::UnbalancedConditional(opcode));
+                    }
+                }
+                _ if !self.is_executing() => (),
+                _ => self.execute_opcode(opcode)?,
+            },
+        }
+        if self.stack.len() + self.altstack.len() > MAX_STACK_SIZE {
+            return Err(ExecutionFailure::StackSizeExceeded);
+        }
+        Ok(())
+    }
+
+    fn execute_opcode(&mut self, opcode: Opcode) -> Result<(), ExecutionFailure> {
+        let len = self.stack.len();
+        let need = |n: usize| {
+            if len < n {
+                Err(ExecutionFailure::StackUnderflow(opcode))
+            } else {
+                Ok(())
+            }
+        };
+        match opcode {
+            OP_PUSHNUM_NEG1 => self.push_num(-1),
+            _ if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&opcode.to_u8()) => {
+                self.push_num((opcode.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64)
+            }
+            OP_NOP | OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 | OP_NOP7 | OP_NOP8 | OP_NOP9
+            | OP_NOP10 | OP_CODESEPARATOR => (),
+            OP_CLTV | OP_CSV => {
+                need(1)?;
+                let locktime = read_num(&self.stack[len - 1], 5)
+                    .ok_or(ExecutionFailure::InvalidNumber(opcode))?;
+                if locktime < 0 {
+                    return Err(ExecutionFailure::NegativeLocktime(opcode));
+                }
+            }
+            OP_VERIFY => {
+                let value = self.pop(opcode)?;
+                verify(read_scriptbool(&value), opcode)?;
+            }
+            OP_RETURN => return Err(ExecutionFailure::OpReturn),
+
+            OP_TOALTSTACK => {
+                let value = self.pop(opcode)?;
+                self.altstack.push(value);
+            }
+            OP_FROMALTSTACK => {
+                let value = self
+                    .altstack
+                    .pop()
+                    .ok_or(ExecutionFailure::AltStackUnderflow)?;
+                self.stack.push(value);
+            }
+            OP_2DROP => {
+                need(2)?;
+                self.stack.truncate(len - 2);
+            }
+            OP_2DUP => {
+                need(2)?;
+                self.stack.extend_from_within(len - 2..);
+            }
+            OP_3DUP => {
+                need(3)?;
+                self.stack.extend_from_within(len - 3..);
+            }
+            OP_2OVER => {
+                need(4)?;
+                self.stack.extend_from_within(len - 4..len - 2);
+            }
+            OP_2ROT => {
+                need(6)?;
+                let moved: Vec<_> = self.stack.drain(len - 6..len - 4).collect();
+                self.stack.extend(moved);
+            }
+            OP_2SWAP => {
+                need(4)?;
+                let moved: Vec<_> = self.stack.drain(len - 4..len - 2).collect();
+                self.stack.extend(moved);
+            }
+            OP_IFDUP => {
+                need(1)?;
+                if read_scriptbool(&self.stack[len - 1]) {
+                    self.stack.extend_from_within(len - 1..);
+                }
+            }
+            OP_DEPTH => self.push_num(len as i64),
+            OP_DROP => {
+                self.pop(opcode)?;
+            }
+            OP_DUP => {
+                need(1)?;
+                self.stack.extend_from_within(len - 1..);
+            }
+            OP_NIP => {
+                need(2)?;
+                self.stack.remove(len - 2);
+            }
+            OP_OVER => {
+                need(2)?;
+                self.stack.extend_from_within(len - 2..len - 1);
+            }
+            OP_PICK | OP_ROLL => {
+                let depth = self.pop_num(opcode)?;
+                let len = self.stack.len();
+                if depth < 0 || depth as usize >= len {
+                    return Err(ExecutionFailure::StackUnderflow(opcode));
+                }
+                let index = len - 1 - depth as usize;
+                let value = match opcode {
+                    OP_PICK => self.stack[index].clone(),
+                    _ => self.stack.remove(index),
+                };
+                self.stack.push(value);
+            }
+            OP_ROT => {
+                need(3)?;
+                let value = self.stack.remove(len - 3);
+                self.stack.push(value);
+            }
+            OP_SWAP => {
+                need(2)?;
+                self.stack.swap(len - 1, len - 2);
+            }
+            OP_TUCK => {
+                need(2)?;
+                let value = self.stack[len - 1].clone();
+                self.stack.insert(len - 2, value);
+            }
+            OP_SIZE => {
+                need(1)?;
+                self.push_num(self.stack[len - 1].len() as i64);
+            }
+
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let b = self.pop(opcode)?;
+                let a = self.pop(opcode)?;
+                self.push_bool(a == b);
+                if opcode == OP_EQUALVERIFY {
+                    self.verify_top(opcode)?;
+                }
+            }
+
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let a = self.pop_num(opcode)?;
+                let result = match opcode {
+                    OP_1ADD => a + 1,
+                    OP_1SUB => a - 1,
+                    OP_NEGATE => -a,
+                    OP_ABS => a.abs(),
+                    OP_NOT => (a == 0) as i64,
+                    _ => (a != 0) as i64,
+                };
+                self.push_num(result);
+            }
+            OP_ADD
+            | OP_SUB
+            | OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => {
+                let b = self.pop_num(opcode)?;
+                let a = self.pop_num(opcode)?;
+                let result = match opcode {
+                    OP_ADD => a + b,
+                    OP_SUB => a - b,
+                    OP_BOOLAND => (a != 0 && b != 0) as i64,
+                    OP_BOOLOR => (a != 0 || b != 0) as i64,
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                    OP_NUMNOTEQUAL => (a != b) as i64,
+                    OP_LESSTHAN => (a < b) as i64,
+                    OP_GREATERTHAN => (a > b) as i64,
+                    OP_LESSTHANOREQUAL => (a <= b) as i64,
+                    OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                    OP_MIN => a.min(b),
+                    _ => a.max(b),
+                };
+                self.push_num(result);
+                if opcode == OP_NUMEQUALVERIFY {
+                    self.verify_top(opcode)?;
+                }
+            }
+            OP_WITHIN => {
+                let max = self.pop_num(opcode)?;
+                let min = self.pop_num(opcode)?;
+                let value = self.pop_num(opcode)?;
+                self.push_bool(min <= value && value < max);
+            }
+
+            OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                let value = self.pop(opcode)?;
+                let hash = match opcode {
+                    OP_RIPEMD160 => ripemd160::Hash::hash(&value).to_byte_array().to_vec(),
+                    OP_SHA1 => sha1::Hash::hash(&value).to_byte_array().to_vec(),
+                    OP_SHA256 => sha256::Hash::hash(&value).to_byte_array().to_vec(),
+                    OP_HASH160 => hash160::Hash::hash(&value).to_byte_array().to_vec(),
+                    _ => sha256d::Hash::hash(&value).to_byte_array().to_vec(),
+                };
+                self.stack.push(hash);
+            }
+
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                self.pop(opcode)?;
+                let signature = self.pop(opcode)?;
+                self.push_bool(!signature.is_empty());
+                if opcode == OP_CHECKSIGVERIFY {
+                    self.verify_top(opcode)?;
+                }
+            }
+            OP_CHECKSIGADD => {
+                self.pop(opcode)?;
+                let n = self.pop_num(opcode)?;
+                let signature = self.pop(opcode)?;
+                self.push_num(n + !signature.is_empty() as i64);
+            }
+
+            _ => return Err(ExecutionFailure::UnsupportedOpcode(opcode)),
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self, opcode: Opcode) -> Result<Vec<u8>, ExecutionFailure> {
+        self.stack
+            .pop()
+            .ok_or(ExecutionFailure::StackUnderflow(opcode))
+    }
+
+    fn pop_num(&mut self, opcode: Opcode) -> Result<i64, ExecutionFailure> {
+        let value = self.pop(opcode)?;
+        read_num(&value, 4).ok_or(ExecutionFailure::InvalidNumber(opcode))
+    }
+
+    fn push_num(&mut self, value: i64) {
+        let mut buffer = [0u8; 8];
+        let len = write_scriptint(&mut buffer, value);
+        self.stack.push(buffer[..len].to_vec());
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.push_num(value as i64);
+    }
+
+    // Pops the result of a VERIFY opcode
+    fn verify_top(&mut self, opcode: Opcode) -> Result<(), ExecutionFailure> {
+        let value = self.pop(opcode)?;
+        verify(read_scriptbool(&value), opcode)
+    }
+}
+
+fn verify(value: bool, opcode: Opcode) -> Result<(), ExecutionFailure> {
+    if value {
+        Ok(())
+    } else {
+        Err(ExecutionFailure::VerifyFailed(opcode))
+    }
+}
+
+// Decodes a minimally encoded number of at most `max_len` bytes
+fn read_num(bytes: &[u8], max_len: usize) -> Option<i64> {
+    let last = match bytes.last() {
+        Some(last) => *last,
+        None => return Some(0),
+    };
+    if bytes.len() > max_len {
+        return None;
+    }
+    // The most significant byte may only be zero apart from the sign bit if the sign bit
+    // of the preceding byte is set
+    if last & 0x7f == 0 && (bytes.len() == 1 || bytes[bytes.len() - 2] & 0x80 == 0) {
+        return None;
+    }
+    let mut value = 0i64;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        value &= !(0x80i64 << (8 * (bytes.len() - 1)));
+        value = -value;
+    }
+    Some(value)
+}
+
+fn collect_steps<'a>(
+    script: &'a StructuredScript,
+    offset: &mut usize,
+    steps: &mut Vec<Step<'a>>,
+    calls: &mut Vec<Call<'a>>,
+) {
+    let call = calls.len();
+    calls.push(Call {
+        steps: steps.len()..steps.len(),
+        block: script,
+    });
+    for block in &script.blocks {
+        match block {
+            Block::Call(id) => {
+                collect_steps(script.get_structured_script(id), offset, steps, calls)
+            }
+            Block::Script(script_buf) => {
+                let block_offset = *offset;
+                for instruction in script_buf.instruction_indices() {
+                    match instruction {
+                        Ok((index, instruction)) => {
+                            *offset = block_offset + index;
+                            steps.push(Step {
+                                offset: *offset,
+                                instruction: Ok(instruction),
+                                block: script,
+                            });
+                        }
+                        Err(error) => {
+                            steps.push(Step {
+                                offset: *offset,
+                                instruction: Err(ExecutionFailure::InvalidInstruction(error)),
+                                block: script,
+                            });
+                            break;
+                        }
+                    }
+                }
+                *offset = block_offset + script_buf.len();
+            }
+            Block::Placeholder { name, len } => {
+                steps.push(Step {
+                    offset: *offset,
+                    instruction: Err(ExecutionFailure::UnfilledPlaceholder(name.clone())),
+                    block: script,
+                });
+                *offset += len;
+            }
+        }
+    }
+    calls[call].steps.end = steps.len();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Chunker;
+    use crate::script;
+
+    #[test]
+    fn execute_arithmetic() {
+        let script = script! {
+            def double {
+                OP_DUP OP_ADD
+            }
+
+            OP_ADD double
+            OP_DUP 10 OP_GREATERTHAN
+            OP_IF
+                OP_1SUB
+            OP_ELSE
+                OP_1ADD
+            OP_ENDIF
+            { 11 } OP_EQUAL
+        };
+        let result = execute(&script, vec![vec![2], vec![4]]);
+        assert_eq!(result.error, None);
+        assert!(result.success());
+
+        let result = execute(&script, vec![vec![1], vec![2]]);
+        assert!(!result.success());
+        assert_eq!(result.final_stack, vec![Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn execute_stack_and_hash_opcodes() {
+        let preimage = [42u8; 32];
+        let hash = sha256::Hash::hash(&preimage).to_byte_array();
+        let script = script! {
+            OP_SHA256 { hash.to_vec() } OP_EQUALVERIFY
+            OP_1 OP_2 OP_3 OP_ROT OP_TOALTSTACK OP_2DUP OP_SWAP OP_FROMALTSTACK
+            2 OP_ROLL OP_DEPTH
+        };
+        let result = execute(&script, vec![preimage.to_vec()]);
+        assert_eq!(result.error, None);
+        assert_eq!(
+            result.final_stack,
+            vec![vec![2], vec![3], vec![2], vec![1], vec![3], vec![5]]
+        );
+    }
+
+    #[test]
+    fn execution_errors() {
+        let script = script! {
+            def check {
+                OP_VERIFY
+            }
+
+            OP_1 OP_ADD check
+        };
+        let err = execute(&script, vec![vec![0x81]]).error.unwrap();
+        assert_eq!(err.failure, ExecutionFailure::VerifyFailed(OP_VERIFY));
+        assert_eq!(err.offset, 2);
+        assert!(err.block.contains("check"));
+
+        let err = execute(&script, vec![]).error.unwrap();
+        assert_eq!(err.failure, ExecutionFailure::StackUnderflow(OP_ADD));
+        assert_eq!(
+            err.to_string(),
+            format!("Stack underflow at OP_ADD in {} at offset 1", err.block)
+        );
+
+        let result = execute(&script! { OP_1 OP_IF OP_1 }, vec![]);
+        assert_eq!(
+            result.error.unwrap().failure,
+            ExecutionFailure::UnbalancedConditional(OP_IF)
+        );
+        let err = execute(&script! { OP_2 OP_IF OP_ENDIF }, vec![])
+            .error
+            .unwrap();
+        assert_eq!(err.failure, ExecutionFailure::NonMinimalIf(OP_IF));
+        let err = execute(&script! { OP_1ADD }, vec![vec![1, 2, 3, 4, 5]])
+            .error
+            .unwrap();
+        assert_eq!(err.failure, ExecutionFailure::InvalidNumber(OP_1ADD));
+        assert!(!execute(&script! { OP_CHECKSIG }, vec![vec![], vec![2; 32]]).success());
+        assert!(execute(&script! { OP_CHECKSIG }, vec![vec![1; 64], vec![2; 32]]).success());
+    }
+
+    #[test]
+    fn execute_chunks_like_the_script() {
+        let script = script! {
+            def count {
+                OP_1ADD OP_DUP OP_TOALTSTACK
+            }
+
+            def sum {
+                OP_FROMALTSTACK OP_ADD
+            }
+
+            OP_0
+            for _ in 0..10 {
+                count
+            }
+            for _ in 0..10 {
+                sum
+            }
+        };
+        let chunks = Chunker::new(script.clone(), 8, 2)
+            .find_chunks_and_analyze_stack()
+            .unwrap();
+        assert!(chunks.len() > 1);
+        assert_chunked_execution(&script, &chunks, vec![]);
+
+        let results = execute_chunks(&chunks, vec![]);
+        assert_eq!(results.len(), chunks.len());
+        assert_eq!(results[0].final_altstack.len(), 2);
+        assert_eq!(results.last().unwrap().final_stack, vec![vec![65]]);
+    }
+
+    #[test]
+    fn read_numbers() {
+        assert_eq!(read_num(&[], 4), Some(0));
+        assert_eq!(read_num(&[0x81], 4), Some(-1));
+        assert_eq!(read_num(&[0xff, 0x00], 4), Some(255));
+        assert_eq!(read_num(&[0xff, 0x80], 4), Some(-255));
+        assert_eq!(read_num(&[0x01, 0x00], 4), None);
+        assert_eq!(read_num(&[0x80], 4), None);
+        assert_eq!(read_num(&[0, 0, 0, 0x80, 0], 5), Some(0x80000000));
+        assert_eq!(read_num(&[0, 0, 0, 0x80, 0], 4), None);
+    }
+}
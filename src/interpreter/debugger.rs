@@ -0,0 +1,157 @@
+use super::{ExecutionError, ExecutionResult, Interpreter};
+use crate::builder::StructuredScript;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::Instruction;
+
+/// Where a [`Debugger`] stops the execution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Before every call of a script with this name. Scripts are named by the last
+    /// segment of their `debug_identifier` like in [`StructuredScript::get_block_by_name`].
+    Block(String),
+    /// Before every instruction with this opcode
+    Opcode(Opcode),
+}
+
+/// Executes a script step by step or up to the next [`Breakpoint`] and shows the stacks
+/// in between.
+pub struct Debugger<'a> {
+    interpreter: Interpreter<'a>,
+    breakpoints: Vec<Breakpoint>,
+    // Whether the execution was stepped or resumed before
+    started: bool,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(script: &'a StructuredScript, initial_stack: Vec<Vec<u8>>) -> Self {
+        Debugger {
+            interpreter: Interpreter::new(script, initial_stack),
+            breakpoints: vec![],
+            started: false,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /// The state of the execution, e.g. the next instruction and the script it belongs to
+    pub fn interpreter(&self) -> &Interpreter<'a> {
+        &self.interpreter
+    }
+
+    /// The main stack, from the bottom to the top element
+    pub fn stack(&self) -> &[Vec<u8>] {
+        self.interpreter.stack()
+    }
+
+    pub fn altstack(&self) -> &[Vec<u8>] {
+        self.interpreter.altstack()
+    }
+
+    pub fn error(&self) -> Option<&ExecutionError> {
+        self.interpreter.error()
+    }
+
+    /// Executes the next instruction. Returns false if the execution is finished.
+    pub fn step(&mut self) -> bool {
+        self.started = true;
+        self.interpreter.step()
+    }
+
+    /// Executes at least one instruction and continues until the next instruction hits a
+    /// breakpoint, which is returned, or the execution is finished. Before the first
+    /// instruction, a breakpoint at that instruction is returned without executing it.
+    pub fn resume(&mut self) -> Option<&Breakpoint> {
+        if !std::mem::replace(&mut self.started, true) {
+            if let Some(index) = self.hit_breakpoint() {
+                return Some(&self.breakpoints[index]);
+            }
+        }
+        while self.interpreter.step() {
+            if let Some(index) = self.hit_breakpoint() {
+                return Some(&self.breakpoints[index]);
+            }
+        }
+        None
+    }
+
+    /// Executes the remaining instructions, ignoring the breakpoints.
+    pub fn finish(self) -> ExecutionResult {
+        self.interpreter.finish()
+    }
+
+    fn hit_breakpoint(&self) -> Option<usize> {
+        let instruction = self.interpreter.next_instruction();
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Breakpoint::Block(name) => self
+                    .interpreter
+                    .entered_blocks()
+                    .any(|block| block.name() == name),
+                Breakpoint::Opcode(opcode) => instruction == Some(Instruction::Op(*opcode)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+    use bitcoin::blockdata::opcodes::all::{OP_ADD, OP_EQUAL, OP_PUSHNUM_1};
+
+    #[test]
+    fn debugger_breakpoints() {
+        let script = script! {
+            def double {
+                OP_DUP OP_ADD
+            }
+
+            OP_1 double double
+            OP_4 OP_EQUAL
+        };
+        let mut debugger = Debugger::new(&script, vec![]);
+        debugger.add_breakpoint(Breakpoint::Block("double".to_string()));
+        debugger.add_breakpoint(Breakpoint::Opcode(OP_EQUAL));
+
+        let double = Breakpoint::Block("double".to_string());
+        assert_eq!(debugger.resume(), Some(&double));
+        assert_eq!(debugger.stack(), &[vec![1]]);
+        assert_eq!(debugger.interpreter().next_offset(), Some(1));
+        assert!(debugger.step());
+        assert_eq!(debugger.stack(), &[vec![1], vec![1]]);
+        assert_eq!(
+            debugger.interpreter().next_instruction(),
+            Some(Instruction::Op(OP_ADD))
+        );
+
+        // Consecutive calls of the same script stop once per call
+        assert_eq!(debugger.resume(), Some(&double));
+        assert_eq!(debugger.stack(), &[vec![2]]);
+        assert_eq!(debugger.resume(), Some(&Breakpoint::Opcode(OP_EQUAL)));
+        assert_eq!(debugger.stack(), &[vec![4], vec![4]]);
+        assert_eq!(debugger.resume(), None);
+        assert!(debugger.finish().success());
+    }
+
+    #[test]
+    fn debugger_breakpoint_at_start() {
+        let script = script! {
+            OP_1 OP_1 OP_ADD
+        };
+        let mut debugger = Debugger::new(&script, vec![]);
+        debugger.add_breakpoint(Breakpoint::Opcode(OP_PUSHNUM_1));
+
+        let breakpoint = Breakpoint::Opcode(OP_PUSHNUM_1);
+        assert_eq!(debugger.resume(), Some(&breakpoint));
+        assert_eq!(debugger.interpreter().next_offset(), Some(0));
+        assert_eq!(debugger.stack(), &[] as &[Vec<u8>]);
+        // Resuming at a breakpoint executes the instruction
+        assert_eq!(debugger.resume(), Some(&breakpoint));
+        assert_eq!(debugger.interpreter().next_offset(), Some(1));
+        assert_eq!(debugger.stack(), &[vec![1]]);
+        assert_eq!(debugger.resume(), None);
+        assert_eq!(debugger.stack(), &[vec![2]]);
+    }
+}
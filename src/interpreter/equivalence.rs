@@ -0,0 +1,178 @@
+use super::{execute, ExecutionResult};
+use crate::analyzer::{StackAnalyzer, StackStatus};
+use crate::builder::StructuredScript;
+use std::fmt;
+
+// Stack elements that the inputs are chosen from: zero, small numbers, negative zero, a
+// non-minimal zero, the largest script number and a hash
+const INPUTS: [&[u8]; 8] = [
+    &[],
+    &[0x01],
+    &[0x02],
+    &[0x81],
+    &[0x80],
+    &[0x00],
+    &[0xff, 0xff, 0xff, 0x7f],
+    &[0xab; 32],
+];
+
+// Deeper stack elements that are not varied
+const FILLER: &[u8] = &[0x03];
+
+/// Why [`equivalent`] considers two scripts different.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Inequivalence {
+    /// The analyzer found that the scripts change the stacks differently.
+    StackEffect {
+        left: StackStatus,
+        right: StackStatus,
+    },
+    /// Executing the scripts with this initial stack ends differently: only one of them
+    /// fails, or they leave different stacks.
+    Counterexample {
+        initial_stack: Vec<Vec<u8>>,
+        left: Box<ExecutionResult>,
+        right: Box<ExecutionResult>,
+    },
+}
+
+impl fmt::Display for Inequivalence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inequivalence::StackEffect { left, right } => write!(
+                f,
+                "The scripts change the stack by {} and {} and the alt stack by {} and {} elements",
+                left.stack_changed,
+                right.stack_changed,
+                left.altstack_changed,
+                right.altstack_changed
+            ),
+            Inequivalence::Counterexample {
+                initial_stack,
+                left,
+                right,
+            } => {
+                let describe = |result: &ExecutionResult| match &result.error {
+                    Some(error) => format!("fails with {}", error),
+                    None => format!("leaves {:?}", result.final_stack),
+                };
+                write!(
+                    f,
+                    "With the initial stack {:?}, the first script {} and the second script {}",
+                    initial_stack,
+                    describe(left),
+                    describe(right)
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Inequivalence {}
+
+/// Checks that the scripts end the same way for the same initial stack: either both fail,
+/// or both leave the same main and alt stack. Optimizer passes can be validated like this.
+///
+/// If the analyzer can handle both scripts, scripts that change the stacks by different
+/// numbers of elements are reported right away. Otherwise the scripts are executed with
+/// every initial stack of up to as many elements as they read. The topmost `max_inputs`
+/// elements run through a fixed set of values, such as zero, small and negative numbers,
+/// non-minimal encodings and a hash, and deeper elements are the same for all runs. So
+/// the check is exhaustive only for this set of values, and for scripts that read at most
+/// `max_inputs` elements. Scripts that can't be analyzed are executed with up to
+/// `max_inputs` elements.
+pub fn equivalent(
+    left: &StructuredScript,
+    right: &StructuredScript,
+    max_inputs: usize,
+) -> Result<(), Inequivalence> {
+    let analyze = |script: &StructuredScript| StackAnalyzer::new().try_analyze(script).ok();
+    let num_inputs = match (analyze(left), analyze(right)) {
+        (Some(left), Some(right)) => {
+            if left.stack_changed != right.stack_changed
+                || left.altstack_changed != right.altstack_changed
+            {
+                return Err(Inequivalence::StackEffect { left, right });
+            }
+            left.deepest_stack_accessed
+                .min(right.deepest_stack_accessed)
+                .unsigned_abs() as usize
+        }
+        _ => max_inputs,
+    };
+
+    for height in 0..=num_inputs {
+        let num_varied = height.min(max_inputs);
+        let num_cases = INPUTS.len().pow(num_varied as u32);
+        for case in 0..num_cases {
+            let mut initial_stack = vec![FILLER.to_vec(); height - num_varied];
+            let mut case = case;
+            for _ in 0..num_varied {
+                initial_stack.push(INPUTS[case % INPUTS.len()].to_vec());
+                case /= INPUTS.len();
+            }
+            let left_result = execute(left, initial_stack.clone());
+            let right_result = execute(right, initial_stack.clone());
+            let same = match (&left_result.error, &right_result.error) {
+                (Some(_), Some(_)) => true,
+                (None, None) => {
+                    left_result.final_stack == right_result.final_stack
+                        && left_result.final_altstack == right_result.final_altstack
+                }
+                _ => false,
+            };
+            if !same {
+                return Err(Inequivalence::Counterexample {
+                    initial_stack,
+                    left: Box::new(left_result),
+                    right: Box::new(right_result),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+
+    #[test]
+    fn find_counterexamples() {
+        assert_eq!(
+            equivalent(&script! { OP_ADD }, &script! { OP_SWAP OP_ADD }, 2),
+            Ok(())
+        );
+        assert_eq!(
+            equivalent(&script! { OP_NOT OP_NOT }, &script! { OP_0NOTEQUAL }, 2),
+            Ok(())
+        );
+        assert_eq!(
+            equivalent(&script! { OP_DUP OP_ADD }, &script! { OP_DUP }, 2),
+            Err(Inequivalence::StackEffect {
+                left: script! { OP_DUP OP_ADD }.analyze_stack(),
+                right: script! { OP_DUP }.analyze_stack(),
+            })
+        );
+
+        // Differ for a single input, the intermediate sum overflows, and `OP_DUP OP_DROP`
+        // fails on an empty stack
+        let counterexample = |left, right| match equivalent(&left, &right, 2) {
+            Err(Inequivalence::Counterexample { initial_stack, .. }) => initial_stack,
+            result => panic!("Unexpected result {:?}", result),
+        };
+        assert_eq!(
+            counterexample(script! { OP_ABS }, script! { OP_NEGATE OP_ABS OP_NEGATE }),
+            vec![vec![0x01]]
+        );
+        assert_eq!(
+            counterexample(script! { OP_1ADD OP_1ADD }, script! { 2 OP_ADD }),
+            vec![vec![0xff, 0xff, 0xff, 0x7f]]
+        );
+        assert_eq!(
+            counterexample(script! { OP_DUP OP_DROP }, script! {}),
+            Vec::<Vec<u8>>::new()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use super::{ExecutionResult, Interpreter};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+
+/// The largest stacks while a script was executed, including the scripts it calls, see
+/// [`Interpreter::finish_with_profile`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockProfile {
+    pub max_stack_depth: usize,
+    pub max_altstack_depth: usize,
+    /// Largest number of elements on the main and alt stack combined, which consensus
+    /// limits to [`crate::chunker::MAX_STACK_SIZE`]
+    pub max_combined_depth: usize,
+    /// Size of the largest element on top of the main stack after an instruction, which
+    /// consensus limits to 520 bytes
+    pub max_element_size: usize,
+}
+
+/// The stack usage of every executed script by name. The profiles of all calls of a script
+/// are merged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    blocks: BTreeMap<String, BlockProfile>,
+}
+
+impl Profile {
+    pub fn get(&self, name: &str) -> Option<&BlockProfile> {
+        self.blocks.get(name)
+    }
+
+    /// Iterates over the executed scripts, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &BlockProfile)> {
+        self.blocks
+            .iter()
+            .map(|(name, profile)| (name.as_str(), profile))
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    /// Executes the remaining instructions like [`Self::finish`] and records the largest
+    /// stacks per script. The stacks after an instruction count for the script that
+    /// contains it and every script that calls this script. Scripts are named by the last
+    /// segment of their `debug_identifier`.
+    pub fn finish_with_profile(mut self) -> (ExecutionResult, Profile) {
+        let mut names: Vec<&str> = vec![];
+        let mut indices = HashMap::new();
+        let call_profiles: Vec<usize> = self
+            .calls
+            .iter()
+            .map(|call| {
+                *indices.entry(call.block.name()).or_insert_with(|| {
+                    names.push(call.block.name());
+                    names.len() - 1
+                })
+            })
+            .collect();
+        let mut profiles = vec![BlockProfile::default(); names.len()];
+
+        // Calls that contain the last executed step, from the outermost to the innermost
+        let mut active: Vec<usize> = vec![];
+        let mut next_call = 0;
+        loop {
+            let index = self.next_step;
+            if !self.step() {
+                break;
+            }
+            active.retain(|call| self.calls[*call].steps.end > index);
+            while next_call < self.calls.len() && self.calls[next_call].steps.start <= index {
+                if self.calls[next_call].steps.end > index {
+                    active.push(next_call);
+                }
+                next_call += 1;
+            }
+            let element_size = self.stack.last().map_or(0, Vec::len);
+            for call in &active {
+                let profile = &mut profiles[call_profiles[*call]];
+                profile.max_stack_depth = max(profile.max_stack_depth, self.stack.len());
+                profile.max_altstack_depth = max(profile.max_altstack_depth, self.altstack.len());
+                profile.max_combined_depth = max(
+                    profile.max_combined_depth,
+                    self.stack.len() + self.altstack.len(),
+                );
+                profile.max_element_size = max(profile.max_element_size, element_size);
+            }
+        }
+
+        let blocks = names
+            .into_iter()
+            .map(str::to_string)
+            .zip(profiles)
+            .collect();
+        (self.finish(), Profile { blocks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Interpreter;
+    use crate::script;
+
+    #[test]
+    fn profile_blocks() {
+        let script = script! {
+            def widen {
+                OP_SHA256 OP_DUP OP_TOALTSTACK
+            }
+
+            def unwind {
+                OP_FROMALTSTACK OP_DROP
+            }
+
+            def run {
+                widen widen unwind unwind
+            }
+
+            OP_1 run OP_DROP OP_1
+        };
+
+        let (result, profile) = Interpreter::new(&script, vec![]).finish_with_profile();
+        assert!(result.success());
+
+        let widen = profile.get("widen").unwrap();
+        assert_eq!(widen.max_stack_depth, 2);
+        assert_eq!(widen.max_altstack_depth, 2);
+        assert_eq!(widen.max_combined_depth, 3);
+        assert_eq!(widen.max_element_size, 32);
+
+        let unwind = profile.get("unwind").unwrap();
+        assert_eq!(unwind.max_altstack_depth, 1);
+        assert_eq!(unwind.max_combined_depth, 3);
+
+        let run = profile.get("run").unwrap();
+        assert_eq!(run.max_combined_depth, 3);
+        assert_eq!(profile.iter().count(), 4);
+    }
+}
@@ -0,0 +1,167 @@
+use super::{ExecutionResult, Interpreter};
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::Instruction;
+use std::collections::HashMap;
+
+// The stacks after an instruction, stored as the difference to the stacks after the
+// previously recorded instruction
+struct TraceEntry {
+    offset: usize,
+    opcode: Option<Opcode>,
+    block: usize,
+    kept: usize,
+    pushed: Vec<Vec<u8>>,
+    altstack_kept: usize,
+    altstack_pushed: Vec<Vec<u8>>,
+}
+
+/// The stacks after every recorded instruction of an execution, see
+/// [`Interpreter::finish_with_trace`]. Only the elements that change between two
+/// instructions are stored.
+#[derive(Default)]
+pub struct Trace {
+    // Debug identifiers of the scripts that contain the recorded instructions
+    blocks: Vec<String>,
+    entries: Vec<TraceEntry>,
+}
+
+/// A recorded instruction and the stacks after it, from the bottom to the top element.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep<'a> {
+    /// Position of the instruction in the compiled script
+    pub offset: usize,
+    /// The opcode of the instruction, `None` for pushes of data
+    pub opcode: Option<Opcode>,
+    /// Debug identifier of the script that contains the instruction
+    pub block: &'a str,
+    pub stack: Vec<Vec<u8>>,
+    pub altstack: Vec<Vec<u8>>,
+}
+
+impl Trace {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over the recorded instructions in the order of their execution.
+    pub fn steps(&self) -> impl Iterator<Item = TraceStep<'_>> {
+        let mut stack: Vec<Vec<u8>> = vec![];
+        let mut altstack: Vec<Vec<u8>> = vec![];
+        self.entries.iter().map(move |entry| {
+            stack.truncate(entry.kept);
+            stack.extend_from_slice(&entry.pushed);
+            altstack.truncate(entry.altstack_kept);
+            altstack.extend_from_slice(&entry.altstack_pushed);
+            TraceStep {
+                offset: entry.offset,
+                opcode: entry.opcode,
+                block: &self.blocks[entry.block],
+                stack: stack.clone(),
+                altstack: altstack.clone(),
+            }
+        })
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    /// Executes the remaining instructions like [`Self::finish`] and records the stacks
+    /// after every instruction of a script named in `blocks` or of a script it calls. All
+    /// instructions are recorded if `blocks` is empty. Scripts are named by the last
+    /// segment of their `debug_identifier`.
+    pub fn finish_with_trace(mut self, blocks: &[&str]) -> (ExecutionResult, Trace) {
+        let mut recorded = vec![blocks.is_empty(); self.steps.len()];
+        for call in &self.calls {
+            if blocks.contains(&call.block.name()) {
+                recorded[call.steps.clone()].fill(true);
+            }
+        }
+
+        let mut trace = Trace::default();
+        let mut block_indices = HashMap::new();
+        let mut stack: Vec<Vec<u8>> = vec![];
+        let mut altstack: Vec<Vec<u8>> = vec![];
+        loop {
+            let index = self.next_step;
+            if !self.step() {
+                break;
+            }
+            if !recorded[index] {
+                continue;
+            }
+            let step = &self.steps[index];
+            let block = *block_indices
+                .entry(step.block as *const _)
+                .or_insert_with(|| {
+                    trace.blocks.push(step.block.debug_identifier.clone());
+                    trace.blocks.len() - 1
+                });
+            let (kept, pushed) = difference(&mut stack, &self.stack);
+            let (altstack_kept, altstack_pushed) = difference(&mut altstack, &self.altstack);
+            trace.entries.push(TraceEntry {
+                offset: step.offset,
+                opcode: match step.instruction {
+                    Ok(Instruction::Op(opcode)) => Some(opcode),
+                    _ => None,
+                },
+                block,
+                kept,
+                pushed,
+                altstack_kept,
+                altstack_pushed,
+            });
+        }
+        (self.finish(), trace)
+    }
+}
+
+// Returns the number of elements that `stack` shares with `previous` from the bottom and
+// the elements above them, and updates `previous` to `stack`
+fn difference(previous: &mut Vec<Vec<u8>>, stack: &[Vec<u8>]) -> (usize, Vec<Vec<u8>>) {
+    let kept = previous
+        .iter()
+        .zip(stack)
+        .take_while(|(previous, element)| previous == element)
+        .count();
+    let pushed = stack[kept..].to_vec();
+    previous.truncate(kept);
+    previous.extend_from_slice(&pushed);
+    (kept, pushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Interpreter;
+    use crate::script;
+    use bitcoin::blockdata::opcodes::all::{OP_ADD, OP_DUP};
+
+    #[test]
+    fn trace_blocks() {
+        let script = script! {
+            def double {
+                OP_DUP OP_ADD
+            }
+
+            OP_1 double OP_1ADD double
+        };
+
+        let (result, trace) = Interpreter::new(&script, vec![]).finish_with_trace(&[]);
+        assert_eq!(result.final_stack, vec![vec![6]]);
+        assert_eq!(trace.len(), 6);
+
+        let (_, trace) = Interpreter::new(&script, vec![]).finish_with_trace(&["double"]);
+        let steps: Vec<_> = trace.steps().collect();
+        assert_eq!(steps.len(), 4);
+        assert!(steps.iter().all(|step| step.block.ends_with("double")));
+        assert_eq!(steps[0].opcode, Some(OP_DUP));
+        assert_eq!(steps[0].stack, vec![vec![1], vec![1]]);
+        assert_eq!(steps[1].opcode, Some(OP_ADD));
+        assert_eq!(steps[1].stack, vec![vec![2]]);
+        assert_eq!(steps[2].offset, 4);
+        assert_eq!(steps[2].stack, vec![vec![3], vec![3]]);
+        assert_eq!(steps[3].stack, vec![vec![6]]);
+    }
+}
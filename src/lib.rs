@@ -1,5 +1,43 @@
+// Allows using `script!` inside this crate
+extern crate self as bitcoin_script;
+
+pub mod analyzer;
 pub mod builder;
+pub mod chunker;
+pub mod generator;
+pub mod interpreter;
+pub mod optimizer;
+pub mod printer;
 
+pub use crate::analyzer::{
+    find_non_minimal_pushes, find_oversized_pushes, BranchStatus, NonMinimalPush, OversizedPush,
+    StackAnalysisError, StackAnalyzer, StackLabels, StackStatus, StackUnderflow,
+};
 pub use crate::builder::StructuredScript as Script;
-pub use script_macro::script;
+pub use crate::builder::{Pushable, ToStructuredScript};
+pub use crate::chunker::{
+    chunk_fingerprint, chunks_to_taproot, Balanced, Chunk, ChunkBorder, ChunkInterface,
+    ChunkSource, ChunkStats, ChunkStrategy, Chunker, ChunkerError, ChunkerState,
+    CompositionMismatch, DpOptimal, Greedy, Manifest, ManifestEntry, Rebalanced, TaprootChunks,
+};
+pub use crate::generator::ScriptGenerator;
+#[cfg(feature = "proptest")]
+pub use crate::generator::{structured_script, ScriptParams};
+#[cfg(feature = "bitcoinconsensus")]
+pub use crate::interpreter::verify_with_consensus;
+pub use crate::interpreter::{
+    assert_chunked_execution, equivalent, execute, execute_chunks, BlockProfile, Breakpoint,
+    Debugger, ExecutionError, ExecutionFailure, ExecutionResult, Inequivalence, Interpreter,
+    Profile, Trace, TraceStep,
+};
+pub use crate::optimizer::{
+    ConstantFolding, MinimalPushes, OptimizationStats, Optimizer, OptimizerPipeline, Pass,
+    PassReport, Peephole, PickRollFolding, PushReuse, StackScheduling,
+};
+pub use crate::printer::TreePrinter;
+pub use script_macro::{include_script, script};
 pub use stdext::function_name;
+
+/// Upper bound for the iterations of a `while` loop inside `script!`, which guards against
+/// loops whose condition never becomes false.
+pub const MAX_WHILE_ITERATIONS: usize = 1_000_000;
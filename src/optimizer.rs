@@ -0,0 +1,175 @@
+//! Passes that shrink a script by rewriting its instructions before it is compiled.
+//!
+//! A pass rewrites every `ScriptBuf` block of a script and of the scripts it calls on its
+//! own, so instructions never move between blocks and the names, metadata and chunk hints
+//! of the scripts are kept. Called scripts are rewritten once, no matter how often they
+//! are called. Rewrites that would make a block larger are discarded, unless the pass allows
+//! it with [`Pass::max_growth`].
+//!
+//! The passes preserve the result of scripts that succeed. Instructions that can only fail,
+//! like an `OP_DUP` on an empty stack followed by `OP_DROP`, may be removed.
+
+mod constant_fold;
+mod minimal_push;
+mod peephole;
+mod pick_roll_fold;
+mod pipeline;
+mod push_reuse;
+mod stack_schedule;
+
+pub use self::constant_fold::ConstantFolding;
+pub use self::minimal_push::MinimalPushes;
+pub use self::peephole::Peephole;
+pub use self::pick_roll_fold::PickRollFolding;
+pub use self::pipeline::{OptimizerPipeline, PassReport};
+pub use self::push_reuse::PushReuse;
+pub use self::stack_schedule::StackScheduling;
+
+use crate::builder::{Block, StructuredScript};
+use bitcoin::blockdata::script::{Instruction, Script};
+use bitcoin::ScriptBuf;
+use std::collections::BTreeMap;
+
+/// A rewrite of the instructions of a block, see [`Optimizer::with_pass`].
+pub trait Pass {
+    /// Returns the rewritten instructions of the block, or `None` if the pass doesn't
+    /// change it. The block only contains complete instructions.
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf>;
+
+    /// Number of bytes by which a rewrite of the block may make it larger, e.g. to make it
+    /// cheaper to execute. Larger rewrites are discarded. By default none.
+    fn max_growth(&self, _block: &Script) -> usize {
+        0
+    }
+
+    /// The name of the pass in the reports of an [`OptimizerPipeline`], by default the
+    /// name of its type.
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+/// The bytes saved by [`Optimizer::optimize`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OptimizationStats {
+    bytes_saved: usize,
+    blocks: BTreeMap<String, usize>,
+}
+
+impl OptimizationStats {
+    /// Number of bytes by which the compiled script shrank, or zero if it grew.
+    pub fn bytes_saved(&self) -> usize {
+        self.bytes_saved
+    }
+
+    /// Number of bytes saved in the instructions of the script with this name, not
+    /// counting the scripts it calls. A script that is called several times counts once.
+    /// Scripts that didn't shrink are not listed.
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.blocks.get(name).copied()
+    }
+
+    /// Iterates over the scripts that were shrunk, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.blocks
+            .iter()
+            .map(|(name, saved)| (name.as_str(), *saved))
+    }
+}
+
+/// Runs passes over a script until none of them changes it anymore.
+#[derive(Default)]
+pub struct Optimizer {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a pass, which runs after the passes that were added before.
+    pub fn with_pass(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Rewrites the script and the scripts it calls in place.
+    pub fn optimize(&self, script: &mut StructuredScript) -> OptimizationStats {
+        run_passes(&self.passes, script)
+    }
+}
+
+// Runs the passes together over every block of the script until none of them changes it
+fn run_passes(passes: &[Box<dyn Pass>], script: &mut StructuredScript) -> OptimizationStats {
+    let len = script.len();
+    let mut stats = OptimizationStats::default();
+    optimize_script(passes, script, &mut stats);
+    stats.bytes_saved = len.saturating_sub(script.len());
+    stats
+}
+
+// Returns whether the script or any script it calls changed
+fn optimize_script(
+    passes: &[Box<dyn Pass>],
+    script: &mut StructuredScript,
+    stats: &mut OptimizationStats,
+) -> bool {
+    // Signed, because passes may make blocks larger
+    let mut saved: isize = 0;
+    let mut changed = false;
+    for block in &mut script.blocks {
+        if let Block::Script(script_buf) = block {
+            while let Some(rewritten) = rewrite(passes, script_buf) {
+                saved += script_buf.len() as isize - rewritten.len() as isize;
+                *script_buf = rewritten;
+                changed = true;
+            }
+        }
+    }
+    script
+        .blocks
+        .retain(|block| !matches!(block, Block::Script(script_buf) if script_buf.is_empty()));
+    if saved > 0 {
+        *stats.blocks.entry(script.name().to_string()).or_default() += saved as usize;
+    }
+    // Also updates the size of the script
+    changed
+        | script
+            .update_called_scripts(|called_script| optimize_script(passes, called_script, stats))
+}
+
+// Yields the instructions of a block together with their encodings, so that rewrites keep
+// the encoding of the instructions they don't change. Panics like `MinimalPushes` if the
+// block is cut off in the middle of a push.
+fn instructions_with_bytes(block: &Script) -> impl Iterator<Item = (Instruction<'_>, &[u8])> {
+    let bytes = block.as_bytes();
+    let mut indices = block.instruction_indices().peekable();
+    std::iter::from_fn(move || {
+        let (start, instruction) = indices
+            .next()?
+            .expect("Blocks only contain complete instructions");
+        let end = match indices.peek() {
+            Some(Ok((end, _))) => *end,
+            _ => bytes.len(),
+        };
+        Some((instruction, &bytes[start..end]))
+    })
+}
+
+// Runs the passes once and returns the block if any of them changed it
+fn rewrite(passes: &[Box<dyn Pass>], block: &Script) -> Option<ScriptBuf> {
+    let mut result: Option<ScriptBuf> = None;
+    for pass in passes {
+        let current = result.as_deref().unwrap_or(block);
+        if let Some(rewritten) = pass.rewrite(current) {
+            if rewritten.len() <= current.len() + pass.max_growth(current)
+                && rewritten.as_script() != current
+            {
+                result = Some(rewritten);
+            }
+        }
+    }
+    result
+}
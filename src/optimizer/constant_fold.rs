@@ -0,0 +1,165 @@
+use super::{instructions_with_bytes, Pass};
+use crate::analyzer::pushnum_value;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::{read_scriptint, Builder, Instruction, Script};
+use bitcoin::ScriptBuf;
+use std::borrow::Cow;
+
+/// Evaluates arithmetic on constants, e.g. `2 3 OP_ADD` becomes `5`. Folds the numeric
+/// opcodes from `OP_1ADD` to `OP_WITHIN` whose operands are all pushed right before them,
+/// and only if the operands are valid script numbers: at most 4 bytes and minimally
+/// encoded. Other operands make the script fail, so they are left for the interpreter.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConstantFolding;
+
+// An instruction with its encoding and, for pushes of valid script numbers, its value
+struct Item<'a> {
+    opcode: Option<Opcode>,
+    bytes: Cow<'a, [u8]>,
+    value: Option<i64>,
+}
+
+// Largest magnitude of a script number of 4 bytes
+const MAX_SCRIPT_NUM: i64 = 0x7fff_ffff;
+
+impl Pass for ConstantFolding {
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf> {
+        let mut items: Vec<Item> = vec![];
+        for (instruction, encoding) in instructions_with_bytes(block) {
+            let (opcode, value) = match instruction {
+                Instruction::PushBytes(data) => (None, read_scriptint(data.as_bytes()).ok()),
+                Instruction::Op(opcode) => (Some(opcode), pushnum_value(opcode)),
+            };
+            items.push(Item {
+                opcode,
+                bytes: Cow::Borrowed(encoding),
+                value,
+            });
+        }
+
+        let mut changed = false;
+        let mut index = 0;
+        while index < items.len() {
+            let folded = items[index].opcode.and_then(|opcode| {
+                let arity = arity(opcode)?;
+                let operands = &items[index.checked_sub(arity)?..index];
+                let values: Option<Vec<i64>> = operands.iter().map(|item| item.value).collect();
+                Some((arity, evaluate(opcode, &values?)))
+            });
+            if let Some((arity, value)) = folded {
+                let result = Item {
+                    opcode: None,
+                    bytes: Cow::Owned(Builder::new().push_int(value).into_bytes()),
+                    // Larger results are not valid operands
+                    value: Some(value).filter(|value| value.abs() <= MAX_SCRIPT_NUM),
+                };
+                index -= arity;
+                items.splice(index..=index + arity, [result]);
+                changed = true;
+            }
+            index += 1;
+        }
+
+        changed.then(|| {
+            ScriptBuf::from_bytes(
+                items
+                    .iter()
+                    .flat_map(|item| item.bytes.iter())
+                    .copied()
+                    .collect(),
+            )
+        })
+    }
+}
+
+fn arity(opcode: Opcode) -> Option<usize> {
+    match opcode {
+        OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => Some(1),
+        OP_ADD
+        | OP_SUB
+        | OP_BOOLAND
+        | OP_BOOLOR
+        | OP_NUMEQUAL
+        | OP_NUMNOTEQUAL
+        | OP_LESSTHAN
+        | OP_GREATERTHAN
+        | OP_LESSTHANOREQUAL
+        | OP_GREATERTHANOREQUAL
+        | OP_MIN
+        | OP_MAX => Some(2),
+        OP_WITHIN => Some(3),
+        _ => None,
+    }
+}
+
+fn evaluate(opcode: Opcode, values: &[i64]) -> i64 {
+    let flag = |condition: bool| condition as i64;
+    match (opcode, values) {
+        (OP_1ADD, [a]) => a + 1,
+        (OP_1SUB, [a]) => a - 1,
+        (OP_NEGATE, [a]) => -a,
+        (OP_ABS, [a]) => a.abs(),
+        (OP_NOT, [a]) => flag(*a == 0),
+        (OP_0NOTEQUAL, [a]) => flag(*a != 0),
+        (OP_ADD, [a, b]) => a + b,
+        (OP_SUB, [a, b]) => a - b,
+        (OP_BOOLAND, [a, b]) => flag(*a != 0 && *b != 0),
+        (OP_BOOLOR, [a, b]) => flag(*a != 0 || *b != 0),
+        (OP_NUMEQUAL, [a, b]) => flag(a == b),
+        (OP_NUMNOTEQUAL, [a, b]) => flag(a != b),
+        (OP_LESSTHAN, [a, b]) => flag(a < b),
+        (OP_GREATERTHAN, [a, b]) => flag(a > b),
+        (OP_LESSTHANOREQUAL, [a, b]) => flag(a <= b),
+        (OP_GREATERTHANOREQUAL, [a, b]) => flag(a >= b),
+        (OP_MIN, [a, b]) => *a.min(b),
+        (OP_MAX, [a, b]) => *a.max(b),
+        (OP_WITHIN, [x, min, max]) => flag(min <= x && x < max),
+        _ => unreachable!("{} is not folded", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::equivalent;
+    use crate::optimizer::Optimizer;
+    use crate::script;
+
+    fn fold(script: crate::builder::StructuredScript) -> ScriptBuf {
+        let mut folded = script.clone();
+        Optimizer::new()
+            .with_pass(ConstantFolding)
+            .optimize(&mut folded);
+        assert_eq!(equivalent(&folded, &script, 2), Ok(()));
+        folded.compile()
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        assert_eq!(fold(script! { 2 3 OP_ADD }), script! { 5 }.compile());
+        assert_eq!(
+            fold(script! { 1000 1 OP_SUB OP_NEGATE 5 OP_MAX 0 1000 OP_WITHIN }),
+            script! { 1 }.compile()
+        );
+        assert_eq!(
+            fold(script! { OP_DUP 2 3 OP_ADD OP_ADD 4 OP_LESSTHAN }),
+            script! { OP_DUP 5 OP_ADD 4 OP_LESSTHAN }.compile()
+        );
+    }
+
+    #[test]
+    fn respect_script_num_limits() {
+        // The sum overflows 4 bytes, so it can't be added to
+        let max: i64 = 0x7fff_ffff;
+        assert_eq!(
+            fold(script! { {max} {max} OP_ADD 1 OP_ADD }),
+            script! { {2 * max} 1 OP_ADD }.compile()
+        );
+        // Operands of 5 bytes and non-minimal encodings are left to fail
+        let overflow = script! { {max + 1} 1 OP_ADD }.compile();
+        assert_eq!(ConstantFolding.rewrite(&overflow), None);
+        let non_minimal = ScriptBuf::from_bytes(vec![0x02, 0x05, 0x00, 0x51, 0x93]);
+        assert_eq!(ConstantFolding.rewrite(&non_minimal), None);
+    }
+}
@@ -0,0 +1,79 @@
+use super::Pass;
+use bitcoin::blockdata::opcodes::all::{OP_PUSHNUM_1, OP_PUSHNUM_NEG1};
+use bitcoin::blockdata::script::{Instruction, PushBytes, Script};
+use bitcoin::ScriptBuf;
+
+/// Rewrites every data push to its shortest encoding, which the `MINIMALDATA` standardness
+/// rule requires: `OP_0`, `OP_1` to `OP_16` and `OP_1NEGATE` for the data they push, and
+/// otherwise the smallest `OP_PUSHBYTES_N` or `OP_PUSHDATA`. See also
+/// [`crate::analyzer::find_non_minimal_pushes`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MinimalPushes;
+
+impl Pass for MinimalPushes {
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf> {
+        let mut rewritten = ScriptBuf::with_capacity(block.len());
+        for instruction in block.instructions() {
+            match instruction.expect("Blocks only contain complete instructions") {
+                Instruction::Op(opcode) => rewritten.push_opcode(opcode),
+                Instruction::PushBytes(data) => push_minimal(&mut rewritten, data),
+            }
+        }
+        (rewritten.as_script() != block).then_some(rewritten)
+    }
+}
+
+fn push_minimal(script: &mut ScriptBuf, data: &PushBytes) {
+    match data.as_bytes() {
+        [n @ 1..=16] => script.push_opcode((OP_PUSHNUM_1.to_u8() + n - 1).into()),
+        [0x81] => script.push_opcode(OP_PUSHNUM_NEG1),
+        _ => script.push_slice(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::find_non_minimal_pushes;
+    use crate::builder::StructuredScript;
+    use crate::interpreter::execute;
+    use crate::optimizer::Optimizer;
+    use crate::script;
+    use bitcoin::blockdata::opcodes::all::OP_2DROP;
+
+    #[test]
+    fn minimize_pushes() {
+        let non_minimal = ScriptBuf::from_bytes(vec![
+            // OP_PUSHDATA1 of 3 bytes
+            0x4c, 0x03, 0xaa, 0xbb, 0xcc, // 5 and -1 as data pushes
+            0x01, 0x05, 0x01, 0x81, // OP_PUSHDATA2 of nothing
+            0x4d, 0x00, 0x00,
+        ]);
+        let gadget = StructuredScript::new("gadget")
+            .push_script(non_minimal)
+            .push_opcode(OP_2DROP)
+            .push_opcode(OP_2DROP);
+        let mut script = script! {
+            OP_1 { gadget.clone() } { gadget }
+        };
+        assert_eq!(find_non_minimal_pushes(&script).len(), 8);
+        let result = execute(&script, vec![]);
+        assert!(result.success());
+
+        let stats = Optimizer::new()
+            .with_pass(MinimalPushes)
+            .optimize(&mut script);
+        assert_eq!(stats.get("gadget"), Some(1 + 1 + 1 + 2));
+        assert_eq!(stats.bytes_saved(), 10);
+        assert!(find_non_minimal_pushes(&script).is_empty());
+        assert_eq!(
+            script.clone().compile().as_bytes(),
+            &[
+                0x51, 0x03, 0xaa, 0xbb, 0xcc, 0x55, 0x4f, 0x00, 0x6d, 0x6d, 0x03, 0xaa, 0xbb, 0xcc,
+                0x55, 0x4f, 0x00, 0x6d, 0x6d
+            ]
+        );
+        assert_eq!(execute(&script, vec![]).final_stack, result.final_stack);
+        assert_eq!(MinimalPushes.rewrite(&script.compile()), None);
+    }
+}
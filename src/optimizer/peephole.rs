@@ -0,0 +1,236 @@
+use super::{instructions_with_bytes, Pass};
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::{Instruction, Script};
+use bitcoin::ScriptBuf;
+
+// Sequences that leave the stacks as they were
+const DEFAULT_RULES: [&[Opcode]; 6] = [
+    &[OP_DUP, OP_DROP],
+    &[OP_2DUP, OP_2DROP],
+    &[OP_SWAP, OP_SWAP],
+    &[OP_2SWAP, OP_2SWAP],
+    &[OP_TOALTSTACK, OP_FROMALTSTACK],
+    &[OP_FROMALTSTACK, OP_TOALTSTACK],
+];
+
+// Sequences and shorter ones with the same effect, also if they fail
+const STRENGTH_REDUCTIONS: [(&[Opcode], &[Opcode]); 11] = [
+    (&[OP_DROP, OP_DROP], &[OP_2DROP]),
+    (&[OP_SWAP, OP_DROP], &[OP_NIP]),
+    (&[OP_NIP, OP_DROP], &[OP_2DROP]),
+    (&[OP_OVER, OP_OVER], &[OP_2DUP]),
+    (&[OP_PUSHNUM_1, OP_ADD], &[OP_1ADD]),
+    (&[OP_PUSHNUM_1, OP_SUB], &[OP_1SUB]),
+    (&[OP_NOT, OP_NOT], &[OP_0NOTEQUAL]),
+    (&[OP_EQUAL, OP_VERIFY], &[OP_EQUALVERIFY]),
+    (&[OP_NUMEQUAL, OP_VERIFY], &[OP_NUMEQUALVERIFY]),
+    (&[OP_CHECKSIG, OP_VERIFY], &[OP_CHECKSIGVERIFY]),
+    (&[OP_PUSHNUM_1, OP_PICK], &[OP_OVER]),
+];
+
+/// Replaces short sequences of opcodes with shorter ones. By default it removes sequences
+/// that don't change the stacks like `OP_DUP OP_DROP`, `OP_SWAP OP_SWAP` and
+/// `OP_TOALTSTACK OP_FROMALTSTACK`, and applies the rewrites of
+/// [`Self::strength_reduction`]. Pushes are never part of a pattern.
+#[derive(Clone, Debug)]
+pub struct Peephole {
+    // Patterns and the encoded replacements
+    rules: Vec<(Vec<Opcode>, Vec<u8>)>,
+}
+
+impl Peephole {
+    /// A pass with the default rules.
+    pub fn new() -> Self {
+        let mut pass = Self::strength_reduction();
+        for pattern in DEFAULT_RULES {
+            pass = pass.with_rule(pattern, &[]);
+        }
+        pass
+    }
+
+    /// A pass that only replaces sequences with cheaper opcodes of the same effect, like
+    /// `OP_DROP OP_DROP` with `OP_2DROP`, `OP_SWAP OP_DROP` with `OP_NIP` and
+    /// `OP_EQUAL OP_VERIFY` with `OP_EQUALVERIFY`. Unlike the removal of neutral sequences,
+    /// this keeps the result of scripts that fail.
+    pub fn strength_reduction() -> Self {
+        let mut pass = Self::empty();
+        for (pattern, replacement) in STRENGTH_REDUCTIONS {
+            pass = pass.with_rule(pattern, replacement);
+        }
+        pass
+    }
+
+    /// A pass without rules.
+    pub fn empty() -> Self {
+        Peephole { rules: vec![] }
+    }
+
+    /// Adds a rule that replaces `pattern` with `replacement`, which must be shorter and
+    /// must have the same effect.
+    pub fn with_rule(mut self, pattern: &[Opcode], replacement: &[Opcode]) -> Self {
+        assert!(
+            replacement.len() < pattern.len(),
+            "Replacement is not shorter than the pattern"
+        );
+        let replacement = replacement.iter().map(|opcode| opcode.to_u8()).collect();
+        self.rules.push((pattern.to_vec(), replacement));
+        self
+    }
+
+    fn max_pattern_len(&self) -> usize {
+        self.rules
+            .iter()
+            .map(|(pattern, _)| pattern.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Default for Peephole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pass for Peephole {
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf> {
+        // Opcodes and the bytes of every instruction, so that pushes keep their encoding
+        let mut instructions: Vec<(Option<Opcode>, &[u8])> = vec![];
+        for (instruction, encoding) in instructions_with_bytes(block) {
+            let opcode = match instruction {
+                Instruction::Op(opcode) => Some(opcode),
+                Instruction::PushBytes(_) => None,
+            };
+            instructions.push((opcode, encoding));
+        }
+
+        let mut changed = false;
+        let mut index = 0;
+        while index < instructions.len() {
+            let rule = self.rules.iter().find(|(pattern, _)| {
+                instructions[index..].len() >= pattern.len()
+                    && pattern
+                        .iter()
+                        .zip(&instructions[index..])
+                        .all(|(opcode, (instruction, _))| Some(*opcode) == *instruction)
+            });
+            match rule {
+                Some((pattern, replacement)) => {
+                    let replacement = replacement
+                        .chunks(1)
+                        .map(|byte| (Some(Opcode::from(byte[0])), byte));
+                    instructions.splice(index..index + pattern.len(), replacement);
+                    // The replacement can complete a pattern that starts before it
+                    index = index.saturating_sub(self.max_pattern_len());
+                    changed = true;
+                }
+                None => index += 1,
+            }
+        }
+
+        changed.then(|| {
+            ScriptBuf::from_bytes(
+                instructions
+                    .into_iter()
+                    .flat_map(|(_, bytes)| bytes)
+                    .copied()
+                    .collect(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StructuredScript;
+    use crate::interpreter::{equivalent, execute};
+    use crate::optimizer::Optimizer;
+    use crate::script;
+
+    #[test]
+    #[should_panic(expected = "Blocks only contain complete instructions")]
+    fn rewrite_incomplete_instruction() {
+        // OP_DUP OP_DROP followed by a push of 2 bytes that is cut off
+        let block = ScriptBuf::from_bytes(vec![0x76, 0x75, 0x02, 0xab]);
+        Peephole::new().rewrite(&block);
+    }
+
+    #[test]
+    fn remove_neutral_sequences() {
+        let mut script = script! {
+            def gadget {
+                OP_DUP OP_SWAP OP_SWAP OP_DROP OP_ADD
+            }
+
+            OP_1 OP_TOALTSTACK OP_FROMALTSTACK
+            OP_2 gadget OP_3 gadget OP_4 gadget
+            OP_10 OP_EQUAL
+        };
+        let result = execute(&script, vec![]);
+        assert!(result.success());
+
+        let len = script.len();
+        let stats = Optimizer::new()
+            .with_pass(Peephole::new())
+            .optimize(&mut script);
+        assert_eq!(stats.get("gadget"), Some(4));
+        assert_eq!(stats.iter().count(), 2);
+        assert_eq!(stats.bytes_saved(), 3 * 4 + 2);
+        assert_eq!(script.len(), len - stats.bytes_saved());
+        assert_eq!(script.clone().compile().len(), script.len());
+        assert_eq!(execute(&script, vec![]).final_stack, result.final_stack);
+    }
+
+    #[test]
+    fn strength_reductions_are_equivalent() {
+        let script = |opcodes: &[Opcode]| {
+            opcodes
+                .iter()
+                .fold(StructuredScript::new("rule"), |script, opcode| {
+                    script.push_opcode(*opcode)
+                })
+        };
+        for (pattern, replacement) in STRENGTH_REDUCTIONS {
+            assert_eq!(
+                equivalent(&script(pattern), &script(replacement), 3),
+                Ok(()),
+                "{:?} is not equivalent to {:?}",
+                pattern,
+                replacement
+            );
+        }
+
+        let block =
+            script! { OP_SWAP OP_SWAP OP_DROP OP_DROP OP_1 OP_ADD OP_EQUAL OP_VERIFY }.compile();
+        assert_eq!(
+            Peephole::new().rewrite(&block).unwrap(),
+            script! { OP_2DROP OP_1ADD OP_EQUALVERIFY }.compile()
+        );
+        assert_eq!(
+            Peephole::strength_reduction().rewrite(&block).unwrap(),
+            script! { OP_SWAP OP_2DROP OP_1ADD OP_EQUALVERIFY }.compile()
+        );
+    }
+
+    #[test]
+    fn keep_pushes() {
+        // A non-minimal push of OP_DUP's byte
+        let block = ScriptBuf::from_bytes(vec![0x4c, 0x01, 0x76, 0x75, 0x76, 0x75]);
+        let rewritten = Peephole::new().rewrite(&block).unwrap();
+        assert_eq!(rewritten.as_bytes(), &[0x4c, 0x01, 0x76, 0x75]);
+        assert_eq!(Peephole::new().rewrite(&rewritten), None);
+    }
+
+    #[test]
+    fn custom_rules() {
+        let pass = Peephole::empty().with_rule(&[OP_PUSHNUM_1, OP_ADD], &[OP_1ADD]);
+        let block = script! { OP_DUP OP_1 OP_ADD OP_DUP OP_DROP }.compile();
+        let rewritten = pass.rewrite(&block).unwrap();
+        assert_eq!(
+            rewritten,
+            script! { OP_DUP OP_1ADD OP_DUP OP_DROP }.compile()
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use super::{instructions_with_bytes, Pass};
+use crate::analyzer::pushnum_value;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::{read_scriptint, Instruction, Script};
+use bitcoin::ScriptBuf;
+use std::borrow::Cow;
+
+/// Removes `OP_PICK` and `OP_ROLL` of elements that the block pushes as constants right
+/// before them, e.g. `<a> <b> 1 OP_ROLL` becomes `<b> <a>`, and `<a> <b> 1 OP_PICK` becomes
+/// `<a> <b> <a>` if the copy is not larger than the access. Shallow accesses become
+/// `OP_DUP`, `OP_OVER`, `OP_SWAP` and `OP_ROT`, and `0 OP_ROLL` is removed.
+///
+/// Only depths given by a constant right before the opcode are handled. Accesses below the
+/// pushes are kept, because the accessed element must end up above them, which takes more
+/// instructions and may make the block larger, see [`StackScheduling`].
+///
+/// [`StackScheduling`]: super::StackScheduling
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PickRollFolding;
+
+// An instruction with its encoding and, for constants, its value as script number
+#[derive(Clone)]
+struct Item<'a> {
+    opcode: Option<Opcode>,
+    bytes: Cow<'a, [u8]>,
+    is_push: bool,
+    value: Option<i64>,
+}
+
+// The single opcode that accesses the element at `depth`, or none for `0 OP_ROLL`
+fn short_access(opcode: Opcode, depth: i64) -> Option<Vec<Item<'static>>> {
+    let opcode = match (opcode, depth) {
+        (OP_PICK, 0) => OP_DUP,
+        (OP_PICK, 1) => OP_OVER,
+        (OP_ROLL, 0) => return Some(vec![]),
+        (OP_ROLL, 1) => OP_SWAP,
+        (OP_ROLL, 2) => OP_ROT,
+        _ => return None,
+    };
+    Some(vec![Item {
+        opcode: Some(opcode),
+        bytes: Cow::Owned(vec![opcode.to_u8()]),
+        is_push: false,
+        value: None,
+    }])
+}
+
+impl Pass for PickRollFolding {
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf> {
+        let mut items: Vec<Item> = vec![];
+        for (instruction, encoding) in instructions_with_bytes(block) {
+            let item = match instruction {
+                Instruction::PushBytes(data) => Item {
+                    opcode: None,
+                    bytes: Cow::Borrowed(encoding),
+                    is_push: true,
+                    value: read_scriptint(data.as_bytes()).ok(),
+                },
+                Instruction::Op(opcode) => Item {
+                    opcode: Some(opcode),
+                    bytes: Cow::Borrowed(encoding),
+                    is_push: pushnum_value(opcode).is_some(),
+                    value: pushnum_value(opcode),
+                },
+            };
+            items.push(item);
+        }
+
+        let mut changed = false;
+        let mut index = 0;
+        while index < items.len() {
+            let opcode = match items[index].opcode {
+                Some(opcode @ (OP_PICK | OP_ROLL)) if index > 0 => opcode,
+                _ => {
+                    index += 1;
+                    continue;
+                }
+            };
+            let depth = match items[index - 1].value {
+                Some(depth) if depth >= 0 => depth,
+                _ => {
+                    index += 1;
+                    continue;
+                }
+            };
+            // The constants that are pushed right before the depth
+            let start = items[..index - 1]
+                .iter()
+                .rposition(|item| !item.is_push)
+                .map_or(0, |position| position + 1);
+            let pushes = &items[start..index - 1];
+
+            let replacement = if depth < pushes.len() as i64 {
+                let target = pushes.len() - 1 - depth as usize;
+                let mut pushes = pushes.to_vec();
+                if opcode == OP_ROLL {
+                    let element = pushes.remove(target);
+                    pushes.push(element);
+                    Some((start, pushes))
+                } else if pushes[target].bytes.len() <= items[index - 1].bytes.len() + 1 {
+                    pushes.push(pushes[target].clone());
+                    Some((start, pushes))
+                } else {
+                    short_access(opcode, depth).map(|access| (index - 1, access))
+                }
+            } else {
+                short_access(opcode, depth).map(|access| (index - 1, access))
+            };
+            match replacement {
+                Some((from, replacement)) => {
+                    items.splice(from..=index, replacement);
+                    changed = true;
+                    index = from;
+                }
+                None => index += 1,
+            }
+        }
+
+        changed.then(|| {
+            ScriptBuf::from_bytes(
+                items
+                    .iter()
+                    .flat_map(|item| item.bytes.iter())
+                    .copied()
+                    .collect(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StructuredScript;
+    use crate::interpreter::equivalent;
+    use crate::optimizer::Optimizer;
+    use crate::script;
+
+    fn fold(script: StructuredScript) -> ScriptBuf {
+        let mut folded = script.clone();
+        Optimizer::new()
+            .with_pass(PickRollFolding)
+            .optimize(&mut folded);
+        assert_eq!(equivalent(&folded, &script, 2), Ok(()));
+        folded.compile()
+    }
+
+    #[test]
+    fn remove_access_of_constants() {
+        let hash = [0xab; 32];
+        assert_eq!(
+            fold(script! { {hash} 10 11 2 OP_ROLL 1 OP_PICK 0 OP_ROLL OP_ADD }),
+            script! { 10 11 {hash} 11 OP_ADD }.compile()
+        );
+        // The hash is larger than picking it
+        assert_eq!(
+            fold(script! { {hash} 10 1 OP_PICK 3 OP_ROLL }),
+            script! { {hash} 10 OP_OVER 3 OP_ROLL }.compile()
+        );
+    }
+
+    #[test]
+    fn shorten_shallow_access() {
+        assert_eq!(
+            fold(script! { 0 OP_PICK 1 OP_PICK 2 OP_PICK 1 OP_ROLL 2 OP_ROLL 3 OP_ROLL }),
+            script! { OP_DUP OP_OVER 2 OP_PICK OP_SWAP OP_ROT 3 OP_ROLL }.compile()
+        );
+        // Accesses below the pushes and of computed depths are kept
+        let block = script! { 10 11 5 OP_ROLL OP_DEPTH OP_PICK 10 OP_DUP OP_PICK }.compile();
+        assert_eq!(PickRollFolding.rewrite(&block), None);
+    }
+}
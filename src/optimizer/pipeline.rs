@@ -0,0 +1,176 @@
+use super::{
+    run_passes, ConstantFolding, MinimalPushes, OptimizationStats, Pass, Peephole, PickRollFolding,
+};
+use crate::builder::StructuredScript;
+
+/// What one pass of an [`OptimizerPipeline`] did to the script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassReport {
+    name: String,
+    size_before: usize,
+    size_after: usize,
+    stats: OptimizationStats,
+}
+
+impl PassReport {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size of the compiled script before the pass ran.
+    pub fn size_before(&self) -> usize {
+        self.size_before
+    }
+
+    /// Size of the compiled script after the pass ran.
+    pub fn size_after(&self) -> usize {
+        self.size_after
+    }
+
+    /// The bytes that the pass saved per script.
+    pub fn stats(&self) -> &OptimizationStats {
+        &self.stats
+    }
+}
+
+/// Runs passes one after another in the order in which they were added. Unlike
+/// [`super::Optimizer`], which interleaves its passes, every pass runs until it doesn't
+/// change the script anymore before the next one starts, so the effect of every pass can be
+/// measured on its own. A pass can be added several times to run it again later.
+#[derive(Default)]
+pub struct OptimizerPipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl OptimizerPipeline {
+    /// A pipeline without passes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Passes that keep the result of every script, including the ones that fail:
+    /// [`ConstantFolding`] and [`Peephole::strength_reduction`]. Suitable for
+    /// consensus-critical scripts whose failure cases matter as much as their success.
+    ///
+    /// The only exception is the limit of 1000 elements on the stacks: constant folding
+    /// lowers the largest number of elements on the stack, so a script that only fails
+    /// because it exceeds the limit may succeed afterwards.
+    pub fn conservative() -> Self {
+        Self::new()
+            .with_pass(ConstantFolding)
+            .with_pass(Peephole::strength_reduction())
+    }
+
+    /// The conservative passes followed by [`PickRollFolding`], [`Peephole::new`] and
+    /// [`MinimalPushes`], which may turn instructions that can only fail into ones that
+    /// succeed, e.g. by removing `0 OP_ROLL` or `OP_DUP OP_DROP` or fixing non-minimal
+    /// pushes.
+    pub fn aggressive() -> Self {
+        Self::conservative()
+            .with_pass(PickRollFolding)
+            .with_pass(Peephole::new())
+            .with_pass(MinimalPushes)
+    }
+
+    /// Adds a pass that runs after the passes that were added before.
+    pub fn with_pass(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Names of the passes in the order in which they run.
+    pub fn passes(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    /// Runs the passes over the script and the scripts it calls in place and reports what
+    /// every pass did, in the order in which they ran.
+    pub fn optimize(&self, script: &mut StructuredScript) -> Vec<PassReport> {
+        self.passes
+            .iter()
+            .map(|pass| {
+                let size_before = script.len();
+                let stats = run_passes(std::slice::from_ref(pass), script);
+                PassReport {
+                    name: pass.name().to_string(),
+                    size_before,
+                    size_after: script.len(),
+                    stats,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{equivalent, execute};
+    use crate::script;
+    use bitcoin::opcodes::all::{OP_BOOLAND, OP_NUMEQUAL};
+    use bitcoin::ScriptBuf;
+
+    #[test]
+    fn report_every_pass() {
+        // Pushes 6 with `OP_PUSHDATA1`
+        let non_minimal = ScriptBuf::from_bytes(vec![0x4c, 0x01, 0x06]);
+        let script = script! { OP_DUP OP_DROP 2 3 OP_ADD 10 11 1 OP_ROLL OP_SUB OP_ADD }
+            .push_script(non_minimal)
+            .push_opcode(OP_NUMEQUAL)
+            .push_opcode(OP_BOOLAND);
+        assert!(execute(&script, vec![vec![1]]).success());
+
+        let mut conservative = script.clone();
+        let reports = OptimizerPipeline::conservative().optimize(&mut conservative);
+        let sizes: Vec<_> = reports
+            .iter()
+            .map(|report| (report.name(), report.size_before(), report.size_after()))
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![("ConstantFolding", 16, 14), ("Peephole", 14, 14)]
+        );
+        assert_eq!(reports[0].stats().bytes_saved(), 2);
+        assert!(execute(&conservative, vec![vec![1]]).success());
+
+        let mut aggressive = script.clone();
+        let pipeline = OptimizerPipeline::aggressive();
+        assert_eq!(
+            pipeline.passes().collect::<Vec<_>>(),
+            vec![
+                "ConstantFolding",
+                "Peephole",
+                "PickRollFolding",
+                "Peephole",
+                "MinimalPushes"
+            ]
+        );
+        let reports = pipeline.optimize(&mut aggressive);
+        assert_eq!(reports[2].size_after(), 12);
+        assert_eq!(reports[3].size_after(), 10);
+        assert_eq!(reports[4].size_after(), 8);
+        assert!(execute(&aggressive, vec![vec![1]]).success());
+    }
+
+    #[test]
+    fn conservative_passes_keep_failures() {
+        let scripts = [
+            // Fails on an empty stack
+            script! { 0 OP_ROLL OP_1 },
+            script! { OP_DROP OP_DROP 2 3 OP_ADD OP_ADD 10 11 1 OP_ROLL OP_SUB },
+            script! { OP_SWAP OP_DROP 1 OP_ADD 5 OP_NUMEQUAL },
+        ];
+        for script in scripts {
+            let mut optimized = script.clone();
+            OptimizerPipeline::conservative().optimize(&mut optimized);
+            assert_eq!(equivalent(&optimized, &script, 3), Ok(()));
+        }
+
+        // The aggressive passes remove `0 OP_ROLL`, which only fails
+        let script = script! { 0 OP_ROLL OP_1 };
+        let mut optimized = script.clone();
+        OptimizerPipeline::aggressive().optimize(&mut optimized);
+        assert_eq!(optimized.len(), 1);
+        assert!(equivalent(&optimized, &script, 3).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use super::{instructions_with_bytes, Pass};
+use crate::analyzer::{opcode_stack_effect, pushnum_value};
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::script::{read_scriptint, Builder, Instruction, Script};
+use bitcoin::ScriptBuf;
+
+/// Replaces a push of a constant that the block pushed before and hasn't touched since with
+/// `OP_DUP`, `OP_OVER` or `<depth> OP_PICK` of the earlier copy, e.g.
+/// `<hash> OP_1 OP_2 <hash>` becomes `<hash> OP_1 OP_2 OP_2 OP_PICK`. Only pushes of at
+/// least `min_size` bytes are replaced, and only with copies at most `max_depth` elements
+/// deep.
+///
+/// The position of the earlier copy is tracked through the instructions in between and
+/// lost at an instruction that reads it or moves it, at a conditional and at an `OP_PICK`
+/// or `OP_ROLL` whose depth is not a constant. The pass is opt-in, because picking
+/// elements takes more time to execute than pushing them.
+#[derive(Clone, Copy, Debug)]
+pub struct PushReuse {
+    min_size: usize,
+    max_depth: usize,
+}
+
+impl PushReuse {
+    /// A pass that reuses pushes of at least 20 bytes, e.g. hashes and keys, from at most
+    /// 16 elements deep, so that the depth is pushed with a single opcode.
+    pub fn new() -> Self {
+        PushReuse {
+            min_size: 20,
+            max_depth: 16,
+        }
+    }
+
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+impl Default for PushReuse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The instructions that push a copy of the element at `depth`
+fn pick(depth: usize) -> Vec<u8> {
+    match depth {
+        0 => vec![OP_DUP.to_u8()],
+        1 => vec![OP_OVER.to_u8()],
+        _ => Builder::new()
+            .push_int(depth as i64)
+            .push_opcode(OP_PICK)
+            .into_bytes(),
+    }
+}
+
+impl Pass for PushReuse {
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf> {
+        let mut result: Vec<u8> = vec![];
+        // The large pushes that are still on the stack and their depths
+        let mut copies: Vec<(&[u8], usize)> = vec![];
+        // The value of the previous instruction if it pushed a number
+        let mut last_value: Option<i64> = None;
+        let mut changed = false;
+
+        for (instruction, mut encoding) in instructions_with_bytes(block) {
+            let value = match instruction {
+                Instruction::PushBytes(data) => {
+                    let data = data.as_bytes();
+                    let copy = copies
+                        .iter()
+                        .filter(|(copy, depth)| *copy == data && *depth <= self.max_depth)
+                        .map(|(_, depth)| *depth)
+                        .min();
+                    let replacement = copy.map(pick);
+                    if let Some(replacement) = replacement.filter(|r| r.len() < encoding.len()) {
+                        result.extend(replacement);
+                        encoding = &[];
+                        changed = true;
+                    }
+                    copies.iter_mut().for_each(|(_, depth)| *depth += 1);
+                    if data.len() >= self.min_size {
+                        copies.push((data, 0));
+                    }
+                    read_scriptint(data).ok()
+                }
+                Instruction::Op(opcode) => {
+                    match opcode {
+                        OP_PICK | OP_ROLL if last_value.is_some_and(|n| n >= 0) => {
+                            let n = last_value.unwrap_or_else(|| unreachable!()) as usize;
+                            copies.retain_mut(|(_, depth)| {
+                                // The depth is popped first
+                                if *depth == 0 {
+                                    return false;
+                                }
+                                *depth -= 1;
+                                if opcode == OP_PICK || *depth < n {
+                                    *depth += 1;
+                                } else if *depth == n {
+                                    *depth = 0;
+                                }
+                                true
+                            });
+                        }
+                        OP_TOALTSTACK => copies.retain_mut(|(_, depth)| {
+                            if *depth == 0 {
+                                return false;
+                            }
+                            *depth -= 1;
+                            true
+                        }),
+                        OP_FROMALTSTACK => copies.iter_mut().for_each(|(_, depth)| *depth += 1),
+                        OP_IF
+                        | OP_NOTIF
+                        | OP_ELSE
+                        | OP_ENDIF
+                        | OP_PICK
+                        | OP_ROLL
+                        | OP_CHECKMULTISIG
+                        | OP_CHECKMULTISIGVERIFY => copies.clear(),
+                        _ => match opcode_stack_effect(opcode) {
+                            Some((accessed, change)) => copies.retain_mut(|(_, depth)| {
+                                if *depth < accessed as usize {
+                                    return false;
+                                }
+                                *depth = (*depth as i32 + change) as usize;
+                                true
+                            }),
+                            None => copies.clear(),
+                        },
+                    }
+                    pushnum_value(opcode)
+                }
+            };
+            result.extend(encoding);
+            last_value = value;
+        }
+
+        changed.then(|| ScriptBuf::from_bytes(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StructuredScript;
+    use crate::interpreter::equivalent;
+    use crate::optimizer::Optimizer;
+    use crate::script;
+
+    fn reuse(script: StructuredScript) -> ScriptBuf {
+        let mut reused = script.clone();
+        Optimizer::new()
+            .with_pass(PushReuse::new())
+            .optimize(&mut reused);
+        assert_eq!(equivalent(&reused, &script, 2), Ok(()));
+        reused.compile()
+    }
+
+    #[test]
+    fn reuse_pushes() {
+        let hash = [0xab; 32];
+        assert_eq!(
+            reuse(script! { {hash} OP_1 OP_2 OP_ADD OP_DROP {hash} OP_EQUAL }),
+            script! { {hash} OP_1 OP_2 OP_ADD OP_DROP OP_DUP OP_EQUAL }.compile()
+        );
+        assert_eq!(
+            reuse(script! { {hash} OP_0 OP_TOALTSTACK OP_1 OP_2 OP_3 {hash} OP_FROMALTSTACK }),
+            script! { {hash} OP_0 OP_TOALTSTACK OP_1 OP_2 OP_3 OP_3 OP_PICK OP_FROMALTSTACK }
+                .compile()
+        );
+        // The copy is rolled to the top
+        assert_eq!(
+            reuse(script! { {hash} 7 8 2 OP_ROLL {hash} OP_EQUALVERIFY }),
+            script! { {hash} 7 8 2 OP_ROLL OP_DUP OP_EQUALVERIFY }.compile()
+        );
+    }
+
+    #[test]
+    fn keep_unreachable_pushes() {
+        let hash = [0xab; 32];
+        for script in [
+            script! { {hash} OP_ADD {hash} },
+            script! { {hash} OP_TOALTSTACK {hash} },
+            script! { {hash} OP_IF OP_ENDIF {hash} },
+            script! { {hash} OP_DEPTH OP_PICK {hash} },
+            script! { {[0xab; 19]} {[0xab; 19]} },
+        ] {
+            assert_eq!(PushReuse::new().rewrite(&script.compile()), None);
+        }
+        let deep = script! { {hash} 1 2 3 4 {hash} }.compile();
+        assert_eq!(PushReuse::new().with_max_depth(3).rewrite(&deep), None);
+    }
+}
@@ -0,0 +1,201 @@
+use super::{instructions_with_bytes, Pass};
+use crate::analyzer::pushnum_value;
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::Opcode;
+use bitcoin::blockdata::script::{read_scriptint, Builder, Instruction, Script};
+use bitcoin::ScriptBuf;
+
+/// Lowers the depth of `OP_PICK` and `OP_ROLL` that reach below constants the block pushes
+/// right before them, by accessing the element before the constants are pushed and moving
+/// it above them, e.g. `<a> <b> 4 OP_PICK` becomes `2 OP_PICK <a> <b> OP_ROT`.
+///
+/// Deep stack access dominates the execution cost of large scripts, so the rewrite may make
+/// the block larger, by default by at most 2 bytes per access. Only depths given by a
+/// constant right before the opcode are handled, and accesses that already are a single
+/// opcode like `OP_ROT` are kept. Accesses of elements inside the pushes are left to
+/// [`PickRollFolding`].
+///
+/// [`PickRollFolding`]: super::PickRollFolding
+#[derive(Clone, Copy, Debug)]
+pub struct StackScheduling {
+    max_growth: usize,
+}
+
+impl StackScheduling {
+    pub fn new() -> Self {
+        Self { max_growth: 2 }
+    }
+
+    /// Sets the number of bytes by which rescheduling one access may make the block larger.
+    pub fn with_max_growth(mut self, bytes: usize) -> Self {
+        self.max_growth = bytes;
+        self
+    }
+}
+
+impl Default for StackScheduling {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The instructions that access the element at `depth`
+fn access(opcode: Opcode, depth: i64) -> Vec<u8> {
+    match (opcode, depth) {
+        (OP_PICK, 0) => vec![OP_DUP.to_u8()],
+        (OP_PICK, 1) => vec![OP_OVER.to_u8()],
+        (OP_ROLL, 0) => vec![],
+        (OP_ROLL, 1) => vec![OP_SWAP.to_u8()],
+        (OP_ROLL, 2) => vec![OP_ROT.to_u8()],
+        _ => Builder::new()
+            .push_int(depth)
+            .push_opcode(opcode)
+            .into_bytes(),
+    }
+}
+
+impl Pass for StackScheduling {
+    fn rewrite(&self, block: &Script) -> Option<ScriptBuf> {
+        let mut result: Vec<u8> = vec![];
+        // The constants that were pushed last, with their encodings and values, and where
+        // the first of them starts in the result
+        let mut pushes: Vec<(&[u8], Option<i64>)> = vec![];
+        let mut pushes_start = 0;
+        let mut changed = false;
+        for (instruction, encoding) in instructions_with_bytes(block) {
+            let (opcode, value) = match instruction {
+                Instruction::PushBytes(data) => (None, read_scriptint(data.as_bytes()).ok()),
+                Instruction::Op(opcode) => (Some(opcode), pushnum_value(opcode)),
+            };
+            if opcode.is_none() || value.is_some() {
+                pushes.push((encoding, value));
+                result.extend_from_slice(encoding);
+                continue;
+            }
+
+            if let Some(opcode @ (OP_PICK | OP_ROLL)) = opcode {
+                if let Some(((depth_bytes, Some(depth)), constants)) = pushes.split_last() {
+                    let constants: Vec<&[u8]> = constants.iter().map(|(bytes, _)| *bytes).collect();
+                    let has_short_form = match opcode {
+                        OP_PICK => *depth < 2,
+                        _ => *depth < 3,
+                    };
+                    if !constants.is_empty() && *depth >= constants.len() as i64 && !has_short_form
+                    {
+                        // Access the element below the constants, push them and move it
+                        // above them
+                        let mut replacement = access(opcode, *depth - constants.len() as i64);
+                        if let [first, second] = constants[..] {
+                            replacement.extend_from_slice(first);
+                            replacement.extend_from_slice(second);
+                            replacement.push(OP_ROT.to_u8());
+                        } else {
+                            for constant in &constants {
+                                replacement.extend_from_slice(constant);
+                                replacement.push(OP_SWAP.to_u8());
+                            }
+                        }
+                        let len = result.len() - pushes_start + depth_bytes.len() + 1;
+                        if replacement.len() <= len + self.max_growth {
+                            result.truncate(pushes_start);
+                            result.extend_from_slice(&replacement);
+                            pushes.clear();
+                            pushes_start = result.len();
+                            changed = true;
+                            continue;
+                        }
+                    }
+                }
+            }
+            result.extend_from_slice(encoding);
+            pushes.clear();
+            pushes_start = result.len();
+        }
+        changed.then(|| ScriptBuf::from_bytes(result))
+    }
+
+    // Every access may grow by the budget
+    fn max_growth(&self, block: &Script) -> usize {
+        let accesses = block
+            .instructions()
+            .filter(|instruction| matches!(instruction, Ok(Instruction::Op(OP_PICK | OP_ROLL))))
+            .count();
+        accesses * self.max_growth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StructuredScript;
+    use crate::interpreter::equivalent;
+    use crate::optimizer::Optimizer;
+    use crate::script;
+
+    fn schedule(script: StructuredScript, pass: StackScheduling) -> ScriptBuf {
+        let mut scheduled = script.clone();
+        Optimizer::new().with_pass(pass).optimize(&mut scheduled);
+        assert_eq!(equivalent(&scheduled, &script, 2), Ok(()));
+        scheduled.compile()
+    }
+
+    #[test]
+    fn lower_depth() {
+        assert_eq!(
+            schedule(script! { OP_ADD 7 8 4 OP_PICK }, StackScheduling::new()),
+            script! { OP_ADD 2 OP_PICK 7 8 OP_ROT }.compile()
+        );
+        assert_eq!(
+            schedule(script! { OP_ADD 7 8 3 OP_ROLL }, StackScheduling::new()),
+            script! { OP_ADD OP_SWAP 7 8 OP_ROT }.compile()
+        );
+        assert_eq!(
+            schedule(script! { OP_ADD 7 2 OP_PICK }, StackScheduling::new()),
+            script! { OP_ADD OP_OVER 7 OP_SWAP }.compile()
+        );
+        // Only the constants after the last other instruction are rescheduled
+        assert_eq!(
+            schedule(script! { 5 OP_DUP 7 8 6 OP_ROLL }, StackScheduling::new()),
+            script! { 5 OP_DUP 4 OP_ROLL 7 8 OP_ROT }.compile()
+        );
+    }
+
+    #[test]
+    fn size_budget() {
+        let script = script! { OP_ADD 7 8 9 5 OP_ROLL };
+        assert_eq!(
+            schedule(script.clone(), StackScheduling::new()),
+            script! { OP_ADD OP_ROT 7 OP_SWAP 8 OP_SWAP 9 OP_SWAP }.compile()
+        );
+        assert_eq!(
+            schedule(script.clone(), StackScheduling::new().with_max_growth(1)),
+            script.clone().compile()
+        );
+        let mut scheduled = script.clone();
+        let stats = Optimizer::new()
+            .with_pass(StackScheduling::new())
+            .optimize(&mut scheduled);
+        assert_eq!(scheduled.len(), script.len() + 2);
+        assert_eq!(stats.bytes_saved(), 0);
+        assert_eq!(stats.iter().count(), 0);
+    }
+
+    #[test]
+    fn keep_accesses() {
+        for script in [
+            // Shallow accesses are single opcodes already
+            script! { OP_ADD 7 2 OP_ROLL },
+            script! { OP_ADD 7 1 OP_PICK },
+            // The element is one of the constants
+            script! { OP_ADD 7 8 1 OP_PICK },
+            // The depth is not a constant
+            script! { OP_ADD 7 8 OP_DEPTH OP_PICK },
+            script! { OP_ADD 3 OP_PICK },
+        ] {
+            assert_eq!(
+                schedule(script.clone(), StackScheduling::new()),
+                script.compile()
+            );
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::analyzer::StackAnalyzer;
+use crate::builder::{Block, StructuredScript};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Renders a [`StructuredScript`] as a tree of the scripts it calls.
+///
+/// Every node shows the name of the script, its length in bytes, its effect on the main
+/// stack and how many `OP_IF`s it leaves open. The stack delta is `?` if the script can't
+/// be analyzed, e.g. because it leaves an `OP_IF` open. Consecutive calls of the same
+/// script are shown once with a repetition count.
+#[derive(Debug, Default)]
+pub struct TreePrinter {
+    max_depth: Option<usize>,
+}
+
+impl TreePrinter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only prints the scripts up to `depth` calls below the root.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn print(&self, script: &StructuredScript) -> String {
+        let mut tree = String::new();
+        let mut unclosed_ifs = HashMap::new();
+        let mut stack_deltas = StackDeltas::default();
+        tree.push_str(&node_label(script, &mut unclosed_ifs, &mut stack_deltas));
+        tree.push('\n');
+        self.print_children(
+            script,
+            "",
+            1,
+            &mut unclosed_ifs,
+            &mut stack_deltas,
+            &mut tree,
+        );
+        tree
+    }
+
+    fn print_children(
+        &self,
+        script: &StructuredScript,
+        prefix: &str,
+        depth: usize,
+        unclosed_ifs: &mut HashMap<u64, i32>,
+        stack_deltas: &mut StackDeltas,
+        tree: &mut String,
+    ) {
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return;
+        }
+
+        // Group consecutive calls of the same script
+        let mut calls: Vec<(u64, usize)> = Vec::new();
+        for block in &script.blocks {
+            if let Block::Call(id) = block {
+                match calls.last_mut() {
+                    Some((last_id, count)) if last_id == id => *count += 1,
+                    _ => calls.push((*id, 1)),
+                }
+            }
+        }
+
+        for (i, (id, count)) in calls.iter().enumerate() {
+            let is_last = i + 1 == calls.len();
+            let called_script = script.get_structured_script(id);
+            let repetitions = if *count > 1 {
+                format!(" x{}", count)
+            } else {
+                String::new()
+            };
+            let _ = writeln!(
+                tree,
+                "{}{}{}{}",
+                prefix,
+                if is_last { "└── " } else { "├── " },
+                node_label(called_script, unclosed_ifs, stack_deltas),
+                repetitions
+            );
+            let child_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
+            self.print_children(
+                called_script,
+                &child_prefix,
+                depth + 1,
+                unclosed_ifs,
+                stack_deltas,
+                tree,
+            );
+        }
+    }
+}
+
+impl StructuredScript {
+    /// Returns the call graph of the script in the Graphviz DOT format. Every distinct
+    /// script is a node labeled with its name and length, every edge is labeled with the
+    /// number of calls.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph script {\n    node [shape=box];\n");
+        write_dot_node(&mut dot, "root", self);
+        let mut visited = HashSet::new();
+        write_dot_calls(&mut dot, "root", self, &mut visited);
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn write_dot_node(dot: &mut String, node: &str, script: &StructuredScript) {
+    let _ = writeln!(
+        dot,
+        "    {} [label=\"{}\\n{} bytes\"];",
+        node,
+        script.name().replace('\\', "\\\\").replace('"', "\\\""),
+        script.len()
+    );
+}
+
+fn write_dot_calls(
+    dot: &mut String,
+    node: &str,
+    script: &StructuredScript,
+    visited: &mut HashSet<u64>,
+) {
+    // Count the calls of every called script in the order of their first call
+    let mut calls: Vec<(u64, usize)> = Vec::new();
+    for block in &script.blocks {
+        if let Block::Call(id) = block {
+            match calls.iter_mut().find(|(called_id, _)| called_id == id) {
+                Some((_, count)) => *count += 1,
+                None => calls.push((*id, 1)),
+            }
+        }
+    }
+
+    for (id, count) in calls {
+        let called_node = format!("s{:016x}", id);
+        let called_script = script.get_structured_script(&id);
+        if visited.insert(id) {
+            write_dot_node(dot, &called_node, called_script);
+            write_dot_calls(dot, &called_node, called_script, visited);
+        }
+        let _ = writeln!(
+            dot,
+            "    {} -> {} [label=\"{}\"];",
+            node, called_node, count
+        );
+    }
+}
+
+// The stack deltas of the printed scripts by their id. The analyzer keeps the effects of
+// the scripts it analyzed, so the root is analyzed bottom-up once and every other script
+// only looks up the effects of the scripts it calls.
+#[derive(Default)]
+struct StackDeltas {
+    analyzer: StackAnalyzer,
+    deltas: HashMap<u64, Option<i32>>,
+}
+
+impl StackDeltas {
+    fn get(&mut self, script: &StructuredScript) -> Option<i32> {
+        let analyzer = &mut self.analyzer;
+        *self.deltas.entry(script.id()).or_insert_with(|| {
+            analyzer
+                .try_analyze(script)
+                .ok()
+                .map(|status| status.stack_changed)
+        })
+    }
+}
+
+fn node_label(
+    script: &StructuredScript,
+    unclosed_ifs: &mut HashMap<u64, i32>,
+    stack_deltas: &mut StackDeltas,
+) -> String {
+    let stack_delta = match stack_deltas.get(script) {
+        Some(stack_changed) => format!("{:+}", stack_changed),
+        None => "?".to_string(),
+    };
+    let mut label = format!(
+        "{} ({} bytes, stack {}",
+        script.name(),
+        script.len(),
+        stack_delta
+    );
+    let unclosed = script.unclosed_ifs(unclosed_ifs);
+    if unclosed != 0 {
+        let _ = write!(label, ", {} unclosed ifs", unclosed);
+    }
+    label.push(')');
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+
+    #[test]
+    fn print_tree() {
+        let script = script! {
+            def double {
+                OP_DUP OP_ADD
+            }
+            def open {
+                OP_DUP OP_IF
+            }
+
+            OP_1 double double
+            open OP_1 OP_DROP OP_ENDIF
+            repeat!(3, OP_2 OP_ADD)
+        };
+
+        assert_eq!(
+            TreePrinter::new().print(&script),
+            "\
+print_tree (16 bytes, stack +1)
+├── double (2 bytes, stack +0) x2
+├── open (2 bytes, stack ?, 1 unclosed ifs)
+└── repeat (2 bytes, stack +0) x3
+"
+        );
+        assert_eq!(
+            TreePrinter::new().max_depth(0).print(&script),
+            "print_tree (16 bytes, stack +1)\n"
+        );
+    }
+
+    #[test]
+    fn dot_graph() {
+        let script = script! {
+            def double {
+                OP_DUP OP_ADD
+            }
+            def quadruple {
+                OP_2 OP_MUL double
+            }
+
+            OP_1 double double quadruple
+        };
+
+        let dot = script.to_dot();
+        assert!(dot.starts_with("digraph script {\n"));
+        assert!(dot.contains("    root [label=\"dot_graph\\n9 bytes\"];\n"));
+        // Both callers of `double` point to the same node
+        assert_eq!(dot.matches("[label=\"double\\n2 bytes\"]").count(), 1);
+        assert_eq!(dot.matches(" -> ").count(), 3);
+        assert!(dot.contains("[label=\"2\"];"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
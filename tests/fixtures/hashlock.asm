@@ -0,0 +1,7 @@
+# Hash lock with a relative timelock fallback
+OP_IF
+    OP_SHA256 OP_PUSHBYTES_4 deadbeef OP_EQUALVERIFY
+OP_ELSE
+    144 OP_CSV OP_DROP
+OP_ENDIF
+OP_TRUE
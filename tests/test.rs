@@ -1,9 +1,18 @@
 use bitcoin::{
     consensus::{encode, Encodable},
-    opcodes::all::OP_ADD,
-    Witness,
+    hashes::{hash160, sha256, Hash},
+    opcodes::all::{
+        OP_ADD, OP_CHECKSIGADD, OP_ENDIF, OP_EQUALVERIFY, OP_NOTIF, OP_PUSHBYTES_32, OP_SHA256,
+    },
+    secp256k1::{self, Secp256k1, SecretKey},
+    taproot::LeafVersion,
+    CompressedPublicKey, PublicKey, ScriptBuf, Witness,
+};
+use bitcoin_script::{
+    builder::{Block, CacheError, ParseError, SigopContext, WitnessError, FOLDED},
+    chunks_to_taproot, execute, include_script, script, Chunker, Pushable, Script, StackAnalyzer,
+    ToStructuredScript,
 };
-use bitcoin_script::{script, Script};
 
 #[test]
 fn test_generic() {
@@ -49,6 +58,892 @@ fn test_pushable_vectors() {
     );
 }
 
+#[test]
+fn test_push_byte_slices() {
+    let data = vec![0xab_u8; 32];
+    let script = script! {
+        { &data[..16] }
+        { data.clone() }
+        { [1u8, 2] }
+        { &[0x05_u8] }
+    };
+
+    let mut expected = vec![16];
+    expected.extend(vec![0xab; 16]);
+    expected.push(32);
+    expected.extend(vec![0xab; 32]);
+    expected.extend(vec![2, 1, 2, 85]);
+    assert_eq!(script.compile().to_bytes(), expected);
+
+    let max_element = vec![0_u8; 520];
+    let script = script! { { &max_element[..] } };
+    assert_eq!(script.len(), 523);
+}
+
+#[test]
+fn test_push_single_bytes() {
+    // Only bytes that are minimal numbers are pushed with an opcode
+    let script = script! {
+        { [0x00_u8] }
+        { [0x80_u8] }
+        { [0x81_u8] }
+        { [0xff_u8] }
+        { [0x10_u8] }
+        { [0x11_u8] }
+    };
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![1, 0x00, 1, 0x80, 0x4f, 1, 0xff, 0x60, 1, 0x11]
+    );
+}
+
+#[test]
+#[should_panic(expected = "Data push of 521 bytes exceeds the maximum stack element size")]
+fn test_push_oversized_slice() {
+    let data = vec![0_u8; 521];
+    let _script = script! { { &data[..] } };
+}
+
+#[test]
+fn test_push_strings() {
+    let script = script! {
+        "BIP0340/challenge"
+        OP_DROP
+        b"\x01\x02"
+        ""
+        "a"
+    };
+
+    let mut expected = vec![17];
+    expected.extend(b"BIP0340/challenge");
+    expected.extend(vec![117, 2, 1, 2, 0, 1, 97]);
+    assert_eq!(script.compile().to_bytes(), expected);
+}
+
+#[test]
+fn test_include_script() {
+    let asm = include_script!("tests/fixtures/hashlock.asm");
+    let hex = include_script!("tests/fixtures/hashlock.hex");
+    assert_eq!(asm.debug_identifier, "tests/fixtures/hashlock.asm");
+    assert_eq!(asm.clone().compile(), hex.compile());
+
+    let script = script! {
+        OP_DUP
+        include_script!("tests/fixtures/hashlock.asm")
+        { include_script!("tests/fixtures/hashlock.asm") }
+    };
+
+    assert!(matches!(script.blocks[1], Block::Call(_)));
+    assert_eq!(script.blocks[1], script.blocks[2]);
+    let mut expected = vec![118];
+    expected.extend(asm.clone().compile().to_bytes());
+    expected.extend(asm.compile().to_bytes());
+    assert_eq!(script.compile().to_bytes(), expected);
+}
+
+#[test]
+fn test_negative_int_encoding() {
+    let cases: Vec<(i64, Vec<u8>)> = vec![
+        (-1, vec![0x4f]),
+        (-2, vec![0x01, 0x82]),
+        (-16, vec![0x01, 0x90]),
+        (-127, vec![0x01, 0xff]),
+        // The sign bit needs an extra byte once the magnitude uses the top bit
+        (-128, vec![0x02, 0x80, 0x80]),
+        (-255, vec![0x02, 0xff, 0x80]),
+        (-256, vec![0x02, 0x00, 0x81]),
+        (-32767, vec![0x02, 0xff, 0xff]),
+        (-32768, vec![0x03, 0x00, 0x80, 0x80]),
+        (-0x7fff_ffff, vec![0x04, 0xff, 0xff, 0xff, 0xff]),
+        (-0x8000_0000, vec![0x05, 0x00, 0x00, 0x00, 0x80, 0x80]),
+        (
+            i64::MAX,
+            vec![0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+        ),
+        (
+            -i64::MAX,
+            vec![0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ),
+        (
+            i64::MIN,
+            vec![0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x80],
+        ),
+    ];
+
+    for (n, expected) in cases {
+        assert_eq!(script! { { n } }.compile().to_bytes(), expected, "{}", n);
+    }
+
+    let script = script! {
+        -128 -0x80 -9223372036854775808
+    };
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![
+            0x02, 0x80, 0x80, 0x02, 0x80, 0x80, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x80, 0x80
+        ]
+    );
+}
+
+#[test]
+fn test_constant_folding() {
+    let script = script! {
+        { 2 * 8 }
+        <1 + 2 * 3>
+        { (1 << 31) - 1 }
+        { 1 << 40 }
+        { 5 }
+    };
+
+    assert_eq!(
+        script.get_metadata(FOLDED),
+        Some("2 * 8 = 16; 1 + 2 * 3 = 7; (1 << 31) - 1 = 2147483647; 1 << 40 = 1099511627776")
+    );
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![96, 87, 4, 255, 255, 255, 127, 6, 0, 0, 0, 0, 0, 1, 85]
+    );
+}
+
+#[test]
+fn test_repeat() {
+    let n = 2;
+    let script = script! {
+        OP_1
+        repeat!(32, OP_DUP OP_TOALTSTACK)
+        repeat!(n + 1, OP_ADD)
+    };
+
+    let mut expected = vec![0x51];
+    expected.extend([0x76, 0x6b].repeat(32));
+    expected.extend([0x93].repeat(3));
+    assert_eq!(script.compile().to_bytes(), expected);
+}
+
+#[test]
+fn test_repeat_is_stored_once() {
+    let script = script! {
+        OP_1
+        repeat!(32, OP_TOALTSTACK)
+    };
+
+    assert_eq!(script.len(), 33);
+    assert_eq!(script.blocks.len(), 33);
+    let Block::Call(id) = script.blocks[1] else {
+        panic!("Expected Block::Call, got {:?}", script.blocks[1]);
+    };
+    assert!(script.blocks[1..]
+        .iter()
+        .all(|block| *block == Block::Call(id)));
+    assert_eq!(script.get_structured_script(&id).len(), 1);
+}
+
+// Fixed-point number with two decimal places
+struct Fixed(f64);
+
+impl Pushable for Fixed {
+    fn bitcoin_script_push(self, builder: Script) -> Script {
+        builder.push_int((self.0 * 100.0).round() as i64)
+    }
+}
+
+#[test]
+fn test_custom_pushable() {
+    let price = Fixed(0.16);
+    let script = script! {
+        { price } { Fixed(-1.0) } OP_ADD
+    };
+
+    assert_eq!(script.compile().to_bytes(), vec![0x60, 0x01, 0xe4, 0x93]);
+}
+
+struct Double(u32);
+
+impl ToStructuredScript for Double {
+    fn to_structured_script(self) -> Script {
+        let mut script = script! {
+            { self.0 } OP_DUP OP_ADD
+        };
+        script.debug_identifier = format!("double({})", self.0);
+        script
+    }
+}
+
+#[test]
+fn test_to_structured_script() {
+    let script = script! {
+        OP_1
+        { Double(2) }
+        { vec![Double(2), Double(3)] }
+    };
+
+    let Block::Call(id) = script.blocks[1] else {
+        panic!("Expected Block::Call, got {:?}", script.blocks[1]);
+    };
+    assert!(script
+        .get_structured_script(&id)
+        .debug_identifier
+        .ends_with("double(2)"));
+    let result = execute(&script, vec![]);
+    assert_eq!(result.error, None);
+    assert_eq!(result.final_stack, vec![vec![1], vec![4], vec![4], vec![6]]);
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![0x51, 0x52, 0x76, 0x93, 0x52, 0x76, 0x93, 0x53, 0x76, 0x93]
+    );
+}
+
+#[test]
+fn test_raw_opcode() {
+    let script = script! {
+        OP_1 opcode!(0xbb) opcode!(80)
+    };
+
+    assert_eq!(script.compile().to_bytes(), vec![0x51, 0xbb, 0x50]);
+}
+
+#[test]
+fn test_hash_helpers() {
+    let preimage = vec![0xab_u8; 32];
+    let script = script! {
+        OP_SHA256 sha256!("secret") OP_EQUALVERIFY
+        OP_SHA256 sha256!(preimage) OP_EQUALVERIFY
+        OP_HASH160 hash160!(0xdeadbeef) OP_EQUAL
+    };
+    let expected = script! {
+        OP_SHA256 { sha256::Hash::hash(b"secret").to_byte_array() } OP_EQUALVERIFY
+        OP_SHA256 { sha256::Hash::hash(&preimage).to_byte_array() } OP_EQUALVERIFY
+        OP_HASH160 { hash160::Hash::hash(&[0xde, 0xad, 0xbe, 0xef]).to_byte_array() } OP_EQUAL
+    };
+
+    assert_eq!(script.compile(), expected.compile());
+}
+
+// The `Hash` trait is deliberately not imported here
+mod hash_helpers_without_imports {
+    use bitcoin_script::script;
+
+    #[test]
+    fn test_hash_runtime_data() {
+        let preimage = vec![0xab_u8; 32];
+        let script = script! { OP_SHA256 sha256!(preimage) OP_HASH160 hash160!(preimage) };
+        assert_eq!(script.len(), 1 + 33 + 1 + 21);
+    }
+}
+
+#[test]
+fn test_concat() {
+    let inner = script! { OP_ADD OP_ADD };
+    let first = script! {
+        /// @name: first
+        OP_1 { inner.clone() }
+    };
+    let second = script! {
+        /// @name: second
+        { inner } OP_EQUAL
+    };
+    let expected = script! {
+        OP_1 OP_ADD OP_ADD OP_ADD OP_ADD OP_EQUAL
+    };
+
+    let script = first.clone().concat(second.clone());
+    assert_eq!(script.len(), 6);
+    assert_eq!(
+        script.blocks.len(),
+        first.blocks.len() + second.blocks.len()
+    );
+    assert_eq!(script.get_metadata("name"), Some("first"));
+    assert_eq!(script.compile(), expected.clone().compile());
+
+    assert_eq!((first + second).compile(), expected.compile());
+}
+
+#[test]
+fn test_insert_and_splice() {
+    let inner = script! { OP_ADD OP_ADD };
+    let mut script = script! {
+        OP_1 { inner.clone() } OP_2 { script! { OP_SUB OP_SUB } } OP_EQUAL
+    };
+    assert_eq!(script.blocks.len(), 5);
+
+    script.insert_at(1, script! { OP_DUP });
+    assert_eq!(script.len(), 8);
+    assert_eq!(
+        script.clone().compile(),
+        script! { OP_1 OP_DUP OP_ADD OP_ADD OP_2 OP_SUB OP_SUB OP_EQUAL }.compile()
+    );
+
+    // Replace OP_2 and the OP_SUB call with another call
+    script.splice(3..5, script! { OP_3 { inner } });
+    assert_eq!(script.len(), 8);
+    assert_eq!(
+        script.compile(),
+        script! { OP_1 OP_DUP OP_ADD OP_ADD OP_3 OP_ADD OP_ADD OP_EQUAL }.compile()
+    );
+}
+
+#[test]
+fn test_instructions() {
+    let inner = script! { OP_ADD <0xdeadbeef_u32 as i64> };
+    let script = script! {
+        OP_1 { inner.clone() } { script! { OP_2 { inner } } } OP_EQUAL
+    };
+
+    let instructions: Vec<_> = script.instructions().map(Result::unwrap).collect();
+    let compiled = script.clone().compile();
+    let expected: Vec<_> = compiled.instructions().map(Result::unwrap).collect();
+    assert_eq!(instructions.len(), 7);
+    assert_eq!(instructions, expected);
+}
+
+#[test]
+fn test_get_block_by_name() {
+    let script = script! {
+        def square {
+            OP_DUP OP_MUL
+        }
+        def cube {
+            OP_DUP square OP_MUL
+        }
+
+        OP_2 cube
+    };
+
+    let cube = script.get_block_by_name("cube").unwrap();
+    assert_eq!(cube.len(), 4);
+    let square = script.get_block_by_name("square").unwrap();
+    assert_eq!(square.len(), 2);
+    assert_eq!(cube.get_block_by_name("square"), Some(square));
+    assert!(script.get_block_by_name("quad").is_none());
+}
+
+#[test]
+fn test_replace_block() {
+    let mut script = script! {
+        def verify {
+            OP_DROP
+        }
+        def gadget {
+            OP_DUP verify
+        }
+
+        OP_1 verify gadget
+    };
+
+    let new_verify = script! { OP_2DROP OP_2DROP };
+    assert!(script.replace_block("verify", new_verify));
+    assert!(!script.replace_block("missing", script! { OP_NOP }));
+    assert_eq!(script.len(), 6);
+    assert_eq!(
+        script.compile(),
+        script! { OP_1 OP_2DROP OP_2DROP OP_DUP OP_2DROP OP_2DROP }.compile()
+    );
+}
+
+#[test]
+fn test_placeholder() {
+    let gadget = script! {
+        OP_DUP { Script::placeholder("checksig", 10) }
+    };
+    let mut script = script! {
+        OP_1 { gadget } OP_VERIFY
+    };
+    assert_eq!(script.len(), 13);
+
+    assert!(script.fill("checksig", script! { OP_DROP OP_CHECKSIG }));
+    assert!(!script.fill("checksig", script! { OP_CHECKSIG }));
+    assert_eq!(script.len(), 5);
+    assert_eq!(
+        script.compile(),
+        script! { OP_1 OP_DUP OP_DROP OP_CHECKSIG OP_VERIFY }.compile()
+    );
+}
+
+#[test]
+#[should_panic(expected = "Unfilled placeholder checksig")]
+fn test_unfilled_placeholder() {
+    script! { OP_1 { Script::placeholder("checksig", 10) } }.compile();
+}
+
+#[test]
+#[should_panic(expected = "Script of 3 bytes does not fit into placeholder checksig of 2 bytes")]
+fn test_overfilled_placeholder() {
+    let mut script = script! { OP_1 { Script::placeholder("checksig", 2) } };
+    script.fill("checksig", script! { OP_DROP OP_DROP OP_CHECKSIG });
+}
+
+#[test]
+fn test_to_asm_tree() {
+    let script = script! {
+        def double {
+            OP_DUP OP_ADD
+        }
+        def quadruple {
+            OP_2 OP_MUL double
+        }
+
+        0xdeadbeef00 quadruple
+        { Script::placeholder("check", 3) }
+    };
+
+    assert_eq!(
+        script.to_asm_tree(),
+        "OP_PUSHBYTES_5 deadbeef00
+# quadruple
+    OP_PUSHNUM_2
+    OP_MUL
+    # double
+        OP_DUP
+        OP_ADD
+# check
+    # placeholder check (3 bytes)
+"
+    );
+}
+
+#[test]
+fn test_dedup() {
+    let split = script! { OP_DUP } + script! { OP_ADD };
+    let joined = script! { OP_DUP OP_ADD };
+    let mut script = script! {
+        OP_1 { split } { joined } { script! { OP_1 { script! { OP_DUP OP_ADD } } } }
+    };
+    let call_ids = |script: &Script| -> Vec<u64> {
+        script
+            .blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Call(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    };
+    let ids = call_ids(&script);
+    assert_ne!(ids[0], ids[1]);
+
+    let compiled = script.clone().compile();
+    script.dedup();
+    let ids = call_ids(&script);
+    assert_eq!(ids[0], ids[1]);
+    assert_ne!(ids[1], ids[2]);
+    assert_eq!(script.len(), 8);
+    assert_eq!(script.compile(), compiled);
+
+    // The script that is called first is kept
+    for _ in 0..10 {
+        let mut first = script! { OP_DUP } + script! { OP_ADD };
+        first.debug_identifier = "first".to_string();
+        let mut second = script! { OP_DUP OP_ADD };
+        second.debug_identifier = "second".to_string();
+        let mut script = script! { OP_1 { first } { second } };
+        script.dedup();
+        assert!(script.get_block_by_name("first").is_some());
+        assert!(script.get_block_by_name("second").is_none());
+    }
+}
+
+#[test]
+fn test_repeated_sequences() {
+    let repeat = script! { OP_2DUP OP_ADD OP_SHA256 OP_SWAP OP_SHA256 }.compile();
+    let with = |before: ScriptBuf, after: ScriptBuf| {
+        ScriptBuf::from_bytes([before.as_bytes(), repeat.as_bytes(), after.as_bytes()].concat())
+    };
+    let inner = Script::new("inner").push_script(with(repeat.clone(), script! { OP_3 }.compile()));
+    let mut script = Script::new("test")
+        .push_script(with(script! { OP_1 }.compile(), script! { OP_2 }.compile()))
+        .push_env_script(inner.clone())
+        .push_script(with(ScriptBuf::new(), ScriptBuf::new()))
+        .push_env_script(inner);
+
+    // The called script counts once, and `OP_1 OP_2` is too short
+    let repeats = script.repeated_sequences(4);
+    assert_eq!(repeats.len(), 1);
+    assert_eq!(repeats[0].script, repeat);
+    assert_eq!(repeats[0].count, 4);
+    assert_eq!(repeats[0].bytes_saved(), 15);
+
+    let compiled = script.clone().compile();
+    let len = script.len();
+    assert_eq!(script.extract_repeated_sequences(4), repeats);
+    assert_eq!(script.get_block_by_name("common_0").unwrap().len(), 5);
+    assert_eq!(script.repeated_sequences(4), vec![]);
+    assert_eq!(script.len(), len);
+    assert_eq!(script.compile(), compiled);
+}
+
+#[test]
+fn test_clone_shares_called_scripts() {
+    let script = script! {
+        OP_1 { script! { OP_DUP OP_ADD } }
+    };
+    let clone = script.clone();
+
+    let Block::Call(id) = script.blocks[1] else {
+        panic!("Expected Block::Call, got {:?}", script.blocks[1]);
+    };
+    assert!(std::ptr::eq(
+        script.get_structured_script(&id),
+        clone.get_structured_script(&id)
+    ));
+}
+
+#[test]
+fn test_semantic_equality() {
+    let split = script! { OP_1 { script! { OP_DUP OP_ADD } } };
+    let joined = script! { OP_1 OP_DUP OP_ADD };
+    let other = script! { OP_1 OP_DUP OP_MUL };
+
+    assert_eq!(split, joined);
+    assert_ne!(joined, other);
+    assert!(!split.structurally_eq(&joined));
+    assert!(split.structurally_eq(&split.clone()));
+
+    let mut cache = std::collections::HashMap::new();
+    cache.insert(split, "split");
+    assert_eq!(cache.get(&joined), Some(&"split"));
+    assert_eq!(cache.get(&other), None);
+}
+
+#[test]
+fn test_cache_bytes() {
+    let script = script! {
+        /// @label: cached
+        def double {
+            OP_DUP OP_ADD
+        }
+
+        OP_1 double double 0xdeadbeef00
+        { Script::placeholder("gadget", 5) }
+    };
+
+    let bytes = script.to_cache_bytes();
+    let cached = Script::from_cache_bytes(&bytes).unwrap();
+    assert_eq!(cached.len(), script.len());
+    assert!(cached.structurally_eq(&script));
+    assert_eq!(cached.to_asm_tree(), script.to_asm_tree());
+    assert_eq!(cached.get_metadata("label"), Some("cached"));
+    assert_eq!(cached.source_location(), script.source_location());
+
+    assert_eq!(
+        Script::from_cache_bytes(&bytes[..bytes.len() - 1]),
+        Err(CacheError::UnexpectedEnd)
+    );
+    assert_eq!(
+        Script::from_cache_bytes(b"script"),
+        Err(CacheError::InvalidHeader)
+    );
+    let mut future = bytes.clone();
+    future[4] = 3;
+    assert_eq!(
+        Script::from_cache_bytes(&future),
+        Err(CacheError::UnsupportedVersion(3))
+    );
+}
+
+#[test]
+fn test_cache_bytes_are_deterministic() {
+    let build = || {
+        let mut script = script! { OP_1 };
+        for i in 0..16 {
+            script = script.push_env_script(script! { { i } OP_ADD });
+        }
+        script
+    };
+    assert_eq!(build().to_cache_bytes(), build().to_cache_bytes());
+}
+
+#[test]
+fn test_cache_bytes_with_wrong_ids() {
+    let inner = script! { OP_2 };
+    let outer = script! { OP_NOP { inner } };
+    let other = script! { OP_3 OP_4 OP_5 };
+    let script = script! { OP_1 { outer.clone() } { other } };
+    let (Block::Call(inner_id), Block::Call(other_id)) = (&outer.blocks[1], &script.blocks[2])
+    else {
+        panic!("Expected calls, got {:?}", script.blocks);
+    };
+
+    // Claim that the other script has the same id as the inner script
+    let mut bytes = script.to_cache_bytes();
+    let (inner_id, other_id) = (inner_id.to_le_bytes(), other_id.to_le_bytes());
+    for start in 0..=bytes.len() - 8 {
+        if bytes[start..start + 8] == other_id {
+            bytes[start..start + 8].copy_from_slice(&inner_id);
+        }
+    }
+    let cached = Script::from_cache_bytes(&bytes).unwrap();
+    assert_eq!(cached.compile(), script.compile());
+}
+
+#[test]
+fn test_count_op() {
+    let script = script! {
+        def check {
+            OP_SHA256 0x00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff OP_EQUALVERIFY
+        }
+
+        OP_1 check check
+        for _ in 0..3 {
+            OP_CHECKSIGADD
+        }
+        OP_3 OP_NUMEQUAL
+    };
+
+    assert_eq!(script.count_op(OP_SHA256), 2);
+    assert_eq!(script.count_op(OP_CHECKSIGADD), 3);
+    assert_eq!(script.count_op(OP_PUSHBYTES_32), 2);
+    assert_eq!(script.count_op(OP_ADD), 0);
+
+    let histogram = script.opcode_histogram();
+    assert_eq!(histogram.len(), 7);
+    assert!(histogram.contains(&(OP_EQUALVERIFY, 2)));
+    assert_eq!(histogram.iter().map(|(_, count)| count).sum::<usize>(), 12);
+}
+
+#[test]
+fn test_max_stack_depth() {
+    let script = script! {
+        OP_ADD
+        for i in 0..10 {
+            { i }
+        }
+        for _ in 0..10 {
+            OP_TOALTSTACK
+        }
+        for _ in 0..10 {
+            OP_FROMALTSTACK OP_DROP
+        }
+    };
+
+    // Two inputs are replaced by their sum, then ten elements are pushed
+    assert_eq!(script.max_stack_depth(), 11);
+}
+
+#[test]
+fn test_num_sigops() {
+    let script = script! {
+        OP_CHECKSIGVERIFY
+        OP_2 { script! { OP_3 } } OP_CHECKMULTISIGVERIFY
+        <17> OP_CHECKMULTISIG OP_VERIFY
+        OP_CHECKSIGADD
+    };
+
+    assert_eq!(script.num_sigops(SigopContext::Legacy), 41);
+    assert_eq!(script.num_sigops(SigopContext::SegwitV0), 24);
+    assert_eq!(script.num_sigops(SigopContext::Tapscript), 2);
+}
+
+#[test]
+fn test_estimate_spend_weight() {
+    let script = script! {
+        OP_CHECKSIGVERIFY OP_CHECKSIGVERIFY OP_CHECKSIG
+    };
+
+    // Item count, three signatures, the script and a control block with two nodes
+    let signatures = vec![vec![0u8; 64]; 3];
+    let spend = script.estimate_spend_weight(2, &signatures);
+    assert_eq!(spend.weight, 1 + 3 * 65 + 4 + 98);
+    assert_eq!(spend.num_sigops, 3);
+    assert_eq!(spend.sigop_budget(), 50 + spend.weight);
+    assert!(spend.fits_sigop_budget());
+
+    // Each signature check needs 50 bytes of witness beyond the first
+    let script = script! {
+        OP_CHECKSIGVERIFY OP_CHECKSIGVERIFY OP_CHECKSIGVERIFY OP_CHECKSIG
+    };
+    assert!(!script.estimate_spend_weight(2, &[]).fits_sigop_budget());
+    assert!(script
+        .estimate_spend_weight(2, &signatures)
+        .fits_sigop_budget());
+
+    let chunks = Chunker::new(script.clone(), 2, 0)
+        .find_chunks_and_analyze_stack()
+        .unwrap();
+    let spend = chunks[0].estimate_spend_weight(0, &signatures[..2]);
+    assert_eq!(spend.weight, 1 + 2 * 65 + 3 + 34);
+    assert_eq!(spend.num_sigops, 2);
+}
+
+#[test]
+fn test_chunks_to_taproot() {
+    let script = script! {
+        OP_1
+        for i in 0..5 {
+            { i + 20 } OP_ADD OP_1ADD
+        }
+    };
+    let chunks = Chunker::new(script, 4, 0).find_greedy_chunks().unwrap();
+    assert_eq!(chunks.len(), 6);
+
+    let secp = Secp256k1::new();
+    let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let (internal_key, _) = secret_key.public_key(&secp).x_only_public_key();
+    let taproot = chunks_to_taproot(&chunks, internal_key).unwrap();
+    assert_eq!(taproot.len(), 6);
+    assert_eq!(taproot.spend_info().internal_key(), internal_key);
+
+    let output_key = taproot.spend_info().output_key().to_x_only_public_key();
+    let mut depths = vec![];
+    for (index, chunk) in chunks.iter().enumerate() {
+        let script = taproot.leaf_script(index);
+        assert_eq!(script.len(), chunk.size());
+        let control_block = taproot.control_block(index);
+        assert!(control_block.verify_taproot_commitment(&secp, output_key, script));
+        depths.push(control_block.merkle_branch.len());
+    }
+    assert_eq!(depths, vec![3, 3, 3, 3, 2, 2]);
+    assert!(taproot.script_pubkey().is_p2tr());
+
+    let single = chunks_to_taproot(&chunks[..1], internal_key).unwrap();
+    assert!(single.control_block(0).merkle_branch.is_empty());
+
+    let mut input = bitcoin::psbt::Input::default();
+    taproot.update_psbt_input(&mut input, 4);
+    assert_eq!(input.tap_internal_key, Some(internal_key));
+    assert_eq!(input.tap_merkle_root, taproot.spend_info().merkle_root());
+    assert_eq!(
+        input.tap_scripts.get(taproot.control_block(4)),
+        Some(&(taproot.leaf_script(4).clone(), LeafVersion::TapScript))
+    );
+    taproot.update_psbt_input(&mut input, 5);
+    assert_eq!(input.tap_scripts.len(), 2);
+
+    let descriptor = single.descriptor();
+    let (descriptor, checksum) = descriptor.split_once('#').unwrap();
+    assert_eq!(
+        descriptor,
+        format!("tr({},rawleaf({:x}))", internal_key, taproot.leaf_script(0))
+    );
+    assert_eq!(checksum.len(), 8);
+    let descriptor = chunks_to_taproot(&chunks[..3], internal_key)
+        .unwrap()
+        .descriptor();
+    assert!(descriptor.starts_with(&format!(
+        "tr({},{{{{rawleaf({:x}),rawleaf({:x})}},rawleaf({:x})}})#",
+        internal_key,
+        taproot.leaf_script(0),
+        taproot.leaf_script(1),
+        taproot.leaf_script(2)
+    )));
+
+    // Equal chunks are spent via the same leaf
+    let equal = [chunks[1].clone(), chunks[1].clone(), chunks[2].clone()];
+    let taproot = chunks_to_taproot(&equal, internal_key).unwrap();
+    assert_eq!(taproot.control_block(0), taproot.control_block(1));
+    assert!(chunks_to_taproot(&[], internal_key).is_err());
+}
+
+#[test]
+fn test_manifest() {
+    use bitcoin::taproot::TapLeafHash;
+    use bitcoin_script::{chunker::MANIFEST_VERSION, Manifest};
+
+    let script = script! {
+        OP_1
+        for i in 0..5 {
+            { i + 20 } OP_ADD OP_1ADD
+        }
+    };
+    let chunks = Chunker::new(script, 4, 0)
+        .find_chunks_and_analyze_stack()
+        .unwrap();
+    let manifest = Manifest::new(&chunks);
+    assert_eq!(manifest.version, MANIFEST_VERSION);
+    assert_eq!(manifest.chunks.len(), chunks.len());
+    for (entry, chunk) in manifest.chunks.iter().zip(&chunks) {
+        assert_eq!(entry.size, chunk.size());
+        assert_eq!(entry.interface.as_ref(), chunk.interface());
+        assert_eq!(entry.content_hash, sha256::Hash::hash(&chunk.to_bytes()));
+        assert_eq!(
+            entry.leaf_hash,
+            TapLeafHash::from_script(&chunk.compile(), LeafVersion::TapScript)
+        );
+    }
+    assert_eq!(manifest.chunks[3].id, 3);
+
+    // A verifier rejects changed chunks
+    let mut changed = chunks.clone();
+    changed.swap(1, 2);
+    assert_ne!(Manifest::new(&changed), manifest);
+
+    #[cfg(feature = "json")]
+    {
+        let json = manifest.to_json();
+        assert_eq!(Manifest::from_json(&json).unwrap(), manifest);
+        assert!(
+            Manifest::from_json(&json.replacen("\"version\": 1", "\"version\": 2", 1)).is_err()
+        );
+    }
+}
+
+#[cfg(feature = "miniscript")]
+#[test]
+fn test_from_miniscript() {
+    use miniscript::{Miniscript, Tap};
+    use std::str::FromStr;
+
+    let secp = Secp256k1::new();
+    let key = |byte| {
+        let secret_key = SecretKey::from_slice(&[byte; 32]).unwrap();
+        secret_key.x_only_public_key(&secp).0
+    };
+    let miniscript = Miniscript::<bitcoin::XOnlyPublicKey, Tap>::from_str(&format!(
+        "and_v(v:pk({}),or_i(pk({}),older(144)))",
+        key(1),
+        key(2)
+    ))
+    .unwrap();
+    let compiled = miniscript.encode();
+
+    let condition = Script::from(miniscript);
+    assert_eq!(condition.debug_identifier, "miniscript");
+    assert!(condition
+        .blocks
+        .iter()
+        .any(|block| matches!(block, Block::Call(_))));
+
+    // Compose the condition with a gadget
+    let script = script! {
+        { condition }
+        OP_SHA256 { vec![0u8; 32] } OP_EQUAL
+    };
+    let mut expected = compiled.into_bytes();
+    expected.extend([0xa8, 0x20]);
+    expected.extend([0; 32]);
+    expected.push(0x87);
+    assert_eq!(script.compile().into_bytes(), expected);
+}
+
+#[test]
+fn test_push_keys() {
+    let secp = Secp256k1::new();
+    let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let inner = secp256k1::PublicKey::from_secret_key(&secp, &secret_key);
+    let compressed = PublicKey::new(inner);
+    let uncompressed = PublicKey::new_uncompressed(inner);
+    let (x_only, _) = inner.x_only_public_key();
+
+    let script = script! {
+        { inner }
+        { compressed }
+        { CompressedPublicKey(inner) }
+        { uncompressed }
+        { x_only }
+    };
+
+    let mut expected = Vec::new();
+    for _ in 0..3 {
+        expected.push(33);
+        expected.extend(inner.serialize());
+    }
+    expected.push(65);
+    expected.extend(inner.serialize_uncompressed());
+    expected.push(32);
+    expected.extend(x_only.serialize());
+    assert_eq!(script.compile().to_bytes(), expected);
+}
+
 #[test]
 #[should_panic]
 fn test_usize_conversion() {
@@ -83,19 +978,52 @@ fn test_minimal_byte_opcode() {
     );
 }
 
-fn script_from_func() -> Script {
-    script! { OP_ADD }
-}
-
+fn script_from_func() -> Script {
+    script! { OP_ADD }
+}
+
+#[test]
+fn test_simple_loop() {
+    let script = script! {
+        for _ in 0..3 {
+            OP_ADD
+        }
+    };
+
+    assert_eq!(script.compile().to_bytes(), vec![147, 147, 147])
+}
+
+#[test]
+fn test_loop_variable_substitution() {
+    fn push_twice(n: u32) -> Script {
+        script! { { n } { n } }
+    }
+
+    let script = script! {
+        for i in 0..3_u32 {
+            { i + 1 }
+            { push_twice(i) }
+            for _ in 0..i {
+                OP_ADD
+            }
+        }
+    };
+
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![81, 0, 0, 82, 81, 81, 147, 83, 82, 82, 147, 147]
+    );
+}
+
 #[test]
-fn test_simple_loop() {
-    let script = script! {
-        for _ in 0..3 {
-            OP_ADD
+fn test_loop_without_import() {
+    let script = bitcoin_script::script! {
+        for _ in 0..2 {
+            OP_DUP
         }
     };
 
-    assert_eq!(script.compile().to_bytes(), vec![147, 147, 147])
+    assert_eq!(script.compile().to_bytes(), vec![118, 118]);
 }
 
 #[test]
@@ -150,6 +1078,258 @@ fn test_if() {
     assert_eq!(script.compile().to_bytes(), vec![83, 85]);
 }
 
+#[test]
+fn test_if_parameterized() {
+    fn gadget(double: bool, extra: Option<i64>) -> Script {
+        script! {
+            if double {
+                OP_DUP
+                OP_ADD
+            }
+            if let Some(value) = extra {
+                { value }
+                OP_ADD
+            } else {
+                OP_1ADD
+            }
+        }
+    }
+
+    assert_eq!(gadget(true, None).compile().to_bytes(), vec![118, 147, 139]);
+    assert_eq!(gadget(false, Some(5)).compile().to_bytes(), vec![85, 147]);
+}
+
+#[test]
+fn test_subroutine() {
+    let script = script! {
+        OP_1
+        def double {
+            OP_DUP
+            OP_ADD
+        }
+        double
+        double
+        for _ in 0..2 {
+            double
+        }
+    };
+
+    assert!(matches!(script.blocks[1], Block::Call(_)));
+    assert_eq!(script.blocks[1], script.blocks[2]);
+    assert!(script.debug_info(1).ends_with("::double"));
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![81, 118, 147, 118, 147, 118, 147, 118, 147]
+    );
+}
+
+#[test]
+fn test_let_binding() {
+    let data = vec![0xabu8; 20];
+    let script = script! {
+        let len = data.len();
+        { data.clone() }
+        OP_SIZE
+        { len }
+        OP_EQUALVERIFY
+        let len = len * 2;
+        { len }
+    };
+
+    let mut expected = vec![20];
+    expected.extend(vec![0xab; 20]);
+    expected.extend(vec![130, 1, 20, 136, 1, 40]);
+    assert_eq!(script.compile().to_bytes(), expected);
+}
+
+#[test]
+fn test_assert_stack_delta() {
+    let script = script! {
+        OP_ADD
+        assert_stack_delta!(-1)
+        for _ in 0..3 {
+            OP_DUP
+        }
+        assert_stack_delta!(2)
+    };
+
+    assert_eq!(script.compile().to_bytes(), vec![147, 118, 118, 118]);
+}
+
+#[test]
+#[should_panic(expected = "Stack delta of test::test_wrong_stack_delta is -1, expected 0")]
+fn test_wrong_stack_delta() {
+    script! {
+        OP_ADD
+        assert_stack_delta!(0)
+    };
+}
+
+#[test]
+fn test_metadata() {
+    let inner = script! {
+        /// @label: hash lock
+        OP_SHA256
+        /// The digest is pushed by the caller
+        OP_EQUALVERIFY
+    };
+    let script = script! {
+        /// @label: outer
+        /// @author: alice
+        { inner.clone() }
+    };
+
+    assert_eq!(inner.get_metadata("label"), Some("hash lock"));
+    assert_eq!(inner.metadata().len(), 1);
+    assert_eq!(script.get_metadata("label"), Some("outer"));
+    assert_eq!(script.get_metadata("author"), Some("alice"));
+    // The inner script is called and keeps its own label
+    let Block::Call(id) = script.blocks[0] else {
+        panic!("Expected Block::Call, got {:?}", script.blocks[0]);
+    };
+    assert_eq!(
+        script.get_structured_script(&id).get_metadata("label"),
+        Some("hash lock")
+    );
+    assert_eq!(script.compile().to_bytes(), vec![168, 136]);
+
+    // Scripts that only differ in their metadata are stored separately
+    let first = script! { OP_SHA256 }.set_metadata("label", "first");
+    let second = script! { OP_SHA256 }.set_metadata("label", "second");
+    let script = script! { OP_1 { first } { second } };
+    let labels: Vec<_> = script.blocks[1..]
+        .iter()
+        .map(|block| match block {
+            Block::Call(id) => script.get_structured_script(id).get_metadata("label"),
+            block => panic!("Expected Block::Call, got {:?}", block),
+        })
+        .collect();
+    assert_eq!(labels, vec![Some("first"), Some("second")]);
+}
+
+#[test]
+fn test_witness_template() {
+    let hash = sha256::Hash::hash(&[42u8; 32]).to_byte_array();
+    let script = script! {
+        /// @witness: signature, preimage
+        OP_SHA256 { hash.to_vec() } OP_EQUALVERIFY
+        { vec![2u8; 33] } OP_CHECKSIG
+    };
+    let template = script.witness_template();
+    assert_eq!(template.items(), &["signature", "preimage"]);
+
+    let witness = template
+        .fill(&[("preimage", &[42u8; 32]), ("signature", &[1u8; 64])])
+        .unwrap();
+    assert_eq!(witness.len(), 2);
+    assert_eq!(witness.nth(0), Some(&[1u8; 64][..]));
+    assert_eq!(witness.nth(1), Some(&[42u8; 32][..]));
+
+    assert_eq!(
+        template.fill(&[("signature", &[1u8; 64])]),
+        Err(WitnessError::MissingItem("preimage".to_string()))
+    );
+    assert_eq!(
+        template.fill(&[("preimage", &[]), ("signature", &[]), ("pubkey", &[])]),
+        Err(WitnessError::UnknownItem("pubkey".to_string()))
+    );
+
+    let script = script! {
+        OP_ADD OP_ADD
+    };
+    assert_eq!(
+        script.witness_template().items(),
+        &["input_0", "input_1", "input_2"]
+    );
+}
+
+#[test]
+#[should_panic(expected = "reads 2 stack elements, but its witness metadata names 1")]
+fn test_witness_template_mismatch() {
+    let script = script! {
+        /// @witness: signature
+        OP_CHECKSIG
+    };
+    script.witness_template();
+}
+
+#[test]
+fn test_match() {
+    enum Hash {
+        Sha256,
+        Hash160,
+        Ripemd160,
+    }
+
+    fn hash_lock(hash: Hash, digest: Vec<u8>) -> Script {
+        script! {
+            match hash {
+                Hash::Sha256 => { OP_SHA256 }
+                Hash::Hash160 => OP_HASH160,
+                Hash::Ripemd160 => OP_RIPEMD160
+            }
+            { digest }
+            OP_EQUAL
+        }
+    }
+
+    assert_eq!(
+        hash_lock(Hash::Sha256, vec![1, 2]).compile().to_bytes(),
+        vec![168, 2, 1, 2, 135]
+    );
+    assert_eq!(
+        hash_lock(Hash::Hash160, vec![1, 2]).compile().to_bytes(),
+        vec![169, 2, 1, 2, 135]
+    );
+    assert_eq!(
+        hash_lock(Hash::Ripemd160, vec![1, 2]).compile().to_bytes(),
+        vec![166, 2, 1, 2, 135]
+    );
+
+    let script = script! {
+        for i in 0..4 {
+            match i % 3 {
+                0 => { OP_DUP }
+                n if n == 1 => { { n } OP_ADD }
+                _ => {}
+            }
+        }
+    };
+    assert_eq!(script.compile().to_bytes(), vec![118, 81, 147, 118]);
+}
+
+#[test]
+fn test_while_loop() {
+    let mut limbs = vec![3, 2, 1];
+    let mut stack = vec![4, 5, 6];
+    let script = script! {
+        while let Some(limb) = limbs.pop() {
+            { limb }
+            OP_ADD
+        }
+        while stack.len() > 1 {
+            let top = stack.pop().unwrap();
+            { top }
+            OP_SUB
+        }
+    };
+
+    assert_eq!(
+        script.compile().to_bytes(),
+        vec![81, 147, 82, 147, 83, 147, 86, 148, 85, 148]
+    );
+}
+
+#[test]
+#[should_panic(expected = "while loop exceeded 1000000 iterations")]
+fn test_unbounded_while_loop() {
+    let _script = script! {
+        while true {
+            OP_NOP
+        }
+    };
+}
+
 #[test]
 fn test_performance_loop() {
     let mut nested_script = script! {
@@ -304,9 +1484,11 @@ fn test_push_witness() {
     );
 }
 
+#[cfg(feature = "serde")]
 #[test]
 fn test_serialization() {
     let script = script! {
+        /// @label: example
         // Example script
         for i in 0..10 {
             {i}
@@ -315,6 +1497,7 @@ fn test_serialization() {
             OP_ADD
             OP_ADD
         }
+        { script! { OP_1 { Script::placeholder("gadget", 5) } } }
     };
 
     let binary_data = bincode::serialize(&script).unwrap();
@@ -323,4 +1506,373 @@ fn test_serialization() {
 
     let deserialized: Script = bincode::deserialize(&binary_data).unwrap();
     assert_eq!(deserialized, script);
+    assert!(deserialized.structurally_eq(&script));
+    assert_eq!(deserialized.to_asm_tree(), script.to_asm_tree());
+    assert_eq!(deserialized.get_metadata("label"), Some("example"));
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_cbor() {
+    use bitcoin_script::{builder::CborError, Manifest};
+
+    let script = script! {
+        /// @label: example
+        def gadget {
+            OP_DUP OP_ADD
+        }
+
+        OP_1
+        for _ in 0..10 {
+            gadget
+        }
+        { Script::placeholder("tail", 5) }
+    };
+    let decoded = Script::from_cbor(&script.to_cbor()).unwrap();
+    assert!(decoded.structurally_eq(&script));
+    assert_eq!(decoded.get_metadata("label"), Some("example"));
+
+    let mut script = decoded;
+    assert!(script.fill("tail", script! { OP_DROP }));
+    let chunks = Chunker::new(script, 6, 0)
+        .find_chunks_and_analyze_stack()
+        .unwrap();
+    let mut manifest = Manifest::new(&chunks);
+    assert_eq!(
+        Manifest::from_cbor(&manifest.to_cbor()),
+        Ok(manifest.clone())
+    );
+
+    manifest.version = 2;
+    assert_eq!(
+        Manifest::from_cbor(&manifest.to_cbor()),
+        Err(CborError::UnsupportedVersion(2))
+    );
+    assert!(matches!(
+        Script::from_cbor(&[0xff]),
+        Err(CborError::InvalidData(_))
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_resume_chunking() {
+    use bitcoin_script::ChunkerState;
+
+    let script = script! {
+        def gadget {
+            OP_1ADD OP_1ADD OP_1ADD OP_1ADD OP_1ADD
+        }
+
+        OP_1
+        for _ in 0..10 {
+            gadget
+        }
+    };
+    let sizes = Chunker::new(script.clone(), 20, 5).find_chunks().unwrap();
+
+    let mut chunker = Chunker::new(script, 20, 5);
+    let first_chunk = chunker.next().unwrap().unwrap();
+    let binary_data = bincode::serialize(&chunker.state()).unwrap();
+    let state: ChunkerState = bincode::deserialize(&binary_data).unwrap();
+    assert_eq!(state.chunk_sizes(), &[first_chunk.size()]);
+    assert!(!state.is_done());
+
+    let mut resumed_sizes = vec![first_chunk.size()];
+    resumed_sizes.extend(Chunker::resume(state).find_chunks().unwrap());
+    assert_eq!(resumed_sizes, sizes);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_chunks_json() {
+    use bitcoin_script::{chunk_fingerprint, chunker::JsonError, Chunk};
+
+    let script = script! {
+        def gadget {
+            OP_DUP OP_ADD OP_DUP OP_ADD OP_DUP OP_ADD
+        }
+
+        OP_1
+        for _ in 0..8 {
+            gadget
+        }
+    };
+    let chunks = Chunker::new(script, 20, 5)
+        .find_chunks_and_analyze_stack()
+        .unwrap();
+    let json = Chunk::to_json(&chunks);
+    let imported = Chunk::from_json(&json).unwrap();
+    assert_eq!(imported.len(), chunks.len());
+    for (chunk, imported) in chunks.iter().zip(&imported) {
+        assert_eq!(imported.size(), chunk.size());
+        assert_eq!(imported.stats(), chunk.stats());
+        assert_eq!(imported.provenance(), chunk.provenance());
+    }
+    assert_eq!(chunk_fingerprint(&imported), chunk_fingerprint(&chunks));
+    assert_eq!(Chunk::to_json(&imported), json);
+
+    let chunk = |script: &str, size: usize, end: usize| {
+        format!(
+            r#"{{"version":1,"chunks":[{{"script":"{}","size":{},"interface":null,
+            "provenance":[{{"script":"main","start":0,"end":{}}}]}}]}}"#,
+            script, size, end
+        )
+    };
+    let imported = Chunk::from_json(&chunk("5193", 2, 2)).unwrap();
+    assert_eq!(imported[0].interface(), None);
+    assert!(matches!(
+        Chunk::from_json(&chunk("5193", 3, 2)),
+        Err(JsonError::SizeMismatch {
+            index: 0,
+            size: 3,
+            actual: 2
+        })
+    ));
+    assert!(matches!(
+        Chunk::from_json(&chunk("519", 2, 2)),
+        Err(JsonError::InvalidScript(0))
+    ));
+    assert!(matches!(
+        Chunk::from_json(&chunk("5193", 2, 1)),
+        Err(JsonError::InvalidProvenance(0))
+    ));
+    assert!(matches!(
+        Chunk::from_json(&chunk("5102", 2, 2)),
+        Err(JsonError::InvalidProvenance(0))
+    ));
+    assert!(matches!(
+        Chunk::from_json(&json.replacen("\"version\": 1", "\"version\": 2", 1)),
+        Err(JsonError::UnsupportedVersion(2))
+    ));
+    assert!(matches!(Chunk::from_json("[]"), Err(JsonError::Json(_))));
+}
+
+#[test]
+fn test_source_location() {
+    let line = line!() + 1;
+    let script = script! {
+        OP_1 OP_IF
+    };
+    let location = script.source_location().unwrap();
+    assert_eq!(location.file, file!());
+    assert_eq!(location.line, line);
+    assert_eq!(location.to_string(), format!("tests/test.rs:{}", line));
+
+    let err = StackAnalyzer::new().try_analyze(&script).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Unclosed OP_IF in test::test_source_location (tests/test.rs:{}) at offset 1",
+            line
+        )
+    );
+}
+
+#[test]
+fn test_size_report() {
+    let script = script! {
+        def double {
+            OP_DUP OP_ADD
+        }
+        def hash {
+            OP_SHA256 OP_SHA256 OP_SHA256
+        }
+
+        OP_1 double double hash
+        repeat!(4, OP_1ADD)
+    };
+
+    assert_eq!(
+        script.size_report(),
+        vec![
+            ("double".to_string(), 4),
+            ("repeat".to_string(), 4),
+            ("hash".to_string(), 3),
+            ("test_size_report".to_string(), 1),
+        ]
+    );
+    let total: usize = script.size_report().iter().map(|(_, size)| size).sum();
+    assert_eq!(total, script.len());
+
+    script.assert_max_size(12);
+}
+
+#[test]
+#[should_panic(
+    expected = "is 12 bytes, expected at most 11. Largest parts: double (4 bytes), repeat (4 bytes)"
+)]
+fn test_assert_max_size() {
+    script! {
+        def double {
+            OP_DUP OP_ADD
+        }
+
+        OP_1 double double OP_SHA256 OP_SHA256 OP_SHA256
+        repeat!(4, OP_1ADD)
+    }
+    .assert_max_size(11);
+}
+
+#[test]
+fn test_compile_into() {
+    let script = script! {
+        def double {
+            OP_DUP OP_ADD
+        }
+
+        OP_1 double double
+        repeat!(3, OP_1ADD)
+    };
+
+    let mut buffer = vec![0x51];
+    script.compile_into(&mut buffer);
+    script.compile_into(&mut buffer);
+    let compiled = script.compile();
+    assert_eq!(buffer[0], 0x51);
+    assert_eq!(&buffer[1..1 + compiled.len()], compiled.as_bytes());
+    assert_eq!(&buffer[1 + compiled.len()..], compiled.as_bytes());
+}
+
+#[test]
+fn test_from_script_buf() {
+    let compiled = script! {
+        OP_1
+        OP_IF
+            OP_2
+            OP_IF OP_3 OP_ENDIF
+        OP_ELSE
+            OP_4
+        OP_ENDIF
+        { vec![1u8; 80] } OP_DROP
+    }
+    .compile();
+
+    // Pushes of more than 75 bytes take an extra length byte
+    let raw = Script::new("raw").push_script(compiled.clone());
+    assert_eq!(raw.len(), compiled.len());
+
+    let script = Script::from_script_buf(&compiled).unwrap();
+    assert_eq!(script.clone().compile(), compiled);
+    assert_eq!(script.blocks.len(), 3);
+    let outer_if = script.get_block_by_name("if_1").unwrap();
+    assert_eq!(outer_if.len(), 8);
+    // OP_IF with the first branch, the nested OP_IF and the OP_ELSE branch
+    assert_eq!(outer_if.blocks.len(), 3);
+    assert_eq!(script.get_block_by_name("if_3").unwrap().len(), 3);
+
+    let script = Script::from_script_buf_with_borders(&compiled, &[91]).unwrap();
+    assert_eq!(script.blocks.len(), 4);
+    assert_eq!(script.clone().compile(), compiled);
+    let chunks = Chunker::new(script, 85, 0).find_chunks().unwrap();
+    assert_eq!(chunks, vec![9, 83]);
+
+    assert_eq!(
+        Script::from_script_buf_with_borders(&compiled, &[10]),
+        Err(ParseError::InvalidBorder(10))
+    );
+    assert_eq!(
+        Script::from_script_buf(&ScriptBuf::from_bytes(vec![0x51, 0x02, 0x01])),
+        Err(ParseError::InvalidInstruction {
+            offset: 1,
+            error: bitcoin::script::Error::EarlyEndOfScript
+        })
+    );
+    assert_eq!(
+        Script::from_script_buf(&ScriptBuf::from_bytes(vec![0x51, 0x68])),
+        Err(ParseError::UnbalancedConditional {
+            opcode: OP_ENDIF,
+            offset: 1
+        })
+    );
+    assert_eq!(
+        Script::from_script_buf(&ScriptBuf::from_bytes(vec![0x51, 0x64, 0x51])),
+        Err(ParseError::UnbalancedConditional {
+            opcode: OP_NOTIF,
+            offset: 1
+        })
+    );
+}
+
+#[test]
+fn test_parse_asm() {
+    let script = Script::parse_asm(
+        "# hash lock
+        OP_SHA256 OP_PUSHBYTES_2 abcd OP_EQUALVERIFY
+        100 OP_CSV OP_DROP 0x0102 -1 EQUAL",
+    )
+    .unwrap();
+    assert_eq!(
+        script.compile().as_bytes(),
+        vec![168, 2, 0xab, 0xcd, 136, 1, 100, 178, 117, 2, 1, 2, 79, 135]
+    );
+
+    let script = Script::parse_asm("OP_1 IF OP_2 OP_ELSE OP_3 OP_ENDIF OP_NOP2").unwrap();
+    assert_eq!(script.blocks.len(), 3);
+    assert!(matches!(script.blocks[1], Block::Call(_)));
+    assert_eq!(
+        script.compile().as_bytes(),
+        vec![81, 99, 82, 103, 83, 104, 177]
+    );
+
+    let compiled = script! {
+        OP_DUP OP_HASH160 0x00112233445566778899aabbccddeeff00112233 OP_EQUALVERIFY
+        OP_CHECKSIG
+    }
+    .compile();
+    assert_eq!(
+        Script::parse_asm(&compiled.to_asm_string())
+            .unwrap()
+            .compile(),
+        compiled
+    );
+
+    // Pushes are minimally encoded like in `include_script!`, so that the script compiles
+    let script = Script::parse_asm("0x05 0x81 0x00 OP_PUSHBYTES_1 00").unwrap();
+    assert_eq!(
+        script.compile().as_bytes(),
+        vec![0x55, 0x4f, 0x01, 0x00, 0x01, 0x00]
+    );
+    assert_eq!(
+        Script::parse_asm("OP_PUSHBYTES_1 05"),
+        Err(ParseError::NonMinimalPush {
+            opcode: bitcoin::opcodes::all::OP_PUSHBYTES_1,
+            minimal: bitcoin::opcodes::all::OP_PUSHNUM_5,
+            len: 1
+        })
+    );
+    assert_eq!(
+        Script::parse_asm("OP_PUSHDATA1 0102")
+            .unwrap_err()
+            .to_string(),
+        "OP_PUSHDATA1 is not the minimal push of 2 bytes, use OP_PUSHBYTES_2"
+    );
+
+    assert_eq!(
+        Script::parse_asm("OP_DUP OP_FOO"),
+        Err(ParseError::UnknownOpcode("OP_FOO".to_string()))
+    );
+    assert_eq!(
+        Script::parse_asm("OP_PUSHBYTES_3 abcd"),
+        Err(ParseError::PushLengthMismatch {
+            opcode: bitcoin::opcodes::all::OP_PUSHBYTES_3,
+            expected: 3,
+            actual: 2
+        })
+    );
+    assert_eq!(
+        Script::parse_asm("OP_PUSHDATA1"),
+        Err(ParseError::MissingPushData("OP_PUSHDATA1".to_string()))
+    );
+    assert_eq!(
+        Script::parse_asm("0xabc"),
+        Err(ParseError::InvalidHex("abc".to_string()))
+    );
+    assert_eq!(
+        Script::parse_asm("OP_1 OP_ENDIF"),
+        Err(ParseError::UnbalancedConditional {
+            opcode: OP_ENDIF,
+            offset: 1
+        })
+    );
 }